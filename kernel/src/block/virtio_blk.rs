@@ -28,11 +28,24 @@ impl VirtIOBlkDriver {
     }
 }
 
+/// Returns the first sector of logical block `block_id`, after checking that a
+/// transfer of `len` bytes from there covers a whole number of blocks.
+fn start_sector(block_size: usize, block_id: usize, len: usize) -> Result<usize, SvsmError> {
+    if len % block_size != 0 {
+        return Err(SvsmError::Block(BlockDeviceError::Failed));
+    }
+    block_id
+        .checked_mul(block_size / SECTOR_SIZE)
+        .ok_or(SvsmError::Block(BlockDeviceError::Failed))
+}
+
 impl BlockDriver for VirtIOBlkDriver {
     fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<(), SvsmError> {
         self.0.device.locked_do(|dev| {
+            let sector = start_sector(dev.logical_block_size(), block_id, buf.len())?;
+            // Logical blocks are at most a page, so every chunk is whole blocks.
             buf.chunks_mut(PAGE_SIZE)
-                .zip((block_id..).step_by(PAGE_SIZE / SECTOR_SIZE))
+                .zip((sector..).step_by(PAGE_SIZE / SECTOR_SIZE))
                 .try_for_each(|(chunk, pos)| {
                     dev.read_blocks(pos, chunk)
                         .map_err(|_| SvsmError::Block(BlockDeviceError::Failed))
@@ -42,8 +55,9 @@ impl BlockDriver for VirtIOBlkDriver {
 
     fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Result<(), SvsmError> {
         self.0.device.locked_do(|dev| {
+            let sector = start_sector(dev.logical_block_size(), block_id, buf.len())?;
             buf.chunks(PAGE_SIZE)
-                .zip((block_id..).step_by(PAGE_SIZE / SECTOR_SIZE))
+                .zip((sector..).step_by(PAGE_SIZE / SECTOR_SIZE))
                 .try_for_each(|(chunk, pos)| {
                     dev.write_blocks(pos, chunk)
                         .map_err(|_| SvsmError::Block(BlockDeviceError::Failed))
@@ -52,19 +66,25 @@ impl BlockDriver for VirtIOBlkDriver {
     }
 
     fn block_size_log2(&self) -> u8 {
-        SECTOR_SIZE.ilog2().try_into().unwrap()
+        self.0
+            .device
+            .lock()
+            .logical_block_size()
+            .ilog2()
+            .try_into()
+            .unwrap()
     }
 
     fn size(&self) -> usize {
-        self.0.device.lock().capacity() as usize * SECTOR_SIZE
+        self.0.device.lock().capacity_bytes() as usize
     }
 
     fn flush(&self) -> Result<(), SvsmError> {
-        self.0
-            .device
-            .lock()
-            .flush()
-            .map_err(|_| SvsmError::Block(BlockDeviceError::Failed))
+        match self.0.device.lock().flush() {
+            // Without VIRTIO_BLK_F_FLUSH there is no write cache to flush.
+            Ok(()) | Err(virtio_drivers::Error::Unsupported) => Ok(()),
+            Err(_) => Err(SvsmError::Block(BlockDeviceError::Failed)),
+        }
     }
 }
 
@@ -77,20 +97,27 @@ mod tests {
     extern crate alloc;
     use super::*;
 
-    /// Find the first virtio-blk device in the hardware-info list
-    fn get_blk_device() -> VirtIOBlkDriver {
+    /// Find the first virtio-blk device in the hardware-info list with the given
+    /// logical block size
+    fn get_blk_device_with_block_size(block_size_log2: u8) -> VirtIOBlkDriver {
         let cfg = FwCfg::new(SVSM_PLATFORM.get_io_port());
 
         let dev = cfg
             .get_virtio_mmio_addresses()
             .unwrap_or_default()
             .iter()
-            .find_map(|a| VirtIOBlkDriver::new(PhysAddr::from(*a)).ok())
+            .filter_map(|a| VirtIOBlkDriver::new(PhysAddr::from(*a)).ok())
+            .find(|dev| dev.block_size_log2() == block_size_log2)
             .expect("No virtio-blk device found");
 
         dev
     }
 
+    /// Find the virtio-blk device backed by the state image
+    fn get_blk_device() -> VirtIOBlkDriver {
+        get_blk_device_with_block_size(SECTOR_SIZE.ilog2() as u8)
+    }
+
     /// Get the sha256 sum of the disk image from the host (see `scripts/test-in-svsm.sh`)
     fn get_image_hash_from_host() -> Option<[u8; 32]> {
         use crate::serial::Terminal;
@@ -169,6 +196,88 @@ mod tests {
         assert_eq!(expected_hash, *hash);
     }
 
+    #[test]
+    #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+    fn test_virtio_4k_blocks() {
+        if is_qemu_test_env() {
+            virtio_4k_blocks();
+        }
+    }
+
+    /// Check that the device with 4096 byte logical blocks (see
+    /// `scripts/test-in-svsm.sh`) rejects transfers which aren't whole blocks, and
+    /// that data written in whole blocks, including several at once, reads back.
+    fn virtio_4k_blocks() {
+        use alloc::vec;
+
+        let blk = get_blk_device_with_block_size(12);
+        let block_size = 1 << blk.block_size_log2();
+        assert_eq!(block_size, PAGE_SIZE);
+        assert!(blk.size() >= 4 * block_size);
+
+        let mut buffer = vec![0u8; 3 * block_size];
+        assert!(blk.read_blocks(0, &mut buffer[..SECTOR_SIZE]).is_err());
+        assert!(blk.read_blocks(0, &mut buffer[..9 * SECTOR_SIZE]).is_err());
+        assert!(blk.write_blocks(1, &buffer[..SECTOR_SIZE]).is_err());
+
+        let mut pattern = (0u64..).flat_map(|x| x.to_le_bytes());
+        buffer.fill_with(|| pattern.next().unwrap());
+        blk.write_blocks(1, &buffer).unwrap();
+        blk.flush().unwrap();
+
+        let mut read_back = vec![0u8; 3 * block_size];
+        blk.read_blocks(1, &mut read_back).unwrap();
+        assert_eq!(read_back, buffer);
+        blk.read_blocks(2, &mut read_back[..block_size]).unwrap();
+        assert_eq!(read_back[..block_size], buffer[block_size..2 * block_size]);
+    }
+
+    #[test]
+    #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+    fn test_virtio_serial() {
+        if is_qemu_test_env() {
+            // Set on the state disk by `scripts/launch_guest.sh`.
+            let blk = get_blk_device();
+            assert_eq!(blk.0.device.lock().serial().unwrap(), "svsm-state");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+    fn test_virtio_discard_write_zeroes() {
+        if is_qemu_test_env() {
+            virtio_discard_write_zeroes();
+        }
+    }
+
+    /// Fill some blocks of the 4096 byte block device, then check that discarding
+    /// them succeeds and that writing zeroes clears them without touching the next one.
+    fn virtio_discard_write_zeroes() {
+        use alloc::vec;
+
+        let blk = get_blk_device_with_block_size(12);
+        let block_size = 1 << blk.block_size_log2();
+        let sectors_per_block = (block_size / SECTOR_SIZE) as u64;
+
+        let mut pattern = (0u64..).flat_map(|x| x.to_le_bytes());
+        let mut buffer = vec![0u8; 5 * block_size];
+        buffer.fill_with(|| pattern.next().unwrap());
+        blk.write_blocks(8, &buffer).unwrap();
+
+        let sectors = 8 * sectors_per_block..12 * sectors_per_block;
+        {
+            let mut dev = blk.0.device.lock();
+            dev.discard(sectors.clone()).unwrap();
+            dev.write_zeroes(sectors).unwrap();
+        }
+        blk.flush().unwrap();
+
+        let mut read_back = vec![0xffu8; 5 * block_size];
+        blk.read_blocks(8, &mut read_back).unwrap();
+        assert!(read_back[..4 * block_size].iter().all(|&byte| byte == 0));
+        assert_eq!(read_back[4 * block_size..], buffer[4 * block_size..]);
+    }
+
     #[test]
     #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
     fn test_virtio_write_4sectors() {
@@ -218,7 +327,7 @@ mod tests {
 
         let mut hasher = Sha256::new();
 
-        let mut gen = (0u64..).flat_map(|x| x.to_le_bytes());
+        let mut pattern = (0u64..).flat_map(|x| x.to_le_bytes());
 
         for (pos, sectors) in (0..n_sectors)
             .step_by(sectors_at_once)
@@ -226,7 +335,7 @@ mod tests {
         {
             buffer.truncate(sectors * SECTOR_SIZE);
 
-            buffer.fill_with(|| gen.next().unwrap());
+            buffer.fill_with(|| pattern.next().unwrap());
 
             blk.write_blocks(pos, &buffer).unwrap();
             blk.flush().unwrap();
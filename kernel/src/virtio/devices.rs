@@ -10,6 +10,7 @@ extern crate alloc;
 use alloc::boxed::Box;
 use core::ptr::NonNull;
 use virtio_drivers::device::blk::VirtIOBlk;
+use virtio_drivers::device::rng::VirtIORng;
 use virtio_drivers::transport::mmio::{MmioError, MmioTransport};
 use virtio_drivers::transport::{DeviceType, Transport};
 use virtio_drivers::PAGE_SIZE;
@@ -32,28 +33,39 @@ impl core::fmt::Debug for VirtIOBlkDevice {
     }
 }
 
-impl VirtIOBlkDevice {
-    pub fn new(mmio_base: PhysAddr) -> Result<Box<Self>, SvsmError> {
-        virtio_init();
+/// Maps the MMIO config area at `mmio_base` and creates a transport for it,
+/// checking that the device there is of the expected type.
+fn mmio_transport(
+    mmio_base: PhysAddr,
+    device_type: DeviceType,
+) -> Result<(MmioTransport<SvsmHal>, GlobalRangeGuard), SvsmError> {
+    virtio_init();
 
-        let mem = map_global_range_4k_shared(mmio_base, PAGE_SIZE, PTEntryFlags::data())?;
+    let mem = map_global_range_4k_shared(mmio_base, PAGE_SIZE, PTEntryFlags::data())?;
 
-        // Not expected to fail, because mem exists.
-        let header = NonNull::new(mem.addr().as_mut_ptr()).unwrap();
+    // Not expected to fail, because mem exists.
+    let header = NonNull::new(mem.addr().as_mut_ptr()).unwrap();
 
-        // SAFETY: `header` is the MMIO config area; we have to trust the content is valid.
-        let transport = unsafe {
-            // TODO: Use more detailed error types ?
-            MmioTransport::<SvsmHal>::new(header).map_err(|e| match e {
-                MmioError::BadMagic(_) => VirtioError::InvalidDevice,
-                MmioError::UnsupportedVersion(_) => VirtioError::InvalidDevice,
-                MmioError::ZeroDeviceId => VirtioError::InvalidDevice,
-            })?
-        };
+    // SAFETY: `header` is the MMIO config area; we have to trust the content is valid.
+    let transport = unsafe {
+        // TODO: Use more detailed error types ?
+        MmioTransport::<SvsmHal>::new(header).map_err(|e| match e {
+            MmioError::BadMagic(_) => VirtioError::InvalidDevice,
+            MmioError::UnsupportedVersion(_) => VirtioError::InvalidDevice,
+            MmioError::ZeroDeviceId => VirtioError::InvalidDevice,
+        })?
+    };
 
-        if transport.device_type() != DeviceType::Block {
-            return Err(VirtioError::InvalidDeviceType)?;
-        }
+    if transport.device_type() != device_type {
+        return Err(VirtioError::InvalidDeviceType)?;
+    }
+
+    Ok((transport, mem))
+}
+
+impl VirtIOBlkDevice {
+    pub fn new(mmio_base: PhysAddr) -> Result<Box<Self>, SvsmError> {
+        let (transport, mem) = mmio_transport(mmio_base, DeviceType::Block)?;
 
         let blk = VirtIOBlk::new(transport).map_err(|_| VirtioError::InvalidDevice)?;
 
@@ -63,3 +75,68 @@ impl VirtIOBlkDevice {
         }))
     }
 }
+
+pub struct VirtIORngDevice {
+    pub device: SpinLock<VirtIORng<SvsmHal, MmioTransport<SvsmHal>>>,
+    _mmio_space: GlobalRangeGuard,
+}
+
+impl core::fmt::Debug for VirtIORngDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("VirtIORngDevice").finish()
+    }
+}
+
+impl VirtIORngDevice {
+    pub fn new(mmio_base: PhysAddr) -> Result<Box<Self>, SvsmError> {
+        let (transport, mem) = mmio_transport(mmio_base, DeviceType::EntropySource)?;
+
+        let rng = VirtIORng::new(transport).map_err(|_| VirtioError::InvalidDevice)?;
+
+        Ok(Box::new(VirtIORngDevice {
+            device: SpinLock::new(rng),
+            _mmio_space: mem,
+        }))
+    }
+}
+
+#[cfg(all(test, test_in_svsm))]
+mod tests {
+    use super::*;
+    use crate::{fw_cfg::FwCfg, platform::SVSM_PLATFORM, testutils::is_qemu_test_env};
+
+    /// Find the virtio-rng device added by `scripts/test-in-svsm.sh`
+    fn get_rng_device() -> Box<VirtIORngDevice> {
+        let cfg = FwCfg::new(SVSM_PLATFORM.get_io_port());
+
+        cfg.get_virtio_mmio_addresses()
+            .unwrap_or_default()
+            .iter()
+            .find_map(|a| VirtIORngDevice::new(PhysAddr::from(*a)).ok())
+            .expect("No virtio-rng device found")
+    }
+
+    #[test]
+    #[cfg_attr(not(test_in_svsm), ignore = "Can only be run inside guest")]
+    fn test_virtio_rng() {
+        if is_qemu_test_env() {
+            let rng = get_rng_device();
+            let mut buffer = [0u8; 64];
+
+            // The device may return fewer bytes than requested, so keep asking.
+            let mut filled = 0;
+            while filled < buffer.len() {
+                let len = rng
+                    .device
+                    .lock()
+                    .request_entropy(&mut buffer[filled..])
+                    .unwrap();
+                assert!(len > 0 && len <= buffer.len() - filled);
+                filled += len;
+            }
+            assert_eq!(filled, 64);
+            // 64 random bytes being all zero is vanishingly unlikely.
+            assert!(buffer.iter().any(|&byte| byte != 0));
+        }
+    }
+}
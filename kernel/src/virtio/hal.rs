@@ -187,6 +187,29 @@ unsafe impl virtio_drivers::Hal for SvsmHal {
         // implicit drop of share_page here.
     }
 
+    /// Unshares a buffer which the device was still using when its virtqueue was torn down,
+    /// without copying anything back.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` must be the value previously returned by the corresponding `share` call, and
+    /// the device must no longer be accessing the buffer.
+    unsafe fn unshare_abandoned(
+        paddr: virtio_drivers::PhysAddr,
+        size: usize,
+        _direction: virtio_drivers::BufferDirection,
+    ) {
+        assert!(size <= PAGE_SIZE);
+
+        // Dropping the shared page returns it to the guest.
+        SHARED_MEM
+            .lock()
+            .get_mut()
+            .unwrap()
+            .pop(paddr.into())
+            .expect("unshare_abandoned: No shared page found at given pa");
+    }
+
     /// Performs memory mapped read from location of `src`. `src` itself is not modified,
     /// the value is returned instead.
     ///
@@ -51,10 +51,23 @@ while [[ $# -gt 0 ]]; do
       STATE_ENABLE="x-svsm-virtio-mmio=on"
       STATE_DEVICE+="-global virtio-mmio.force-legacy=false "
       STATE_DEVICE+="-drive file=$2,format=raw,if=none,id=svsm_storage,cache=none "
-      STATE_DEVICE+="-device virtio-blk-device,drive=svsm_storage "
+      STATE_DEVICE+="-device virtio-blk-device,drive=svsm_storage,serial=svsm-state "
       shift
       shift
       ;;
+    --test-disk-4k)
+      # A second disk with 4096 byte logical blocks for the in-SVSM tests,
+      # which needs --state as well.
+      STATE_DEVICE+="-drive file=$2,format=raw,if=none,id=svsm_test_4k,cache=none "
+      STATE_DEVICE+="-device virtio-blk-device,drive=svsm_test_4k,logical_block_size=4096,physical_block_size=4096 "
+      shift
+      shift
+      ;;
+    --test-rng)
+      # A virtio-rng device for the in-SVSM tests, which needs --state as well.
+      STATE_DEVICE+="-device virtio-rng-device "
+      shift
+      ;;
     -d|--debugserial)
       COM2_SERIAL="-serial pty"
       shift
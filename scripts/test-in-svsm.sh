@@ -43,12 +43,16 @@ mkfifo $TEST_DIR/pipe.in
 mkfifo $TEST_DIR/pipe.out
 # Create a raw disk image (512kB in size) for virtio-blk tests containing random data
 dd if=/dev/urandom of="$TEST_DIR/svsm_state.raw" bs=512 count=1024
+# And a 64kB disk with 4kB logical blocks
+dd if=/dev/zero of="$TEST_DIR/svsm_test_4k.raw" bs=4096 count=16
 
 test_io $TEST_DIR/pipe.in $TEST_DIR/pipe.out &
 TEST_IO_PID=$!
 
 $SCRIPT_DIR/launch_guest.sh --igvm $SCRIPT_DIR/../bin/coconut-test-qemu.igvm \
     --state "$TEST_DIR/svsm_state.raw" \
+    --test-disk-4k "$TEST_DIR/svsm_test_4k.raw" \
+    --test-rng \
     --unit-tests $TEST_DIR/pipe || true
 
 kill $TEST_IO_PID
@@ -0,0 +1,685 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO memory balloon devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::{BufferDirection, Dma, DmaPurpose, Hal};
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::{ReadOnly, Volatile};
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::mem::{offset_of, size_of, size_of_val};
+use log::info;
+use zerocopy::{Immutable, IntoBytes, KnownLayout};
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+const STATS_QUEUE: u16 = 2;
+const QUEUE_SIZE: u16 = 8;
+/// The driver only ever gives the device a single statistics buffer at a time.
+const STATS_QUEUE_SIZE: usize = 2;
+/// The maximum number of page frame numbers to send to the device in a single request.
+const MAX_PFNS_PER_REQUEST: usize = 256;
+const SUPPORTED_FEATURES: BalloonFeature = BalloonFeature::MUST_TELL_HOST
+    .union(BalloonFeature::STATS_VQ)
+    .union(BalloonFeature::DEFLATE_ON_OOM)
+    .union(BalloonFeature::NOTIFICATION_DATA)
+    .union(BalloonFeature::VERSION_1);
+const REQUIRED_FEATURES: BalloonFeature = BalloonFeature::VERSION_1;
+
+/// The size of the pages which the balloon device deals in, regardless of the guest page size.
+pub const BALLOON_PAGE_SIZE: usize = 4096;
+
+/// The maximum number of statistics which may be passed to [`VirtIOBalloon::report_stats`].
+pub const MAX_STATS: usize = 16;
+
+/// Driver for a VirtIO memory balloon device.
+///
+/// The device tells the driver how many pages it would like the guest to give up, and the driver
+/// gives pages to the device by inflating the balloon, or takes them back by deflating it. Pages
+/// are identified by page frame number, i.e. guest physical address divided by
+/// [`BALLOON_PAGE_SIZE`].
+///
+/// If `VIRTIO_BALLOON_F_STATS_VQ` is negotiated the driver can also report guest memory statistics
+/// to the device, with [`report_stats`](Self::report_stats).
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::balloon::VirtIOBalloon;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, free_pfns: &[u64]) -> Result<(), Error> {
+/// let mut balloon = VirtIOBalloon::<HalImpl, _>::new(transport)?;
+///
+/// let wanted = balloon.target_pages().saturating_sub(balloon.actual_pages()) as usize;
+/// balloon.inflate(&free_pfns[..wanted.min(free_pfns.len())])?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOBalloon<H: Hal, T: Transport> {
+    transport: T,
+    inflate_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    deflate_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    /// The statistics queue, if `VIRTIO_BALLOON_F_STATS_VQ` was negotiated.
+    stats_queue: Option<StatsQueue<H>>,
+    negotiated_features: BalloonFeature,
+    /// The number of pages the driver has given to the device.
+    actual: u32,
+    /// The target which was last returned by `poll_target_change`.
+    last_target: u32,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOBalloon<H, T> {
+    /// Create a new VirtIO-Balloon driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        let negotiated_features = transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+
+        let inflate_queue = VirtQueue::new(&mut transport, INFLATE_QUEUE, false, false, false)?;
+        let deflate_queue = VirtQueue::new(&mut transport, DEFLATE_QUEUE, false, false, false)?;
+        let stats_queue = if negotiated_features.contains(BalloonFeature::STATS_VQ) {
+            Some(StatsQueue {
+                queue: VirtQueue::new(&mut transport, STATS_QUEUE, false, false, false)?,
+                buffer: Dma::new(1, BufferDirection::DriverToDevice, DmaPurpose::Buffer)?,
+                in_flight: None,
+            })
+        } else {
+            None
+        };
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        // The fields for the free page hinting and page poisoning features aren't read, as
+        // devices which don't offer them needn't provide them.
+        let num_pages = transport.read_config::<H, u32>(offset_of!(BalloonConfig, num_pages))?;
+        let actual = transport.read_config::<H, u32>(offset_of!(BalloonConfig, actual))?;
+        info!(
+            "balloon target {} pages, currently {} pages",
+            num_pages, actual
+        );
+
+        Ok(VirtIOBalloon {
+            transport,
+            inflate_queue,
+            deflate_queue,
+            stats_queue,
+            negotiated_features,
+            actual,
+            last_target: num_pages,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the number of pages the device would like the balloon to hold.
+    pub fn target_pages(&self) -> u32 {
+        // This was read successfully when the driver was created, so it must fit.
+        self.transport
+            .read_config::<H, _>(offset_of!(BalloonConfig, num_pages))
+            .unwrap()
+    }
+
+    /// Returns the number of pages the balloon currently holds.
+    pub fn actual_pages(&self) -> u32 {
+        self.actual
+    }
+
+    /// Returns true if the device may deflate the balloon itself when the guest runs out of
+    /// memory, i.e. `VIRTIO_BALLOON_F_DEFLATE_ON_OOM` was negotiated.
+    pub fn deflate_on_oom(&self) -> bool {
+        self.negotiated_features
+            .contains(BalloonFeature::DEFLATE_ON_OOM)
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.inflate_queue.stats()
+            + self.deflate_queue.stats()
+            + self
+                .stats_queue
+                .as_ref()
+                .map_or_else(QueueStats::default, |stats| stats.queue.stats())
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [self.inflate_queue.can_pop(), self.deflate_queue.can_pop()]
+                .into_iter()
+                .chain(self.stats_queue.as_ref().map(|stats| stats.queue.can_pop())),
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Returns the new target number of pages if it has changed since the last call, which the
+    /// device signals with a configuration change interrupt.
+    ///
+    /// This should be called after acknowledging an interrupt.
+    pub fn poll_target_change(&mut self) -> Option<u32> {
+        let target = self.target_pages();
+        if target == self.last_target {
+            None
+        } else {
+            self.last_target = target;
+            Some(target)
+        }
+    }
+
+    /// Gives the pages with the given page frame numbers to the device, and blocks until it has
+    /// taken them.
+    ///
+    /// The caller must not access the pages again until they have been returned with
+    /// [`deflate`](Self::deflate). Returns [`Error::InvalidParam`] if any page frame number
+    /// doesn't fit in 32 bits, which is the limit of the balloon protocol.
+    pub fn inflate(&mut self, pfns: &[u64]) -> Result {
+        Self::send_pfns(&mut self.inflate_queue, &mut self.transport, pfns)?;
+        self.actual = self.actual.saturating_add(pfns.len() as u32);
+        self.update_actual();
+        Ok(())
+    }
+
+    /// Takes back the pages with the given page frame numbers from the device, and blocks until
+    /// the device has acknowledged it.
+    ///
+    /// Returns [`Error::InvalidParam`] if any page frame number doesn't fit in 32 bits.
+    pub fn deflate(&mut self, pfns: &[u64]) -> Result {
+        Self::send_pfns(&mut self.deflate_queue, &mut self.transport, pfns)?;
+        self.actual = self.actual.saturating_sub(pfns.len() as u32);
+        self.update_actual();
+        Ok(())
+    }
+
+    /// Sends the given page frame numbers to the device on the given queue, in as many requests as
+    /// necessary.
+    fn send_pfns(
+        queue: &mut VirtQueue<H, { QUEUE_SIZE as usize }>,
+        transport: &mut T,
+        pfns: &[u64],
+    ) -> Result {
+        let mut buffer = [0u32; MAX_PFNS_PER_REQUEST];
+        // Check them all before sending any, so the device sees all or nothing.
+        if pfns.iter().any(|&pfn| u32::try_from(pfn).is_err()) {
+            return Err(Error::InvalidParam);
+        }
+        for chunk in pfns.chunks(MAX_PFNS_PER_REQUEST) {
+            for (entry, &pfn) in buffer.iter_mut().zip(chunk) {
+                *entry = (pfn as u32).to_le();
+            }
+            queue.add_notify_wait_pop(&[buffer[..chunk.len()].as_bytes()], &mut [], transport)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the device is waiting for new memory statistics, and so
+    /// [`report_stats`](Self::report_stats) should be called.
+    ///
+    /// This is true until the first statistics are reported, and afterwards whenever the device
+    /// has used the last ones, which it does when the host asks for an update. Returns false if
+    /// `VIRTIO_BALLOON_F_STATS_VQ` wasn't negotiated.
+    pub fn stats_requested(&self) -> bool {
+        self.stats_queue
+            .as_ref()
+            .is_some_and(|stats| stats.in_flight.is_none() || stats.queue.can_pop())
+    }
+
+    /// Gives the device the given memory statistics, for it to use the next time the host asks.
+    ///
+    /// The driver holds on to only one set of statistics at a time, so this should be called when
+    /// [`stats_requested`](Self::stats_requested) returns true, usually after a used buffer
+    /// interrupt on the statistics queue. The device is notified, but this doesn't wait for it.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_BALLOON_F_STATS_VQ` wasn't negotiated,
+    /// [`Error::InvalidParam`] if `stats` is empty or has more than [`MAX_STATS`] entries, or
+    /// [`Error::NotReady`] if the device still holds the last statistics reported.
+    pub fn report_stats(&mut self, stats: &[BalloonStat]) -> Result {
+        let Some(stats_queue) = &mut self.stats_queue else {
+            return Err(Error::Unsupported);
+        };
+        if stats.is_empty() || stats.len() > MAX_STATS {
+            return Err(Error::InvalidParam);
+        }
+        if let Some((token, len)) = stats_queue.in_flight {
+            if !stats_queue.queue.can_pop() {
+                return Err(Error::NotReady);
+            }
+            // SAFETY: The buffer is the one which was added with this token below.
+            unsafe {
+                let buffer = &stats_queue.buffer.raw_slice().as_ref()[..len];
+                stats_queue.queue.pop_used(token, &[buffer], &mut [])?;
+            }
+            stats_queue.in_flight = None;
+        }
+
+        let len = size_of_val(stats);
+        // SAFETY: The buffer isn't in the queue, and is a whole page so is large enough for
+        // `MAX_STATS` entries.
+        let buffer = unsafe { &mut stats_queue.buffer.raw_slice().as_mut()[..len] };
+        for (entry, stat) in buffer.chunks_exact_mut(size_of::<BalloonStat>()).zip(stats) {
+            let le = BalloonStat {
+                tag: BalloonStatTag(stat.tag.0.to_le()),
+                val: stat.val.to_le(),
+            };
+            entry.copy_from_slice(le.as_bytes());
+        }
+        // SAFETY: The buffer is owned by the driver and is only accessed again once it has been
+        // popped, above or when the queue is dropped.
+        let token = unsafe { stats_queue.queue.add(&[buffer], &mut []) }?;
+        stats_queue.in_flight = Some((token, len));
+        if stats_queue.queue.should_notify() {
+            stats_queue.queue.notify_device(&mut self.transport);
+        }
+        Ok(())
+    }
+
+    /// Tells the device how many pages the balloon currently holds.
+    fn update_actual(&mut self) {
+        // This was read successfully when the driver was created, so it must fit.
+        self.transport
+            .write_config::<H, _>(offset_of!(BalloonConfig, actual), self.actual)
+            .unwrap();
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOBalloon<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOBalloon::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(
+            &self.transport,
+            [self.inflate_queue.snapshot(), self.deflate_queue.snapshot()]
+                .into_iter()
+                .chain(
+                    self.stats_queue
+                        .as_ref()
+                        .map(|stats| stats.queue.snapshot()),
+                ),
+        )
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.inflate_queue.dma_footprint()
+            + self.deflate_queue.dma_footprint()
+            + self
+                .stats_queue
+                .as_ref()
+                .map_or(0, |stats| stats.queue.dma_footprint() + stats.buffer.size())
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOBalloon<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(INFLATE_QUEUE);
+        self.transport.queue_unset(DEFLATE_QUEUE);
+        if self.stats_queue.is_some() {
+            self.transport.queue_unset(STATS_QUEUE);
+        }
+    }
+}
+
+/// The statistics queue, and the buffer the driver writes statistics to.
+struct StatsQueue<H: Hal> {
+    // Declared before `buffer` so that the queue is dropped, and abandons the buffer if it is in
+    // flight, before the buffer is freed.
+    queue: VirtQueue<H, STATS_QUEUE_SIZE>,
+    buffer: Dma<H>,
+    /// The token and length of the statistics the device holds, if any.
+    in_flight: Option<(u16, usize)>,
+}
+
+/// A guest memory statistic, as reported to the device with [`VirtIOBalloon::report_stats`].
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Eq, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct BalloonStat {
+    /// Which statistic this is.
+    pub tag: BalloonStatTag,
+    /// The value of the statistic. Amounts of memory are in bytes.
+    pub val: u64,
+}
+
+/// Identifies a [`BalloonStat`].
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Eq, Immutable, IntoBytes, KnownLayout, PartialEq)]
+pub struct BalloonStatTag(pub u16);
+
+impl BalloonStatTag {
+    /// The amount of memory swapped in.
+    pub const SWAP_IN: Self = Self(0);
+    /// The amount of memory swapped out.
+    pub const SWAP_OUT: Self = Self(1);
+    /// The number of major page faults.
+    pub const MAJOR_FAULTS: Self = Self(2);
+    /// The number of minor page faults.
+    pub const MINOR_FAULTS: Self = Self(3);
+    /// The amount of memory not being used for any purpose.
+    pub const FREE_MEMORY: Self = Self(4);
+    /// The total amount of memory available.
+    pub const TOTAL_MEMORY: Self = Self(5);
+    /// An estimate of how much memory is available for starting new applications, without
+    /// pushing the system to swap.
+    pub const AVAILABLE_MEMORY: Self = Self(6);
+    /// The amount of memory in disk caches, which can be quickly reclaimed.
+    pub const DISK_CACHES: Self = Self(7);
+    /// The number of successful hugetlb page allocations.
+    pub const HUGETLB_ALLOCATIONS: Self = Self(8);
+    /// The number of failed hugetlb page allocations.
+    pub const HUGETLB_FAILURES: Self = Self(9);
+}
+
+#[repr(C)]
+struct BalloonConfig {
+    /// The number of pages the device wants the balloon to hold.
+    num_pages: ReadOnly<u32>,
+    /// The number of pages the balloon actually holds, written by the driver.
+    actual: Volatile<u32>,
+    free_page_hint_cmd_id: ReadOnly<u32>,
+    poison_val: Volatile<u32>,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct BalloonFeature: u64 {
+        /// The host must be told before pages from the balloon are used.
+        const MUST_TELL_HOST        = 1 << 0;
+        /// A virtqueue for reporting guest memory statistics is present.
+        const STATS_VQ              = 1 << 1;
+        /// The device may deflate the balloon when the guest is out of memory.
+        const DEFLATE_ON_OOM        = 1 << 2;
+        /// The device supports free page hinting.
+        const FREE_PAGE_HINT        = 1 << 3;
+        /// The driver will poison pages it gives to the device.
+        const PAGE_POISON           = 1 << 4;
+        /// The device supports free page reporting.
+        const PAGE_REPORTING        = 1 << 5;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of a memory balloon device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to
+/// `VirtIOBalloon::new_with_features` to forbid the feature. Use `trailing_zeros` to get the bit
+/// number for `VirtIOBalloon::supports`.
+pub mod features {
+    use super::BalloonFeature;
+    pub use crate::transport::features::*;
+
+    /// The host must be told before pages from the balloon are used.
+    pub const MUST_TELL_HOST: u64 = BalloonFeature::MUST_TELL_HOST.bits();
+    /// A virtqueue for reporting guest memory statistics is present.
+    pub const STATS_VQ: u64 = BalloonFeature::STATS_VQ.bits();
+    /// The device may deflate the balloon when the guest is out of memory.
+    pub const DEFLATE_ON_OOM: u64 = BalloonFeature::DEFLATE_ON_OOM.bits();
+    /// The device supports free page hinting.
+    pub const FREE_PAGE_HINT: u64 = BalloonFeature::FREE_PAGE_HINT.bits();
+    /// The driver will poison pages it gives to the device.
+    pub const PAGE_POISON: u64 = BalloonFeature::PAGE_POISON.bits();
+    /// The device supports free page reporting.
+    pub const PAGE_REPORTING: u64 = BalloonFeature::PAGE_REPORTING.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec::Vec};
+    use std::{sync::Mutex, thread};
+
+    fn make_balloon(
+        config_space: &mut BalloonConfig,
+    ) -> (
+        VirtIOBalloon<FakeHal, FakeTransport<BalloonConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        make_balloon_with_features(
+            config_space,
+            BalloonFeature::MUST_TELL_HOST | BalloonFeature::VERSION_1,
+        )
+    }
+
+    fn make_balloon_with_features(
+        config_space: &mut BalloonConfig,
+        device_features: BalloonFeature,
+    ) -> (
+        VirtIOBalloon<FakeHal, FakeTransport<BalloonConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::MemoryBalloon,
+            QUEUE_SIZE.into(),
+            3,
+            device_features.bits(),
+            config_space,
+        );
+        (VirtIOBalloon::new(transport).unwrap(), state)
+    }
+
+    fn new_config(num_pages: u32) -> BalloonConfig {
+        BalloonConfig {
+            num_pages: ReadOnly::new(num_pages),
+            actual: Volatile::new(0),
+            free_page_hint_cmd_id: ReadOnly::new(0),
+            poison_val: Volatile::new(0),
+        }
+    }
+
+    /// Returns the page frame numbers the device receives in the next request on the given queue.
+    fn receive_pfns(state: &Mutex<State>, queue: u16) -> Vec<u32> {
+        State::wait_until_queue_notified(state, queue);
+        state
+            .lock()
+            .unwrap()
+            .read_from_queue::<{ QUEUE_SIZE as usize }>(queue)
+            .chunks(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn inflate_deflate() {
+        let mut config_space = new_config(3);
+        let (mut balloon, state) = make_balloon(&mut config_space);
+        assert_eq!(balloon.target_pages(), 3);
+        assert_eq!(balloon.actual_pages(), 0);
+        assert!(!balloon.deflate_on_oom());
+
+        let device_state = state.clone();
+        let handle = thread::spawn(move || receive_pfns(&device_state, INFLATE_QUEUE));
+        balloon.inflate(&[0x100, 0x101, 0x2345]).unwrap();
+        assert_eq!(handle.join().unwrap(), [0x100, 0x101, 0x2345]);
+        assert_eq!(balloon.actual_pages(), 3);
+
+        let device_state = state.clone();
+        let handle = thread::spawn(move || receive_pfns(&device_state, DEFLATE_QUEUE));
+        balloon.deflate(&[0x101]).unwrap();
+        assert_eq!(handle.join().unwrap(), [0x101]);
+        assert_eq!(balloon.actual_pages(), 2);
+
+        drop(balloon);
+        assert_eq!(config_space.actual.0, 2);
+    }
+
+    #[test]
+    fn inflate_many() {
+        let mut config_space = new_config(300);
+        let (mut balloon, state) = make_balloon(&mut config_space);
+
+        // More page frame numbers than fit in one request are split between two.
+        let pfns: Vec<u64> = (0..300).collect();
+        let handle = thread::spawn(move || {
+            let mut received = receive_pfns(&state, INFLATE_QUEUE);
+            assert_eq!(received.len(), MAX_PFNS_PER_REQUEST);
+            received.extend(receive_pfns(&state, INFLATE_QUEUE));
+            received
+        });
+        balloon.inflate(&pfns).unwrap();
+        let received = handle.join().unwrap();
+        assert!(received.iter().map(|&pfn| u64::from(pfn)).eq(pfns));
+        assert_eq!(balloon.actual_pages(), 300);
+    }
+
+    #[test]
+    fn inflate_invalid_pfn() {
+        let mut config_space = new_config(1);
+        let (mut balloon, _state) = make_balloon(&mut config_space);
+        assert_eq!(balloon.inflate(&[1, 1 << 32]), Err(Error::InvalidParam));
+        assert_eq!(balloon.actual_pages(), 0);
+    }
+
+    #[test]
+    fn report_stats() {
+        let mut config_space = new_config(1);
+        let (mut balloon, state) = make_balloon_with_features(
+            &mut config_space,
+            BalloonFeature::STATS_VQ | BalloonFeature::VERSION_1,
+        );
+        let stats = [
+            BalloonStat {
+                tag: BalloonStatTag::FREE_MEMORY,
+                val: 0x1234_5678,
+            },
+            BalloonStat {
+                tag: BalloonStatTag::TOTAL_MEMORY,
+                val: 1 << 40,
+            },
+        ];
+        assert_eq!(balloon.report_stats(&[]), Err(Error::InvalidParam));
+
+        // The device has no statistics until the first are reported.
+        assert!(balloon.stats_requested());
+        assert_eq!(balloon.report_stats(&stats), Ok(()));
+        assert!(!balloon.stats_requested());
+        assert_eq!(balloon.report_stats(&stats), Err(Error::NotReady));
+
+        // The host asks for an update, so the device uses the buffer.
+        let received = state
+            .lock()
+            .unwrap()
+            .read_from_queue::<STATS_QUEUE_SIZE>(STATS_QUEUE);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&4u16.to_le_bytes());
+        expected.extend_from_slice(&0x1234_5678u64.to_le_bytes());
+        expected.extend_from_slice(&5u16.to_le_bytes());
+        expected.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(received, expected);
+        assert!(balloon.stats_requested());
+
+        assert_eq!(balloon.report_stats(&stats[..1]), Ok(()));
+        assert_eq!(
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<STATS_QUEUE_SIZE>(STATS_QUEUE),
+            expected[..size_of::<BalloonStat>()]
+        );
+    }
+
+    #[test]
+    fn report_stats_unsupported() {
+        let mut config_space = new_config(1);
+        let (mut balloon, _state) = make_balloon(&mut config_space);
+        assert!(!balloon.stats_requested());
+        assert_eq!(
+            balloon.report_stats(&[BalloonStat {
+                tag: BalloonStatTag::SWAP_IN,
+                val: 0,
+            }]),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn target_change() {
+        let mut config_space = new_config(1);
+        let (mut balloon, _state) = make_balloon(&mut config_space);
+        assert_eq!(balloon.poll_target_change(), None);
+
+        config_space.num_pages = ReadOnly::new(42);
+        assert_eq!(balloon.poll_target_change(), Some(42));
+        assert_eq!(balloon.poll_target_change(), None);
+    }
+}
@@ -2,22 +2,117 @@
 
 //! Driver for VirtIO block devices.
 
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, Volatile};
-use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+mod cursor;
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::{BufferDirection, Dma, DmaPurpose, Hal, SharedRegion};
+use crate::queue::{
+    check_usable, negotiated::NegotiatedQueue, VirtQueue, NEEDS_RESET_POLL_INTERVAL,
+};
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::Volatile;
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+#[cfg(feature = "alloc")]
+use crate::{DmaBuffer, DmaPool};
+use crate::{Error, Result, PAGE_SIZE};
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
 use bitflags::bitflags;
-use log::info;
+use core::cmp::min;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::iter::once;
+use core::marker::PhantomPinned;
+use core::mem::{offset_of, size_of, take};
+use core::ops::Range;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use log::{info, warn};
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
+#[cfg(feature = "alloc")]
+pub use cursor::{BlockCursor, SeekFrom};
+
 const QUEUE: u16 = 0;
 const QUEUE_SIZE: u16 = 16;
+/// The maximum number of request queues to use, if the device supports multiqueue.
+const MAX_QUEUES: usize = 4;
+/// The maximum number of segments to send in a single discard or write zeroes request, if the
+/// device doesn't have a lower limit.
+const MAX_SEGMENTS: usize = 16;
+/// The maximum number of non-empty buffers which may be passed to
+/// [`VirtIOBlk::read_blocks_iov`] or [`VirtIOBlk::write_blocks_iov`].
+///
+/// This leaves room in a queue of the default size for the request header and response, so that
+/// the chain fits even without indirect descriptors.
+pub const MAX_FRAGMENTS: usize = QUEUE_SIZE as usize - 2;
+/// The length of a request header followed by its response.
+const HEADER_LEN: usize = size_of::<BlkReq>() + size_of::<BlkResp>();
 const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
+    .union(BlkFeature::SIZE_MAX)
+    .union(BlkFeature::SEG_MAX)
+    .union(BlkFeature::GEOMETRY)
+    .union(BlkFeature::BLK_SIZE)
+    .union(BlkFeature::TOPOLOGY)
     .union(BlkFeature::FLUSH)
+    .union(BlkFeature::CONFIG_WCE)
+    .union(BlkFeature::DISCARD)
+    .union(BlkFeature::WRITE_ZEROES)
+    .union(BlkFeature::MQ)
+    .union(BlkFeature::LIFETIME)
     .union(BlkFeature::RING_INDIRECT_DESC)
     .union(BlkFeature::RING_EVENT_IDX)
-    .union(BlkFeature::VERSION_1);
+    .union(BlkFeature::VERSION_1)
+    .union(BlkFeature::RING_PACKED)
+    .union(BlkFeature::IN_ORDER)
+    .union(BlkFeature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: BlkFeature = BlkFeature::VERSION_1;
+/// The end of the config space fields needed by each optional feature, so that the feature isn't
+/// negotiated if the device's config space is too small to hold them.
+const CONFIG_FIELDS: [(BlkFeature, usize); 9] = [
+    (
+        BlkFeature::SIZE_MAX,
+        offset_of!(BlkConfig, size_max) + size_of::<u32>(),
+    ),
+    (
+        BlkFeature::SEG_MAX,
+        offset_of!(BlkConfig, seg_max) + size_of::<u32>(),
+    ),
+    (
+        BlkFeature::GEOMETRY,
+        offset_of!(BlkConfig, sectors) + size_of::<u8>(),
+    ),
+    (
+        BlkFeature::BLK_SIZE,
+        offset_of!(BlkConfig, blk_size) + size_of::<u32>(),
+    ),
+    (
+        BlkFeature::TOPOLOGY,
+        offset_of!(BlkConfig, opt_io_size) + size_of::<u32>(),
+    ),
+    (
+        BlkFeature::CONFIG_WCE,
+        offset_of!(BlkConfig, writeback) + size_of::<u8>(),
+    ),
+    (
+        BlkFeature::MQ,
+        offset_of!(BlkConfig, num_queues) + size_of::<u16>(),
+    ),
+    (
+        BlkFeature::DISCARD,
+        offset_of!(BlkConfig, discard_sector_alignment) + size_of::<u32>(),
+    ),
+    (
+        BlkFeature::WRITE_ZEROES,
+        offset_of!(BlkConfig, write_zeroes_may_unmap) + size_of::<u8>(),
+    ),
+];
 
 /// Driver for a VirtIO block device.
 ///
@@ -36,10 +131,7 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
 /// let mut disk = VirtIOBlk::<HalImpl, _>::new(transport)?;
 ///
-/// println!(
-///     "VirtIO block device: {} kB",
-///     disk.capacity() * SECTOR_SIZE as u64 / 2
-/// );
+/// println!("VirtIO block device: {} kB", disk.capacity_bytes() / 1024);
 ///
 /// // Read sector 0 and then copy it to sector 1.
 /// let mut buf = [0; SECTOR_SIZE];
@@ -48,116 +140,894 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// # Ok(())
 /// # }
 /// ```
+///
+/// # Block sizes
+///
+/// Requests always address the device in 512 byte ([`SECTOR_SIZE`]) sectors, so block ids and
+/// [`capacity`](Self::capacity) are in sectors whatever the device's block size. If the device
+/// advertises a larger logical block size with `VIRTIO_BLK_F_BLK_SIZE`, reads and writes must cover
+/// whole logical blocks: buffer lengths must be a multiple of
+/// [`logical_block_size`](Self::logical_block_size), and block ids a multiple of the number of
+/// sectors in a logical block.
+///
+/// # Asynchronous requests
+///
+/// [`read_blocks_async`](Self::read_blocks_async) and
+/// [`write_blocks_async`](Self::write_blocks_async) return futures which submit the request when
+/// first polled. As they borrow the device until they complete, they acknowledge interrupts
+/// themselves whenever they are polled, so the executor only needs to poll the task again when
+/// the device raises an interrupt, e.g. by waking it from the interrupt handler.
+///
+/// Callers which keep the device somewhere the interrupt handler can reach can instead use the
+/// non-blocking token API, register a [`Waker`] for each token with
+/// [`register_waker`](Self::register_waker), and call [`ack_interrupt`](Self::ack_interrupt) from
+/// the interrupt handler to wake whichever request has completed.
+///
+/// # Multiple queues
+///
+/// If the device supports multiqueue, the driver sets up to 4 request queues, so that requests
+/// can be serviced in parallel. The `_on` variants of the request methods take the index of the
+/// queue to use, below [`queue_count`](Self::queue_count); the others all use the first queue.
+/// Tokens are only meaningful for the queue they were returned for.
 pub struct VirtIOBlk<H: Hal, T: Transport> {
     transport: T,
-    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    /// The request queues, of which the first `num_queues` are set up.
+    queues: [Option<BlkQueue<H>>; MAX_QUEUES],
+    num_queues: u16,
     capacity: u64,
+    /// The config generation at which `capacity` was last read.
+    config_generation: u32,
     negotiated_features: BlkFeature,
+    /// The maximum length of a single data segment, or 0 if the device doesn't limit it.
+    size_max: u32,
+    /// The maximum number of data segments in a request, or 0 if the device doesn't limit it.
+    seg_max: u32,
+    /// The logical block size in bytes, which reads and writes must be a whole number of.
+    block_size: usize,
+    /// Wakers to wake when the request with the corresponding token completes, for each queue.
+    wakers: [[Option<Waker>; QUEUE_SIZE as usize]; MAX_QUEUES],
+    /// A request on each queue which timed out but which the device may still complete, with its
+    /// token.
+    timed_out: [Option<(u16, StagedRequest<H>)>; MAX_QUEUES],
+    /// How [`poll_completion`](Self::poll_completion) waits for requests to complete.
+    poll_mode: PollMode,
+    /// Already shared memory for the headers and responses of blocking requests.
+    #[cfg(feature = "alloc")]
+    header_pool: DmaPool<H>,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
 }
 
+type BlkQueue<H> = NegotiatedQueue<H, { QUEUE_SIZE as usize }>;
+
 impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// Create a new VirtIO-Blk driver.
-    pub fn new(mut transport: T) -> Result<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+    ///
+    /// Optional features are not negotiated if the device's config space is too small for the
+    /// fields they need. `VERSION_1` isn't required if the transport talks to a legacy device (see
+    /// [`Transport::requires_legacy_layout`]), and the legacy queue layout is used instead.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::init(transport, QUEUE_SIZE, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(transport: T, forbidden_features: u64) -> Result<Self> {
+        Self::init(transport, QUEUE_SIZE, forbidden_features)
+    }
+
+    /// Like [`new`](Self::new), but with about `queue_size` descriptors in each queue rather than
+    /// the default of 16.
+    ///
+    /// The size is rounded up to a power of two, and then limited to 16 and to what the device
+    /// supports; [`virt_queue_size`](Self::virt_queue_size) returns the result. Smaller queues
+    /// use less DMA memory but allow fewer requests in flight. Returns [`Error::InvalidParam`] if
+    /// `queue_size` is 0.
+    pub fn with_queue_size(transport: T, queue_size: u16) -> Result<Self> {
+        Self::init(transport, queue_size, 0)
+    }
+
+    fn init(mut transport: T, queue_size: u16, forbidden_features: u64) -> Result<Self> {
+        if queue_size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let negotiated_features = transport.begin_init_with_config(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+            &CONFIG_FIELDS,
+        )?;
 
         // Read configuration space.
-        let config = transport.config_space::<BlkConfig>()?;
-        info!("config: {:?}", config);
-        // SAFETY: Safe because config is a valid pointer to the device configuration space.
-        let capacity = unsafe {
-            volread!(H, config, capacity_low) as u64
-                | (volread!(H, config, capacity_high) as u64) << 32
-        };
+        let capacity = Self::read_capacity(&transport)?;
         info!("found a block device of size {}KB", capacity / 2);
+        let config_generation = transport.config_generation();
+        let size_max = if negotiated_features.contains(BlkFeature::SIZE_MAX) {
+            transport.read_config::<H, _>(offset_of!(BlkConfig, size_max))?
+        } else {
+            0
+        };
+        let seg_max = if negotiated_features.contains(BlkFeature::SEG_MAX) {
+            transport.read_config::<H, _>(offset_of!(BlkConfig, seg_max))?
+        } else {
+            0
+        };
+        let block_size = Self::read_block_size(&transport, negotiated_features)?;
 
-        let queue = VirtQueue::new(
-            &mut transport,
-            QUEUE,
-            negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
-            negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
-        )?;
+        let num_queues = if negotiated_features.contains(BlkFeature::MQ) {
+            let num_queues: u16 =
+                transport.read_config::<H, _>(offset_of!(BlkConfig, num_queues))?;
+            // The device must have at least one queue, but don't trust it.
+            num_queues.clamp(1, MAX_QUEUES as u16)
+        } else {
+            1
+        };
+
+        let mut queues = [const { None }; MAX_QUEUES];
+        for (queue_idx, queue) in (0..num_queues).zip(&mut queues) {
+            *queue = Some(BlkQueue::with_size(
+                &mut transport,
+                queue_idx,
+                queue_size,
+                negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
+                negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
+                negotiated_features.contains(BlkFeature::IN_ORDER),
+            )?);
+        }
+        #[cfg(feature = "alloc")]
+        let header_pool = DmaPool::new(1, HEADER_LEN, BufferDirection::Both)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
         transport.finish_init();
 
         Ok(VirtIOBlk {
             transport,
-            queue,
+            queues,
+            num_queues,
             capacity,
+            config_generation,
             negotiated_features,
+            size_max,
+            seg_max,
+            block_size,
+            wakers: [const { [const { None }; QUEUE_SIZE as usize] }; MAX_QUEUES],
+            timed_out: [const { None }; MAX_QUEUES],
+            poll_mode: PollMode::Interrupt,
+            #[cfg(feature = "alloc")]
+            header_pool,
+            #[cfg(feature = "async")]
+            notifier,
         })
     }
 
+    /// Resets the device and initialises it again in place, to recover a device which has stopped
+    /// completing requests.
+    ///
+    /// The same features are negotiated again and the queues are replaced with fresh ones of the
+    /// same size, so the driver carries on as before. Any requests still in flight, including one
+    /// which timed out, are abandoned: their tokens are no longer valid, and any wakers registered
+    /// for them are woken so their tasks can find out.
+    ///
+    /// Returns [`Error::FeatureNegotiationFailed`] if the device no longer offers all of the
+    /// features which were negotiated before. If this fails the device is left reset, and all
+    /// requests fail until a later `reset` succeeds.
+    pub fn reset(&mut self) -> Result {
+        self.transport.set_status(DeviceStatus::empty());
+        for queue in 0..self.num_queues {
+            self.transport.queue_unset(queue);
+        }
+        // The device can't access the queues or any staged requests now, so they can be freed.
+        // Abandon the requests in the queues first, as that unshares the buffers of the staged
+        // requests.
+        for (queue, wakers) in self.queues.iter_mut().zip(&mut self.wakers) {
+            let Some(queue) = queue else {
+                continue;
+            };
+            // SAFETY: The device has been reset, so it no longer accesses the queue.
+            unsafe {
+                queue.abort_all(|token| {
+                    if let Some(waker) = wakers[usize::from(token)].take() {
+                        waker.wake();
+                    }
+                });
+            }
+        }
+        self.timed_out = [const { None }; MAX_QUEUES];
+        let queue_sizes = self
+            .queues
+            .each_mut()
+            .map(|queue| queue.take().map(|queue| queue.size()));
+
+        let features = self.negotiated_features;
+        self.transport.begin_init_with_mask(features, features, 0)?;
+        self.capacity = Self::read_capacity(&self.transport)?;
+        self.config_generation = self.transport.config_generation();
+        for ((queue_idx, queue), size) in
+            (0..self.num_queues).zip(&mut self.queues).zip(queue_sizes)
+        {
+            *queue = Some(BlkQueue::with_size(
+                &mut self.transport,
+                queue_idx,
+                size.unwrap_or(QUEUE_SIZE),
+                features.contains(BlkFeature::RING_INDIRECT_DESC),
+                features.contains(BlkFeature::RING_EVENT_IDX),
+                features.contains(BlkFeature::IN_ORDER),
+            )?);
+        }
+        // The new queues start with interrupts enabled.
+        self.set_poll_mode(self.poll_mode);
+        self.transport.finish_init();
+        info!("reset block device of size {}KB", self.capacity / 2);
+        Ok(())
+    }
+
+    /// Returns the number of request queues in use.
+    ///
+    /// This is 1 unless the device supports multiqueue.
+    pub fn queue_count(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// Returns the request queue with the given index along with the transport, or
+    /// [`Error::InvalidParam`] if there is no such queue.
+    fn queue(&mut self, queue: u16) -> Result<(&mut BlkQueue<H>, &mut T)> {
+        let virt_queue = self
+            .queues
+            .get_mut(usize::from(queue))
+            .and_then(Option::as_mut)
+            .ok_or(Error::InvalidParam)?;
+        Ok((virt_queue, &mut self.transport))
+    }
+
+    /// Like [`queue`](Self::queue), but for submitting a new request. Returns
+    /// [`Error::DeviceRemoved`] if the device has been removed, [`Error::DeviceNeedsReset`] if it
+    /// needs a reset, or [`Error::NotReady`] if a request on the queue timed out and the device
+    /// still hasn't completed it.
+    fn submit_queue(&mut self, queue: u16) -> Result<(&mut BlkQueue<H>, &mut T)> {
+        check_usable(&self.transport)?;
+        self.reap_timed_out(queue)?;
+        self.queue(queue)
+    }
+
+    /// Pops the request which timed out on the given queue, if there is one and the device has
+    /// completed it since.
+    ///
+    /// Returns [`Error::NotReady`] if the device hasn't completed it yet.
+    fn reap_timed_out(&mut self, queue: u16) -> Result {
+        let index = usize::from(queue);
+        let Some((token, staged)) = self.timed_out.get(index).and_then(Option::as_ref) else {
+            return Ok(());
+        };
+        let virt_queue = self.queues[index].as_mut().unwrap();
+        if virt_queue.peek_used() != Some(*token) {
+            return Err(Error::NotReady);
+        }
+        // SAFETY: The request was added with `staged.add` and returned this token.
+        unsafe { staged.pop(virt_queue, *token) }?;
+        // Nobody is waiting for the result any more.
+        self.timed_out[index] = None;
+        Ok(())
+    }
+
     /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    ///
+    /// This is in sectors even if the device has a larger
+    /// [logical block size](Self::logical_block_size).
     pub fn capacity(&self) -> u64 {
         self.capacity
     }
 
+    /// Gets the capacity of the block device in bytes.
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity * SECTOR_SIZE as u64
+    }
+
+    /// Checks whether the device configuration has changed since it was last read, e.g. because
+    /// the disk was resized, and returns what changed if so.
+    ///
+    /// The device signals changes with a configuration change interrupt, so this should be called
+    /// when [`ack_interrupt_detailed`](Self::ack_interrupt_detailed) reports one. On transports
+    /// with a config generation counter this only re-reads the configuration if the generation has
+    /// changed. Requests are checked against the new capacity from then on.
+    pub fn poll_config_change(&mut self) -> Option<BlkConfigChange> {
+        let generation = self.transport.config_generation();
+        // Legacy devices have no generation counter, so always have to be checked.
+        if generation == self.config_generation && !self.transport.requires_legacy_layout() {
+            return None;
+        }
+        self.config_generation = generation;
+
+        let capacity = Self::read_capacity(&self.transport).ok()?;
+        if capacity == self.capacity {
+            return None;
+        }
+        info!(
+            "block device resized from {}KB to {}KB",
+            self.capacity / 2,
+            capacity / 2
+        );
+        self.capacity = capacity;
+        Some(BlkConfigChange::Capacity(capacity))
+    }
+
+    /// Reads the capacity in 512 byte sectors from the config space.
+    fn read_capacity(transport: &T) -> Result<u64> {
+        transport.read_config_space_atomic(|| {
+            let low: u32 = transport.read_config::<H, _>(offset_of!(BlkConfig, capacity_low))?;
+            let high: u32 = transport.read_config::<H, _>(offset_of!(BlkConfig, capacity_high))?;
+            Ok(u64::from(low) | u64::from(high) << 32)
+        })
+    }
+
+    /// Returns the block size of the device in bytes, which is the smallest unit it can read or
+    /// write without a read-modify-write cycle.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_BLK_SIZE`
+    /// feature.
+    pub fn block_size(&self) -> Result<u32> {
+        if !self.negotiated_features.contains(BlkFeature::BLK_SIZE) {
+            return Err(Error::Unsupported);
+        }
+        self.transport
+            .read_config::<H, _>(offset_of!(BlkConfig, blk_size))
+    }
+
+    /// Returns the size in bytes of the device's logical blocks, which reads and writes must cover
+    /// a whole number of.
+    ///
+    /// This is the [`block_size`](Self::block_size) if the device advertises one, or
+    /// [`SECTOR_SIZE`] otherwise.
+    pub fn logical_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Reads the logical block size from the config space if the device advertises it.
+    ///
+    /// Sizes which aren't a power of two between [`SECTOR_SIZE`] and [`PAGE_SIZE`] are ignored, as
+    /// requests are split into pages in places.
+    fn read_block_size(transport: &T, negotiated_features: BlkFeature) -> Result<usize> {
+        if !negotiated_features.contains(BlkFeature::BLK_SIZE) {
+            return Ok(SECTOR_SIZE);
+        }
+        let block_size = transport.read_config::<H, u32>(offset_of!(BlkConfig, blk_size))? as usize;
+        if block_size.is_power_of_two() && (SECTOR_SIZE..=PAGE_SIZE).contains(&block_size) {
+            Ok(block_size)
+        } else {
+            warn!("ignoring unsupported block size {}", block_size);
+            Ok(SECTOR_SIZE)
+        }
+    }
+
+    /// Checks that a read or write of `len` bytes starting at sector `block_id` covers a non-zero
+    /// number of whole logical blocks, returning [`Error::InvalidParam`] if not.
+    fn check_blocks(&self, block_id: usize, len: usize) -> Result {
+        if len == 0 || len % self.block_size != 0 || block_id % (self.block_size / SECTOR_SIZE) != 0
+        {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Returns the optimal I/O alignment and size information of the device.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_TOPOLOGY`
+    /// feature.
+    pub fn topology(&self) -> Result<BlkTopology> {
+        if !self.negotiated_features.contains(BlkFeature::TOPOLOGY) {
+            return Err(Error::Unsupported);
+        }
+        let transport = &self.transport;
+        transport.read_config_space_atomic(|| {
+            Ok(BlkTopology {
+                physical_block_exp: transport
+                    .read_config::<H, _>(offset_of!(BlkConfig, physical_block_exp))?,
+                alignment_offset: transport
+                    .read_config::<H, _>(offset_of!(BlkConfig, alignment_offset))?,
+                min_io_size: transport.read_config::<H, _>(offset_of!(BlkConfig, min_io_size))?,
+                opt_io_size: transport.read_config::<H, _>(offset_of!(BlkConfig, opt_io_size))?,
+            })
+        })
+    }
+
+    /// Returns the disk-style geometry of the device.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_GEOMETRY`
+    /// feature.
+    pub fn geometry(&self) -> Result<BlkGeometry> {
+        if !self.negotiated_features.contains(BlkFeature::GEOMETRY) {
+            return Err(Error::Unsupported);
+        }
+        let transport = &self.transport;
+        transport.read_config_space_atomic(|| {
+            Ok(BlkGeometry {
+                cylinders: transport.read_config::<H, _>(offset_of!(BlkConfig, cylinders))?,
+                heads: transport.read_config::<H, _>(offset_of!(BlkConfig, heads))?,
+                sectors: transport.read_config::<H, _>(offset_of!(BlkConfig, sectors))?,
+            })
+        })
+    }
+
+    /// Returns the maximum length in bytes of a single data segment, as the device reports with
+    /// `VIRTIO_BLK_F_SIZE_MAX`. Longer buffers are split into several segments.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the feature.
+    pub fn size_max(&self) -> Result<u32> {
+        if !self.negotiated_features.contains(BlkFeature::SIZE_MAX) {
+            return Err(Error::Unsupported);
+        }
+        Ok(self.size_max)
+    }
+
+    /// Returns the maximum number of data segments in a single request, as the device reports
+    /// with `VIRTIO_BLK_F_SEG_MAX`. Transfers with more segments are split into several requests.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the feature.
+    pub fn seg_max(&self) -> Result<u32> {
+        if !self.negotiated_features.contains(BlkFeature::SEG_MAX) {
+            return Err(Error::Unsupported);
+        }
+        Ok(self.seg_max)
+    }
+
     /// Returns true if the block device is read-only, or false if it allows writes.
     pub fn readonly(&self) -> bool {
         self.negotiated_features.contains(BlkFeature::RO)
     }
 
-    /// Acknowledges a pending interrupt, if any.
+    /// Returns true if the device's cache is in writeback mode, in which case writes are only
+    /// stable once they have been [`flush`](Self::flush)ed, or false if it is in writethrough mode.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_CONFIG_WCE`
+    /// feature.
+    pub fn writeback_cache(&self) -> Result<bool> {
+        if !self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            return Err(Error::Unsupported);
+        }
+        let writeback: u8 = self
+            .transport
+            .read_config::<H, _>(offset_of!(BlkConfig, writeback))?;
+        Ok(writeback != 0)
+    }
+
+    /// Switches the device's cache to writeback mode if `on` is true, or to writethrough mode
+    /// otherwise.
+    ///
+    /// Switching to writethrough mode doesn't make earlier writes stable, so [`flush`](Self::flush)
+    /// first if that matters.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_CONFIG_WCE`
+    /// feature, or ignores the change.
+    pub fn set_writeback_cache(&mut self, on: bool) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            return Err(Error::Unsupported);
+        }
+        self.transport
+            .write_config::<H, u8>(offset_of!(BlkConfig, writeback), on.into())?;
+        // Some devices ignore the write, so check that it took effect.
+        if self.writeback_cache()? != on {
+            return Err(Error::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.queues
+            .iter()
+            .flatten()
+            .map(BlkQueue::stats)
+            .fold(QueueStats::default(), |total, stats| total + stats)
+    }
+
+    /// Acknowledges a pending interrupt, if any, and wakes the wakers registered for the next
+    /// completed request on each queue.
     ///
     /// Returns true if there was an interrupt to acknowledge.
     pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Like [`ack_interrupt`](Self::ack_interrupt), but returns what the interrupt was raised for
+    /// and which queues have completed requests waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        let status = self.transport.ack_interrupt_status();
+        for queue in 0..self.num_queues {
+            self.wake_next_used(queue);
+        }
+        InterruptDetails::new(
+            status,
+            self.queues
+                .iter()
+                .map(|queue| queue.as_ref().is_some_and(BlkQueue::can_pop)),
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Registers a waker to be woken by [`ack_interrupt`](Self::ack_interrupt) once the request
+    /// with the given token has completed, replacing any waker previously registered for it.
+    ///
+    /// The waker is discarded once the request is completed.
+    pub fn register_waker(&mut self, token: u16, waker: &Waker) {
+        self.register_waker_on(QUEUE, token, waker);
+    }
+
+    /// Like [`register_waker`](Self::register_waker), but for a request submitted on the given
+    /// queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queue` is not less than [`queue_count`](Self::queue_count).
+    pub fn register_waker_on(&mut self, queue: u16, token: u16, waker: &Waker) {
+        assert!(queue < self.num_queues);
+        let slot = &mut self.wakers[usize::from(queue)][usize::from(token)];
+        if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    /// Wakes the waker registered for the token at the head of the given queue's used ring, if
+    /// any.
+    ///
+    /// Requests can only be completed in the order the device used them, so there's no point
+    /// waking any others.
+    fn wake_next_used(&mut self, queue: u16) {
+        if let Some(token) = self.peek_used_on(queue) {
+            // The token comes from the device, so don't trust it to be in range.
+            if let Some(waker) = self.wakers[usize::from(queue)]
+                .get_mut(usize::from(token))
+                .and_then(Option::take)
+            {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Cleans up after the request with the given token has been popped from the given queue.
+    fn request_completed(&mut self, queue: u16, token: u16) {
+        self.wakers[usize::from(queue)][usize::from(token)] = None;
+        // The device may have completed more requests than the interrupt woke.
+        self.wake_next_used(queue);
     }
 
     /// Enables interrupts from the device.
     pub fn enable_interrupts(&mut self) {
-        self.queue.set_dev_notify(true);
+        for queue in self.queues.iter_mut().flatten() {
+            queue.set_dev_notify(true);
+        }
     }
 
     /// Disables interrupts from the device.
     pub fn disable_interrupts(&mut self) {
-        self.queue.set_dev_notify(false);
+        for queue in self.queues.iter_mut().flatten() {
+            queue.set_dev_notify(false);
+        }
+    }
+
+    /// Sets how [`poll_completion`](Self::poll_completion) waits for requests to complete.
+    ///
+    /// [`PollMode::BusyPoll`] disables interrupts from the device, as with
+    /// [`disable_interrupts`](Self::disable_interrupts); the other modes enable them. The mode is
+    /// kept across [`reset`](Self::reset).
+    pub fn set_poll_mode(&mut self, mode: PollMode) {
+        self.poll_mode = mode;
+        if mode == PollMode::BusyPoll {
+            self.disable_interrupts();
+        } else {
+            self.enable_interrupts();
+        }
+    }
+
+    /// Returns the mode set with [`set_poll_mode`](Self::set_poll_mode).
+    pub fn poll_mode(&self) -> PollMode {
+        self.poll_mode
+    }
+
+    /// Returns the token of the next completed request, waiting for one to complete according to
+    /// the [poll mode](Self::set_poll_mode).
+    ///
+    /// Like [`peek_used`](Self::peek_used) this doesn't remove the request from the used ring, so
+    /// it must still be completed with the corresponding `complete_` method. Returns `None` if no
+    /// request has completed by the time the mode gives up, in which case the caller should wait
+    /// for an interrupt, or if there are no requests in flight.
+    ///
+    /// Returns [`Error::DeviceRemoved`] or [`Error::DeviceNeedsReset`] if the device goes away or
+    /// needs a reset while polling. The requests in flight are left as they are.
+    pub fn poll_completion(&mut self) -> Result<Option<u16>> {
+        self.poll_completion_on(QUEUE)
+    }
+
+    /// Like [`poll_completion`](Self::poll_completion), but for the given queue.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than
+    /// [`queue_count`](Self::queue_count).
+    pub fn poll_completion_on(&mut self, queue: u16) -> Result<Option<u16>> {
+        let poll_mode = self.poll_mode;
+        let (virt_queue, transport) = self.queue(queue)?;
+        if let Some(token) = virt_queue.peek_used() {
+            return Ok(Some(token));
+        }
+        if virt_queue.in_flight() == 0 {
+            return Ok(None);
+        }
+        match poll_mode {
+            PollMode::Interrupt => Ok(None),
+            PollMode::BusyPoll => poll_used(virt_queue, transport, None),
+            PollMode::Hybrid { budget } => {
+                virt_queue.set_dev_notify(false);
+                let polled = poll_used(virt_queue, transport, Some(budget));
+                virt_queue.set_dev_notify(true);
+                match polled {
+                    Ok(None) => {
+                        // A request which completed after the last poll but before interrupts were
+                        // enabled again may not raise an interrupt, so look once more.
+                        H::mb();
+                        Ok(virt_queue.peek_used())
+                    }
+                    polled => polled,
+                }
+            }
+        }
     }
 
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+        self.send_request(QUEUE, request, &[], &mut [], None)?;
+        Ok(())
     }
 
-    /// Sends the given request to the device and waits for a response, including the given data.
-    fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes()],
-            &mut [data, resp.as_mut_bytes()],
-            &mut self.transport,
-        )?;
-        resp.status.into()
+    /// Sends the given request to the device on the given queue and waits for a response,
+    /// including the given data.
+    ///
+    /// Returns the number of bytes of data which the device wrote.
+    fn request_read(&mut self, queue: u16, request: BlkReq, data: &mut [u8]) -> Result<usize> {
+        self.send_request(queue, request, &[], &mut [data], None)
     }
 
-    /// Sends the given request and data to the device and waits for a response.
-    fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
-        let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
-            &[request.as_bytes(), data],
-            &mut [resp.as_mut_bytes()],
+    /// Sends the given request and data to the device on the given queue and waits for a
+    /// response.
+    fn request_write(&mut self, queue: u16, request: BlkReq, data: &[u8]) -> Result {
+        self.send_request(queue, request, &[data], &mut [], None)?;
+        Ok(())
+    }
+
+    /// Sends the given request on the given queue, followed by `inputs` for the device to read and
+    /// `outputs` for it to write, and waits for a response. The output buffers are taken from
+    /// `outputs`, leaving it empty.
+    ///
+    /// With the `alloc` feature the request header and response go in the header pool, so they
+    /// needn't be shared with the device for each request. Data which lies within `premapped`
+    /// isn't shared again either.
+    ///
+    /// Returns the number of bytes of data which the device wrote.
+    fn send_request(
+        &mut self,
+        queue: u16,
+        request: BlkReq,
+        inputs: &[&[u8]],
+        outputs: &mut [&mut [u8]],
+        premapped: Option<SharedRegion>,
+    ) -> Result<usize> {
+        check_usable(&self.transport)?;
+        self.reap_timed_out(queue)?;
+        // Borrow the fields separately, so that the header can be in the pool while the queue is
+        // in use.
+        let virt_queue = self
+            .queues
+            .get_mut(usize::from(queue))
+            .and_then(Option::as_mut)
+            .ok_or(Error::InvalidParam)?;
+
+        #[cfg(feature = "alloc")]
+        let pool_buffer = self.header_pool.alloc(HEADER_LEN)?;
+        #[cfg(feature = "alloc")]
+        // SAFETY: The buffer has just been allocated, and the device only accesses it while the
+        // request is in flight, when nothing else does.
+        let header = unsafe { pool_buffer.raw_slice().as_mut() };
+        #[cfg(feature = "alloc")]
+        let pool_regions = [
+            pool_buffer.region(),
+            premapped.unwrap_or(pool_buffer.region()),
+        ];
+        #[cfg(feature = "alloc")]
+        let regions = &pool_regions[..1 + usize::from(premapped.is_some())];
+        #[cfg(not(feature = "alloc"))]
+        let mut stack_header = [0; HEADER_LEN];
+        #[cfg(not(feature = "alloc"))]
+        let header = &mut stack_header[..];
+        #[cfg(not(feature = "alloc"))]
+        let regions = premapped.as_slice();
+
+        let (req, resp) = header.split_at_mut(size_of::<BlkReq>());
+        req.copy_from_slice(request.as_bytes());
+        // Don't report success if the device doesn't write a response.
+        resp.copy_from_slice(BlkResp::default().as_bytes());
+        let mut all_inputs: [&[u8]; MAX_FRAGMENTS + 1] = [&[]; MAX_FRAGMENTS + 1];
+        all_inputs[0] = req;
+        all_inputs[1..=inputs.len()].copy_from_slice(inputs);
+        let mut all_outputs: [&mut [u8]; MAX_FRAGMENTS + 1] = Default::default();
+        let mut data_len = 0;
+        for (output, data) in all_outputs.iter_mut().zip(outputs.iter_mut()) {
+            data_len += data.len();
+            *output = take(data);
+        }
+        all_outputs[outputs.len()] = resp;
+        let used_len = virt_queue.add_notify_wait_pop_premapped(
+            &all_inputs[..=inputs.len()],
+            &mut all_outputs[..=outputs.len()],
+            regions,
             &mut self.transport,
         )?;
-        resp.status.into()
+        Result::from(RespStatus(header[size_of::<BlkReq>()]))?;
+        // The used length includes the response. Don't trust the device to have written more data
+        // than there was room for.
+        Ok((used_len as usize)
+            .saturating_sub(size_of::<BlkResp>())
+            .min(data_len))
     }
 
-    /// Requests the device to flush any pending writes to storage.
+    /// Requests the device to flush any pending writes to storage, and blocks until it has done so.
     ///
-    /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_FLUSH`
+    /// feature. Such a device has no write cache, so writes are already stable once they complete.
     pub fn flush(&mut self) -> Result {
-        if self.negotiated_features.contains(BlkFeature::FLUSH) {
-            self.request(BlkReq {
-                type_: ReqType::Flush,
-                ..Default::default()
-            })
+        if !self.negotiated_features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        self.request(BlkReq {
+            type_: ReqType::Flush,
+            ..Default::default()
+        })
+    }
+
+    /// Submits a request to flush any pending writes to storage, but returns immediately without
+    /// waiting for the flush to complete.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_FLUSH`
+    /// feature.
+    ///
+    /// # Usage
+    ///
+    /// See [VirtIOBlk::read_blocks_nb]. Once the request has completed, the caller must call
+    /// `complete_flush` with the same buffers.
+    ///
+    /// # Safety
+    ///
+    /// `req` and `resp` are still borrowed by the underlying VirtIO block device even after this
+    /// method returns, so the caller must not access them until the request is completed.
+    pub unsafe fn flush_nb(&mut self, req: &mut BlkReq, resp: &mut BlkResp) -> Result<u16> {
+        if !self.negotiated_features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        *req = BlkReq {
+            type_: ReqType::Flush,
+            reserved: 0,
+            sector: 0,
+        };
+        let (queue, transport) = self.submit_queue(QUEUE)?;
+        let token = queue.add(&[req.as_bytes()], &mut [resp.as_mut_bytes()])?;
+        if queue.should_notify() {
+            queue.notify_device(transport);
+        }
+        Ok(token)
+    }
+
+    /// Completes a flush operation which was started by `flush_nb`.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `flush_nb` when it returned the
+    /// token.
+    pub unsafe fn complete_flush(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        let (queue, _) = self.queue(QUEUE)?;
+        queue.pop_used(token, &[req.as_bytes()], &mut [resp.as_mut_bytes()])?;
+        self.request_completed(QUEUE, token);
+        resp.status.into()
+    }
+
+    /// Tells the device that the given range of sectors is no longer in use, so it may free the
+    /// underlying storage.
+    ///
+    /// Large ranges are split into several segments and requests, according to the limits the
+    /// device gives in its configuration space.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_DISCARD`
+    /// feature.
+    pub fn discard(&mut self, sectors: Range<u64>) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::DISCARD) {
+            return Err(Error::Unsupported);
+        }
+        let transport = &self.transport;
+        let (max_sectors, max_segments) = transport.read_config_space_atomic(|| -> Result<_> {
+            Ok((
+                transport.read_config::<H, _>(offset_of!(BlkConfig, max_discard_sectors))?,
+                transport.read_config::<H, _>(offset_of!(BlkConfig, max_discard_seg))?,
+            ))
+        })?;
+        self.discard_write_zeroes(ReqType::Discard, sectors, max_sectors, max_segments)
+    }
+
+    /// Sets the given range of sectors to zero.
+    ///
+    /// Large ranges are split into several segments and requests, according to the limits the
+    /// device gives in its configuration space.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the
+    /// `VIRTIO_BLK_F_WRITE_ZEROES` feature.
+    pub fn write_zeroes(&mut self, sectors: Range<u64>) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::WRITE_ZEROES) {
+            return Err(Error::Unsupported);
+        }
+        let transport = &self.transport;
+        let (max_sectors, max_segments) = transport.read_config_space_atomic(|| -> Result<_> {
+            Ok((
+                transport.read_config::<H, _>(offset_of!(BlkConfig, max_write_zeroes_sectors))?,
+                transport.read_config::<H, _>(offset_of!(BlkConfig, max_write_zeroes_seg))?,
+            ))
+        })?;
+        self.discard_write_zeroes(ReqType::WriteZeroes, sectors, max_sectors, max_segments)
+    }
+
+    /// Sends discard or write zeroes requests for the given range of sectors, splitting it into
+    /// segments of at most `max_sectors` sectors and requests of at most `max_segments` segments.
+    ///
+    /// A limit of 0 is treated as no limit.
+    fn discard_write_zeroes(
+        &mut self,
+        type_: ReqType,
+        sectors: Range<u64>,
+        max_sectors: u32,
+        max_segments: u32,
+    ) -> Result {
+        if sectors.start > sectors.end || sectors.end > self.capacity {
+            return Err(Error::InvalidParam);
+        }
+        let max_sectors = if max_sectors == 0 {
+            u32::MAX
         } else {
-            Ok(())
+            max_sectors
+        };
+        let max_segments = match max_segments {
+            0 => MAX_SEGMENTS,
+            max_segments => min(max_segments as usize, MAX_SEGMENTS),
+        };
+
+        let mut segments = [DiscardWriteZeroes::default(); MAX_SEGMENTS];
+        let mut next_sector = sectors.start;
+        while next_sector < sectors.end {
+            let mut count = 0;
+            while count < max_segments && next_sector < sectors.end {
+                let num_sectors = min(sectors.end - next_sector, max_sectors.into()) as u32;
+                segments[count] = DiscardWriteZeroes {
+                    sector: next_sector,
+                    num_sectors,
+                    flags: 0,
+                };
+                next_sector += u64::from(num_sectors);
+                count += 1;
+            }
+            self.request_write(
+                QUEUE,
+                BlkReq {
+                    type_,
+                    ..Default::default()
+                },
+                segments[..count].as_bytes(),
+            )?;
         }
+        Ok(())
     }
 
     /// Gets the device ID.
@@ -166,6 +1036,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// length returned.
     pub fn device_id(&mut self, id: &mut [u8; 20]) -> Result<usize> {
         self.request_read(
+            QUEUE,
             BlkReq {
                 type_: ReqType::GetId,
                 ..Default::default()
@@ -177,46 +1048,118 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         Ok(length)
     }
 
-    /// Reads one or more blocks into the given buffer.
+    /// Gets the storage lifetime information of the device, such as how worn its flash is.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// Returns [`Error::Unsupported`] if the device doesn't support the `VIRTIO_BLK_F_LIFETIME`
+    /// feature, or [`Error::IoError`] if it returns less than the whole structure.
     ///
-    /// Blocks until the read completes or there is an error.
-    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_read(
+    /// Ref: 5.2.6 Device Operation
+    pub fn lifetime(&mut self) -> Result<BlkLifetime> {
+        if !self.negotiated_features.contains(BlkFeature::LIFETIME) {
+            return Err(Error::Unsupported);
+        }
+        let mut lifetime = [0; 6];
+        let len = self.request_read(
+            QUEUE,
             BlkReq {
-                type_: ReqType::In,
-                reserved: 0,
-                sector: block_id as u64,
+                type_: ReqType::GetLifetime,
+                ..Default::default()
             },
-            buf,
-        )
+            &mut lifetime,
+        )?;
+        if len < lifetime.len() {
+            return Err(Error::IoError);
+        }
+        let field = |i: usize| u16::from_le_bytes([lifetime[i], lifetime[i + 1]]);
+        Ok(BlkLifetime {
+            pre_eol_info: PreEolInfo(field(0)),
+            device_lifetime_est_typ_a: field(2),
+            device_lifetime_est_typ_b: field(4),
+        })
     }
 
-    /// Submits a request to read one or more blocks, but returns immediately without waiting for
-    /// the read to complete.
-    ///
-    /// # Arguments
+    /// Gets the serial number of the device, which is the same as its device ID.
     ///
-    /// * `block_id` - The identifier of the first block to read.
-    /// * `req` - A buffer which the driver can use for the request to send to the device. The
-    ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
-    ///   it needs to be valid (and not otherwise used) until the corresponding
-    ///   `complete_read_blocks` call. Its length must be a non-zero multiple of [`SECTOR_SIZE`].
-    /// * `buf` - The buffer in memory into which the block should be read.
-    /// * `resp` - A mutable reference to a variable provided by the caller
-    ///   to contain the status of the request. The caller can safely
-    ///   read the variable only after the request is complete.
+    /// Returns an empty string if the device doesn't have a serial number, or
+    /// [`Error::Unsupported`] if the device doesn't support the request.
+    #[cfg(feature = "alloc")]
+    pub fn serial(&mut self) -> Result<String> {
+        let mut id = [0; 20];
+        let length = self.device_id(&mut id)?;
+        Ok(String::from_utf8(id[..length].to_vec())?)
+    }
+
+    /// Reads one or more blocks into the given buffer.
     ///
-    /// # Usage
+    /// `block_id` is in 512 byte ([`SECTOR_SIZE`]) sectors. It must be aligned to the logical block
+    /// size, and the buffer length must be a non-zero multiple of it, or [`Error::InvalidParam`] is
+    /// returned; the other read and write methods check the same. Returns [`Error::IoError`] if the
+    /// device reports that it filled less than the whole buffer; use
+    /// [`read_blocks_len`](Self::read_blocks_len) to accept short reads instead.
     ///
-    /// It will submit request to the VirtIO block device and return a token identifying
-    /// the position of the first Descriptor in the chain. If there are not enough
-    /// Descriptors to allocate, then it returns [`Error::QueueFull`].
+    /// If the buffer is longer than the device's [`size_max`](Self::size_max) and
+    /// [`seg_max`](Self::seg_max) allow in one request, it is read with several, one after another.
     ///
-    /// The caller can then call `peek_used` with the returned token to check whether the device has
+    /// Blocks until the read completes or there is an error.
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        self.read_blocks_on(QUEUE, block_id, buf)
+    }
+
+    /// Like [`read_blocks`](Self::read_blocks), but sends the request on the given queue.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than
+    /// [`queue_count`](Self::queue_count).
+    pub fn read_blocks_on(&mut self, queue: u16, block_id: usize, buf: &mut [u8]) -> Result {
+        if self.read_blocks_len_on(queue, block_id, buf)? != buf.len() {
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+
+    /// Like [`read_blocks`](Self::read_blocks), but returns the number of bytes which the device
+    /// reports having read into the buffer, which may be less than its length, e.g. at the end of
+    /// the backing file.
+    pub fn read_blocks_len(&mut self, block_id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.read_blocks_len_on(QUEUE, block_id, buf)
+    }
+
+    /// Like [`read_blocks_len`](Self::read_blocks_len), but sends the request on the given queue.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than
+    /// [`queue_count`](Self::queue_count).
+    pub fn read_blocks_len_on(
+        &mut self,
+        queue: u16,
+        block_id: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.check_blocks(block_id, buf.len())?;
+        self.read_segmented(queue, block_id as u64, once(buf), None)
+    }
+
+    /// Submits a request to read one or more blocks, but returns immediately without waiting for
+    /// the read to complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id` - The identifier of the first block to read.
+    /// * `req` - A buffer which the driver can use for the request to send to the device. The
+    ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
+    ///   it needs to be valid (and not otherwise used) until the corresponding
+    ///   `complete_read_blocks` call. Its length must be a non-zero multiple of the logical block
+    ///   size.
+    /// * `buf` - The buffer in memory into which the block should be read.
+    /// * `resp` - A mutable reference to a variable provided by the caller
+    ///   to contain the status of the request. The caller can safely
+    ///   read the variable only after the request is complete.
+    ///
+    /// # Usage
+    ///
+    /// It will submit request to the VirtIO block device and return a token identifying
+    /// the position of the first Descriptor in the chain. If there are not enough
+    /// Descriptors to allocate, then it returns [`Error::QueueFull`].
+    ///
+    /// The caller can then call `peek_used` with the returned token to check whether the device has
     /// finished handling the request. Once it has, the caller must call `complete_read_blocks` with
     /// the same buffers before reading the response.
     ///
@@ -259,18 +1202,35 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<u16> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.read_blocks_nb_on(QUEUE, block_id, req, buf, resp)
+    }
+
+    /// Like [`read_blocks_nb`](Self::read_blocks_nb), but submits the request on the given queue.
+    ///
+    /// The returned token must only be used with the same queue. Returns [`Error::InvalidParam`]
+    /// if `queue` is not less than [`queue_count`](Self::queue_count).
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn read_blocks_nb_on(
+        &mut self,
+        queue: u16,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.check_blocks(block_id, buf.len())?;
         *req = BlkReq {
             type_: ReqType::In,
             reserved: 0,
             sector: block_id as u64,
         };
-        let token = self
-            .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
+        let (virt_queue, transport) = self.submit_queue(queue)?;
+        let token = virt_queue.add(&[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
+        if virt_queue.should_notify() {
+            virt_queue.notify_device(transport);
         }
         Ok(token)
     }
@@ -288,29 +1248,448 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
+        self.complete_read_blocks_on(QUEUE, token, req, buf, resp)
+    }
+
+    /// Completes a read operation which was started by `read_blocks_nb_on` on the given queue.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `read_blocks_nb_on` when it
+    /// returned the token.
+    pub unsafe fn complete_read_blocks_on(
+        &mut self,
+        queue: u16,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        let (virt_queue, _) = self.queue(queue)?;
+        virt_queue.pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_mut_bytes()])?;
+        self.request_completed(queue, token);
         resp.status.into()
     }
 
     /// Writes the contents of the given buffer to a block or blocks.
     ///
-    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// The block and buffer length must be whole logical blocks, and the buffer is split into
+    /// several requests if need be, as for [`read_blocks`](Self::read_blocks).
     ///
     /// Blocks until the write is complete or there is an error.
     pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        self.request_write(
-            BlkReq {
-                type_: ReqType::Out,
-                sector: block_id as u64,
-                ..Default::default()
-            },
-            buf,
+        self.write_blocks_on(QUEUE, block_id, buf)
+    }
+
+    /// Like [`write_blocks`](Self::write_blocks), but sends the request on the given queue.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than
+    /// [`queue_count`](Self::queue_count).
+    pub fn write_blocks_on(&mut self, queue: u16, block_id: usize, buf: &[u8]) -> Result {
+        self.check_blocks(block_id, buf.len())?;
+        self.write_segmented(queue, block_id as u64, once(buf), None)
+    }
+
+    /// Reads one or more blocks into the given DMA buffer, which the device writes to directly
+    /// rather than through a newly shared buffer.
+    ///
+    /// The buffer length must be a non-zero multiple of the logical block size. Returns
+    /// [`Error::IoError`] if the device reports that it filled less than the whole buffer.
+    ///
+    /// Blocks until the read completes or there is an error.
+    #[cfg(feature = "alloc")]
+    pub fn read_blocks_into(&mut self, block_id: usize, buf: &mut DmaBuffer<H>) -> Result {
+        self.check_blocks(block_id, buf.len())?;
+        let region = buf.region();
+        let len = self.read_segmented(
+            QUEUE,
+            block_id as u64,
+            once(buf.as_mut_slice()),
+            Some(region),
+        )?;
+        if len != buf.len() {
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of the given DMA buffer to a block or blocks, letting the device read it
+    /// directly rather than through a newly shared buffer.
+    ///
+    /// The buffer length must be a non-zero multiple of the logical block size.
+    ///
+    /// Blocks until the write is complete or there is an error.
+    #[cfg(feature = "alloc")]
+    pub fn write_blocks_from(&mut self, block_id: usize, buf: &DmaBuffer<H>) -> Result {
+        self.check_blocks(block_id, buf.len())?;
+        self.write_segmented(
+            QUEUE,
+            block_id as u64,
+            once(buf.as_slice()),
+            Some(buf.region()),
         )
     }
 
+    /// Reads one or more blocks into the given list of buffers, filling each in turn.
+    ///
+    /// The total length of the buffers must be a non-zero multiple of the logical block size, and
+    /// there may be at most [`MAX_FRAGMENTS`] non-empty buffers, otherwise [`Error::InvalidParam`]
+    /// is returned. Empty buffers are skipped. Buffers are split into several segments, and the
+    /// segments into several requests, as the device's [`size_max`](Self::size_max) and
+    /// [`seg_max`](Self::seg_max) need, as long as each request can be whole logical blocks.
+    ///
+    /// Blocks until the read is complete or there is an error.
+    pub fn read_blocks_iov(&mut self, block_id: usize, bufs: &mut [&mut [u8]]) -> Result {
+        self.check_fragments(block_id, bufs.iter().map(|buf| buf.len()))?;
+        self.read_segmented(
+            QUEUE,
+            block_id as u64,
+            bufs.iter_mut().map(|buf| &mut **buf),
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Writes the contents of the given list of buffers, one after another, to a block or blocks.
+    ///
+    /// The total length of the buffers must be a non-zero multiple of the logical block size, and
+    /// there may be at most [`MAX_FRAGMENTS`] non-empty buffers, otherwise [`Error::InvalidParam`]
+    /// is returned. Empty buffers are skipped. The buffers are split into requests as for
+    /// [`read_blocks_iov`](Self::read_blocks_iov).
+    ///
+    /// Blocks until the write is complete or there is an error.
+    pub fn write_blocks_iov(&mut self, block_id: usize, bufs: &[&[u8]]) -> Result {
+        self.check_fragments(block_id, bufs.iter().map(|buf| buf.len()))?;
+        self.write_segmented(QUEUE, block_id as u64, bufs.iter().copied(), None)
+    }
+
+    /// Reads the sectors starting at `start_sector` into the given buffer, however large it is.
+    ///
+    /// The buffer is split into as few data segments as the device's `size_max` allows, and the
+    /// segments into as few requests as `seg_max` and the queue size allow. If the queue uses
+    /// indirect descriptors, each request takes a single slot in the queue however many segments
+    /// it has. The requests are sent one after another, and this blocks until they have all
+    /// completed or one fails.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer length isn't a non-zero multiple of
+    /// [`SECTOR_SIZE`], or if the sectors extend past the end of the device. If a request fails,
+    /// the sectors before it have already been read.
+    pub fn read_sectors(&mut self, start_sector: u64, buf: &mut [u8]) -> Result {
+        self.check_sectors(start_sector, buf.len())?;
+        self.read_segmented(QUEUE, start_sector, once(buf), None)?;
+        Ok(())
+    }
+
+    /// Writes the contents of the given buffer, however large it is, to the sectors starting at
+    /// `start_sector`.
+    ///
+    /// The buffer is split into requests in the same way as for
+    /// [`read_sectors`](Self::read_sectors), with the same restrictions on its length. If a request
+    /// fails, the sectors before it have already been written.
+    pub fn write_sectors(&mut self, start_sector: u64, buf: &[u8]) -> Result {
+        self.check_sectors(start_sector, buf.len())?;
+        self.write_segmented(QUEUE, start_sector, once(buf), None)
+    }
+
+    /// Checks that data survives the round trip to the device and back, by writing a pattern to
+    /// the first page of the device, reading it back and comparing.
+    ///
+    /// This is a diagnostic for bringing up a new [`Hal`] implementation: a failure means that
+    /// address translation or DMA coherence is broken. The data is written once through buffers
+    /// shared with [`Hal::share`], which use indirect descriptors if they were negotiated, and
+    /// with the `alloc` feature once more from a [`DmaBuffer`], which always uses direct
+    /// descriptors. It overwrites what was on the device, so should only be used on a scratch
+    /// device.
+    ///
+    /// Returns [`Error::Unsupported`] if the device is read-only, or [`Error::IoError`] if the data
+    /// read back isn't what was written.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn self_test(&mut self) -> Result {
+        if self.readonly() {
+            return Err(Error::Unsupported);
+        }
+        let sectors = min(self.capacity, (PAGE_SIZE / SECTOR_SIZE) as u64) as usize;
+        let len = sectors * SECTOR_SIZE / self.block_size * self.block_size;
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut expected = [0; PAGE_SIZE];
+        let mut actual = [0; PAGE_SIZE];
+        let (expected, actual) = (&mut expected[..len], &mut actual[..len]);
+        fill_test_pattern(expected, 0);
+        self.write_blocks(0, expected)?;
+        self.read_blocks(0, actual)?;
+        check_test_pattern(expected, actual)?;
+
+        #[cfg(feature = "alloc")]
+        {
+            let mut buf = DmaBuffer::new(len, BufferDirection::Both)?;
+            fill_test_pattern(expected, 1);
+            buf.as_mut_slice().copy_from_slice(expected);
+            self.write_blocks_from(0, &buf)?;
+            buf.as_mut_slice().fill(0);
+            self.read_blocks_into(0, &mut buf)?;
+            check_test_pattern(expected, buf.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a transfer of `len` bytes starting at `start_sector` is a whole number of
+    /// logical blocks and lies within the device.
+    fn check_sectors(&self, start_sector: u64, len: usize) -> Result {
+        let block_sectors = (self.block_size / SECTOR_SIZE) as u64;
+        if len == 0 || len % self.block_size != 0 || start_sector % block_sectors != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let end = start_sector
+            .checked_add((len / SECTOR_SIZE) as u64)
+            .ok_or(Error::InvalidParam)?;
+        if end > self.capacity {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Checks that a list of buffers with the given lengths is suitable for a scatter-gather read
+    /// or write starting at sector `block_id`.
+    fn check_fragments(
+        &self,
+        block_id: usize,
+        lengths: impl Iterator<Item = usize> + Clone,
+    ) -> Result {
+        self.check_blocks(block_id, lengths.clone().sum())?;
+        if lengths.filter(|&len| len != 0).count() > MAX_FRAGMENTS {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Returns the maximum number of data segments in a single request, and the maximum length in
+    /// bytes of each, taking into account both the device's limits and the size of the queue.
+    fn segment_limits(&self) -> (usize, usize) {
+        // Leave room in the chain for the request header and response.
+        let mut max_segments = usize::from(self.virt_queue_size())
+            .saturating_sub(2)
+            .clamp(1, MAX_FRAGMENTS);
+        if self.seg_max != 0 {
+            max_segments = max_segments.min(self.seg_max as usize);
+        }
+        // A descriptor can't describe more than `u32::MAX` bytes, and segments must be whole
+        // logical blocks so that every request is.
+        let mut max_segment_len = u32::MAX as usize;
+        if self.size_max != 0 {
+            max_segment_len = max_segment_len.min(self.size_max as usize);
+        }
+        let max_segment_len = (max_segment_len / self.block_size).max(1) * self.block_size;
+        (max_segments, max_segment_len)
+    }
+
+    /// Returns how many of the leading segments with the given lengths make up the largest whole
+    /// number of logical blocks, and their total length, or [`Error::InvalidParam`] if none do.
+    fn whole_blocks(&self, lengths: impl Iterator<Item = usize>) -> Result<(usize, usize)> {
+        let mut total = 0;
+        let mut whole = (0, 0);
+        for (count, len) in lengths.enumerate() {
+            total += len;
+            if total % self.block_size == 0 {
+                whole = (count + 1, total);
+            }
+        }
+        if whole.0 == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(whole)
+    }
+
+    /// Reads the sectors starting at `sector` into the given buffers, filling each in turn, by
+    /// sending as many requests on the given queue as the device's limits need.
+    ///
+    /// The buffers are split into data segments as long as `size_max` allows, and the segments
+    /// into requests with as many as `seg_max` and the queue size allow, each of whole logical
+    /// blocks. The requests are sent one after another. Data which lies within `premapped` isn't
+    /// shared again.
+    ///
+    /// Returns the total number of bytes of data which the device wrote, or
+    /// [`Error::InvalidParam`] if the buffers can't be split into requests of whole blocks. If a
+    /// request fails, the sectors before it have already been read.
+    fn read_segmented<'a>(
+        &mut self,
+        queue: u16,
+        mut sector: u64,
+        bufs: impl Iterator<Item = &'a mut [u8]>,
+        premapped: Option<SharedRegion>,
+    ) -> Result<usize> {
+        let (max_segments, max_segment_len) = self.segment_limits();
+        let mut segments = bufs.flat_map(|buf| buf.chunks_mut(max_segment_len));
+        // Segments which have been taken from `segments` but not yet sent.
+        let mut pending: [&mut [u8]; MAX_FRAGMENTS] = Default::default();
+        let mut num_pending = 0;
+        let mut total = 0;
+        loop {
+            while num_pending < max_segments {
+                let Some(segment) = segments.next() else {
+                    break;
+                };
+                pending[num_pending] = segment;
+                num_pending += 1;
+            }
+            if num_pending == 0 {
+                return Ok(total);
+            }
+            let (count, len) =
+                self.whole_blocks(pending[..num_pending].iter().map(|segment| segment.len()))?;
+
+            let request = BlkReq {
+                type_: ReqType::In,
+                reserved: 0,
+                sector,
+            };
+            total += self.send_request(queue, request, &[], &mut pending[..count], premapped)?;
+
+            pending.rotate_left(count);
+            num_pending -= count;
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+    }
+
+    /// Writes the contents of the given buffers, one after another, to the sectors starting at
+    /// `sector`, split into requests on the given queue in the same way as for
+    /// [`read_segmented`](Self::read_segmented).
+    fn write_segmented<'a>(
+        &mut self,
+        queue: u16,
+        mut sector: u64,
+        bufs: impl Iterator<Item = &'a [u8]>,
+        premapped: Option<SharedRegion>,
+    ) -> Result {
+        let (max_segments, max_segment_len) = self.segment_limits();
+        let mut segments = bufs.flat_map(|buf| buf.chunks(max_segment_len));
+        // Segments which have been taken from `segments` but not yet sent.
+        let mut pending: [&[u8]; MAX_FRAGMENTS] = [&[]; MAX_FRAGMENTS];
+        let mut num_pending = 0;
+        loop {
+            while num_pending < max_segments {
+                let Some(segment) = segments.next() else {
+                    break;
+                };
+                pending[num_pending] = segment;
+                num_pending += 1;
+            }
+            if num_pending == 0 {
+                return Ok(());
+            }
+            let (count, len) =
+                self.whole_blocks(pending[..num_pending].iter().map(|segment| segment.len()))?;
+
+            let request = BlkReq {
+                type_: ReqType::Out,
+                reserved: 0,
+                sector,
+            };
+            self.send_request(queue, request, &pending[..count], &mut [], premapped)?;
+
+            pending.rotate_left(count);
+            num_pending -= count;
+            sector += (len / SECTOR_SIZE) as u64;
+        }
+    }
+
+    /// Reads one or more blocks into the given buffer, giving up with [`Error::Timeout`] if the
+    /// device doesn't complete the read within `spins` polls of the used ring.
+    ///
+    /// The buffer length must be a non-zero multiple of the logical block size. The data goes
+    /// through a staging buffer owned by the driver, a page at a time, so that a request which
+    /// times out can be left with the device without it still having access to `buf`. Until the
+    /// device completes such a request, new requests fail with [`Error::NotReady`].
+    pub fn read_blocks_timeout(&mut self, block_id: usize, buf: &mut [u8], spins: usize) -> Result {
+        self.check_blocks(block_id, buf.len())?;
+        let mut spins = spins;
+        for (i, chunk) in buf.chunks_mut(PAGE_SIZE).enumerate() {
+            let staged = StagedRequest::new(
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector: (block_id + i * PAGE_SIZE / SECTOR_SIZE) as u64,
+                },
+                chunk.len(),
+            )?;
+            let staged = self.submit_staged(QUEUE, staged, &mut spins)?;
+            // SAFETY: The request has been popped, so the device is done with the staging buffers.
+            chunk.copy_from_slice(unsafe { staged.data() });
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of the given buffer to one or more blocks, giving up with
+    /// [`Error::Timeout`] if the device doesn't complete the write within `spins` polls of the
+    /// used ring.
+    ///
+    /// The buffer length must be a non-zero multiple of the logical block size. As with
+    /// [`read_blocks_timeout`](Self::read_blocks_timeout), the data is staged a page at a time;
+    /// after a timeout some of the blocks may have been written.
+    pub fn write_blocks_timeout(&mut self, block_id: usize, buf: &[u8], spins: usize) -> Result {
+        self.check_blocks(block_id, buf.len())?;
+        let mut spins = spins;
+        for (i, chunk) in buf.chunks(PAGE_SIZE).enumerate() {
+            let staged = StagedRequest::new(
+                BlkReq {
+                    type_: ReqType::Out,
+                    reserved: 0,
+                    sector: (block_id + i * PAGE_SIZE / SECTOR_SIZE) as u64,
+                },
+                chunk.len(),
+            )?;
+            // SAFETY: The request hasn't been submitted yet, so nothing else is using the staging
+            // buffers.
+            unsafe { staged.data_mut() }.copy_from_slice(chunk);
+            self.submit_staged(QUEUE, staged, &mut spins)?;
+        }
+        Ok(())
+    }
+
+    /// Submits the given staged request on the given queue and waits for it to complete, for at
+    /// most `spins` polls of the used ring, which are subtracted from `spins`.
+    ///
+    /// If it times out, or the device sets `DEVICE_NEEDS_RESET` meanwhile, the request is kept in
+    /// `timed_out` until the device completes it or is reset.
+    fn submit_staged(
+        &mut self,
+        queue: u16,
+        staged: StagedRequest<H>,
+        spins: &mut usize,
+    ) -> Result<StagedRequest<H>> {
+        let (virt_queue, transport) = self.submit_queue(queue)?;
+        // SAFETY: The staging buffers are only freed after the request is popped, either below or
+        // by `reap_timed_out`, or when the queue is dropped.
+        let token = unsafe { staged.add(virt_queue) }?;
+        if virt_queue.should_notify() {
+            virt_queue.notify_device(transport);
+        }
+
+        let mut polls = 0usize;
+        while virt_queue.peek_used() != Some(token) {
+            if *spins == 0 {
+                self.timed_out[usize::from(queue)] = Some((token, staged));
+                return Err(Error::Timeout);
+            }
+            polls += 1;
+            if polls % NEEDS_RESET_POLL_INTERVAL == 0 {
+                if let Err(e) = check_usable(transport) {
+                    self.timed_out[usize::from(queue)] = Some((token, staged));
+                    return Err(e);
+                }
+            }
+            *spins -= 1;
+            spin_loop();
+        }
+
+        // SAFETY: The request was added with `staged.add` above and returned this token.
+        unsafe { staged.pop(virt_queue, token) }?;
+        staged.status()?;
+        Ok(staged)
+    }
+
     /// Submits a request to write one or more blocks, but returns immediately without waiting for
     /// the write to complete.
     ///
@@ -322,7 +1701,7 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ///   it needs to be valid (and not otherwise used) until the corresponding
     ///   `complete_write_blocks` call.
     /// * `buf` - The buffer in memory containing the data to write to the blocks. Its length must
-    ///   be a non-zero multiple of [`SECTOR_SIZE`].
+    ///   be a non-zero multiple of the logical block size.
     /// * `resp` - A mutable reference to a variable provided by the caller
     ///   to contain the status of the request. The caller can safely
     ///   read the variable only after the request is complete.
@@ -341,18 +1720,35 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<u16> {
-        assert_ne!(buf.len(), 0);
-        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.write_blocks_nb_on(QUEUE, block_id, req, buf, resp)
+    }
+
+    /// Like [`write_blocks_nb`](Self::write_blocks_nb), but submits the request on the given
+    /// queue.
+    ///
+    /// See [VirtIOBlk::read_blocks_nb_on].
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb_on(
+        &mut self,
+        queue: u16,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        self.check_blocks(block_id, buf.len())?;
         *req = BlkReq {
             type_: ReqType::Out,
             reserved: 0,
             sector: block_id as u64,
         };
-        let token = self
-            .queue
-            .add(&[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
+        let (virt_queue, transport) = self.submit_queue(queue)?;
+        let token = virt_queue.add(&[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
+        if virt_queue.should_notify() {
+            virt_queue.notify_device(transport);
         }
         Ok(token)
     }
@@ -370,78 +1766,680 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
+        self.complete_write_blocks_on(QUEUE, token, req, buf, resp)
+    }
+
+    /// Completes a write operation which was started by `write_blocks_nb_on` on the given queue.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `write_blocks_nb_on` when it
+    /// returned the token.
+    pub unsafe fn complete_write_blocks_on(
+        &mut self,
+        queue: u16,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        let (virt_queue, _) = self.queue(queue)?;
+        virt_queue.pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_mut_bytes()])?;
+        self.request_completed(queue, token);
         resp.status.into()
     }
 
+    /// Returns a future which reads one or more blocks into the given buffer.
+    ///
+    /// The buffer length must be a non-zero multiple of the logical block size.
+    ///
+    /// The request is submitted when the future is first polled. It may not complete if there are
+    /// other requests in flight from the non-blocking API, as they must be completed in the order
+    /// the device finishes them.
+    ///
+    /// If the future is dropped after the request has been submitted but before it completes, the
+    /// drop blocks until the device has finished with the buffer.
+    pub fn read_blocks_async<'a>(
+        &'a mut self,
+        block_id: usize,
+        buf: &'a mut [u8],
+    ) -> BlkFuture<'a, H, T> {
+        BlkFuture::new(self, block_id, BlkData::Read(buf))
+    }
+
+    /// Returns a future which writes the contents of the given buffer to a block or blocks.
+    ///
+    /// The buffer length must be a non-zero multiple of the logical block size.
+    ///
+    /// See [`read_blocks_async`](Self::read_blocks_async) for how the request is submitted and
+    /// completed.
+    pub fn write_blocks_async<'a>(
+        &'a mut self,
+        block_id: usize,
+        buf: &'a [u8],
+    ) -> BlkFuture<'a, H, T> {
+        BlkFuture::new(self, block_id, BlkData::Write(buf))
+    }
+
     /// Fetches the token of the next completed request from the used ring and returns it, without
     /// removing it from the used ring. If there are no pending completed requests returns `None`.
     pub fn peek_used(&mut self) -> Option<u16> {
-        self.queue.peek_used()
+        self.peek_used_on(QUEUE)
+    }
+
+    /// Like [`peek_used`](Self::peek_used), but for the given queue.
+    ///
+    /// Returns `None` if `queue` is not less than [`queue_count`](Self::queue_count).
+    pub fn peek_used_on(&mut self, queue: u16) -> Option<u16> {
+        self.queues
+            .get(usize::from(queue))?
+            .as_ref()
+            .and_then(BlkQueue::peek_used)
+    }
+
+    /// Returns whether the request with the given token on the default queue has completed, setting
+    /// aside any completed requests ahead of it so that they can still be popped later.
+    fn token_used(&mut self, token: u16) -> bool {
+        self.queues[usize::from(QUEUE)]
+            .as_mut()
+            .is_some_and(|virt_queue| virt_queue.token_used(token))
+    }
+
+    /// Returns how many requests with a single data buffer, such as those submitted by
+    /// [`read_blocks_nb`](Self::read_blocks_nb), can be in flight on each queue at once.
+    ///
+    /// Callers submitting non-blocking requests can use this to pace themselves rather than run
+    /// into [`Error::QueueFull`], along with [`in_flight`](Self::in_flight).
+    pub fn max_in_flight(&self) -> usize {
+        // Each request is a header, the data and a status byte.
+        self.queues[usize::from(QUEUE)]
+            .as_ref()
+            .map_or(0, |queue| queue.max_in_flight(3))
+    }
+
+    /// Returns how many requests have been submitted but not completed yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight_on(QUEUE)
+    }
+
+    /// Like [`in_flight`](Self::in_flight), but for the given queue.
+    ///
+    /// Returns 0 if `queue` is not less than [`queue_count`](Self::queue_count).
+    pub fn in_flight_on(&self, queue: u16) -> usize {
+        self.queues
+            .get(usize::from(queue))
+            .and_then(Option::as_ref)
+            .map_or(0, BlkQueue::in_flight)
+    }
+
+    /// Lends the given request queue to `f`, along with a [`QueueNotifier`] to tell the device
+    /// about buffers added to it, so that the caller can submit and pop requests itself, e.g. to
+    /// batch them in its own way.
+    ///
+    /// The driver keeps the transport and configuration space, and can't be used until `f`
+    /// returns. Chains which `f` leaves in flight must be popped with their own buffers in a later
+    /// call; meanwhile [`peek_used`](Self::peek_used) reports them like any other request, the
+    /// driver's blocking requests set them aside while waiting, and [`reset`](Self::reset)
+    /// abandons them.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than
+    /// [`queue_count`](Self::queue_count), or without calling `f` if a new request on the queue
+    /// would fail because the device needs a reset, has been removed or hasn't completed a request
+    /// which timed out. Returns [`Error::Unsupported`] if the device negotiated
+    /// `VIRTIO_F_RING_PACKED`, as only split queues can be lent out.
+    pub fn with_queue<R>(
+        &mut self,
+        queue: u16,
+        f: impl FnOnce(&mut VirtQueue<H, { QUEUE_SIZE as usize }>, &mut QueueNotifier<'_, T>) -> R,
+    ) -> Result<R> {
+        let (virt_queue, transport) = self.submit_queue(queue)?;
+        let virt_queue = virt_queue.split_mut().ok_or(Error::Unsupported)?;
+        Ok(f(virt_queue, &mut QueueNotifier { transport }))
     }
 
     /// Returns the size of the device's VirtQueue.
     ///
     /// This can be used to tell the caller how many channels to monitor on.
     pub fn virt_queue_size(&self) -> u16 {
-        QUEUE_SIZE
+        self.queues[usize::from(QUEUE)]
+            .as_ref()
+            .map_or(QUEUE_SIZE, BlkQueue::size)
     }
 }
 
-impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
-    fn drop(&mut self) {
-        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
-        // after they have been freed.
-        self.transport.queue_unset(QUEUE);
+/// Notifies the device about buffers added to a queue lent out by [`VirtIOBlk::with_queue`],
+/// without giving access to the rest of the transport.
+pub struct QueueNotifier<'a, T: Transport> {
+    transport: &'a mut T,
+}
+
+impl<T: Transport> QueueNotifier<'_, T> {
+    /// Notifies the device about the buffers added to `queue`, unless it has asked not to be
+    /// notified. `queue` should be the one lent out along with the notifier.
+    pub fn notify<H: Hal, const SIZE: usize>(&mut self, queue: &mut VirtQueue<H, SIZE>) {
+        if queue.should_notify() {
+            queue.notify_device(self.transport);
+        }
     }
 }
 
-#[repr(C)]
-struct BlkConfig {
-    /// Number of 512 Bytes sectors
-    capacity_low: Volatile<u32>,
-    capacity_high: Volatile<u32>,
-    size_max: Volatile<u32>,
-    seg_max: Volatile<u32>,
-    cylinders: Volatile<u16>,
-    heads: Volatile<u8>,
-    sectors: Volatile<u8>,
-    blk_size: Volatile<u32>,
-    physical_block_exp: Volatile<u8>,
-    alignment_offset: Volatile<u8>,
-    min_io_size: Volatile<u16>,
-    opt_io_size: Volatile<u32>,
-    // ... ignored
+/// The data buffer of an asynchronous request.
+#[derive(Debug)]
+enum BlkData<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
 }
 
-/// A VirtIO block device request.
-#[repr(C)]
-#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
-pub struct BlkReq {
-    type_: ReqType,
-    reserved: u32,
-    sector: u64,
+/// A future for an asynchronous block read or write, returned by
+/// [`VirtIOBlk::read_blocks_async`] or [`VirtIOBlk::write_blocks_async`].
+///
+/// The request and response buffers live inside the future, which is why it must be pinned.
+pub struct BlkFuture<'a, H: Hal, T: Transport> {
+    blk: &'a mut VirtIOBlk<H, T>,
+    block_id: usize,
+    data: BlkData<'a>,
+    req: BlkReq,
+    resp: BlkResp,
+    /// The token of the request, once it has been submitted and until it completes.
+    token: Option<u16>,
+    _pinned: PhantomPinned,
 }
 
-impl Default for BlkReq {
-    fn default() -> Self {
+impl<'a, H: Hal, T: Transport> BlkFuture<'a, H, T> {
+    fn new(blk: &'a mut VirtIOBlk<H, T>, block_id: usize, data: BlkData<'a>) -> Self {
         Self {
-            type_: ReqType::In,
-            reserved: 0,
-            sector: 0,
+            blk,
+            block_id,
+            data,
+            req: BlkReq::default(),
+            resp: BlkResp::default(),
+            token: None,
+            _pinned: PhantomPinned,
         }
     }
-}
 
-/// Response of a VirtIOBlk request.
-#[repr(C)]
-#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
-pub struct BlkResp {
-    status: RespStatus,
+    /// Pops the submitted request, which the device must have completed.
+    fn complete(&mut self, token: u16) -> Result {
+        self.token = None;
+        // SAFETY: These are the same buffers which were passed to `submit`.
+        unsafe {
+            match &mut self.data {
+                BlkData::Read(buf) => {
+                    self.blk
+                        .complete_read_blocks(token, &self.req, buf, &mut self.resp)
+                }
+                BlkData::Write(buf) => {
+                    self.blk
+                        .complete_write_blocks(token, &self.req, buf, &mut self.resp)
+                }
+            }
+        }
+    }
 }
 
-impl BlkResp {
+impl<H: Hal, T: Transport> Future for BlkFuture<'_, H, T> {
+    type Output = Result;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        // SAFETY: Nothing is moved out of the future. The request and response buffers stay where
+        // they are from submission until `complete` or `drop`.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        let token = match this.token {
+            Some(token) => token,
+            None => {
+                // SAFETY: The future is pinned so `req` and `resp` won't move, the data buffer is
+                // borrowed for the lifetime of the future, and none of them are accessed again
+                // until the request is completed, either in `poll` or in `drop`.
+                let submitted = unsafe {
+                    match &mut this.data {
+                        BlkData::Read(buf) => this.blk.read_blocks_nb(
+                            this.block_id,
+                            &mut this.req,
+                            buf,
+                            &mut this.resp,
+                        ),
+                        BlkData::Write(buf) => this.blk.write_blocks_nb(
+                            this.block_id,
+                            &mut this.req,
+                            buf,
+                            &mut this.resp,
+                        ),
+                    }
+                };
+                match submitted {
+                    Ok(token) => {
+                        this.token = Some(token);
+                        token
+                    }
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+        };
+
+        // Register the waker before checking the used ring, so that a completion between the two
+        // still wakes the task.
+        this.blk.register_waker(token, cx.waker());
+        this.blk.ack_interrupt();
+        if this.blk.token_used(token) {
+            Poll::Ready(this.complete(token))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for BlkFuture<'_, H, T> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token {
+            // The device may still be using the buffers, so they can't be freed until it is done.
+            while !self.blk.token_used(token) {
+                spin_loop();
+            }
+            // There's nobody to report an error to.
+            let _ = self.complete(token);
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOBlk<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOBlk::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        if let Err(e) = VirtIOBlk::reset(self) {
+            warn!("Failed to reinitialise block device after reset: {}", e);
+        }
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(
+            &self.transport,
+            self.queues.iter().flatten().map(BlkQueue::snapshot),
+        )
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    /// Returns the number of bytes of DMA memory the driver currently holds, for its virtqueues, its
+    /// pool of request headers and any requests which timed out but may still be completed by the
+    /// device.
+    fn dma_footprint(&self) -> usize {
+        let queues: usize = self
+            .queues
+            .iter()
+            .flatten()
+            .map(BlkQueue::dma_footprint)
+            .sum();
+        let timed_out: usize = self
+            .timed_out
+            .iter()
+            .flatten()
+            .map(|(_, staged)| staged.dma_footprint())
+            .sum();
+        #[cfg(feature = "alloc")]
+        let header_pool = self.header_pool.size();
+        #[cfg(not(feature = "alloc"))]
+        let header_pool = 0;
+        queues + timed_out + header_pool
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        for queue in 0..self.num_queues {
+            self.transport.queue_unset(queue);
+        }
+    }
+}
+
+/// A request whose header, data and response live in DMA memory owned by the driver, rather than
+/// in buffers borrowed from the caller, so that it can outlive the call which submitted it.
+struct StagedRequest<H: Hal> {
+    /// The request header, followed by the response.
+    header: Dma<H>,
+    data: Dma<H>,
+    data_len: usize,
+    /// Whether the device writes the data, rather than reading it.
+    read: bool,
+}
+
+impl<H: Hal> StagedRequest<H> {
+    /// Returns the total size in bytes of the staging buffers.
+    fn dma_footprint(&self) -> usize {
+        self.header.size() + self.data.size()
+    }
+
+    /// Allocates staging buffers for the given request, with up to a page of data.
+    fn new(request: BlkReq, data_len: usize) -> Result<Self> {
+        assert!(data_len <= PAGE_SIZE);
+        let read = matches!(request.type_, ReqType::In);
+        let staged = Self {
+            header: Dma::new(1, BufferDirection::Both, DmaPurpose::Buffer)?,
+            data: Dma::new(
+                1,
+                if read {
+                    BufferDirection::DeviceToDriver
+                } else {
+                    BufferDirection::DriverToDevice
+                },
+                DmaPurpose::Buffer,
+            )?,
+            data_len,
+            read,
+        };
+        // SAFETY: Nothing else has access to the staging buffers yet.
+        let (req, _, resp) = unsafe { staged.buffers() };
+        req.copy_from_slice(request.as_bytes());
+        // Don't report success if the device doesn't write a response.
+        resp.copy_from_slice(BlkResp::default().as_bytes());
+        Ok(staged)
+    }
+
+    /// Returns the request header, data and response buffers.
+    ///
+    /// # Safety
+    ///
+    /// No other references to the staging buffers may exist while the returned ones are alive.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn buffers(&self) -> (&mut [u8], &mut [u8], &mut [u8]) {
+        // SAFETY: The DMA regions are valid for their whole length, and our caller promises that
+        // there are no other references to them.
+        let (header, data) = unsafe {
+            (
+                self.header.raw_slice().as_mut(),
+                self.data.raw_slice().as_mut(),
+            )
+        };
+        let (req, rest) = header.split_at_mut(size_of::<BlkReq>());
+        (
+            req,
+            &mut data[..self.data_len],
+            &mut rest[..size_of::<BlkResp>()],
+        )
+    }
+
+    /// Returns the data buffer.
+    ///
+    /// # Safety
+    ///
+    /// The request must not be in the queue.
+    unsafe fn data(&self) -> &[u8] {
+        // SAFETY: Our caller promises that the device isn't using the buffer.
+        unsafe { self.buffers().1 }
+    }
+
+    /// Returns the data buffer for writing.
+    ///
+    /// # Safety
+    ///
+    /// The request must not be in the queue.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn data_mut(&self) -> &mut [u8] {
+        // SAFETY: Our caller promises that the device isn't using the buffer.
+        unsafe { self.buffers().1 }
+    }
+
+    /// Returns the status of the completed request.
+    fn status(&self) -> Result {
+        // SAFETY: The response is only read after the request has been popped.
+        let resp = unsafe { self.buffers().2 };
+        RespStatus(resp[0]).into()
+    }
+
+    /// Adds the request to the given queue.
+    ///
+    /// # Safety
+    ///
+    /// The staging buffers must not be dropped or otherwise accessed until the request has been
+    /// popped with `pop`.
+    unsafe fn add(&self, queue: &mut BlkQueue<H>) -> Result<u16> {
+        // SAFETY: Our caller promises that the buffers stay valid and aren't otherwise accessed
+        // until the request is popped.
+        unsafe {
+            let (req, data, resp) = self.buffers();
+            if self.read {
+                queue.add(&[req], &mut [data, resp])
+            } else {
+                queue.add(&[req, data], &mut [resp])
+            }
+        }
+    }
+
+    /// Pops the request from the given queue.
+    ///
+    /// # Safety
+    ///
+    /// The request must have been added to the queue with `add`, which returned `token`.
+    unsafe fn pop(&self, queue: &mut BlkQueue<H>, token: u16) -> Result<u32> {
+        // SAFETY: The buffers are the same ones which were passed to `add`.
+        unsafe {
+            let (req, data, resp) = self.buffers();
+            if self.read {
+                queue.pop_used(token, &[req], &mut [data, resp])
+            } else {
+                queue.pop_used(token, &[req, data], &mut [resp])
+            }
+        }
+    }
+}
+
+#[repr(C)]
+struct BlkConfig {
+    /// Number of 512 Bytes sectors
+    capacity_low: Volatile<u32>,
+    capacity_high: Volatile<u32>,
+    size_max: Volatile<u32>,
+    seg_max: Volatile<u32>,
+    cylinders: Volatile<u16>,
+    heads: Volatile<u8>,
+    sectors: Volatile<u8>,
+    blk_size: Volatile<u32>,
+    physical_block_exp: Volatile<u8>,
+    alignment_offset: Volatile<u8>,
+    min_io_size: Volatile<u16>,
+    opt_io_size: Volatile<u32>,
+    writeback: Volatile<u8>,
+    unused0: Volatile<u8>,
+    num_queues: Volatile<u16>,
+    max_discard_sectors: Volatile<u32>,
+    max_discard_seg: Volatile<u32>,
+    discard_sector_alignment: Volatile<u32>,
+    max_write_zeroes_sectors: Volatile<u32>,
+    max_write_zeroes_seg: Volatile<u32>,
+    write_zeroes_may_unmap: Volatile<u8>,
+    unused1: [u8; 3],
+    max_secure_erase_sectors: Volatile<u32>,
+    max_secure_erase_seg: Volatile<u32>,
+    secure_erase_sector_alignment: Volatile<u32>,
+}
+
+/// How [`VirtIOBlk::poll_completion`] waits for requests to complete.
+///
+/// Spinning gives the lowest latency for fast devices, at the cost of CPU time; relying on
+/// interrupts frees the CPU but adds the latency of the interrupt.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PollMode {
+    /// Don't spin; the caller waits for an interrupt. This is the default.
+    #[default]
+    Interrupt,
+    /// Spin until a request completes, with interrupts from the device disabled.
+    BusyPoll,
+    /// Spin for up to `budget` polls of the used ring with interrupts from the device disabled,
+    /// then enable them again and let the caller wait for an interrupt.
+    Hybrid {
+        /// The maximum number of polls.
+        budget: usize,
+    },
+}
+
+/// Polls the used ring of the given queue until a request completes, or for at most `budget`
+/// polls if given, checking every [`NEEDS_RESET_POLL_INTERVAL`] polls whether the device is still
+/// usable.
+fn poll_used<H: Hal>(
+    queue: &BlkQueue<H>,
+    transport: &impl Transport,
+    budget: Option<usize>,
+) -> Result<Option<u16>> {
+    let mut polls = 0usize;
+    loop {
+        if let Some(token) = queue.peek_used() {
+            return Ok(Some(token));
+        }
+        if budget.is_some_and(|budget| polls >= budget) {
+            return Ok(None);
+        }
+        polls = polls.wrapping_add(1);
+        if polls % NEEDS_RESET_POLL_INTERVAL == 0 {
+            check_usable(transport)?;
+        }
+        spin_loop();
+    }
+}
+
+/// Fills `buf` with the pattern for [`VirtIOBlk::self_test`], which differs between sectors and
+/// between passes so that data written to or read from the wrong place is noticed.
+#[cfg(any(test, feature = "test-utils"))]
+fn fill_test_pattern(buf: &mut [u8], pass: u8) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (i as u8) ^ ((i / SECTOR_SIZE) as u8).wrapping_mul(17) ^ pass.wrapping_mul(0x5a);
+    }
+}
+
+/// Compares the data read back by [`VirtIOBlk::self_test`] with what was written.
+#[cfg(any(test, feature = "test-utils"))]
+fn check_test_pattern(expected: &[u8], actual: &[u8]) -> Result {
+    if let Some(offset) = expected.iter().zip(actual).position(|(e, a)| e != a) {
+        warn!(
+            "Block device self-test read back {:#04x} rather than {:#04x} at byte {}",
+            actual[offset], expected[offset], offset
+        );
+        return Err(Error::IoError);
+    }
+    Ok(())
+}
+
+/// The I/O alignment and size information of a block device.
+///
+/// Sizes are in units of logical blocks, i.e. [`VirtIOBlk::block_size`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlkTopology {
+    /// The base-2 logarithm of the number of logical blocks per physical block.
+    pub physical_block_exp: u8,
+    /// The offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// The suggested minimum I/O size.
+    pub min_io_size: u16,
+    /// The suggested maximum sustained I/O size.
+    pub opt_io_size: u32,
+}
+
+/// A change to the configuration of a block device, as returned by
+/// [`VirtIOBlk::poll_config_change`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlkConfigChange {
+    /// The device was resized, and now has the given capacity in 512 byte ([`SECTOR_SIZE`])
+    /// sectors.
+    Capacity(u64),
+}
+
+/// The storage lifetime information of a block device, as returned by [`VirtIOBlk::lifetime`].
+///
+/// The estimates follow the eMMC and UFS standards: in steps of 10% of the device's estimated
+/// lifetime used, from 0x01 for up to 10% to 0x0b for more than its estimated lifetime, with 0 for
+/// undefined.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlkLifetime {
+    /// How much of the device's reserved blocks have been consumed.
+    pub pre_eol_info: PreEolInfo,
+    /// The estimated lifetime used of the device's type A (e.g. SLC) memory.
+    pub device_lifetime_est_typ_a: u16,
+    /// The estimated lifetime used of the device's type B (e.g. MLC) memory.
+    pub device_lifetime_est_typ_b: u16,
+}
+
+/// How much of a block device's reserved blocks have been consumed, as part of [`BlkLifetime`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PreEolInfo(pub u16);
+
+impl PreEolInfo {
+    /// Not defined by the device.
+    pub const UNDEFINED: PreEolInfo = PreEolInfo(0);
+    /// Less than 80% of the reserved blocks have been consumed.
+    pub const NORMAL: PreEolInfo = PreEolInfo(1);
+    /// 80% of the reserved blocks have been consumed.
+    pub const WARNING: PreEolInfo = PreEolInfo(2);
+    /// 90% of the reserved blocks have been consumed.
+    pub const URGENT: PreEolInfo = PreEolInfo(3);
+}
+
+/// The disk-style geometry of a block device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlkGeometry {
+    /// The number of cylinders.
+    pub cylinders: u16,
+    /// The number of heads.
+    pub heads: u8,
+    /// The number of sectors per track.
+    pub sectors: u8,
+}
+
+/// A VirtIO block device request.
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+pub struct BlkReq {
+    type_: ReqType,
+    reserved: u32,
+    sector: u64,
+}
+
+impl Default for BlkReq {
+    fn default() -> Self {
+        Self {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: 0,
+        }
+    }
+}
+
+/// A segment of a discard or write zeroes request.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Immutable, IntoBytes, KnownLayout)]
+struct DiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    /// Bit 0 is the unmap flag, which is only used for write zeroes requests.
+    flags: u32,
+}
+
+/// Response of a VirtIOBlk request.
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+pub struct BlkResp {
+    status: RespStatus,
+}
+
+impl BlkResp {
     /// Return the status of a VirtIOBlk request.
     pub fn status(&self) -> RespStatus {
         self.status
@@ -449,7 +2447,7 @@ impl BlkResp {
 }
 
 #[repr(u32)]
-#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+#[derive(Clone, Copy, Debug, Immutable, IntoBytes, KnownLayout)]
 enum ReqType {
     In = 0,
     Out = 1,
@@ -557,83 +2555,1323 @@ bitflags! {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        hal::fake::FakeHal,
-        transport::{
-            fake::{FakeTransport, QueueStatus, State},
-            DeviceType,
-        },
-    };
-    use alloc::{sync::Arc, vec};
-    use core::{mem::size_of, ptr::NonNull};
-    use std::{sync::Mutex, thread};
+/// The feature bits of a block device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to `VirtIOBlk::new_with_features`
+/// to forbid the feature. Use `trailing_zeros` to get the bit number for
+/// [`VirtioDevice::supports`](crate::device::VirtioDevice::supports).
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::blk::{features, VirtIOBlk};
+/// use virtio_drivers::device::VirtioDevice;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// // Don't use indirect descriptors or discard requests, even if the device supports them.
+/// let blk = VirtIOBlk::<HalImpl, _>::new_with_features(
+///     transport,
+///     features::RING_INDIRECT_DESC | features::DISCARD,
+/// )?;
+/// assert!(!blk.supports(features::DISCARD.trailing_zeros()));
+/// # Ok(())
+/// # }
+/// ```
+pub mod features {
+    use super::BlkFeature;
+    pub use crate::transport::features::*;
+
+    /// Device supports request barriers. (legacy)
+    pub const BARRIER: u64 = BlkFeature::BARRIER.bits();
+    /// Maximum size of any single segment is in `size_max`.
+    pub const SIZE_MAX: u64 = BlkFeature::SIZE_MAX.bits();
+    /// Maximum number of segments in a request is in `seg_max`.
+    pub const SEG_MAX: u64 = BlkFeature::SEG_MAX.bits();
+    /// Disk-style geometry specified in geometry.
+    pub const GEOMETRY: u64 = BlkFeature::GEOMETRY.bits();
+    /// Device is read-only.
+    pub const RO: u64 = BlkFeature::RO.bits();
+    /// Block size of disk is in `blk_size`.
+    pub const BLK_SIZE: u64 = BlkFeature::BLK_SIZE.bits();
+    /// Device supports scsi packet commands. (legacy)
+    pub const SCSI: u64 = BlkFeature::SCSI.bits();
+    /// Cache flush command support.
+    pub const FLUSH: u64 = BlkFeature::FLUSH.bits();
+    /// Device exports information on optimal I/O alignment.
+    pub const TOPOLOGY: u64 = BlkFeature::TOPOLOGY.bits();
+    /// Device can toggle its cache between writeback and writethrough modes.
+    pub const CONFIG_WCE: u64 = BlkFeature::CONFIG_WCE.bits();
+    /// Device supports multiqueue.
+    pub const MQ: u64 = BlkFeature::MQ.bits();
+    /// Device can support discard command, maximum discard sectors size in `max_discard_sectors`
+    /// and maximum discard segment number in `max_discard_seg`.
+    pub const DISCARD: u64 = BlkFeature::DISCARD.bits();
+    /// Device can support write zeroes command, maximum write zeroes sectors size in
+    /// `max_write_zeroes_sectors` and maximum write zeroes segment number in
+    /// `max_write_zeroes_seg`.
+    pub const WRITE_ZEROES: u64 = BlkFeature::WRITE_ZEROES.bits();
+    /// Device supports providing storage lifetime information.
+    pub const LIFETIME: u64 = BlkFeature::LIFETIME.bits();
+    /// Device can support the secure erase command.
+    pub const SECURE_ERASE: u64 = BlkFeature::SECURE_ERASE.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::{FakeHal, TrackingHal},
+        queue::packed::FakePackedDevice,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{
+        mem::size_of,
+        pin::pin,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+    use std::{sync::Mutex, task::Wake, thread};
+
+    /// A waker which counts how many times it has been woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the config space of a device with the given capacity in sectors, and every other
+    /// field zero.
+    fn blk_config(capacity: u32) -> BlkConfig {
+        BlkConfig {
+            capacity_low: Volatile::new(capacity),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+            writeback: Volatile::new(0),
+            unused0: Volatile::new(0),
+            num_queues: Volatile::new(0),
+            max_discard_sectors: Volatile::new(0),
+            max_discard_seg: Volatile::new(0),
+            discard_sector_alignment: Volatile::new(0),
+            max_write_zeroes_sectors: Volatile::new(0),
+            max_write_zeroes_seg: Volatile::new(0),
+            write_zeroes_may_unmap: Volatile::new(0),
+            unused1: [0; 3],
+            max_secure_erase_sectors: Volatile::new(0),
+            max_secure_erase_seg: Volatile::new(0),
+            secure_erase_sector_alignment: Volatile::new(0),
+        }
+    }
+
+    /// Responds to a read request on the given queue for the given sector with a block starting
+    /// with "Test data".
+    fn respond_to_read(state: &Mutex<State>, queue: u16, sector: u64) {
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(queue, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In,
+                        reserved: 0,
+                        sector,
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            }));
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = BlkConfig {
+            capacity_high: Volatile::new(0x02),
+            ..blk_config(0x42)
+        };
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RO | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.capacity(), 0x02_0000_0042);
+        assert!(blk.readonly());
+        assert_eq!(blk.block_size(), Err(Error::Unsupported));
+        assert_eq!(blk.topology(), Err(Error::Unsupported));
+        assert_eq!(blk.geometry(), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn short_config_space() {
+        // Only the capacity, size_max and seg_max fields.
+        let mut config_space = [66u32, 0, 4096, 8];
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::SIZE_MAX
+                | BlkFeature::SEG_MAX
+                | BlkFeature::BLK_SIZE
+                | BlkFeature::MQ
+                | BlkFeature::VERSION_1)
+                .bits(),
+            &mut config_space,
+        );
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<[u32; 4]>>::new(transport).unwrap();
+
+        // The features whose fields are missing are treated as unavailable.
+        assert_eq!(
+            blk.negotiated_features(),
+            (BlkFeature::SIZE_MAX | BlkFeature::SEG_MAX | BlkFeature::VERSION_1).bits()
+        );
+        assert_eq!(blk.capacity(), 66);
+        assert_eq!(blk.block_size(), Err(Error::Unsupported));
+        assert_eq!(blk.logical_block_size(), SECTOR_SIZE);
+        assert_eq!(blk.queue_count(), 1);
+    }
+
+    #[test]
+    fn poll_config_change() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let config_space = transport.config_space;
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.poll_config_change(), None);
+
+        // A change to the config space isn't noticed until the generation changes.
+        // SAFETY: The config space outlives the device.
+        unsafe { (*config_space.as_ptr()).capacity_high = Volatile::new(1) };
+        assert_eq!(blk.poll_config_change(), None);
+        assert_eq!(blk.capacity(), 66);
+
+        state.lock().unwrap().config_generation += 1;
+        assert_eq!(
+            blk.poll_config_change(),
+            Some(BlkConfigChange::Capacity(0x1_0000_0042))
+        );
+        assert_eq!(blk.capacity(), 0x1_0000_0042);
+        assert_eq!(blk.poll_config_change(), None);
+
+        // A generation change without a capacity change isn't reported.
+        state.lock().unwrap().config_generation += 1;
+        assert_eq!(blk.poll_config_change(), None);
+    }
+
+    #[test]
+    fn config_topology_geometry() {
+        let mut config_space = BlkConfig {
+            capacity_high: Volatile::new(0x02),
+            cylinders: Volatile::new(1024),
+            heads: Volatile::new(16),
+            sectors: Volatile::new(63),
+            blk_size: Volatile::new(4096),
+            physical_block_exp: Volatile::new(3),
+            alignment_offset: Volatile::new(1),
+            min_io_size: Volatile::new(8),
+            opt_io_size: Volatile::new(256),
+            ..blk_config(0x42)
+        };
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::GEOMETRY
+                | BlkFeature::BLK_SIZE
+                | BlkFeature::TOPOLOGY
+                | BlkFeature::VERSION_1)
+                .bits(),
+            &mut config_space,
+        );
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert!(!blk.readonly());
+        assert_eq!(blk.writeback_cache(), Err(Error::Unsupported));
+        assert_eq!(blk.block_size(), Ok(4096));
+        assert_eq!(
+            blk.topology(),
+            Ok(BlkTopology {
+                physical_block_exp: 3,
+                alignment_offset: 1,
+                min_io_size: 8,
+                opt_io_size: 256,
+            })
+        );
+        assert_eq!(
+            blk.geometry(),
+            Ok(BlkGeometry {
+                cylinders: 1024,
+                heads: 16,
+                sectors: 63,
+            })
+        );
+    }
+
+    #[test]
+    fn block_size_4096() {
+        let mut config_space = BlkConfig {
+            blk_size: Volatile::new(4096),
+            ..blk_config(64)
+        };
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::BLK_SIZE | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.logical_block_size(), 4096);
+        // The capacity is still in 512 byte sectors.
+        assert_eq!(blk.capacity(), 64);
+        assert_eq!(blk.capacity_bytes(), 32768);
+
+        // Transfers must be whole logical blocks, starting at the beginning of one.
+        let mut buffer = [0; 4096];
+        assert_eq!(
+            blk.read_sectors(8, &mut buffer[..SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.read_sectors(4, &mut buffer), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.read_blocks_iov(4, &mut [&mut buffer]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.read_blocks(4, &mut buffer), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.read_blocks(8, &mut buffer[..SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.write_blocks(4, &buffer), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.write_blocks(8, &buffer[..SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        let mut req = BlkReq::default();
+        let mut resp = BlkResp::default();
+        assert_eq!(
+            unsafe { blk.read_blocks_nb(4, &mut req, &mut buffer, &mut resp) },
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.in_flight(), 0);
+        // The last logical block is sectors 56 to 63.
+        assert_eq!(blk.read_sectors(64, &mut buffer), Err(Error::InvalidParam));
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 56,
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = vec![0; 4096];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                }));
+        });
+
+        assert_eq!(blk.read_sectors(56, &mut buffer), Ok(()));
+        assert_eq!(&buffer[0..9], b"Test data");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn writeback_cache() {
+        let mut config_space = BlkConfig {
+            capacity_high: Volatile::new(0x02),
+            writeback: Volatile::new(1),
+            ..blk_config(0x42)
+        };
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::CONFIG_WCE | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.writeback_cache(), Ok(true));
+        assert_eq!(blk.set_writeback_cache(false), Ok(()));
+        assert_eq!(blk.writeback_cache(), Ok(false));
+        drop(blk);
+        assert_eq!(config_space.writeback.0, 0);
+    }
+
+    #[test]
+    fn read() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                }));
+        });
+
+        // Read a block from the device.
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    /// Tests that blocking requests take their header and response from the header pool, so only
+    /// the data is shared with the device.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn read_header_pool() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<TrackingHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.push(RespStatus::OK.0);
+                    response
+                }));
+        });
+
+        let shares = TrackingHal::shares();
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+        assert_eq!(TrackingHal::shares(), shares + 1);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_legacy() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::RING_INDIRECT_DESC.bits(),
+            &mut config_space,
+        );
+        state.lock().unwrap().legacy = true;
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(state.driver_features, BlkFeature::RING_INDIRECT_DESC.bits());
+            assert_eq!(
+                state.status,
+                DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::DRIVER_OK
+            );
+        }
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                }));
+        });
+
+        // Read a block from the device.
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_write_packed() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_PACKED | BlkFeature::IN_ORDER | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert!(blk.supports(BlkFeature::RING_PACKED.bits().trailing_zeros()));
+        assert!(!blk.supports(BlkFeature::IN_ORDER.bits().trailing_zeros()));
+        assert_eq!(blk.with_queue(QUEUE, |_, _| ()), Err(Error::Unsupported));
+
+        // Start a thread to simulate the device handling a read and then a write over the packed
+        // ring.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            let mut device = FakePackedDevice::new(&state.lock().unwrap().queues[0]);
+            assert!(device.read_write(|request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.push(RespStatus::OK.0);
+                response
+            }));
+
+            State::wait_until_queue_notified(&state, QUEUE);
+            assert!(device.read_write(|request| {
+                assert_eq!(
+                    &request[0..size_of::<BlkReq>()],
+                    BlkReq {
+                        type_: ReqType::Out,
+                        reserved: 0,
+                        sector: 43
+                    }
+                    .as_bytes()
+                );
+                assert_eq!(&request[size_of::<BlkReq>()..][0..4], b"data");
+                vec![RespStatus::OK.0]
+            }));
+        });
+
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+        buffer[0..4].copy_from_slice(b"data");
+        blk.write_blocks(43, &buffer).unwrap();
+        assert_eq!(blk.in_flight(), 0);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_short() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // The device only fills the first of two sectors, both times.
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue_with_used_len::<{ QUEUE_SIZE as usize }>(
+                        QUEUE,
+                        (SECTOR_SIZE + size_of::<BlkResp>()) as u32,
+                        |_| {
+                            let mut response = vec![0; 2 * SECTOR_SIZE];
+                            response[0..9].copy_from_slice(b"Test data");
+                            response.extend_from_slice(
+                                BlkResp {
+                                    status: RespStatus::OK,
+                                }
+                                .as_bytes(),
+                            );
+                            response
+                        }
+                    ));
+            }
+        });
+
+        let mut buffer = [0; 2 * SECTOR_SIZE];
+        assert_eq!(blk.read_blocks_len(64, &mut buffer), Ok(SECTOR_SIZE));
+        assert_eq!(&buffer[0..9], b"Test data");
+        assert_eq!(blk.read_blocks(64, &mut buffer), Err(Error::IoError));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_multiqueue() {
+        let mut config_space = BlkConfig {
+            num_queues: Volatile::new(2),
+            ..blk_config(66)
+        };
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            2,
+            (BlkFeature::MQ | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.queue_count(), 2);
+
+        // Submit a request on each queue.
+        let mut requests = [BlkReq::default(), BlkReq::default()];
+        let mut buffers = [[0; SECTOR_SIZE]; 2];
+        let mut responses = [BlkResp::default(), BlkResp::default()];
+        let mut tokens = [0; 2];
+        for (queue, (((req, buf), resp), token)) in requests
+            .iter_mut()
+            .zip(&mut buffers)
+            .zip(&mut responses)
+            .zip(&mut tokens)
+            .enumerate()
+        {
+            let queue = queue as u16;
+            *token =
+                unsafe { blk.read_blocks_nb_on(queue, 40 + usize::from(queue), req, buf, resp) }
+                    .unwrap();
+            assert!(State::poll_queue_notified(&state, queue));
+        }
+
+        // Only complete the request on the second queue, which must not be visible on the first.
+        respond_to_read(&state, 1, 41);
+        state.lock().unwrap().interrupt_pending = true;
+        let details = blk.ack_interrupt_detailed();
+        assert!(details.acknowledged());
+        assert!(!details.config_changed());
+        assert_eq!(details.used_queues(), 0b10);
+        assert!(!details.queue_used(0));
+        assert!(details.queue_used(1));
+        assert_eq!(blk.peek_used_on(0), None);
+        assert_eq!(blk.peek_used_on(1), Some(tokens[1]));
+        unsafe {
+            blk.complete_read_blocks_on(
+                1,
+                tokens[1],
+                &requests[1],
+                &mut buffers[1],
+                &mut responses[1],
+            )
+        }
+        .unwrap();
+        assert_eq!(&buffers[1][0..9], b"Test data");
+
+        respond_to_read(&state, 0, 40);
+        assert_eq!(blk.peek_used_on(0), Some(tokens[0]));
+        unsafe {
+            blk.complete_read_blocks_on(
+                0,
+                tokens[0],
+                &requests[0],
+                &mut buffers[0],
+                &mut responses[0],
+            )
+        }
+        .unwrap();
+        assert_eq!(&buffers[0][0..9], b"Test data");
+    }
+
+    #[test]
+    fn multiqueue_unsupported() {
+        // The device claims to have several queues but doesn't offer multiqueue.
+        let mut config_space = BlkConfig {
+            num_queues: Volatile::new(4),
+            ..blk_config(66)
+        };
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.queue_count(), 1);
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(
+            blk.read_blocks_on(1, 42, &mut buffer),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.peek_used_on(1), None);
+    }
+
+    #[test]
+    fn write() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a write request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        &request[0..size_of::<BlkReq>()],
+                        BlkReq {
+                            type_: ReqType::Out,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+                    let data = &request[size_of::<BlkReq>()..];
+                    assert_eq!(data.len(), SECTOR_SIZE);
+                    assert_eq!(&data[0..9], b"Test data");
+
+                    let mut response = Vec::new();
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                }));
+        });
+
+        // Write a block to the device.
+        let mut buffer = [0; 512];
+        buffer[0..9].copy_from_slice(b"Test data");
+        blk.write_blocks(42, &buffer).unwrap();
+
+        // Flushing isn't supported by the device, so there's nothing to flush.
+        assert_eq!(blk.flush(), Err(Error::Unsupported));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn new_with_features() {
+        let mut config_space = blk_config(66);
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new_with_features(
+            transport,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::RO).bits(),
+        )
+        .unwrap();
+        assert_eq!(
+            blk.negotiated_features(),
+            (BlkFeature::FLUSH | BlkFeature::VERSION_1).bits()
+        );
+    }
+
+    /// Runs `VirtIOBlk::self_test` against a fake device backed by memory, which flips a bit of
+    /// every read if `corrupt` is set.
+    fn run_self_test(corrupt: bool) -> Result {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut disk = vec![0; 66 * SECTOR_SIZE];
+                while !stop.load(Ordering::SeqCst) {
+                    if !State::poll_queue_notified(&state, QUEUE) {
+                        thread::yield_now();
+                        continue;
+                    }
+                    while state
+                        .lock()
+                        .unwrap()
+                        .read_write_queue_sized::<{ QUEUE_SIZE as usize }>(
+                            QUEUE,
+                            |request, writable_len| {
+                                let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+                                let start = sector as usize * SECTOR_SIZE;
+                                let mut response = if request[0] == ReqType::In as u8 {
+                                    let len = writable_len - size_of::<BlkResp>();
+                                    let mut data = disk[start..start + len].to_vec();
+                                    if corrupt {
+                                        data[len / 2] ^= 0x10;
+                                    }
+                                    data
+                                } else {
+                                    let data = &request[size_of::<BlkReq>()..];
+                                    disk[start..start + data.len()].copy_from_slice(data);
+                                    vec![]
+                                };
+                                response.push(RespStatus::OK.0);
+                                response
+                            },
+                        )
+                    {}
+                }
+            }
+        });
+        let result = blk.self_test();
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn self_test() {
+        assert_eq!(run_self_test(false), Ok(()));
+        assert_eq!(run_self_test(true), Err(Error::IoError));
+    }
+
+    #[test]
+    fn with_queue() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.with_queue(1, |_, _| ()), Err(Error::InvalidParam));
+
+        let request = [1, 2, 3];
+        let mut response = [0; 2];
+        let token = blk
+            .with_queue(QUEUE, |queue, notifier| {
+                // SAFETY: The buffers outlive the request, as it is popped before they are dropped.
+                let token = unsafe { queue.add(&[&request], &mut [&mut response]) }.unwrap();
+                notifier.notify(queue);
+                token
+            })
+            .unwrap();
+        assert!(State::poll_queue_notified(&state, QUEUE));
+        assert_eq!(blk.in_flight(), 1);
+
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(request, [1, 2, 3]);
+                vec![4, 5]
+            }));
+        assert_eq!(blk.peek_used(), Some(token));
+        let used_len = blk
+            .with_queue(QUEUE, |queue, _| {
+                // SAFETY: These are the same buffers which were passed to `add`.
+                unsafe { queue.pop_used(token, &[&request], &mut [&mut response]) }
+            })
+            .unwrap();
+        assert_eq!(used_len, Ok(5));
+        assert_eq!(response, [4, 5]);
+        assert_eq!(blk.in_flight(), 0);
+    }
+
+    #[test]
+    fn flush() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(
+            blk.negotiated_features(),
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH | BlkFeature::VERSION_1).bits()
+        );
+        assert!(blk.supports(9));
+        assert!(!blk.supports(5));
+
+        // Start a thread to simulate the device waiting for a flush request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::Flush,
+                            reserved: 0,
+                            sector: 0,
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = Vec::new();
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                }));
+        });
+
+        // Request to flush.
+        blk.flush().unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_async() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut context = Context::from_waker(&waker);
+
+        let mut buffer = [0; 512];
+        {
+            let mut future = pin!(blk.read_blocks_async(42, &mut buffer));
+
+            // The first poll submits the request.
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+            assert!(State::poll_queue_notified(&state, QUEUE));
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+
+            respond_to_read(&state, QUEUE, 42);
+            state.lock().unwrap().interrupt_pending = true;
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+            assert!(!state.lock().unwrap().interrupt_pending);
+        }
+        assert_eq!(&buffer[0..9], b"Test data");
+    }
+
+    #[test]
+    fn read_async_behind_nb() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut context = Context::from_waker(&waker);
+
+        // A non-blocking request which nobody pops until after the future is done.
+        let mut req = BlkReq::default();
+        let mut resp = BlkResp::default();
+        let mut nb_buffer = [0; 512];
+        let nb_token =
+            unsafe { blk.read_blocks_nb(1, &mut req, &mut nb_buffer, &mut resp) }.unwrap();
+
+        let mut buffer = [0; 512];
+        {
+            let mut future = pin!(blk.read_blocks_async(42, &mut buffer));
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+
+            respond_to_read(&state, QUEUE, 1);
+            respond_to_read(&state, QUEUE, 42);
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Ready(Ok(())));
+        }
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        // Dropping a future behind an unpopped request doesn't wait forever either.
+        let mut req2 = BlkReq::default();
+        let mut resp2 = BlkResp::default();
+        let mut nb_buffer2 = [0; 512];
+        let nb_token2 =
+            unsafe { blk.read_blocks_nb(2, &mut req2, &mut nb_buffer2, &mut resp2) }.unwrap();
+        {
+            let mut future = pin!(blk.read_blocks_async(43, &mut buffer));
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+            respond_to_read(&state, QUEUE, 2);
+            respond_to_read(&state, QUEUE, 43);
+        }
+
+        // The non-blocking requests can still be completed.
+        assert_eq!(blk.peek_used(), Some(nb_token));
+        unsafe { blk.complete_read_blocks(nb_token, &req, &mut nb_buffer, &mut resp) }.unwrap();
+        assert_eq!(blk.peek_used(), Some(nb_token2));
+        unsafe { blk.complete_read_blocks(nb_token2, &req2, &mut nb_buffer2, &mut resp2) }.unwrap();
+        assert_eq!(&nb_buffer[0..9], b"Test data");
+        assert_eq!(blk.peek_used(), None);
+    }
+
+    #[test]
+    fn ack_interrupt_wakes() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+
+        let mut request = BlkReq::default();
+        let mut buffer = [0; 512];
+        let mut response = BlkResp::default();
+        let token =
+            unsafe { blk.read_blocks_nb(42, &mut request, &mut buffer, &mut response) }.unwrap();
+        blk.register_waker(token, &waker);
+
+        // Nothing has completed yet.
+        assert!(!blk.ack_interrupt());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        respond_to_read(&state, QUEUE, 42);
+        state.lock().unwrap().interrupt_pending = true;
+        assert!(blk.ack_interrupt());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        unsafe { blk.complete_read_blocks(token, &request, &mut buffer, &mut response) }.unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+        assert!(blk.wakers[0].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn discard() {
+        let mut config_space = BlkConfig {
+            max_discard_sectors: Volatile::new(4),
+            max_discard_seg: Volatile::new(2),
+            ..blk_config(66)
+        };
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::DISCARD | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device handling the discard requests. Ten sectors with at
+        // most four sectors per segment and two segments per request should take two requests.
+        let handle = thread::spawn(move || {
+            let expected_segments: [&[(u64, u32)]; 2] = [&[(1, 4), (5, 4)], &[(9, 2)]];
+            for expected in expected_segments {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            &request[0..size_of::<BlkReq>()],
+                            BlkReq {
+                                type_: ReqType::Discard,
+                                reserved: 0,
+                                sector: 0
+                            }
+                            .as_bytes()
+                        );
+                        let mut segments = Vec::new();
+                        for &(sector, num_sectors) in expected {
+                            segments.extend_from_slice(
+                                DiscardWriteZeroes {
+                                    sector,
+                                    num_sectors,
+                                    flags: 0,
+                                }
+                                .as_bytes(),
+                            );
+                        }
+                        assert_eq!(&request[size_of::<BlkReq>()..], segments);
+
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_owned()
+                    }));
+            }
+        });
+
+        blk.discard(1..11).unwrap();
+        // Empty ranges don't need a request.
+        blk.discard(3..3).unwrap();
+        assert_eq!(blk.discard(60..67), Err(Error::InvalidParam));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn discard_write_zeroes_unsupported() {
+        let mut config_space = blk_config(66);
+        let (transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.discard(0..1), Err(Error::Unsupported));
+        assert_eq!(blk.write_zeroes(0..1), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn flush_nb() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        let mut request = BlkReq::default();
+        let mut response = BlkResp::default();
+        let token = unsafe { blk.flush_nb(&mut request, &mut response) }.unwrap();
+        assert!(State::poll_queue_notified(&state, QUEUE));
+        assert_eq!(blk.peek_used(), None);
+
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::Flush,
+                        reserved: 0,
+                        sector: 0,
+                    }
+                    .as_bytes()
+                );
+                BlkResp {
+                    status: RespStatus::OK,
+                }
+                .as_bytes()
+                .to_owned()
+            }));
+
+        assert_eq!(blk.peek_used(), Some(token));
+        unsafe { blk.complete_flush(token, &request, &mut response) }.unwrap();
+        assert_eq!(response.status(), RespStatus::OK);
+    }
 
     #[test]
-    fn config() {
-        let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(0x42),
-            capacity_high: Volatile::new(0x02),
-            size_max: Volatile::new(0),
-            seg_max: Volatile::new(0),
-            cylinders: Volatile::new(0),
-            heads: Volatile::new(0),
-            sectors: Volatile::new(0),
-            blk_size: Volatile::new(0),
-            physical_block_exp: Volatile::new(0),
-            alignment_offset: Volatile::new(0),
-            min_io_size: Volatile::new(0),
-            opt_io_size: Volatile::new(0),
-        };
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RO.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+    fn poll_mode() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::FLUSH | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.poll_mode(), PollMode::Interrupt);
+
+        // With nothing in flight there's nothing to wait for, even when busy polling.
+        blk.set_poll_mode(PollMode::BusyPoll);
+        assert_eq!(blk.poll_completion(), Ok(None));
+        assert_eq!(blk.poll_completion_on(1), Err(Error::InvalidParam));
+
+        let mut request = BlkReq::default();
+        let mut response = BlkResp::default();
+        let token = unsafe { blk.flush_nb(&mut request, &mut response) }.unwrap();
+
+        // The device hasn't completed the request, so these give up.
+        blk.set_poll_mode(PollMode::Interrupt);
+        assert_eq!(blk.poll_completion(), Ok(None));
+        blk.set_poll_mode(PollMode::Hybrid { budget: 10 });
+        assert_eq!(blk.poll_completion(), Ok(None));
+
+        // Busy polling waits for the device.
+        blk.set_poll_mode(PollMode::BusyPoll);
+        let handle = {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_owned()
+                    }));
+            })
         };
-        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.poll_completion(), Ok(Some(token)));
+        handle.join().unwrap();
 
-        assert_eq!(blk.capacity(), 0x02_0000_0042);
-        assert!(blk.readonly());
+        // A completed request is returned straight away whatever the mode.
+        blk.set_poll_mode(PollMode::Interrupt);
+        assert_eq!(blk.poll_completion(), Ok(Some(token)));
+        unsafe { blk.complete_flush(token, &request, &mut response) }.unwrap();
+        assert_eq!(response.status(), RespStatus::OK);
+        assert_eq!(blk.poll_completion(), Ok(None));
     }
 
     #[test]
-    fn read() {
-        let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
-            capacity_high: Volatile::new(0),
-            size_max: Volatile::new(0),
-            seg_max: Volatile::new(0),
-            cylinders: Volatile::new(0),
-            heads: Volatile::new(0),
-            sectors: Volatile::new(0),
-            blk_size: Volatile::new(0),
-            physical_block_exp: Volatile::new(0),
-            alignment_offset: Volatile::new(0),
-            min_io_size: Volatile::new(0),
-            opt_io_size: Volatile::new(0),
-        };
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
+    fn device_id() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
-        // Start a thread to simulate the device waiting for a read request.
+        // Start a thread to simulate the device waiting for a flush request.
         let handle = thread::spawn(move || {
             println!("Device waiting for a request.");
             State::wait_until_queue_notified(&state, QUEUE);
@@ -646,15 +3884,15 @@ mod tests {
                     assert_eq!(
                         request,
                         BlkReq {
-                            type_: ReqType::In,
+                            type_: ReqType::GetId,
                             reserved: 0,
-                            sector: 42
+                            sector: 0,
                         }
                         .as_bytes()
                     );
 
-                    let mut response = vec![0; SECTOR_SIZE];
-                    response[0..9].copy_from_slice(b"Test data");
+                    let mut response = Vec::new();
+                    response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
                     response.extend_from_slice(
                         BlkResp {
                             status: RespStatus::OK,
@@ -666,67 +3904,47 @@ mod tests {
                 }));
         });
 
-        // Read a block from the device.
-        let mut buffer = [0; 512];
-        blk.read_blocks(42, &mut buffer).unwrap();
-        assert_eq!(&buffer[0..9], b"Test data");
+        let mut id = [0; 20];
+        let length = blk.device_id(&mut id).unwrap();
+        assert_eq!(&id[0..length], b"device_id");
 
         handle.join().unwrap();
+
+        // The device doesn't offer VIRTIO_BLK_F_LIFETIME.
+        assert_eq!(blk.lifetime(), Err(Error::Unsupported));
     }
 
     #[test]
-    fn write() {
-        let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
-            capacity_high: Volatile::new(0),
-            size_max: Volatile::new(0),
-            seg_max: Volatile::new(0),
-            cylinders: Volatile::new(0),
-            heads: Volatile::new(0),
-            sectors: Volatile::new(0),
-            blk_size: Volatile::new(0),
-            physical_block_exp: Volatile::new(0),
-            alignment_offset: Volatile::new(0),
-            min_io_size: Volatile::new(0),
-            opt_io_size: Volatile::new(0),
-        };
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
+    fn lifetime() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::LIFETIME | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
-        // Start a thread to simulate the device waiting for a write request.
+        // Start a thread to simulate the device waiting for a lifetime request.
         let handle = thread::spawn(move || {
-            println!("Device waiting for a request.");
             State::wait_until_queue_notified(&state, QUEUE);
-            println!("Transmit queue was notified.");
-
             assert!(state
                 .lock()
                 .unwrap()
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
-                        &request[0..size_of::<BlkReq>()],
+                        request,
                         BlkReq {
-                            type_: ReqType::Out,
+                            type_: ReqType::GetLifetime,
                             reserved: 0,
-                            sector: 42
+                            sector: 0,
                         }
                         .as_bytes()
                     );
-                    let data = &request[size_of::<BlkReq>()..];
-                    assert_eq!(data.len(), SECTOR_SIZE);
-                    assert_eq!(&data[0..9], b"Test data");
 
                     let mut response = Vec::new();
+                    response.extend_from_slice(&[2, 0, 0x03, 0, 0x0b, 0]);
                     response.extend_from_slice(
                         BlkResp {
                             status: RespStatus::OK,
@@ -738,150 +3956,486 @@ mod tests {
                 }));
         });
 
-        // Write a block to the device.
-        let mut buffer = [0; 512];
-        buffer[0..9].copy_from_slice(b"Test data");
-        blk.write_blocks(42, &buffer).unwrap();
+        assert_eq!(
+            blk.lifetime(),
+            Ok(BlkLifetime {
+                pre_eol_info: PreEolInfo::WARNING,
+                device_lifetime_est_typ_a: 0x03,
+                device_lifetime_est_typ_b: 0x0b,
+            })
+        );
 
-        // Request to flush should be ignored as the device doesn't support it.
-        blk.flush().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn serial() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Simulate a device with a serial number, then one without, then one which doesn't
+        // support the request.
+        let handle = thread::spawn(move || {
+            for (id, status) in [
+                (*b"foo\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", RespStatus::OK),
+                ([0; 20], RespStatus::OK),
+                ([0; 20], RespStatus::UNSUPPORTED),
+            ] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                        let mut response = id.to_vec();
+                        response.extend_from_slice(BlkResp { status }.as_bytes());
+                        response
+                    }));
+            }
+        });
+
+        assert_eq!(blk.serial().unwrap(), "foo");
+        assert_eq!(blk.serial().unwrap(), "");
+        assert_eq!(blk.serial(), Err(Error::Unsupported));
 
         handle.join().unwrap();
     }
 
     #[test]
-    fn flush() {
-        let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
-            capacity_high: Volatile::new(0),
-            size_max: Volatile::new(0),
-            seg_max: Volatile::new(0),
-            cylinders: Volatile::new(0),
-            heads: Volatile::new(0),
-            sectors: Volatile::new(0),
-            blk_size: Volatile::new(0),
-            physical_block_exp: Volatile::new(0),
-            alignment_offset: Volatile::new(0),
-            min_io_size: Volatile::new(0),
-            opt_io_size: Volatile::new(0),
+    fn drop_in_flight() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<TrackingHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_ne!(TrackingHal::outstanding_dma(), 0);
+        // Without indirect descriptors each request takes three of the queue's descriptors.
+        assert_eq!(blk.max_in_flight(), usize::from(QUEUE_SIZE) / 3);
+
+        // Complete one request, so that its buffers are unshared before the device is dropped.
+        let mut req = BlkReq::default();
+        let mut buffer = [0; 512];
+        let mut resp = BlkResp::default();
+        let token = unsafe { blk.read_blocks_nb(0, &mut req, &mut buffer, &mut resp) }.unwrap();
+        assert_eq!(blk.in_flight(), 1);
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                let mut response = vec![0; SECTOR_SIZE];
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            }));
+        unsafe { blk.complete_read_blocks(token, &req, &mut buffer, &mut resp) }.unwrap();
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        assert_eq!(blk.in_flight(), 0);
+
+        // Leave another one in flight.
+        unsafe { blk.read_blocks_nb(1, &mut req, &mut buffer, &mut resp) }.unwrap();
+        assert_eq!(TrackingHal::outstanding_shares(), 3);
+        assert_eq!(blk.in_flight(), 1);
+
+        drop(blk);
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        assert_eq!(TrackingHal::outstanding_dma(), 0);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            BlkFeature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<TrackingHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let respond = |request: Vec<u8>| {
+            assert_eq!(
+                &request,
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector: 42,
+                }
+                .as_bytes()
+            );
+            let mut response = vec![0; SECTOR_SIZE];
+            response[0..9].copy_from_slice(b"Test data");
+            response.extend_from_slice(
+                BlkResp {
+                    status: RespStatus::OK,
+                }
+                .as_bytes(),
+            );
+            response
         };
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH).bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
+
+        // The device doesn't respond in time.
+        let footprint = blk.dma_footprint();
+        let mut buffer = [0; 512];
+        assert_eq!(
+            blk.read_blocks_timeout(42, &mut buffer, 10),
+            Err(Error::Timeout)
+        );
+        // The staging buffers of the abandoned request are kept until the device completes it.
+        assert_eq!(blk.dma_footprint(), footprint + 2 * PAGE_SIZE);
+        // The queue can't be used until the device completes the abandoned request.
+        assert_eq!(blk.read_blocks(42, &mut buffer), Err(Error::NotReady));
+        State::wait_until_queue_notified(&state, QUEUE);
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, respond));
+        assert_eq!(buffer, [0; 512]);
+
+        // This time the device responds.
+        let handle = {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, respond));
+            })
+        };
+        assert_eq!(blk.read_blocks_timeout(42, &mut buffer, usize::MAX), Ok(()));
+        assert_eq!(&buffer[0..9], b"Test data");
+        handle.join().unwrap();
+        assert_eq!(blk.dma_footprint(), footprint);
+
+        // A request which is still outstanding when the device is dropped is cleaned up.
+        assert_eq!(
+            blk.read_blocks_timeout(42, &mut buffer, 0),
+            Err(Error::Timeout)
+        );
+        drop(blk);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        assert_eq!(TrackingHal::outstanding_dma(), 0);
+    }
+
+    #[test]
+    fn reset() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::FLUSH | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk =
+            VirtIOBlk::<TrackingHal, FakeTransport<BlkConfig>>::with_queue_size(transport, 4)
+                .unwrap();
+        let footprint = blk.dma_footprint();
+
+        // The device never completes the request, so the queue is stuck.
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(
+            blk.read_blocks_timeout(42, &mut buffer, 10),
+            Err(Error::Timeout)
+        );
+        State::wait_until_queue_notified(&state, QUEUE);
+        assert_eq!(blk.read_blocks(42, &mut buffer), Err(Error::NotReady));
+
+        // The device gives up, so nothing more is sent until it is reset.
+        state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+        assert!(VirtioDevice::needs_reset(&blk));
+        assert_eq!(
+            blk.read_blocks(42, &mut buffer),
+            Err(Error::DeviceNeedsReset)
+        );
+
+        // Resetting frees the abandoned request and sets up a new queue of the same size.
+        assert_eq!(blk.reset(), Ok(()));
+        assert!(!VirtioDevice::needs_reset(&blk));
+        assert_eq!(blk.dma_footprint(), footprint);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        assert_eq!(blk.virt_queue_size(), 4);
+        assert_eq!(
+            blk.negotiated_features(),
+            (BlkFeature::FLUSH | BlkFeature::VERSION_1).bits()
+        );
+        assert_eq!(blk.capacity(), 66);
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(state.queues[usize::from(QUEUE)].size, 4);
+            assert!(state.status.contains(DeviceStatus::DRIVER_OK));
+        }
+
+        let handle = {
+            let state = state.clone();
+            thread::spawn(move || {
+                State::wait_until_queue_notified(&state, QUEUE);
+                respond_to_read(&state, QUEUE, 42);
+            })
         };
+        assert_eq!(blk.read_blocks(42, &mut buffer), Ok(()));
+        assert_eq!(&buffer[0..9], b"Test data");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_iov() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
-        // Start a thread to simulate the device waiting for a flush request.
         let handle = thread::spawn(move || {
-            println!("Device waiting for a request.");
             State::wait_until_queue_notified(&state, QUEUE);
-            println!("Transmit queue was notified.");
+            respond_to_read(&state, QUEUE, 42);
+        });
+
+        // The fragments add up to one sector, and the empty one is skipped.
+        let mut header = [0; 9];
+        let mut empty = [0; 0];
+        let mut rest = [0xff; SECTOR_SIZE - 9];
+        assert_eq!(
+            blk.read_blocks_iov(42, &mut [&mut header, &mut empty, &mut rest]),
+            Ok(())
+        );
+        assert_eq!(&header, b"Test data");
+        assert_eq!(rest, [0; SECTOR_SIZE - 9]);
+        handle.join().unwrap();
+
+        // Nothing is sent to the device if the total length isn't a whole number of sectors.
+        assert_eq!(
+            blk.read_blocks_iov(42, &mut [&mut header]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.read_blocks_iov(42, &mut []), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn write_iov() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
             assert!(state
                 .lock()
                 .unwrap()
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
-                        request,
+                        &request[0..size_of::<BlkReq>()],
                         BlkReq {
-                            type_: ReqType::Flush,
+                            type_: ReqType::Out,
                             reserved: 0,
-                            sector: 0,
+                            sector: 42
                         }
                         .as_bytes()
                     );
+                    let data = &request[size_of::<BlkReq>()..];
+                    assert_eq!(data.len(), 2 * SECTOR_SIZE);
+                    assert_eq!(&data[0..9], b"Test data");
+                    assert_eq!(data[9..], [0x42; 2 * SECTOR_SIZE - 9]);
 
-                    let mut response = Vec::new();
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes()
+                    .to_vec()
                 }));
         });
 
-        // Request to flush.
-        blk.flush().unwrap();
-
+        let payload = [0x42; 2 * SECTOR_SIZE - 9];
+        assert_eq!(
+            blk.write_blocks_iov(42, &[b"Test data", &[], &payload]),
+            Ok(())
+        );
         handle.join().unwrap();
+
+        // There are too many fragments for the queue.
+        let fragments = [&[0; SECTOR_SIZE][..]; MAX_FRAGMENTS + 1];
+        assert_eq!(
+            blk.write_blocks_iov(0, &fragments),
+            Err(Error::InvalidParam)
+        );
     }
 
     #[test]
-    fn device_id() {
-        let mut config_space = BlkConfig {
-            capacity_low: Volatile::new(66),
-            capacity_high: Volatile::new(0),
-            size_max: Volatile::new(0),
-            seg_max: Volatile::new(0),
-            cylinders: Volatile::new(0),
-            heads: Volatile::new(0),
-            sectors: Volatile::new(0),
-            blk_size: Volatile::new(0),
-            physical_block_exp: Volatile::new(0),
-            alignment_offset: Volatile::new(0),
-            min_io_size: Volatile::new(0),
-            opt_io_size: Volatile::new(0),
-        };
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: QUEUE_SIZE.into(),
-            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
+    fn read_write_dma_buffer() {
+        let mut config_space = blk_config(66);
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::RING_INDIRECT_DESC | BlkFeature::VERSION_1).bits(),
+            &mut config_space,
+        );
         let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
 
-        // Start a thread to simulate the device waiting for a flush request.
         let handle = thread::spawn(move || {
-            println!("Device waiting for a request.");
             State::wait_until_queue_notified(&state, QUEUE);
-            println!("Transmit queue was notified.");
-
+            respond_to_read(&state, QUEUE, 42);
+            State::wait_until_queue_notified(&state, QUEUE);
             assert!(state
                 .lock()
                 .unwrap()
                 .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
                     assert_eq!(
-                        request,
+                        &request[0..size_of::<BlkReq>()],
                         BlkReq {
-                            type_: ReqType::GetId,
+                            type_: ReqType::Out,
                             reserved: 0,
-                            sector: 0,
+                            sector: 43,
                         }
                         .as_bytes()
                     );
+                    let data = &request[size_of::<BlkReq>()..];
+                    assert_eq!(data.len(), SECTOR_SIZE);
+                    assert_eq!(&data[0..9], b"Test data");
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes()
+                    .to_vec()
+                }));
+        });
 
-                    let mut response = Vec::new();
-                    response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
-                    response.extend_from_slice(
+        // The same buffer is used to read a block and write it back elsewhere.
+        let mut buffer = DmaBuffer::<FakeHal>::new(SECTOR_SIZE, BufferDirection::Both).unwrap();
+        blk.read_blocks_into(42, &mut buffer).unwrap();
+        assert_eq!(&buffer.as_slice()[0..9], b"Test data");
+        blk.write_blocks_from(43, &buffer).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_write_sectors() {
+        let mut config_space = BlkConfig {
+            size_max: Volatile::new(2 * SECTOR_SIZE as u32),
+            seg_max: Volatile::new(2),
+            ..blk_config(66)
+        };
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            (BlkFeature::SIZE_MAX
+                | BlkFeature::SEG_MAX
+                | BlkFeature::RING_INDIRECT_DESC
+                | BlkFeature::VERSION_1)
+                .bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Each request can carry at most 2 segments of 2 sectors, so 6 sectors take 2 requests.
+        let handle = thread::spawn(move || {
+            for (sector, sectors) in [(10, 4), (14, 2)] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            request,
+                            BlkReq {
+                                type_: ReqType::In,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        let mut response = vec![sector as u8; sectors * SECTOR_SIZE];
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+                        response
+                    }));
+            }
+            for (sector, sectors) in [(60, 4), (64, 2), (70, 2), (72, 3)] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                assert!(state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            &request[0..size_of::<BlkReq>()],
+                            BlkReq {
+                                type_: ReqType::Out,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        assert_eq!(request.len(), size_of::<BlkReq>() + sectors * SECTOR_SIZE);
                         BlkResp {
                             status: RespStatus::OK,
                         }
-                        .as_bytes(),
-                    );
-
-                    response
-                }));
+                        .as_bytes()
+                        .to_vec()
+                    }));
+            }
         });
 
-        let mut id = [0; 20];
-        let length = blk.device_id(&mut id).unwrap();
-        assert_eq!(&id[0..length], b"device_id");
-
+        let mut buf = [0; 6 * SECTOR_SIZE];
+        assert_eq!(blk.read_sectors(10, &mut buf), Ok(()));
+        assert_eq!(buf[..4 * SECTOR_SIZE], [10; 4 * SECTOR_SIZE]);
+        assert_eq!(buf[4 * SECTOR_SIZE..], [14; 2 * SECTOR_SIZE]);
+        assert_eq!(blk.write_sectors(60, &buf), Ok(()));
+        // The third buffer is split into two segments, and the request after its first.
+        assert_eq!(
+            blk.write_blocks_iov(
+                70,
+                &[
+                    &buf[..SECTOR_SIZE],
+                    &buf[..SECTOR_SIZE],
+                    &buf[..3 * SECTOR_SIZE]
+                ]
+            ),
+            Ok(())
+        );
         handle.join().unwrap();
+        assert_eq!(blk.size_max(), Ok(2 * SECTOR_SIZE as u32));
+        assert_eq!(blk.seg_max(), Ok(2));
+
+        // Partial sectors and transfers past the end of the device are rejected up front.
+        assert_eq!(
+            blk.read_sectors(0, &mut buf[..SECTOR_SIZE - 1]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.read_sectors(0, &mut []), Err(Error::InvalidParam));
+        assert_eq!(blk.write_sectors(61, &buf), Err(Error::InvalidParam));
+        assert_eq!(
+            blk.write_sectors(u64::MAX, &buf[..SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
     }
 }
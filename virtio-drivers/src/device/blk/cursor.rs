@@ -0,0 +1,462 @@
+// SPDX-License-Identifier: MIT
+
+//! A byte-granular cursor over a block device.
+
+use super::{VirtIOBlk, SECTOR_SIZE};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{vec, vec::Vec};
+use core::cmp::min;
+use log::warn;
+
+/// A position to seek to with [`BlockCursor::seek`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SeekFrom {
+    /// The given number of bytes from the start of the device.
+    Start(u64),
+    /// The given number of bytes from the end of the device.
+    End(i64),
+    /// The given number of bytes from the current position.
+    Current(i64),
+}
+
+/// Reads and writes a block device as a seekable stream of bytes.
+///
+/// Transfers which cover whole logical blocks go straight to the device. The parts of a transfer
+/// which only cover part of a block go through a buffer holding a single block, which is read from
+/// the device first so that the rest of the block is preserved. Written data may stay in the
+/// buffer until the cursor moves to another block, [`flush`](Self::flush) is called, or the
+/// cursor is dropped. Errors writing it back on drop are only logged, so call `flush` before
+/// dropping the cursor to find out about them.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::blk::{BlockCursor, SeekFrom, VirtIOBlk};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(blk: &mut VirtIOBlk<HalImpl, T>) -> Result<(), Error> {
+/// let mut cursor = BlockCursor::new(blk);
+/// cursor.seek(SeekFrom::Start(1000))?;
+/// cursor.write(b"hello")?;
+/// cursor.seek(SeekFrom::Current(-5))?;
+/// let mut buf = [0; 5];
+/// assert_eq!(cursor.read(&mut buf)?, 5);
+/// cursor.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlockCursor<'a, H: Hal, T: Transport> {
+    blk: &'a mut VirtIOBlk<H, T>,
+    /// The byte offset of the cursor from the start of the device.
+    position: u64,
+    /// A copy of the logical block `buffered`, for transfers which only cover part of a block.
+    buffer: Vec<u8>,
+    /// The index of the logical block held in `buffer`, if any.
+    buffered: Option<u64>,
+    /// Whether `buffer` has been written to since it was last read from or written to the device.
+    dirty: bool,
+}
+
+impl<'a, H: Hal, T: Transport> BlockCursor<'a, H, T> {
+    /// Creates a new cursor at the start of the given device.
+    pub fn new(blk: &'a mut VirtIOBlk<H, T>) -> Self {
+        let block_size = blk.logical_block_size();
+        Self {
+            blk,
+            position: 0,
+            buffer: vec![0; block_size],
+            buffered: None,
+            dirty: false,
+        }
+    }
+
+    /// Returns the current byte offset of the cursor from the start of the device.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves the cursor to the given position, and returns the new offset from the start of the
+    /// device.
+    ///
+    /// The cursor may be moved past the end of the device, but reads and writes there transfer
+    /// nothing. Any data written to the block buffer is written back to the device first. Returns
+    /// [`Error::InvalidParam`] if the position would be before the start of the device or doesn't
+    /// fit in a `u64`.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.flush()?;
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.blk.capacity_bytes().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(Error::InvalidParam)?;
+        Ok(self.position)
+    }
+
+    /// Reads bytes from the current position into `buf`, and advances the cursor past them.
+    ///
+    /// Returns the number of bytes read, which is less than the length of `buf` only if the end
+    /// of the device is reached. If reading from the device fails the cursor is left after the
+    /// bytes which were read before the error.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(buf.len());
+        let block_size = self.buffer.len();
+        let mut done = 0;
+        while done < len {
+            let (block, offset) = self.split_position();
+            let remaining = len - done;
+            let count = if offset == 0 && remaining >= block_size {
+                let count = remaining - remaining % block_size;
+                let blocks = block..block + (count / block_size) as u64;
+                // The device must see anything written to the buffer before it is read directly.
+                if self
+                    .buffered
+                    .is_some_and(|buffered| blocks.contains(&buffered))
+                {
+                    self.flush()?;
+                }
+                self.blk
+                    .read_sectors(self.sector(block), &mut buf[done..done + count])?;
+                count
+            } else {
+                let count = min(block_size - offset, remaining);
+                self.load(block)?;
+                buf[done..done + count].copy_from_slice(&self.buffer[offset..offset + count]);
+                count
+            };
+            done += count;
+            self.position += count as u64;
+        }
+        Ok(len)
+    }
+
+    /// Writes the bytes in `buf` at the current position, and advances the cursor past them.
+    ///
+    /// Returns the number of bytes written, which is less than the length of `buf` only if the end
+    /// of the device is reached. Partial blocks are written to the block buffer, after reading the
+    /// rest of the block from the device. If reading or writing fails the cursor is left after the
+    /// bytes which were written before the error.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.clamp(buf.len());
+        let block_size = self.buffer.len();
+        let mut done = 0;
+        while done < len {
+            let (block, offset) = self.split_position();
+            let remaining = len - done;
+            let count = if offset == 0 && remaining >= block_size {
+                let count = remaining - remaining % block_size;
+                let blocks = block..block + (count / block_size) as u64;
+                self.blk
+                    .write_sectors(self.sector(block), &buf[done..done + count])?;
+                // The buffered block has been overwritten, so whatever it held is stale.
+                if self
+                    .buffered
+                    .is_some_and(|buffered| blocks.contains(&buffered))
+                {
+                    self.buffered = None;
+                    self.dirty = false;
+                }
+                count
+            } else {
+                let count = min(block_size - offset, remaining);
+                self.load(block)?;
+                self.buffer[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+                self.dirty = true;
+                count
+            };
+            done += count;
+            self.position += count as u64;
+        }
+        Ok(len)
+    }
+
+    /// Writes anything written to the block buffer back to the device.
+    ///
+    /// This doesn't flush the device's own write cache; use [`VirtIOBlk::flush`] for that.
+    pub fn flush(&mut self) -> Result {
+        if let (Some(block), true) = (self.buffered, self.dirty) {
+            self.blk.write_sectors(self.sector(block), &self.buffer)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Reads the given logical block into the block buffer, unless it is already there, first
+    /// writing back the block it held if necessary.
+    fn load(&mut self, block: u64) -> Result {
+        if self.buffered == Some(block) {
+            return Ok(());
+        }
+        self.flush()?;
+        // Don't leave the previous block's index if the read fails part way.
+        self.buffered = None;
+        self.blk
+            .read_sectors(self.sector(block), &mut self.buffer)?;
+        self.buffered = Some(block);
+        Ok(())
+    }
+
+    /// Returns the index of the logical block containing the cursor, and the cursor's offset
+    /// within it.
+    fn split_position(&self) -> (u64, usize) {
+        let block_size = self.buffer.len() as u64;
+        (
+            self.position / block_size,
+            (self.position % block_size) as usize,
+        )
+    }
+
+    /// Returns the first sector of the given logical block.
+    fn sector(&self, block: u64) -> u64 {
+        block * (self.buffer.len() / SECTOR_SIZE) as u64
+    }
+
+    /// Returns how many of `len` bytes from the current position lie within the device.
+    fn clamp(&self, len: usize) -> usize {
+        let available = self.blk.capacity_bytes().saturating_sub(self.position);
+        min(len as u64, available) as usize
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for BlockCursor<'_, H, T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to write back block buffer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{BlkConfig, BlkFeature, BlkResp, RespStatus, QUEUE, QUEUE_SIZE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+        volatile::Volatile,
+    };
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use std::{sync::Mutex, thread};
+    use zerocopy::IntoBytes;
+
+    const SECTORS: usize = 8;
+
+    fn config(blk_size: u32) -> BlkConfig {
+        BlkConfig {
+            capacity_low: Volatile::new(SECTORS as u32),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(blk_size),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+            writeback: Volatile::new(0),
+            unused0: Volatile::new(0),
+            num_queues: Volatile::new(0),
+            max_discard_sectors: Volatile::new(0),
+            max_discard_seg: Volatile::new(0),
+            discard_sector_alignment: Volatile::new(0),
+            max_write_zeroes_sectors: Volatile::new(0),
+            max_write_zeroes_seg: Volatile::new(0),
+            write_zeroes_may_unmap: Volatile::new(0),
+            unused1: [0; 3],
+            max_secure_erase_sectors: Volatile::new(0),
+            max_secure_erase_seg: Volatile::new(0),
+            secure_erase_sector_alignment: Volatile::new(0),
+        }
+    }
+
+    /// Simulates a device backed by `disk` on another thread, serving read and write requests
+    /// until `stop` is set. Returns the number of requests served.
+    fn serve(
+        state: Arc<Mutex<State>>,
+        disk: Arc<Mutex<Vec<u8>>>,
+        stop: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut requests = 0;
+            while !stop.load(Ordering::SeqCst) {
+                if !State::poll_queue_notified(&state, QUEUE) {
+                    thread::yield_now();
+                    continue;
+                }
+                while state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue_sized::<{ QUEUE_SIZE as usize }>(
+                        QUEUE,
+                        |request, writable_len| {
+                            requests += 1;
+                            let type_ = u32::from_le_bytes(request[0..4].try_into().unwrap());
+                            let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+                            let start = sector as usize * SECTOR_SIZE;
+                            let mut disk = disk.lock().unwrap();
+                            let mut response = match type_ {
+                                // In
+                                0 => {
+                                    let len = writable_len - size_of::<BlkResp>();
+                                    disk[start..start + len].to_vec()
+                                }
+                                // Out
+                                1 => {
+                                    let data = &request[16..];
+                                    disk[start..start + data.len()].copy_from_slice(data);
+                                    Vec::new()
+                                }
+                                _ => panic!("Unexpected request type {}", type_),
+                            };
+                            response.extend_from_slice(
+                                BlkResp {
+                                    status: RespStatus::OK,
+                                }
+                                .as_bytes(),
+                            );
+                            response
+                        },
+                    )
+                {}
+            }
+            requests
+        })
+    }
+
+    fn with_device(
+        blk_size: u32,
+        test: impl FnOnce(&mut VirtIOBlk<FakeHal, FakeTransport<BlkConfig>>),
+    ) -> (Vec<u8>, usize) {
+        let mut config_space = config(blk_size);
+        let mut device_features = BlkFeature::VERSION_1;
+        if blk_size != 0 {
+            device_features |= BlkFeature::BLK_SIZE;
+        }
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            QUEUE_SIZE.into(),
+            1,
+            device_features.bits(),
+            &mut config_space,
+        );
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let disk = Arc::new(Mutex::new(
+            (0..SECTORS * SECTOR_SIZE).map(|i| i as u8).collect(),
+        ));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = serve(state, disk.clone(), stop.clone());
+
+        test(&mut blk);
+
+        stop.store(true, Ordering::SeqCst);
+        let requests = handle.join().unwrap();
+        let disk = disk.lock().unwrap().clone();
+        (disk, requests)
+    }
+
+    #[test]
+    fn read_unaligned() {
+        let (_, requests) = with_device(0, |blk| {
+            let mut cursor = BlockCursor::new(blk);
+            assert_eq!(cursor.seek(SeekFrom::Start(500)), Ok(500));
+
+            // Across a block boundary, reading the two blocks.
+            let mut buf = [0; 20];
+            assert_eq!(cursor.read(&mut buf), Ok(20));
+            assert!(buf.iter().zip(500..).all(|(&b, i)| b == i as u8));
+            assert_eq!(cursor.position(), 520);
+
+            // Within the block which is already buffered.
+            let mut buf = [0; 4];
+            assert_eq!(cursor.read(&mut buf), Ok(4));
+            assert_eq!(buf, [8, 9, 10, 11]);
+
+            // Whole blocks go straight to the device, in a single request.
+            cursor.seek(SeekFrom::Start(1024)).unwrap();
+            let mut buf = [0; 2 * SECTOR_SIZE];
+            assert_eq!(cursor.read(&mut buf), Ok(2 * SECTOR_SIZE));
+            assert!(buf.iter().zip(1024..).all(|(&b, i)| b == i as u8));
+        });
+        assert_eq!(requests, 3);
+    }
+
+    #[test]
+    fn read_end() {
+        with_device(0, |blk| {
+            let mut cursor = BlockCursor::new(blk);
+            assert_eq!(cursor.seek(SeekFrom::End(-3)), Ok(4093));
+            let mut buf = [0; 10];
+            assert_eq!(cursor.read(&mut buf), Ok(3));
+            assert_eq!(buf[..3], [253, 254, 255]);
+            assert_eq!(cursor.read(&mut buf), Ok(0));
+
+            assert_eq!(cursor.seek(SeekFrom::End(10)), Ok(4106));
+            assert_eq!(cursor.read(&mut buf), Ok(0));
+            assert_eq!(cursor.write(&buf), Ok(0));
+            assert_eq!(
+                cursor.seek(SeekFrom::Current(-5000)),
+                Err(Error::InvalidParam)
+            );
+        });
+    }
+
+    #[test]
+    fn write_read_modify_write() {
+        let (disk, _) = with_device(0, |blk| {
+            let mut cursor = BlockCursor::new(blk);
+            cursor.seek(SeekFrom::Start(510)).unwrap();
+            assert_eq!(cursor.write(&[0xaa; 4]), Ok(4));
+
+            // The data written is read back from the buffer before it is written back.
+            cursor.seek(SeekFrom::Start(508)).unwrap();
+            let mut buf = [0; 8];
+            assert_eq!(cursor.read(&mut buf), Ok(8));
+            assert_eq!(buf, [252, 253, 0xaa, 0xaa, 0xaa, 0xaa, 2, 3]);
+        });
+        let mut expected: Vec<u8> = (0..SECTORS * SECTOR_SIZE).map(|i| i as u8).collect();
+        expected[510..514].fill(0xaa);
+        assert!(disk == expected);
+    }
+
+    #[test]
+    fn write_flushed_on_drop() {
+        let (disk, _) = with_device(0, |blk| {
+            let mut cursor = BlockCursor::new(blk);
+            cursor.seek(SeekFrom::Start(100)).unwrap();
+            // A partial block, then a whole one, then another partial one.
+            assert_eq!(cursor.write(&[1; 1000]), Ok(1000));
+            assert_eq!(cursor.position(), 1100);
+        });
+        assert!(disk[..100].iter().zip(0..).all(|(&b, i)| b == i as u8));
+        assert!(disk[100..1100].iter().all(|&b| b == 1));
+        assert!(disk[1100..].iter().zip(1100..).all(|(&b, i)| b == i as u8));
+    }
+
+    #[test]
+    fn large_logical_blocks() {
+        let (disk, _) = with_device(1024, |blk| {
+            let mut cursor = BlockCursor::new(blk);
+            cursor.seek(SeekFrom::Start(1020)).unwrap();
+            assert_eq!(cursor.write(&[0xff; 8]), Ok(8));
+            cursor.flush().unwrap();
+
+            cursor.seek(SeekFrom::Start(1016)).unwrap();
+            let mut buf = [0; 16];
+            assert_eq!(cursor.read(&mut buf), Ok(16));
+            assert_eq!(
+                buf,
+                [248, 249, 250, 251, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 4, 5, 6, 7]
+            );
+        });
+        assert!(disk[1020..1028].iter().all(|&b| b == 0xff));
+    }
+}
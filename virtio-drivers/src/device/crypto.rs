@@ -0,0 +1,1070 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO crypto devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::ReadOnly;
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::mem::offset_of;
+use log::info;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+/// The data queue used for all operations. The control queue comes after all the data queues.
+const DATA_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+const SUPPORTED_FEATURES: CryptoFeature =
+    CryptoFeature::VERSION_1.union(CryptoFeature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: CryptoFeature = CryptoFeature::VERSION_1;
+
+/// Driver for a VirtIO crypto device.
+///
+/// Only the cipher and hash services are implemented, in session mode: a session is created
+/// with the algorithm and key, and then used for any number of operations.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::crypto::{CipherAlgorithm, CipherDirection, VirtIOCrypto};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut crypto = VirtIOCrypto::<HalImpl, _>::new(transport)?;
+///
+/// let key = [0x42; 16];
+/// let session =
+///     crypto.create_cipher_session(CipherAlgorithm::AesCbc, CipherDirection::Encrypt, &key)?;
+/// let iv = [0; 16];
+/// let plaintext = [0x11; 32];
+/// let mut ciphertext = [0; 32];
+/// crypto.encrypt(&session, &iv, &plaintext, &mut ciphertext)?;
+/// crypto.destroy_session(session)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOCrypto<H: Hal, T: Transport> {
+    transport: T,
+    data_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    /// The index of the control queue, which is the number of data queues.
+    control_queue_idx: u16,
+    services: CryptoServices,
+    cipher_algorithms: u64,
+    hash_algorithms: u32,
+    max_cipher_key_len: u32,
+    max_size: u64,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOCrypto<H, T> {
+    /// Create a new VirtIO-Crypto driver.
+    ///
+    /// Returns [`Error::NotReady`] if the device reports that its backend isn't ready.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+
+        let config = transport.read_config_space_atomic(|| {
+            let read = |offset| transport.read_config::<H, u32>(offset);
+            let read_u64 =
+                |low, high| Ok::<_, Error>(u64::from(read(low)?) | u64::from(read(high)?) << 32);
+            Ok::<_, Error>((
+                read(offset_of!(CryptoConfig, status))?,
+                read(offset_of!(CryptoConfig, max_dataqueues))?,
+                read(offset_of!(CryptoConfig, crypto_services))?,
+                read_u64(
+                    offset_of!(CryptoConfig, cipher_algo_l),
+                    offset_of!(CryptoConfig, cipher_algo_h),
+                )?,
+                read(offset_of!(CryptoConfig, hash_algo))?,
+                read(offset_of!(CryptoConfig, max_cipher_key_len))?,
+                read_u64(
+                    offset_of!(CryptoConfig, max_size_low),
+                    offset_of!(CryptoConfig, max_size_high),
+                )?,
+            ))
+        })?;
+        let (
+            status,
+            max_dataqueues,
+            services,
+            cipher_algorithms,
+            hash_algorithms,
+            max_key_len,
+            max_size,
+        ) = config;
+        info!(
+            "crypto status {:#x}, {} data queues, services {:#x}",
+            status, max_dataqueues, services
+        );
+        if status & VIRTIO_CRYPTO_S_HW_READY == 0 {
+            return Err(Error::NotReady);
+        }
+        // The device must have at least one data queue, but don't trust it.
+        let control_queue_idx =
+            u16::try_from(max_dataqueues.max(1)).map_err(|_| Error::InvalidParam)?;
+
+        let data_queue = VirtQueue::new(&mut transport, DATA_QUEUE, false, false, false)?;
+        let control_queue = VirtQueue::new(&mut transport, control_queue_idx, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        Ok(VirtIOCrypto {
+            transport,
+            data_queue,
+            control_queue,
+            control_queue_idx,
+            services: CryptoServices::from_bits_truncate(services),
+            cipher_algorithms,
+            hash_algorithms,
+            max_cipher_key_len: max_key_len,
+            max_size,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the services which the device offers.
+    pub fn services(&self) -> CryptoServices {
+        self.services
+    }
+
+    /// Returns whether the device offers the cipher service with the given algorithm.
+    pub fn supports_cipher(&self, algorithm: CipherAlgorithm) -> bool {
+        self.services.contains(CryptoServices::CIPHER)
+            && self.cipher_algorithms & (1 << algorithm as u32) != 0
+    }
+
+    /// Returns whether the device offers the hash service with the given algorithm.
+    pub fn supports_hash(&self, algorithm: HashAlgorithm) -> bool {
+        self.services.contains(CryptoServices::HASH)
+            && self.hash_algorithms & (1 << algorithm as u32) != 0
+    }
+
+    /// Returns the maximum length in bytes of a cipher key.
+    pub fn max_cipher_key_len(&self) -> u32 {
+        self.max_cipher_key_len
+    }
+
+    /// Returns the maximum length in bytes of the data of a single operation.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.data_queue.stats() + self.control_queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    ///
+    /// Bit `n` of the used queues is the data queue for `n` of 0, and the control queue for `n` of
+    /// 1.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [self.data_queue.can_pop(), self.control_queue.can_pop()],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Creates a session for encrypting or decrypting data with the given cipher algorithm and
+    /// key.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't offer the algorithm, or
+    /// [`Error::InvalidParam`] if the key is empty or longer than
+    /// [`max_cipher_key_len`](Self::max_cipher_key_len).
+    pub fn create_cipher_session(
+        &mut self,
+        algorithm: CipherAlgorithm,
+        direction: CipherDirection,
+        key: &[u8],
+    ) -> Result<CryptoSession> {
+        if !self.supports_cipher(algorithm) {
+            return Err(Error::Unsupported);
+        }
+        if key.is_empty() || key.len() > self.max_cipher_key_len as usize {
+            return Err(Error::InvalidParam);
+        }
+        let request = CipherCreateSessionReq {
+            header: CtrlHeader::new(VIRTIO_CRYPTO_CIPHER_CREATE_SESSION, algorithm as u32),
+            algo: algorithm as u32,
+            key_len: key.len() as u32,
+            op: direction as u32,
+            padding0: 0,
+            padding1: [0; 32],
+            op_type: VIRTIO_CRYPTO_SYM_OP_CIPHER,
+            padding2: 0,
+        };
+        let id = self.create_session(&[request.as_bytes(), key])?;
+        Ok(CryptoSession {
+            id,
+            kind: SessionKind::Cipher {
+                algorithm,
+                direction,
+            },
+        })
+    }
+
+    /// Creates a session for hashing data with the given algorithm, producing results of
+    /// `result_len` bytes.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't offer the algorithm, or
+    /// [`Error::InvalidParam`] if `result_len` is 0.
+    pub fn create_hash_session(
+        &mut self,
+        algorithm: HashAlgorithm,
+        result_len: u32,
+    ) -> Result<CryptoSession> {
+        if !self.supports_hash(algorithm) {
+            return Err(Error::Unsupported);
+        }
+        if result_len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let request = HashCreateSessionReq {
+            header: CtrlHeader::new(VIRTIO_CRYPTO_HASH_CREATE_SESSION, algorithm as u32),
+            algo: algorithm as u32,
+            hash_result_len: result_len,
+            padding: [0; 48],
+        };
+        let id = self.create_session(&[request.as_bytes()])?;
+        Ok(CryptoSession {
+            id,
+            kind: SessionKind::Hash {
+                algorithm,
+                result_len,
+            },
+        })
+    }
+
+    /// Sends the given session creation request on the control queue, and returns the ID of the
+    /// new session.
+    fn create_session(&mut self, request: &[&[u8]]) -> Result<u64> {
+        // Don't report success if the device doesn't write a response.
+        let mut input = SessionInput {
+            session_id: 0,
+            status: VIRTIO_CRYPTO_ERR.into(),
+            padding: 0,
+        };
+        self.control_queue.add_notify_wait_pop(
+            request,
+            &mut [input.as_mut_bytes()],
+            &mut self.transport,
+        )?;
+        check_status(input.status)?;
+        Ok(input.session_id)
+    }
+
+    /// Destroys the given session on the device.
+    pub fn destroy_session(&mut self, session: CryptoSession) -> Result {
+        let (opcode, algo) = match session.kind {
+            SessionKind::Cipher { algorithm, .. } => {
+                (VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION, algorithm as u32)
+            }
+            SessionKind::Hash { algorithm, .. } => {
+                (VIRTIO_CRYPTO_HASH_DESTROY_SESSION, algorithm as u32)
+            }
+        };
+        let request = DestroySessionReq {
+            header: CtrlHeader::new(opcode, algo),
+            session_id: session.id,
+            padding: [0; 48],
+        };
+        let mut status = VIRTIO_CRYPTO_ERR;
+        self.control_queue.add_notify_wait_pop(
+            &[request.as_bytes()],
+            &mut [status.as_mut_bytes()],
+            &mut self.transport,
+        )?;
+        check_status(status.into())
+    }
+
+    /// Encrypts `src` into `dst` with the given initialisation vector, using a session created for
+    /// encryption, and blocks until the device has done so.
+    ///
+    /// `iv` may be empty for algorithms which don't use one. Returns [`Error::InvalidParam`] if the
+    /// session isn't a cipher session for encryption, or if `src` is empty, a different length to
+    /// `dst` or longer than [`max_size`](Self::max_size).
+    pub fn encrypt(
+        &mut self,
+        session: &CryptoSession,
+        iv: &[u8],
+        src: &[u8],
+        dst: &mut [u8],
+    ) -> Result {
+        self.cipher(session, CipherDirection::Encrypt, iv, src, dst)
+    }
+
+    /// Like [`encrypt`](Self::encrypt), but decrypts with a session created for decryption.
+    pub fn decrypt(
+        &mut self,
+        session: &CryptoSession,
+        iv: &[u8],
+        src: &[u8],
+        dst: &mut [u8],
+    ) -> Result {
+        self.cipher(session, CipherDirection::Decrypt, iv, src, dst)
+    }
+
+    fn cipher(
+        &mut self,
+        session: &CryptoSession,
+        direction: CipherDirection,
+        iv: &[u8],
+        src: &[u8],
+        dst: &mut [u8],
+    ) -> Result {
+        let SessionKind::Cipher {
+            algorithm,
+            direction: session_direction,
+        } = session.kind
+        else {
+            return Err(Error::InvalidParam);
+        };
+        if session_direction != direction
+            || src.is_empty()
+            || src.len() != dst.len()
+            || src.len() as u64 > self.max_size
+        {
+            return Err(Error::InvalidParam);
+        }
+        let opcode = match direction {
+            CipherDirection::Encrypt => VIRTIO_CRYPTO_CIPHER_ENCRYPT,
+            CipherDirection::Decrypt => VIRTIO_CRYPTO_CIPHER_DECRYPT,
+        };
+        let request = CipherDataReq {
+            header: OpHeader::new(opcode, algorithm as u32, session.id),
+            iv_len: iv.len() as u32,
+            src_data_len: src.len() as u32,
+            dst_data_len: dst.len() as u32,
+            padding0: 0,
+            padding1: [0; 24],
+            op_type: VIRTIO_CRYPTO_SYM_OP_CIPHER,
+            padding2: 0,
+        };
+        let mut status = VIRTIO_CRYPTO_ERR;
+        if iv.is_empty() {
+            self.data_queue.add_notify_wait_pop(
+                &[request.as_bytes(), src],
+                &mut [dst, status.as_mut_bytes()],
+                &mut self.transport,
+            )?;
+        } else {
+            self.data_queue.add_notify_wait_pop(
+                &[request.as_bytes(), iv, src],
+                &mut [dst, status.as_mut_bytes()],
+                &mut self.transport,
+            )?;
+        }
+        check_status(status.into())
+    }
+
+    /// Hashes `src` using a hash session, writes the result to `result`, and blocks until the
+    /// device has done so.
+    ///
+    /// Returns [`Error::InvalidParam`] if the session isn't a hash session, if `result` isn't the
+    /// length the session was created with, or if `src` is empty or longer than
+    /// [`max_size`](Self::max_size).
+    pub fn hash(&mut self, session: &CryptoSession, src: &[u8], result: &mut [u8]) -> Result {
+        let SessionKind::Hash {
+            algorithm,
+            result_len,
+        } = session.kind
+        else {
+            return Err(Error::InvalidParam);
+        };
+        if result.len() != result_len as usize || src.is_empty() || src.len() as u64 > self.max_size
+        {
+            return Err(Error::InvalidParam);
+        }
+        let request = HashDataReq {
+            header: OpHeader::new(VIRTIO_CRYPTO_HASH, algorithm as u32, session.id),
+            src_data_len: src.len() as u32,
+            hash_result_len: result_len,
+            padding: [0; 40],
+        };
+        let mut status = VIRTIO_CRYPTO_ERR;
+        self.data_queue.add_notify_wait_pop(
+            &[request.as_bytes(), src],
+            &mut [result, status.as_mut_bytes()],
+            &mut self.transport,
+        )?;
+        check_status(status.into())
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOCrypto<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOCrypto::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(
+            &self.transport,
+            [self.data_queue.snapshot(), self.control_queue.snapshot()],
+        )
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.data_queue.dma_footprint() + self.control_queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOCrypto<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(DATA_QUEUE);
+        self.transport.queue_unset(self.control_queue_idx);
+    }
+}
+
+/// Converts a status returned by the device to a result.
+fn check_status(status: u32) -> Result {
+    match status {
+        VIRTIO_CRYPTO_OK => Ok(()),
+        VIRTIO_CRYPTO_NOTSUPP => Err(Error::Unsupported),
+        VIRTIO_CRYPTO_INVSESS => Err(Error::InvalidParam),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// A session created on the device, which is used for operations with the algorithm and
+/// parameters it was created with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CryptoSession {
+    id: u64,
+    kind: SessionKind,
+}
+
+impl CryptoSession {
+    /// Returns the ID which the device assigned to the session.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SessionKind {
+    Cipher {
+        algorithm: CipherAlgorithm,
+        direction: CipherDirection,
+    },
+    Hash {
+        algorithm: HashAlgorithm,
+        result_len: u32,
+    },
+}
+
+/// Whether a cipher session encrypts or decrypts.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CipherDirection {
+    /// Encrypt plaintext into ciphertext.
+    Encrypt = 1,
+    /// Decrypt ciphertext into plaintext.
+    Decrypt = 2,
+}
+
+/// Cipher algorithms, numbered as in the device's `cipher_algo_l` and `cipher_algo_h` bitmaps.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum CipherAlgorithm {
+    Arc4 = 1,
+    AesEcb = 2,
+    AesCbc = 3,
+    AesCtr = 4,
+    DesEcb = 5,
+    DesCbc = 6,
+    TripleDesEcb = 7,
+    TripleDesCbc = 8,
+    TripleDesCtr = 9,
+    KasumiF8 = 10,
+    Snow3gUea2 = 11,
+    AesF8 = 12,
+    AesXts = 13,
+    ZucEea3 = 14,
+}
+
+/// Hash algorithms, numbered as in the device's `hash_algo` bitmap.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum HashAlgorithm {
+    Md5 = 1,
+    Sha1 = 2,
+    Sha224 = 3,
+    Sha256 = 4,
+    Sha384 = 5,
+    Sha512 = 6,
+    Sha3_224 = 7,
+    Sha3_256 = 8,
+    Sha3_384 = 9,
+    Sha3_512 = 10,
+    Sha3Shake128 = 11,
+    Sha3Shake256 = 12,
+}
+
+bitflags! {
+    /// The services offered by a crypto device.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct CryptoServices: u32 {
+        /// Symmetric ciphers.
+        const CIPHER = 1 << 0;
+        /// Hashes.
+        const HASH = 1 << 1;
+        /// Message authentication codes.
+        const MAC = 1 << 2;
+        /// Authenticated encryption with associated data.
+        const AEAD = 1 << 3;
+        /// Asymmetric ciphers.
+        const AKCIPHER = 1 << 4;
+    }
+}
+
+/// The device's backend is ready to handle requests.
+const VIRTIO_CRYPTO_S_HW_READY: u32 = 1 << 0;
+
+#[repr(C)]
+struct CryptoConfig {
+    status: ReadOnly<u32>,
+    max_dataqueues: ReadOnly<u32>,
+    crypto_services: ReadOnly<u32>,
+    /// Bitmaps of the supported algorithms of each service.
+    cipher_algo_l: ReadOnly<u32>,
+    cipher_algo_h: ReadOnly<u32>,
+    hash_algo: ReadOnly<u32>,
+    mac_algo_l: ReadOnly<u32>,
+    mac_algo_h: ReadOnly<u32>,
+    aead_algo: ReadOnly<u32>,
+    max_cipher_key_len: ReadOnly<u32>,
+    max_auth_key_len: ReadOnly<u32>,
+    akcipher_algo: ReadOnly<u32>,
+    /// The maximum length of the data of a single request.
+    max_size_low: ReadOnly<u32>,
+    max_size_high: ReadOnly<u32>,
+}
+
+/// Builds an opcode from a service number and an operation.
+const fn opcode(service: u32, op: u32) -> u32 {
+    service << 8 | op
+}
+
+const VIRTIO_CRYPTO_SERVICE_CIPHER: u32 = 0;
+const VIRTIO_CRYPTO_SERVICE_HASH: u32 = 1;
+
+// Control queue opcodes.
+const VIRTIO_CRYPTO_CIPHER_CREATE_SESSION: u32 = opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x02);
+const VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION: u32 = opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x03);
+const VIRTIO_CRYPTO_HASH_CREATE_SESSION: u32 = opcode(VIRTIO_CRYPTO_SERVICE_HASH, 0x02);
+const VIRTIO_CRYPTO_HASH_DESTROY_SESSION: u32 = opcode(VIRTIO_CRYPTO_SERVICE_HASH, 0x03);
+
+// Data queue opcodes.
+const VIRTIO_CRYPTO_CIPHER_ENCRYPT: u32 = opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x00);
+const VIRTIO_CRYPTO_CIPHER_DECRYPT: u32 = opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x01);
+const VIRTIO_CRYPTO_HASH: u32 = opcode(VIRTIO_CRYPTO_SERVICE_HASH, 0x00);
+
+/// A plain cipher operation, rather than one chained with a hash or MAC.
+const VIRTIO_CRYPTO_SYM_OP_CIPHER: u32 = 1;
+
+// Request statuses.
+const VIRTIO_CRYPTO_OK: u32 = 0;
+const VIRTIO_CRYPTO_ERR: u8 = 1;
+const VIRTIO_CRYPTO_NOTSUPP: u32 = 3;
+const VIRTIO_CRYPTO_INVSESS: u32 = 4;
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct CtrlHeader {
+    opcode: u32,
+    algo: u32,
+    flag: u32,
+    /// The data queue which the session will be used on.
+    queue_id: u32,
+}
+
+impl CtrlHeader {
+    fn new(opcode: u32, algo: u32) -> Self {
+        Self {
+            opcode,
+            algo,
+            flag: 0,
+            queue_id: DATA_QUEUE.into(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct CipherCreateSessionReq {
+    header: CtrlHeader,
+    algo: u32,
+    key_len: u32,
+    /// A `CipherDirection`.
+    op: u32,
+    padding0: u32,
+    padding1: [u8; 32],
+    op_type: u32,
+    padding2: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct HashCreateSessionReq {
+    header: CtrlHeader,
+    algo: u32,
+    hash_result_len: u32,
+    padding: [u8; 48],
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct DestroySessionReq {
+    header: CtrlHeader,
+    session_id: u64,
+    padding: [u8; 48],
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SessionInput {
+    session_id: u64,
+    status: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct OpHeader {
+    opcode: u32,
+    algo: u32,
+    session_id: u64,
+    flag: u32,
+    padding: u32,
+}
+
+impl OpHeader {
+    fn new(opcode: u32, algo: u32, session_id: u64) -> Self {
+        Self {
+            opcode,
+            algo,
+            session_id,
+            flag: 0,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct CipherDataReq {
+    header: OpHeader,
+    iv_len: u32,
+    src_data_len: u32,
+    dst_data_len: u32,
+    padding0: u32,
+    padding1: [u8; 24],
+    op_type: u32,
+    padding2: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct HashDataReq {
+    header: OpHeader,
+    src_data_len: u32,
+    hash_result_len: u32,
+    padding: [u8; 40],
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct CryptoFeature: u64 {
+        /// The device supports the revision 1 request formats.
+        const REVISION_1            = 1 << 0;
+        /// Stateless mode requests for each service.
+        const CIPHER_STATELESS_MODE = 1 << 1;
+        const HASH_STATELESS_MODE   = 1 << 2;
+        const MAC_STATELESS_MODE    = 1 << 3;
+        const AEAD_STATELESS_MODE   = 1 << 4;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of a crypto device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to
+/// `VirtIOCrypto::new_with_features` to forbid the feature. Use `trailing_zeros` to get the bit
+/// number for `VirtIOCrypto::supports`.
+pub mod features {
+    use super::CryptoFeature;
+    pub use crate::transport::features::*;
+
+    /// The device supports the revision 1 request formats.
+    pub const REVISION_1: u64 = CryptoFeature::REVISION_1.bits();
+    /// Stateless mode requests for the cipher service.
+    pub const CIPHER_STATELESS_MODE: u64 = CryptoFeature::CIPHER_STATELESS_MODE.bits();
+    /// Stateless mode requests for the hash service.
+    pub const HASH_STATELESS_MODE: u64 = CryptoFeature::HASH_STATELESS_MODE.bits();
+    /// Stateless mode requests for the MAC service.
+    pub const MAC_STATELESS_MODE: u64 = CryptoFeature::MAC_STATELESS_MODE.bits();
+    /// Stateless mode requests for the AEAD service.
+    pub const AEAD_STATELESS_MODE: u64 = CryptoFeature::AEAD_STATELESS_MODE.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec::Vec};
+    use std::{sync::Mutex, thread};
+
+    const CONTROL_QUEUE: u16 = 1;
+
+    type FakeCrypto = VirtIOCrypto<FakeHal, FakeTransport<CryptoConfig>>;
+
+    fn make_crypto(config_space: &mut CryptoConfig) -> (Result<FakeCrypto>, Arc<Mutex<State>>) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Crypto,
+            QUEUE_SIZE.into(),
+            2,
+            CryptoFeature::VERSION_1.bits(),
+            config_space,
+        );
+        (VirtIOCrypto::new(transport), state)
+    }
+
+    fn new_config() -> CryptoConfig {
+        CryptoConfig {
+            status: ReadOnly::new(VIRTIO_CRYPTO_S_HW_READY),
+            max_dataqueues: ReadOnly::new(1),
+            crypto_services: ReadOnly::new((CryptoServices::CIPHER | CryptoServices::HASH).bits()),
+            cipher_algo_l: ReadOnly::new(1 << CipherAlgorithm::AesCbc as u32),
+            cipher_algo_h: ReadOnly::new(0),
+            hash_algo: ReadOnly::new(1 << HashAlgorithm::Sha256 as u32),
+            mac_algo_l: ReadOnly::new(0),
+            mac_algo_h: ReadOnly::new(0),
+            aead_algo: ReadOnly::new(0),
+            max_cipher_key_len: ReadOnly::new(32),
+            max_auth_key_len: ReadOnly::new(0),
+            akcipher_algo: ReadOnly::new(0),
+            max_size_low: ReadOnly::new(4096),
+            max_size_high: ReadOnly::new(0),
+        }
+    }
+
+    /// Responds to a session creation request with the given session ID, and returns the request.
+    fn create_session(state: &Arc<Mutex<State>>, session_id: u64) -> Vec<u8> {
+        State::wait_until_queue_notified(state, CONTROL_QUEUE);
+        let mut request = Vec::new();
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(CONTROL_QUEUE, |bytes| {
+                request = bytes.to_vec();
+                SessionInput {
+                    session_id,
+                    status: VIRTIO_CRYPTO_OK,
+                    padding: 0,
+                }
+                .as_bytes()
+                .to_vec()
+            }));
+        request
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = new_config();
+        let (crypto, _) = make_crypto(&mut config_space);
+        let crypto = crypto.unwrap();
+
+        assert_eq!(
+            crypto.services(),
+            CryptoServices::CIPHER | CryptoServices::HASH
+        );
+        assert!(crypto.supports_cipher(CipherAlgorithm::AesCbc));
+        assert!(!crypto.supports_cipher(CipherAlgorithm::AesEcb));
+        assert!(crypto.supports_hash(HashAlgorithm::Sha256));
+        assert!(!crypto.supports_hash(HashAlgorithm::Md5));
+        assert_eq!(crypto.max_cipher_key_len(), 32);
+        assert_eq!(crypto.max_size(), 4096);
+    }
+
+    #[test]
+    fn not_ready() {
+        let mut config_space = new_config();
+        config_space.status = ReadOnly::new(0);
+        let (crypto, _) = make_crypto(&mut config_space);
+        assert_eq!(crypto.err(), Some(Error::NotReady));
+    }
+
+    #[test]
+    fn unsupported_algorithm() {
+        let mut config_space = new_config();
+        let (crypto, _) = make_crypto(&mut config_space);
+        let mut crypto = crypto.unwrap();
+
+        assert_eq!(
+            crypto.create_cipher_session(
+                CipherAlgorithm::AesEcb,
+                CipherDirection::Encrypt,
+                &[1; 16]
+            ),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            crypto.create_hash_session(HashAlgorithm::Md5, 16),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            crypto.create_cipher_session(
+                CipherAlgorithm::AesCbc,
+                CipherDirection::Encrypt,
+                &[1; 33]
+            ),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn encrypt() {
+        let mut config_space = new_config();
+        let (crypto, state) = make_crypto(&mut config_space);
+        let mut crypto = crypto.unwrap();
+
+        let handle = thread::spawn(move || {
+            let request = create_session(&state, 42);
+            let expected = CipherCreateSessionReq {
+                header: CtrlHeader::new(
+                    VIRTIO_CRYPTO_CIPHER_CREATE_SESSION,
+                    CipherAlgorithm::AesCbc as u32,
+                ),
+                algo: CipherAlgorithm::AesCbc as u32,
+                key_len: 16,
+                op: CipherDirection::Encrypt as u32,
+                padding0: 0,
+                padding1: [0; 32],
+                op_type: VIRTIO_CRYPTO_SYM_OP_CIPHER,
+                padding2: 0,
+            };
+            assert_eq!(&request[..72], expected.as_bytes());
+            assert_eq!(&request[72..], &[0xaa; 16]);
+
+            State::wait_until_queue_notified(&state, DATA_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(DATA_QUEUE, |request| {
+                    let expected = CipherDataReq {
+                        header: OpHeader::new(
+                            VIRTIO_CRYPTO_CIPHER_ENCRYPT,
+                            CipherAlgorithm::AesCbc as u32,
+                            42,
+                        ),
+                        iv_len: 16,
+                        src_data_len: 4,
+                        dst_data_len: 4,
+                        padding0: 0,
+                        padding1: [0; 24],
+                        op_type: VIRTIO_CRYPTO_SYM_OP_CIPHER,
+                        padding2: 0,
+                    };
+                    assert_eq!(&request[..72], expected.as_bytes());
+                    assert_eq!(&request[72..88], &[0x55; 16]);
+                    assert_eq!(&request[88..], b"text");
+                    let mut response = b"TEXT".to_vec();
+                    response.push(VIRTIO_CRYPTO_OK as u8);
+                    response
+                }));
+        });
+
+        let session = crypto
+            .create_cipher_session(
+                CipherAlgorithm::AesCbc,
+                CipherDirection::Encrypt,
+                &[0xaa; 16],
+            )
+            .unwrap();
+        assert_eq!(session.id(), 42);
+        let mut ciphertext = [0; 4];
+        crypto
+            .encrypt(&session, &[0x55; 16], b"text", &mut ciphertext)
+            .unwrap();
+        assert_eq!(&ciphertext, b"TEXT");
+        handle.join().unwrap();
+
+        // The session is only for encryption, and the buffers must be the same length.
+        assert_eq!(
+            crypto.decrypt(&session, &[0x55; 16], b"TEXT", &mut ciphertext),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            crypto.encrypt(&session, &[0x55; 16], b"text", &mut [0; 3]),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn hash() {
+        let mut config_space = new_config();
+        let (crypto, state) = make_crypto(&mut config_space);
+        let mut crypto = crypto.unwrap();
+
+        let handle = thread::spawn(move || {
+            let request = create_session(&state, 7);
+            let expected = HashCreateSessionReq {
+                header: CtrlHeader::new(
+                    VIRTIO_CRYPTO_HASH_CREATE_SESSION,
+                    HashAlgorithm::Sha256 as u32,
+                ),
+                algo: HashAlgorithm::Sha256 as u32,
+                hash_result_len: 32,
+                padding: [0; 48],
+            };
+            assert_eq!(request, expected.as_bytes());
+
+            State::wait_until_queue_notified(&state, DATA_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(DATA_QUEUE, |request| {
+                    assert_eq!(&request[72..], b"data");
+                    let mut response = [0x99; 32].to_vec();
+                    response.push(VIRTIO_CRYPTO_NOTSUPP as u8);
+                    response
+                }));
+        });
+
+        let session = crypto
+            .create_hash_session(HashAlgorithm::Sha256, 32)
+            .unwrap();
+        let mut result = [0; 32];
+        assert_eq!(
+            crypto.hash(&session, b"data", &mut result),
+            Err(Error::Unsupported)
+        );
+        handle.join().unwrap();
+
+        assert_eq!(
+            crypto.hash(&session, b"data", &mut [0; 16]),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn destroy_session() {
+        let mut config_space = new_config();
+        let (crypto, state) = make_crypto(&mut config_space);
+        let mut crypto = crypto.unwrap();
+
+        let handle = thread::spawn(move || {
+            create_session(&state, 3);
+
+            State::wait_until_queue_notified(&state, CONTROL_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(CONTROL_QUEUE, |request| {
+                    let expected = DestroySessionReq {
+                        header: CtrlHeader::new(
+                            VIRTIO_CRYPTO_HASH_DESTROY_SESSION,
+                            HashAlgorithm::Sha256 as u32,
+                        ),
+                        session_id: 3,
+                        padding: [0; 48],
+                    };
+                    assert_eq!(request, expected.as_bytes());
+                    vec![VIRTIO_CRYPTO_OK as u8]
+                }));
+        });
+
+        let session = crypto
+            .create_hash_session(HashAlgorithm::Sha256, 32)
+            .unwrap();
+        crypto.destroy_session(session).unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO file system devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, RequestPriority, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, SharedMemoryRegion, Transport};
+use crate::volatile::{volatile_read_bytes, ReadOnly};
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+use crate::{nonnull_slice_from_raw_parts, Error, Result};
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::cmp::min;
+use core::mem::offset_of;
+use log::info;
+
+const HIPRIO_QUEUE: u16 = 0;
+/// The first request queue. This would be queue 2 if `VIRTIO_FS_F_NOTIFICATION` were negotiated.
+const REQUEST_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 8;
+const SUPPORTED_FEATURES: FsFeature = FsFeature::VERSION_1.union(FsFeature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: FsFeature = FsFeature::VERSION_1;
+/// The ID of the shared memory region used as the DAX window.
+const DAX_WINDOW_SHM_ID: u8 = 0;
+
+/// The length of the file system tag in the device configuration space.
+pub const TAG_LEN: usize = 36;
+
+/// Driver for a VirtIO file system device.
+///
+/// This only provides the transport for FUSE messages. Building the requests and parsing the
+/// replies is up to a FUSE client sitting on top of it.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::fs::{VirtIOFs, TAG_LEN};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, fuse_init: &[u8]) -> Result<(), Error> {
+/// let mut fs = VirtIOFs::<HalImpl, _>::new(transport)?;
+///
+/// let mut tag = [0; TAG_LEN];
+/// let tag_len = fs.tag(&mut tag);
+/// println!("Mounting tag {:?}", core::str::from_utf8(&tag[..tag_len]));
+///
+/// let mut reply = [0; 80];
+/// let reply_len = fs.request(fuse_init, &mut reply)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOFs<H: Hal, T: Transport> {
+    transport: T,
+    hiprio_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    request_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    num_request_queues: u32,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOFs<H, T> {
+    /// Create a new VirtIO-Fs driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+
+        let hiprio_queue = VirtQueue::new(&mut transport, HIPRIO_QUEUE, false, false, false)?;
+        let request_queue = VirtQueue::new(&mut transport, REQUEST_QUEUE, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        // The notification buffer size needn't be provided, as the notification feature isn't
+        // supported.
+        let num_request_queues =
+            transport.read_config::<H, u32>(offset_of!(FsConfig, num_request_queues))?;
+        info!("fs num_request_queues: {}", num_request_queues);
+
+        Ok(VirtIOFs {
+            transport,
+            hiprio_queue,
+            request_queue,
+            num_request_queues,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Gets the tag which identifies the file system to mount.
+    ///
+    /// The tag is copied as UTF-8 into the given buffer, and its length returned.
+    pub fn tag(&self, tag: &mut [u8; TAG_LEN]) -> usize {
+        // The tag comes before `num_request_queues`, which was read when the driver was created, so
+        // it is within the config space.
+        let config = self.transport.config_space::<u8>().unwrap();
+        let field = nonnull_slice_from_raw_parts(
+            // SAFETY: Safe because the tag is within the device configuration space.
+            unsafe { config.add(offset_of!(FsConfig, tag)) },
+            TAG_LEN,
+        );
+        // SAFETY: Safe because field points to the tag in the device configuration space.
+        self.transport
+            .read_config_space_atomic(|| unsafe { volatile_read_bytes::<H>(field, tag) })
+            // The buffer is exactly as long as the field.
+            .unwrap();
+        tag.iter().position(|&x| x == 0).unwrap_or(TAG_LEN)
+    }
+
+    /// Returns the number of request queues the device has. Only the first one is used.
+    pub fn num_request_queues(&self) -> u32 {
+        self.num_request_queues
+    }
+
+    /// Returns the shared memory region which the device offers as a DAX window, if any.
+    pub fn dax_window(&mut self) -> Option<SharedMemoryRegion> {
+        self.transport.get_shared_memory_region(DAX_WINDOW_SHM_ID)
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.hiprio_queue.stats() + self.request_queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [self.hiprio_queue.can_pop(), self.request_queue.can_pop()],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Sends the given FUSE request on the request queue, and blocks until the device replies.
+    ///
+    /// `fuse_in` must start with a `fuse_in_header`, and `fuse_out` should be big enough for the
+    /// whole reply. Returns the length of the reply written to `fuse_out`. Requests without a
+    /// reply, such as `FUSE_FORGET`, may pass an empty `fuse_out`.
+    pub fn request(&mut self, fuse_in: &[u8], fuse_out: &mut [u8]) -> Result<usize> {
+        if fuse_in.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let len = if fuse_out.is_empty() {
+            self.request_queue
+                .add_notify_wait_pop(&[fuse_in], &mut [], &mut self.transport)?
+        } else {
+            self.request_queue.add_notify_wait_pop(
+                &[fuse_in],
+                &mut [fuse_out],
+                &mut self.transport,
+            )?
+        };
+        // Don't trust the device to report a length which fits in the buffer.
+        Ok(min(len as usize, fuse_out.len()))
+    }
+
+    /// Sends the given FUSE request on the high priority queue, and blocks until the device has
+    /// taken it.
+    ///
+    /// This is meant for `FUSE_INTERRUPT` and `FUSE_FORGET` requests, which have no reply.
+    pub fn request_hiprio(&mut self, fuse_in: &[u8]) -> Result {
+        if fuse_in.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        self.hiprio_queue
+            .add_notify_wait_pop(&[fuse_in], &mut [], &mut self.transport)?;
+        Ok(())
+    }
+
+    /// Sends the given FUSE request on the queue for the given priority: like
+    /// [`request`](Self::request) for [`RequestPriority::Normal`], or like
+    /// [`request_hiprio`](Self::request_hiprio) for [`RequestPriority::High`].
+    ///
+    /// High priority requests have no reply, so `fuse_out` must be empty for them, otherwise
+    /// [`Error::InvalidParam`] is returned.
+    pub fn request_with_priority(
+        &mut self,
+        priority: RequestPriority,
+        fuse_in: &[u8],
+        fuse_out: &mut [u8],
+    ) -> Result<usize> {
+        match priority {
+            RequestPriority::Normal => self.request(fuse_in, fuse_out),
+            RequestPriority::High if fuse_out.is_empty() => {
+                self.request_hiprio(fuse_in)?;
+                Ok(0)
+            }
+            RequestPriority::High => Err(Error::InvalidParam),
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOFs<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOFs::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(
+            &self.transport,
+            [self.hiprio_queue.snapshot(), self.request_queue.snapshot()],
+        )
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.hiprio_queue.dma_footprint() + self.request_queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOFs<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(HIPRIO_QUEUE);
+        self.transport.queue_unset(REQUEST_QUEUE);
+    }
+}
+
+#[repr(C)]
+struct FsConfig {
+    /// The name of the file system, UTF-8 encoded and padded with NUL bytes.
+    tag: ReadOnly<[u8; TAG_LEN]>,
+    num_request_queues: ReadOnly<u32>,
+    /// Only valid if `VIRTIO_FS_F_NOTIFICATION` is negotiated.
+    notify_buf_size: ReadOnly<u32>,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct FsFeature: u64 {
+        /// The device has a notification queue.
+        const NOTIFICATION          = 1 << 0;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of a file system device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to `VirtIOFs::new_with_features`
+/// to forbid the feature. Use `trailing_zeros` to get the bit number for `VirtIOFs::supports`.
+pub mod features {
+    use super::FsFeature;
+    pub use crate::transport::features::*;
+
+    /// The device has a notification queue.
+    pub const NOTIFICATION: u64 = FsFeature::NOTIFICATION.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec::Vec};
+    use std::{sync::Mutex, thread};
+
+    fn make_fs(
+        config_space: &mut FsConfig,
+    ) -> (
+        VirtIOFs<FakeHal, FakeTransport<FsConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::FileSystem,
+            QUEUE_SIZE.into(),
+            2,
+            FsFeature::VERSION_1.bits(),
+            config_space,
+        );
+        (VirtIOFs::new(transport).unwrap(), state)
+    }
+
+    fn new_config(tag: &[u8]) -> FsConfig {
+        let mut padded_tag = [0; TAG_LEN];
+        padded_tag[..tag.len()].copy_from_slice(tag);
+        FsConfig {
+            tag: ReadOnly::new(padded_tag),
+            num_request_queues: ReadOnly::new(1),
+            notify_buf_size: ReadOnly::new(0),
+        }
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = new_config(b"myfs");
+        let (mut fs, _) = make_fs(&mut config_space);
+
+        let mut tag = [0; TAG_LEN];
+        assert_eq!(fs.tag(&mut tag), 4);
+        assert_eq!(&tag[..4], b"myfs");
+        assert_eq!(fs.num_request_queues(), 1);
+        assert_eq!(fs.dax_window(), None);
+        assert!(fs.supports(32));
+    }
+
+    #[test]
+    fn tag_full_length() {
+        let mut config_space = new_config(&[b'a'; TAG_LEN]);
+        let (fs, _) = make_fs(&mut config_space);
+
+        let mut tag = [0; TAG_LEN];
+        assert_eq!(fs.tag(&mut tag), TAG_LEN);
+    }
+
+    #[test]
+    fn request() {
+        let mut config_space = new_config(b"myfs");
+        let (mut fs, state) = make_fs(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, REQUEST_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(REQUEST_QUEUE, |request| {
+                    assert_eq!(request, b"fuse request");
+                    b"reply".to_vec()
+                }));
+        });
+
+        let mut reply = [0; 5];
+        assert_eq!(fs.request(b"fuse request", &mut reply), Ok(5));
+        assert_eq!(&reply, b"reply");
+        handle.join().unwrap();
+
+        assert_eq!(fs.request(&[], &mut reply), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn request_hiprio() {
+        let mut config_space = new_config(b"myfs");
+        let (mut fs, state) = make_fs(&mut config_space);
+
+        let handle = thread::spawn(move || -> Vec<u8> {
+            State::wait_until_queue_notified(&state, HIPRIO_QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<{ QUEUE_SIZE as usize }>(HIPRIO_QUEUE)
+        });
+
+        fs.request_hiprio(b"forget").unwrap();
+        assert_eq!(handle.join().unwrap(), b"forget");
+    }
+    #[test]
+    fn request_with_priority() {
+        let mut config_space = new_config(b"myfs");
+        let (mut fs, state) = make_fs(&mut config_space);
+
+        // High priority requests can't have a reply.
+        let mut reply = [0; 5];
+        assert_eq!(
+            fs.request_with_priority(RequestPriority::High, b"forget", &mut reply),
+            Err(Error::InvalidParam)
+        );
+
+        let handle = thread::spawn(move || -> Vec<u8> {
+            State::wait_until_queue_notified(&state, HIPRIO_QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<{ QUEUE_SIZE as usize }>(HIPRIO_QUEUE)
+        });
+
+        assert_eq!(
+            fs.request_with_priority(RequestPriority::High, b"forget", &mut []),
+            Ok(0)
+        );
+        assert_eq!(handle.join().unwrap(), b"forget");
+    }
+}
@@ -0,0 +1,720 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO IOMMU devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::ReadOnly;
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result, PAGE_SIZE};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::mem::{offset_of, size_of};
+use core::ops::RangeInclusive;
+use log::info;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+const SUPPORTED_FEATURES: IommuFeature = IommuFeature::INPUT_RANGE
+    .union(IommuFeature::DOMAIN_RANGE)
+    .union(IommuFeature::MAP_UNMAP)
+    .union(IommuFeature::NOTIFICATION_DATA)
+    .union(IommuFeature::VERSION_1);
+const REQUIRED_FEATURES: IommuFeature = IommuFeature::VERSION_1;
+/// The end of the config space fields needed by each optional feature, so that the feature isn't
+/// negotiated if the device's config space is too small to hold them.
+const CONFIG_FIELDS: [(IommuFeature, usize); 2] = [
+    (
+        IommuFeature::INPUT_RANGE,
+        offset_of!(IommuConfig, input_range_end_high) + size_of::<u32>(),
+    ),
+    (
+        IommuFeature::DOMAIN_RANGE,
+        offset_of!(IommuConfig, domain_range_end) + size_of::<u32>(),
+    ),
+];
+
+/// Driver for a VirtIO IOMMU device.
+///
+/// The device translates the addresses used for DMA by the endpoints (i.e. other devices) behind
+/// it. Endpoints are attached to domains, and each domain has its own set of mappings from virtual
+/// (I/O) addresses to guest physical addresses, which must be set up before the endpoints can DMA
+/// to them.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::iommu::{MapFlags, VirtIOIommu};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut iommu = VirtIOIommu::<HalImpl, _>::new(transport)?;
+///
+/// iommu.attach(1, 0x10)?;
+/// iommu.map(1, 0x1000_0000, 0x8000_0000, 0x10000, MapFlags::READ | MapFlags::WRITE)?;
+/// // ... the endpoint can now DMA to 0x1000_0000..0x1001_0000 ...
+/// iommu.unmap(1, 0x1000_0000, 0x10000)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOIommu<H: Hal, T: Transport> {
+    transport: T,
+    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    negotiated_features: IommuFeature,
+    page_size_mask: u64,
+    input_range: RangeInclusive<u64>,
+    domain_range: RangeInclusive<u32>,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOIommu<H, T> {
+    /// Create a new VirtIO-IOMMU driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        let negotiated_features = transport.begin_init_with_config(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+            &CONFIG_FIELDS,
+        )?;
+
+        // Only the fields which are needed are read, as devices needn't provide the rest.
+        let (page_size_mask, input_range, domain_range) =
+            transport.read_config_space_atomic(|| -> Result<_> {
+                let read_u64 = |low, high| -> Result<u64> {
+                    let low: u32 = transport.read_config::<H, _>(low)?;
+                    let high: u32 = transport.read_config::<H, _>(high)?;
+                    Ok(u64::from(low) | u64::from(high) << 32)
+                };
+                let page_size_mask = read_u64(
+                    offset_of!(IommuConfig, page_size_mask_low),
+                    offset_of!(IommuConfig, page_size_mask_high),
+                )?;
+                // The ranges are only valid if the corresponding features were negotiated,
+                // otherwise there is no restriction.
+                let input_range = if negotiated_features.contains(IommuFeature::INPUT_RANGE) {
+                    let start = read_u64(
+                        offset_of!(IommuConfig, input_range_start_low),
+                        offset_of!(IommuConfig, input_range_start_high),
+                    )?;
+                    let end = read_u64(
+                        offset_of!(IommuConfig, input_range_end_low),
+                        offset_of!(IommuConfig, input_range_end_high),
+                    )?;
+                    start..=end
+                } else {
+                    0..=u64::MAX
+                };
+                let domain_range = if negotiated_features.contains(IommuFeature::DOMAIN_RANGE) {
+                    let start = transport
+                        .read_config::<H, _>(offset_of!(IommuConfig, domain_range_start))?;
+                    let end =
+                        transport.read_config::<H, _>(offset_of!(IommuConfig, domain_range_end))?;
+                    start..=end
+                } else {
+                    0..=u32::MAX
+                };
+                Ok((page_size_mask, input_range, domain_range))
+            })?;
+        info!(
+            "found an IOMMU with page sizes {:#x}, input range {:#x?}, domains {:?}",
+            page_size_mask, input_range, domain_range
+        );
+
+        let queue = VirtQueue::new(&mut transport, REQUEST_QUEUE, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        Ok(VirtIOIommu {
+            transport,
+            queue,
+            negotiated_features,
+            page_size_mask,
+            input_range,
+            domain_range,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the bitmask of page sizes which the device supports for mappings. The smallest of
+    /// them is the granularity of [`map`](Self::map) and [`unmap`](Self::unmap).
+    pub fn page_size_mask(&self) -> u64 {
+        self.page_size_mask
+    }
+
+    /// Returns the range of virtual addresses which the device can translate.
+    pub fn input_range(&self) -> RangeInclusive<u64> {
+        self.input_range.clone()
+    }
+
+    /// Returns the range of domain IDs which the device supports.
+    pub fn domain_range(&self) -> RangeInclusive<u32> {
+        self.domain_range.clone()
+    }
+
+    /// Returns the counters of operations on the request queue.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and whether
+    /// the request queue has used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [self.queue.can_pop()],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Attaches the given endpoint to the given domain, so that its DMA is translated by the
+    /// domain's mappings. The endpoint is detached from any domain it was attached to before.
+    ///
+    /// Returns [`Error::InvalidParam`] if the domain is outside
+    /// [`domain_range`](Self::domain_range) or the device doesn't know the endpoint.
+    pub fn attach(&mut self, domain: u32, endpoint: u32) -> Result {
+        self.check_domain(domain)?;
+        let request = AttachReq {
+            head: ReqHead::new(VIRTIO_IOMMU_T_ATTACH),
+            domain,
+            endpoint,
+            flags: 0,
+            reserved: [0; 8],
+        };
+        self.request(request.as_bytes())
+    }
+
+    /// Detaches the given endpoint from the given domain.
+    pub fn detach(&mut self, domain: u32, endpoint: u32) -> Result {
+        self.check_domain(domain)?;
+        let request = DetachReq {
+            head: ReqHead::new(VIRTIO_IOMMU_T_DETACH),
+            domain,
+            endpoint,
+            reserved: [0; 8],
+        };
+        self.request(request.as_bytes())
+    }
+
+    /// Maps `size` bytes of virtual addresses starting at `virt` in the given domain to the guest
+    /// physical addresses starting at `phys`.
+    ///
+    /// The addresses and size must be aligned to the smallest page size in
+    /// [`page_size_mask`](Self::page_size_mask), and the virtual addresses must lie within
+    /// [`input_range`](Self::input_range), otherwise [`Error::InvalidParam`] is returned. Returns
+    /// [`Error::Unsupported`] if the device doesn't support the `VIRTIO_IOMMU_F_MAP_UNMAP`
+    /// feature.
+    pub fn map(&mut self, domain: u32, virt: u64, phys: u64, size: u64, flags: MapFlags) -> Result {
+        let virt_end = self.check_mapping(domain, virt, size)?;
+        if phys & (self.granule() - 1) != 0 || phys.checked_add(size - 1).is_none() {
+            return Err(Error::InvalidParam);
+        }
+        let request = MapReq {
+            head: ReqHead::new(VIRTIO_IOMMU_T_MAP),
+            domain,
+            virt_start: virt,
+            virt_end,
+            phys_start: phys,
+            flags: flags.bits(),
+        };
+        self.request(request.as_bytes())
+    }
+
+    /// Removes the mappings of the `size` bytes of virtual addresses starting at `virt` in the
+    /// given domain.
+    ///
+    /// The same restrictions apply as for [`map`](Self::map). The range must not split an
+    /// existing mapping, or the device fails the request.
+    pub fn unmap(&mut self, domain: u32, virt: u64, size: u64) -> Result {
+        let virt_end = self.check_mapping(domain, virt, size)?;
+        let request = UnmapReq {
+            head: ReqHead::new(VIRTIO_IOMMU_T_UNMAP),
+            domain,
+            virt_start: virt,
+            virt_end,
+            reserved: [0; 4],
+        };
+        self.request(request.as_bytes())
+    }
+
+    /// Returns the smallest page size which the device supports.
+    fn granule(&self) -> u64 {
+        // The device must support at least one page size, but don't trust it.
+        1u64.checked_shl(self.page_size_mask.trailing_zeros())
+            .unwrap_or(PAGE_SIZE as u64)
+    }
+
+    fn check_domain(&self, domain: u32) -> Result {
+        if !self.domain_range.contains(&domain) {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Checks the parameters of a map or unmap request, and returns the last virtual address of
+    /// the range.
+    fn check_mapping(&self, domain: u32, virt: u64, size: u64) -> Result<u64> {
+        if !self.negotiated_features.contains(IommuFeature::MAP_UNMAP) {
+            return Err(Error::Unsupported);
+        }
+        self.check_domain(domain)?;
+        let granule_mask = self.granule() - 1;
+        if size == 0 || virt & granule_mask != 0 || size & granule_mask != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let virt_end = virt.checked_add(size - 1).ok_or(Error::InvalidParam)?;
+        if !self.input_range.contains(&virt) || !self.input_range.contains(&virt_end) {
+            return Err(Error::InvalidParam);
+        }
+        Ok(virt_end)
+    }
+
+    /// Sends the given request to the device and waits for it to complete.
+    fn request(&mut self, request: &[u8]) -> Result {
+        // Don't report success if the device doesn't write a status.
+        let mut tail = ReqTail {
+            status: VIRTIO_IOMMU_S_IOERR,
+            reserved: [0; 3],
+        };
+        self.queue.add_notify_wait_pop(
+            &[request],
+            &mut [tail.as_mut_bytes()],
+            &mut self.transport,
+        )?;
+        match tail.status {
+            VIRTIO_IOMMU_S_OK => Ok(()),
+            VIRTIO_IOMMU_S_UNSUPP => Err(Error::Unsupported),
+            VIRTIO_IOMMU_S_INVAL | VIRTIO_IOMMU_S_RANGE | VIRTIO_IOMMU_S_NOENT => {
+                Err(Error::InvalidParam)
+            }
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOIommu<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOIommu::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(&self.transport, [self.queue.snapshot()])
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOIommu<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(REQUEST_QUEUE);
+    }
+}
+
+bitflags! {
+    /// How an endpoint may access a mapping.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct MapFlags: u32 {
+        /// The endpoint may read from the mapping.
+        const READ = 1 << 0;
+        /// The endpoint may write to the mapping.
+        const WRITE = 1 << 1;
+        /// The mapping is of a memory-mapped I/O region rather than RAM.
+        const MMIO = 1 << 2;
+    }
+}
+
+#[repr(C)]
+struct IommuConfig {
+    /// The page sizes which the device supports for mappings.
+    page_size_mask_low: ReadOnly<u32>,
+    page_size_mask_high: ReadOnly<u32>,
+    /// The virtual addresses which the device can translate.
+    input_range_start_low: ReadOnly<u32>,
+    input_range_start_high: ReadOnly<u32>,
+    input_range_end_low: ReadOnly<u32>,
+    input_range_end_high: ReadOnly<u32>,
+    /// The domain IDs which the device supports.
+    domain_range_start: ReadOnly<u32>,
+    domain_range_end: ReadOnly<u32>,
+    probe_size: ReadOnly<u32>,
+    bypass: ReadOnly<u8>,
+    reserved: [u8; 3],
+}
+
+// Request types.
+const VIRTIO_IOMMU_T_ATTACH: u8 = 1;
+const VIRTIO_IOMMU_T_DETACH: u8 = 2;
+const VIRTIO_IOMMU_T_MAP: u8 = 3;
+const VIRTIO_IOMMU_T_UNMAP: u8 = 4;
+
+// Request statuses.
+const VIRTIO_IOMMU_S_OK: u8 = 0;
+const VIRTIO_IOMMU_S_IOERR: u8 = 1;
+const VIRTIO_IOMMU_S_UNSUPP: u8 = 2;
+const VIRTIO_IOMMU_S_INVAL: u8 = 4;
+const VIRTIO_IOMMU_S_RANGE: u8 = 5;
+const VIRTIO_IOMMU_S_NOENT: u8 = 6;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Immutable, IntoBytes, KnownLayout)]
+struct ReqHead {
+    type_: u8,
+    reserved: [u8; 3],
+}
+
+impl ReqHead {
+    fn new(type_: u8) -> Self {
+        Self {
+            type_,
+            reserved: [0; 3],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct ReqTail {
+    status: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct AttachReq {
+    head: ReqHead,
+    domain: u32,
+    endpoint: u32,
+    flags: u32,
+    reserved: [u8; 8],
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct DetachReq {
+    head: ReqHead,
+    domain: u32,
+    endpoint: u32,
+    reserved: [u8; 8],
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct MapReq {
+    head: ReqHead,
+    domain: u32,
+    virt_start: u64,
+    /// The last address of the range, inclusive.
+    virt_end: u64,
+    phys_start: u64,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct UnmapReq {
+    head: ReqHead,
+    domain: u32,
+    virt_start: u64,
+    /// The last address of the range, inclusive.
+    virt_end: u64,
+    reserved: [u8; 4],
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct IommuFeature: u64 {
+        /// `input_range` is valid.
+        const INPUT_RANGE       = 1 << 0;
+        /// `domain_range` is valid.
+        const DOMAIN_RANGE      = 1 << 1;
+        /// Map and unmap requests are available.
+        const MAP_UNMAP         = 1 << 2;
+        /// Endpoints which aren't attached to a domain bypass the IOMMU.
+        const BYPASS            = 1 << 3;
+        /// Probe requests are available.
+        const PROBE             = 1 << 4;
+        /// The `MMIO` mapping flag is available.
+        const MMIO              = 1 << 5;
+        /// `bypass` is valid.
+        const BYPASS_CONFIG     = 1 << 6;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of an IOMMU device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to
+/// `VirtIOIommu::new_with_features` to forbid the feature. Use `trailing_zeros` to get the bit
+/// number for `VirtIOIommu::supports`.
+pub mod features {
+    use super::IommuFeature;
+    pub use crate::transport::features::*;
+
+    /// `input_range` is valid.
+    pub const INPUT_RANGE: u64 = IommuFeature::INPUT_RANGE.bits();
+    /// `domain_range` is valid.
+    pub const DOMAIN_RANGE: u64 = IommuFeature::DOMAIN_RANGE.bits();
+    /// Map and unmap requests are available.
+    pub const MAP_UNMAP: u64 = IommuFeature::MAP_UNMAP.bits();
+    /// Endpoints which aren't attached to a domain bypass the IOMMU.
+    pub const BYPASS: u64 = IommuFeature::BYPASS.bits();
+    /// Probe requests are available.
+    pub const PROBE: u64 = IommuFeature::PROBE.bits();
+    /// The `MMIO` mapping flag is available.
+    pub const MMIO: u64 = IommuFeature::MMIO.bits();
+    /// `bypass` is valid.
+    pub const BYPASS_CONFIG: u64 = IommuFeature::BYPASS_CONFIG.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use std::{sync::Mutex, thread};
+
+    fn make_iommu(
+        config_space: &mut IommuConfig,
+        device_features: IommuFeature,
+    ) -> (
+        VirtIOIommu<FakeHal, FakeTransport<IommuConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::IOMMU,
+            QUEUE_SIZE.into(),
+            2,
+            (device_features | IommuFeature::VERSION_1).bits(),
+            config_space,
+        );
+        (VirtIOIommu::new(transport).unwrap(), state)
+    }
+
+    fn new_config() -> IommuConfig {
+        IommuConfig {
+            page_size_mask_low: ReadOnly::new(0xffff_f000),
+            page_size_mask_high: ReadOnly::new(0xffff_ffff),
+            input_range_start_low: ReadOnly::new(0),
+            input_range_start_high: ReadOnly::new(0),
+            input_range_end_low: ReadOnly::new(0xffff_ffff),
+            input_range_end_high: ReadOnly::new(0),
+            domain_range_start: ReadOnly::new(1),
+            domain_range_end: ReadOnly::new(16),
+            probe_size: ReadOnly::new(0),
+            bypass: ReadOnly::new(0),
+            reserved: [0; 3],
+        }
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = new_config();
+        let (iommu, _) = make_iommu(
+            &mut config_space,
+            IommuFeature::INPUT_RANGE | IommuFeature::DOMAIN_RANGE,
+        );
+        assert_eq!(iommu.page_size_mask(), 0xffff_ffff_ffff_f000);
+        assert_eq!(iommu.input_range(), 0..=0xffff_ffff);
+        assert_eq!(iommu.domain_range(), 1..=16);
+
+        // Without the features the ranges in the config space aren't valid.
+        let mut config_space = new_config();
+        let (iommu, _) = make_iommu(&mut config_space, IommuFeature::empty());
+        assert_eq!(iommu.input_range(), 0..=u64::MAX);
+        assert_eq!(iommu.domain_range(), 0..=u32::MAX);
+    }
+
+    #[test]
+    fn attach() {
+        let mut config_space = new_config();
+        let (mut iommu, state) = make_iommu(&mut config_space, IommuFeature::DOMAIN_RANGE);
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, REQUEST_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(REQUEST_QUEUE, |request| {
+                    let expected = AttachReq {
+                        head: ReqHead::new(VIRTIO_IOMMU_T_ATTACH),
+                        domain: 3,
+                        endpoint: 0x10,
+                        flags: 0,
+                        reserved: [0; 8],
+                    };
+                    assert_eq!(request, expected.as_bytes());
+                    vec![VIRTIO_IOMMU_S_OK, 0, 0, 0]
+                }));
+        });
+
+        assert_eq!(iommu.attach(3, 0x10), Ok(()));
+        handle.join().unwrap();
+
+        // The domain is out of range, so nothing is sent.
+        assert_eq!(iommu.attach(17, 0x10), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn map() {
+        let mut config_space = new_config();
+        let (mut iommu, state) = make_iommu(
+            &mut config_space,
+            IommuFeature::INPUT_RANGE | IommuFeature::MAP_UNMAP,
+        );
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, REQUEST_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(REQUEST_QUEUE, |request| {
+                    let expected = MapReq {
+                        head: ReqHead::new(VIRTIO_IOMMU_T_MAP),
+                        domain: 1,
+                        virt_start: 0x1000_0000,
+                        virt_end: 0x1000_ffff,
+                        phys_start: 0x8000_0000,
+                        flags: (MapFlags::READ | MapFlags::WRITE).bits(),
+                    };
+                    assert_eq!(request, expected.as_bytes());
+                    vec![VIRTIO_IOMMU_S_OK, 0, 0, 0]
+                }));
+
+            State::wait_until_queue_notified(&state, REQUEST_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(REQUEST_QUEUE, |request| {
+                    let expected = UnmapReq {
+                        head: ReqHead::new(VIRTIO_IOMMU_T_UNMAP),
+                        domain: 1,
+                        virt_start: 0x1000_0000,
+                        virt_end: 0x1000_0fff,
+                        reserved: [0; 4],
+                    };
+                    assert_eq!(request, expected.as_bytes());
+                    vec![VIRTIO_IOMMU_S_RANGE, 0, 0, 0]
+                }));
+        });
+
+        assert_eq!(
+            iommu.map(
+                1,
+                0x1000_0000,
+                0x8000_0000,
+                0x10000,
+                MapFlags::READ | MapFlags::WRITE
+            ),
+            Ok(())
+        );
+        // The device refuses to split the mapping.
+        assert_eq!(
+            iommu.unmap(1, 0x1000_0000, 0x1000),
+            Err(Error::InvalidParam)
+        );
+        handle.join().unwrap();
+
+        // Unaligned, empty and out of range mappings are rejected without asking the device.
+        assert_eq!(
+            iommu.map(1, 0x1000_0800, 0x8000_0000, 0x1000, MapFlags::READ),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            iommu.map(1, 0x1000_0000, 0x8000_0000, 0, MapFlags::READ),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            iommu.map(1, 0xffff_f000, 0x8000_0000, 0x2000, MapFlags::READ),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn map_unsupported() {
+        let mut config_space = new_config();
+        let (mut iommu, _) = make_iommu(&mut config_space, IommuFeature::empty());
+        assert_eq!(
+            iommu.map(1, 0x1000, 0x1000, 0x1000, MapFlags::READ),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(iommu.unmap(1, 0x1000, 0x1000), Err(Error::Unsupported));
+    }
+}
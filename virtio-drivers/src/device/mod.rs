@@ -2,5 +2,371 @@
 
 //! Drivers for specific VirtIO devices.
 
+pub mod balloon;
 pub mod blk;
 pub(crate) mod common;
+pub mod crypto;
+pub mod fs;
+pub mod iommu;
+pub mod pmem;
+#[cfg(feature = "alloc")]
+pub mod raw;
+pub mod rng;
+#[cfg(feature = "alloc")]
+pub mod scsi;
+pub mod sound;
+#[cfg(feature = "alloc")]
+pub mod watchdog;
+
+use crate::transport::{DeviceStatus, DeviceType, InterruptStatus, Transport};
+use crate::{Error, QueueSnapshot, Result};
+use core::fmt::{self, Display, Formatter};
+use core::hint::spin_loop;
+
+/// The maximum number of queues recorded in a [`DeviceSnapshot`].
+const SNAPSHOT_MAX_QUEUES: usize = 8;
+
+/// The operations which all device drivers support, without the drivers' type parameters, so that
+/// drivers for different devices can be kept together, e.g. as `Box<dyn VirtioDevice>`.
+pub trait VirtioDevice {
+    /// Returns the type of the device.
+    fn device_type(&self) -> DeviceType;
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    fn ack_interrupt(&mut self) -> bool;
+
+    /// Resets the device, so that it stops using any buffers and raising interrupts.
+    ///
+    /// [`VirtIOBlk`](blk::VirtIOBlk) then initialises the device again as its own `reset` does,
+    /// logging a warning if that fails. Other drivers leave the device reset, so they can only be
+    /// dropped afterwards.
+    fn reset(&mut self);
+
+    /// Returns whether the device must be reset before it can be used again, because it has set
+    /// `DEVICE_NEEDS_RESET` or the driver has given up on it.
+    ///
+    /// Until then requests fail with [`Error::DeviceNeedsReset`] rather than waiting for a device
+    /// which may never complete them. A device usually raises a config change interrupt when it
+    /// starts needing a reset.
+    fn needs_reset(&self) -> bool;
+
+    /// Returns a snapshot of the state of the device and the driver's queues, for debugging.
+    ///
+    /// This only reads from the device and queues, and doesn't allocate or take any locks, so it
+    /// can be called from an interrupt handler or while panicking.
+    fn debug_snapshot(&self) -> DeviceSnapshot;
+
+    /// Returns the features negotiated with the device.
+    fn negotiated_features(&self) -> u64;
+
+    /// Returns the features the device offered, including those the driver didn't accept.
+    fn offered_features(&self) -> u64;
+
+    /// Returns whether the feature with the given bit number, e.g. `VIRTIO_F_VERSION_1` (32), was
+    /// negotiated with the device.
+    fn supports(&self, feature_bit: u32) -> bool {
+        feature_bit < u64::BITS && self.negotiated_features() & (1 << feature_bit) != 0
+    }
+
+    /// Returns the number of bytes of DMA memory the driver currently holds for its virtqueues.
+    fn dma_footprint(&self) -> usize;
+}
+
+/// How urgently a request should be handled, for devices with a separate queue for urgent requests.
+///
+/// Only [`VirtIOFs`](fs::VirtIOFs) honours this, by sending high priority requests on its high
+/// priority queue. The request queues of block and SCSI devices are all equivalent, so their
+/// drivers don't take a priority.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum RequestPriority {
+    /// The request is sent on an ordinary request queue.
+    #[default]
+    Normal,
+    /// The request is sent on the high priority queue, ahead of ordinary requests.
+    High,
+}
+
+/// Calls `op` on the device, retrying up to `max_attempts` times in total while it fails with
+/// [`Error::QueueFull`]. `op` is always called at least once.
+///
+/// Before each retry the device's interrupt is acknowledged, which wakes the wakers of any requests
+/// it has completed, so that their futures can pop them and free their descriptors. Requests made
+/// with a non-blocking API only free their descriptors once they are completed, so something else
+/// must do that, e.g. another thread or the woken tasks. If the queue is still full after the last
+/// attempt, `QueueFull` is returned rather than waiting forever on a device which has stopped
+/// completing requests.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::{blk::VirtIOBlk, retry_on_full};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(blk: &mut VirtIOBlk<HalImpl, T>) -> Result<(), Error> {
+/// let mut buffer = [0; 512];
+/// retry_on_full(blk, 10, |blk| blk.read_blocks(0, &mut buffer))?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn retry_on_full<D: VirtioDevice + ?Sized, R>(
+    device: &mut D,
+    max_attempts: usize,
+    mut op: impl FnMut(&mut D) -> Result<R>,
+) -> Result<R> {
+    for _ in 1..max_attempts {
+        match op(device) {
+            Err(Error::QueueFull) => {
+                device.ack_interrupt();
+                spin_loop();
+            }
+            result => return result,
+        }
+    }
+    op(device)
+}
+
+/// The state of a device and its driver's queues at some point, as returned by
+/// [`VirtioDevice::debug_snapshot`].
+///
+/// Only the first 8 queues of the driver are recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceSnapshot {
+    device_type: DeviceType,
+    status: DeviceStatus,
+    negotiated_features: u64,
+    offered_features: u64,
+    queues: [QueueSnapshot; SNAPSHOT_MAX_QUEUES],
+    num_queues: usize,
+}
+
+impl DeviceSnapshot {
+    /// Creates a new snapshot of the device behind the given transport, and the given queues.
+    pub(crate) fn new(
+        transport: &impl Transport,
+        queues: impl IntoIterator<Item = QueueSnapshot>,
+    ) -> Self {
+        let mut snapshot = Self {
+            device_type: transport.device_type(),
+            status: transport.get_status(),
+            negotiated_features: transport.negotiated_features(),
+            offered_features: transport.offered_features(),
+            queues: [QueueSnapshot::default(); SNAPSHOT_MAX_QUEUES],
+            num_queues: 0,
+        };
+        for (slot, queue) in snapshot.queues.iter_mut().zip(queues) {
+            *slot = queue;
+            snapshot.num_queues += 1;
+        }
+        snapshot
+    }
+
+    /// Returns the type of the device.
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    /// Returns the device status register.
+    pub fn status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    /// Returns the features negotiated with the device.
+    pub fn negotiated_features(&self) -> u64 {
+        self.negotiated_features
+    }
+
+    /// Returns the features the device offered, including those the driver didn't accept.
+    pub fn offered_features(&self) -> u64 {
+        self.offered_features
+    }
+
+    /// Returns the snapshots of the driver's queues.
+    pub fn queues(&self) -> &[QueueSnapshot] {
+        &self.queues[..self.num_queues]
+    }
+}
+
+impl Display for DeviceSnapshot {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} device, status {:#x}, features {:#x} of {:#x} offered",
+            self.device_type,
+            self.status.bits(),
+            self.negotiated_features,
+            self.offered_features
+        )?;
+        for queue in self.queues() {
+            write!(f, "\n  {}", queue)?;
+        }
+        Ok(())
+    }
+}
+
+/// What an interrupt acknowledged by a driver's `ack_interrupt_detailed` method was raised for,
+/// and which of the driver's queues have used buffers waiting to be popped.
+///
+/// The queues are checked whatever the interrupt status, as the device may have used buffers
+/// without raising an interrupt, e.g. if interrupts were disabled.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterruptDetails {
+    status: InterruptStatus,
+    used_queues: u64,
+}
+
+impl InterruptDetails {
+    /// Creates a new instance from the given interrupt status, and whether each queue in turn,
+    /// starting from queue 0, has used buffers. Only the first 64 queues are recorded.
+    pub(crate) fn new(
+        status: InterruptStatus,
+        used_queues: impl IntoIterator<Item = bool>,
+    ) -> Self {
+        let used_queues = used_queues
+            .into_iter()
+            .take(u64::BITS as usize)
+            .enumerate()
+            .filter(|&(_, used)| used)
+            .fold(0, |mask, (queue, _)| mask | 1 << queue);
+        Self {
+            status,
+            used_queues,
+        }
+    }
+
+    /// Returns whether there was an interrupt to acknowledge.
+    pub fn acknowledged(&self) -> bool {
+        !self.status.is_empty()
+    }
+
+    /// Returns the interrupt status which the device reported.
+    pub fn status(&self) -> InterruptStatus {
+        self.status
+    }
+
+    /// Returns whether the interrupt was raised because the device configuration changed.
+    pub fn config_changed(&self) -> bool {
+        self.status
+            .contains(InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT)
+    }
+
+    /// Returns a bitmask of the queues which have used buffers waiting to be popped, with bit `n`
+    /// set for queue `n`.
+    pub fn used_queues(&self) -> u64 {
+        self.used_queues
+    }
+
+    /// Returns whether the given queue has used buffers waiting to be popped.
+    pub fn queue_used(&self, queue: u16) -> bool {
+        u32::from(queue) < u64::BITS && self.used_queues & (1 << queue) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device whose queue is full until its interrupt has been acknowledged a number of times.
+    struct FullDevice {
+        full_until_acks: usize,
+        acks: usize,
+        calls: usize,
+    }
+
+    impl FullDevice {
+        fn new(full_until_acks: usize) -> Self {
+            Self {
+                full_until_acks,
+                acks: 0,
+                calls: 0,
+            }
+        }
+
+        fn submit(&mut self) -> Result<u16> {
+            self.calls += 1;
+            if self.acks < self.full_until_acks {
+                Err(Error::QueueFull)
+            } else {
+                Ok(42)
+            }
+        }
+    }
+
+    impl VirtioDevice for FullDevice {
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+
+        fn ack_interrupt(&mut self) -> bool {
+            self.acks += 1;
+            true
+        }
+
+        fn reset(&mut self) {}
+
+        fn needs_reset(&self) -> bool {
+            false
+        }
+
+        fn debug_snapshot(&self) -> DeviceSnapshot {
+            DeviceSnapshot {
+                device_type: self.device_type(),
+                status: DeviceStatus::empty(),
+                negotiated_features: 0,
+                offered_features: 0,
+                queues: [QueueSnapshot::default(); SNAPSHOT_MAX_QUEUES],
+                num_queues: 0,
+            }
+        }
+
+        fn negotiated_features(&self) -> u64 {
+            0
+        }
+
+        fn offered_features(&self) -> u64 {
+            0
+        }
+
+        fn dma_footprint(&self) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn retry_on_full_succeeds() {
+        let mut device = FullDevice::new(2);
+        assert_eq!(retry_on_full(&mut device, 5, FullDevice::submit), Ok(42));
+        assert_eq!(device.calls, 3);
+        assert_eq!(device.acks, 2);
+    }
+
+    #[test]
+    fn retry_on_full_gives_up() {
+        let mut device = FullDevice::new(usize::MAX);
+        assert_eq!(
+            retry_on_full(&mut device, 3, FullDevice::submit),
+            Err(Error::QueueFull)
+        );
+        assert_eq!(device.calls, 3);
+        assert_eq!(device.acks, 2);
+
+        // Zero attempts still tries once.
+        let mut device = FullDevice::new(0);
+        assert_eq!(retry_on_full(&mut device, 0, FullDevice::submit), Ok(42));
+        assert_eq!(device.calls, 1);
+    }
+
+    #[test]
+    fn retry_on_full_other_error() {
+        let mut device = FullDevice::new(0);
+        let result: Result = retry_on_full(&mut device, 3, |device| {
+            device.calls += 1;
+            Err(Error::IoError)
+        });
+        assert_eq!(result, Err(Error::IoError));
+        assert_eq!(device.calls, 1);
+        assert_eq!(device.acks, 0);
+    }
+}
@@ -0,0 +1,392 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO persistent memory devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::ReadOnly;
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, PhysAddr, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::mem::offset_of;
+use log::info;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+const QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+const SUPPORTED_FEATURES: PmemFeature =
+    PmemFeature::VERSION_1.union(PmemFeature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: PmemFeature = PmemFeature::VERSION_1;
+
+/// Driver for a VirtIO persistent memory device.
+///
+/// The device exposes a range of guest physical memory which is backed by persistent storage on
+/// the host. Mapping and accessing that range is up to the caller; the driver only reports where
+/// it is and lets the caller ask the device to make earlier writes to it durable.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::pmem::VirtIOPmem;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut pmem = VirtIOPmem::<HalImpl, _>::new(transport)?;
+///
+/// let (start, size) = pmem.region();
+/// println!("Persistent memory at {:#x}, {} bytes", start, size);
+/// // ... map the region and write to it ...
+/// pmem.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOPmem<H: Hal, T: Transport> {
+    transport: T,
+    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    start: PhysAddr,
+    size: u64,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOPmem<H, T> {
+    /// Create a new VirtIO-Pmem driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+
+        let (start, size) = transport.read_config_space_atomic(|| {
+            let read = |offset| transport.read_config::<H, u32>(offset).map(u64::from);
+            Ok::<_, Error>((
+                read(offset_of!(PmemConfig, start_low))?
+                    | read(offset_of!(PmemConfig, start_high))? << 32,
+                read(offset_of!(PmemConfig, size_low))?
+                    | read(offset_of!(PmemConfig, size_high))? << 32,
+            ))
+        })?;
+        info!("found persistent memory at {:#x}, size {:#x}", start, size);
+
+        let queue = VirtQueue::new(&mut transport, QUEUE, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        Ok(VirtIOPmem {
+            transport,
+            queue,
+            start: start as PhysAddr,
+            size,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the guest physical address and the size in bytes of the persistent memory region.
+    pub fn region(&self) -> (PhysAddr, u64) {
+        (self.start, self.size)
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [self.queue.can_pop()],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Asks the device to make all earlier writes to the persistent memory region durable, and
+    /// blocks until it has done so.
+    ///
+    /// Returns [`Error::IoError`] if the device reports that the flush failed.
+    pub fn flush(&mut self) -> Result {
+        let request = PmemReq {
+            type_: PMEM_REQ_TYPE_FLUSH,
+        };
+        // Don't report success if the device doesn't write a response.
+        let mut response = PmemResp { ret: u32::MAX };
+        self.queue.add_notify_wait_pop(
+            &[request.as_bytes()],
+            &mut [response.as_mut_bytes()],
+            &mut self.transport,
+        )?;
+        if response.ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::IoError)
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOPmem<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOPmem::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(&self.transport, [self.queue.snapshot()])
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOPmem<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(QUEUE);
+    }
+}
+
+#[repr(C)]
+struct PmemConfig {
+    /// Guest physical address of the start of the region.
+    start_low: ReadOnly<u32>,
+    start_high: ReadOnly<u32>,
+    /// Size of the region in bytes.
+    size_low: ReadOnly<u32>,
+    size_high: ReadOnly<u32>,
+}
+
+/// The only request type, to flush the region to persistent storage.
+const PMEM_REQ_TYPE_FLUSH: u32 = 0;
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct PmemReq {
+    type_: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct PmemResp {
+    /// 0 on success, or non-zero on failure.
+    ret: u32,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct PmemFeature: u64 {
+        /// The region is advertised as a shared memory region rather than in the config space.
+        const SHMEM_REGION          = 1 << 0;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of a persistent memory device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to `VirtIOPmem::new_with_features`
+/// to forbid the feature. Use `trailing_zeros` to get the bit number for `VirtIOPmem::supports`.
+pub mod features {
+    use super::PmemFeature;
+    pub use crate::transport::features::*;
+
+    /// The region is advertised as a shared memory region rather than in the config space.
+    pub const SHMEM_REGION: u64 = PmemFeature::SHMEM_REGION.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::{boxed::Box, sync::Arc};
+    use std::{sync::Mutex, thread};
+
+    fn make_pmem(
+        config_space: &mut PmemConfig,
+    ) -> (
+        VirtIOPmem<FakeHal, FakeTransport<PmemConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::PersistentMemory,
+            QUEUE_SIZE.into(),
+            1,
+            PmemFeature::VERSION_1.bits(),
+            config_space,
+        );
+        (VirtIOPmem::new(transport).unwrap(), state)
+    }
+
+    fn new_config() -> PmemConfig {
+        PmemConfig {
+            start_low: ReadOnly::new(0x4000_0000),
+            start_high: ReadOnly::new(0x1),
+            size_low: ReadOnly::new(0x1000_0000),
+            size_high: ReadOnly::new(0),
+        }
+    }
+
+    fn respond_to_flush(state: &Mutex<State>, ret: u32) {
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    PmemReq {
+                        type_: PMEM_REQ_TYPE_FLUSH
+                    }
+                    .as_bytes()
+                );
+                PmemResp { ret }.as_bytes().to_vec()
+            }));
+    }
+
+    #[test]
+    fn region() {
+        let mut config_space = new_config();
+        let (pmem, _) = make_pmem(&mut config_space);
+
+        assert_eq!(pmem.region(), (0x1_4000_0000, 0x1000_0000));
+    }
+
+    #[test]
+    fn flush() {
+        let mut config_space = new_config();
+        let (mut pmem, state) = make_pmem(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            respond_to_flush(&state, 0);
+            State::wait_until_queue_notified(&state, QUEUE);
+            respond_to_flush(&state, 1);
+        });
+
+        assert_eq!(pmem.flush(), Ok(()));
+        assert_eq!(pmem.flush(), Err(Error::IoError));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn virtio_device() {
+        let mut config_space = new_config();
+        let (pmem, state) = make_pmem(&mut config_space);
+        let mut device: Box<dyn VirtioDevice> = Box::new(pmem);
+
+        assert_eq!(device.device_type(), DeviceType::PersistentMemory);
+        let snapshot = device.debug_snapshot();
+        assert_eq!(
+            snapshot.negotiated_features(),
+            PmemFeature::VERSION_1.bits()
+        );
+        assert_eq!(snapshot.queues().len(), 1);
+        assert_eq!(
+            snapshot.to_string(),
+            "PersistentMemory device, status 0xf, features 0x100000000 of 0x100000000 offered\n  \
+             queue 0: avail 0 used 0 last used 0, 0/8 descriptors in use"
+        );
+        state.lock().unwrap().interrupt_pending = true;
+        assert!(device.ack_interrupt());
+        assert!(!device.ack_interrupt());
+        device.reset();
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn notifier() {
+        use core::{future::Future, pin::pin, task::Context};
+        use std::task::{Wake, Waker};
+
+        struct NoopWaker;
+
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let mut config_space = new_config();
+        let (pmem, _) = make_pmem(&mut config_space);
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+
+        // FakeHal doesn't route interrupts, so the platform has to notify the device's notifier.
+        let mut notified = pin!(pmem.notifier().notified());
+        assert!(notified.as_mut().poll(&mut cx).is_pending());
+        pmem.notifier().clone().notify();
+        assert!(notified.as_mut().poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,447 @@
+// SPDX-License-Identifier: MIT
+
+//! Generic driver for prototyping VirtIO devices which don't have a driver in this crate.
+
+use crate::device::common::Feature;
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::transport::{ConfigValue, DeviceStatus, DeviceType, Transport};
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result, VirtQueue};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::mem::{align_of, size_of};
+use core::ptr::NonNull;
+use log::info;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+const REQUIRED_FEATURES: RawFeature = RawFeature::VERSION_1;
+
+/// A VirtIO device of any type, which is initialised but otherwise left to the caller to drive.
+///
+/// This negotiates features and sets up the requested number of virtqueues, each of `QUEUE_SIZE`
+/// descriptors, and then gives direct access to the queues and the device configuration space. It
+/// is meant for bringing up device types which don't have a driver yet, and as a base for drivers
+/// living outside this crate.
+///
+/// The queues are borrowed from the device for each use, so they can't outlive it, and a request
+/// is added, notified and popped in separate steps:
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::raw::RawDevice;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// // Use one queue, and just the device independent features.
+/// let mut device = RawDevice::<HalImpl, _, 16>::new(transport, 0, 1)?;
+///
+/// let request = [1, 2, 3, 4];
+/// let mut response = [0; 4];
+/// // SAFETY: The buffers outlive the request, as it is popped before they are dropped.
+/// let token = unsafe { device.queue(0).add(&[&request], &mut [&mut response]) }?;
+/// device.notify(0);
+/// while device.queue(0).peek_used() != Some(token) {
+///     core::hint::spin_loop();
+/// }
+/// // SAFETY: These are the same buffers which were passed to `add`.
+/// let len = unsafe { device.queue(0).pop_used(token, &[&request], &mut [&mut response]) }?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RawDevice<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    transport: T,
+    queues: Vec<VirtQueue<H, QUEUE_SIZE>>,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> RawDevice<H, T, QUEUE_SIZE> {
+    /// Initialises the device, negotiating any of the given device specific features which it
+    /// offers, and sets up queues `0..num_queues`.
+    ///
+    /// `supported_features` may include device independent features too. Indirect descriptors,
+    /// event index suppression and in-order completion are used for the queues if they are both
+    /// supported and offered.
+    pub fn new(mut transport: T, supported_features: u64, num_queues: u16) -> Result<Self> {
+        let negotiated_features = transport.begin_init(
+            RawFeature::from_bits_retain(supported_features) | RawFeature::VERSION_1,
+            REQUIRED_FEATURES,
+        )?;
+        info!(
+            "{:?} device negotiated features {:#x}",
+            transport.device_type(),
+            negotiated_features.bits()
+        );
+
+        let indirect = negotiated_features.contains(RawFeature::RING_INDIRECT_DESC);
+        let event_idx = negotiated_features.contains(RawFeature::RING_EVENT_IDX);
+        let in_order = negotiated_features.contains(RawFeature::IN_ORDER);
+        let queues = (0..num_queues)
+            .map(|idx| VirtQueue::new(&mut transport, idx, indirect, event_idx, in_order))
+            .collect::<Result<Vec<_>>>()?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        Ok(RawDevice {
+            transport,
+            queues,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the type of the device.
+    pub fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    /// Returns whether the device is still there, or has been removed by a surprise hot-unplug.
+    pub fn is_present(&self) -> bool {
+        self.transport.is_present()
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.queues
+            .iter()
+            .fold(QueueStats::default(), |stats, queue| stats + queue.stats())
+    }
+
+    /// Returns the number of queues which were set up.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+
+    /// Returns the queue with the given index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` isn't less than the number of queues passed to [`new`](Self::new).
+    pub fn queue(&mut self, idx: u16) -> &mut VirtQueue<H, QUEUE_SIZE> {
+        &mut self.queues[usize::from(idx)]
+    }
+
+    /// Notifies the device that there are new buffers in the given queue, unless it has asked not
+    /// to be notified.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` isn't less than the number of queues passed to [`new`](Self::new).
+    pub fn notify(&mut self, idx: u16) {
+        let queue = &self.queues[usize::from(idx)];
+        if queue.should_notify() {
+            queue.notify_device(&mut self.transport);
+        }
+    }
+
+    /// Adds each of the given buffers to the given queue as a chain of its own for the device to
+    /// write to, and notifies the device once, as described for [`VirtQueue::add_many`].
+    ///
+    /// # Safety
+    ///
+    /// Each buffer which is added must remain valid and not be accessed until it is popped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` isn't less than the number of queues passed to [`new`](Self::new).
+    pub unsafe fn add_many(
+        &mut self,
+        idx: u16,
+        buffers: &mut [&mut [u8]],
+        tokens: &mut [u16],
+    ) -> Result<usize> {
+        // SAFETY: Our caller promises that the buffers remain valid until they are popped.
+        unsafe { self.queues[usize::from(idx)].add_many(buffers, tokens, &mut self.transport) }
+    }
+
+    /// Resets the given queue on its own and sets it up again, abandoning any requests still in it
+    /// as described for [`VirtQueue::reset`].
+    ///
+    /// Returns [`Error::Unsupported`] unless `VIRTIO_F_RING_RESET` was passed to
+    /// [`new`](Self::new) and negotiated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` isn't less than the number of queues passed to [`new`](Self::new).
+    pub fn reset_queue(&mut self, idx: u16, abandoned: impl FnMut(u16)) -> Result {
+        self.queues[usize::from(idx)].reset(&mut self.transport, abandoned)
+    }
+
+    /// Adds the given buffers to the given queue, notifies the device, blocks until the device
+    /// uses them and then pops them.
+    ///
+    /// Returns the length which the device reported that it wrote.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx` isn't less than the number of queues passed to [`new`](Self::new).
+    pub fn add_notify_wait_pop<'a>(
+        &mut self,
+        idx: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        self.queues[usize::from(idx)].add_notify_wait_pop(inputs, outputs, &mut self.transport)
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            self.queues.iter().map(VirtQueue::can_pop),
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Returns the size of the device configuration space in bytes.
+    pub fn config_space_len(&self) -> usize {
+        self.transport.config_space_len()
+    }
+
+    /// Reads a value from the device configuration space at the given byte offset, converting it
+    /// from the device's byte order.
+    ///
+    /// Unlike [`read_config`](Self::read_config) this checks that the value is within the config
+    /// space, returning [`Error::ConfigSpaceTooSmall`] if it isn't, or [`Error::InvalidParam`] if
+    /// the offset isn't aligned for `V`.
+    pub fn read_config_value<V: ConfigValue>(&self, offset: usize) -> Result<V> {
+        self.transport.read_config::<H, V>(offset)
+    }
+
+    /// Reads a value from the device configuration space at the given byte offset.
+    ///
+    /// Returns [`Error::InvalidParam`] if the offset isn't aligned for `V`.
+    ///
+    /// # Safety
+    ///
+    /// `offset + size_of::<V>()` must be within the device configuration space.
+    pub unsafe fn read_config<V: FromBytes + Immutable>(&self, offset: usize) -> Result<V> {
+        let field = self.config_field::<V>(offset)?;
+        // SAFETY: The field is aligned, and our caller promises that it is within the config
+        // space.
+        Ok(self
+            .transport
+            .read_config_space_atomic(|| unsafe { H::mmio_read(field.as_ref()) }))
+    }
+
+    /// Writes a value to the device configuration space at the given byte offset.
+    ///
+    /// Returns [`Error::InvalidParam`] if the offset isn't aligned for `V`.
+    ///
+    /// # Safety
+    ///
+    /// `offset + size_of::<V>()` must be within the device configuration space, and the device
+    /// must allow the driver to write to that part of it.
+    pub unsafe fn write_config<V: IntoBytes + Immutable>(
+        &mut self,
+        offset: usize,
+        value: V,
+    ) -> Result {
+        let mut field = self.config_field::<V>(offset)?;
+        // SAFETY: The field is aligned, and our caller promises that it is within the config
+        // space and writable.
+        unsafe { H::mmio_write(field.as_mut(), value) };
+        Ok(())
+    }
+
+    /// Returns a pointer to the config space field of type `V` at the given byte offset.
+    fn config_field<V>(&self, offset: usize) -> Result<NonNull<V>> {
+        // VirtIO only guarantees 4 byte alignment of the config space.
+        if offset % align_of::<V>() != 0 || align_of::<V>() > 4 || size_of::<V>() == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let config = self.transport.config_space::<u8>()?;
+        // SAFETY: Our caller promises that the offset is within the config space.
+        Ok(unsafe { config.add(offset) }.cast())
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtioDevice for RawDevice<H, T, QUEUE_SIZE> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        RawDevice::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(&self.transport, self.queues.iter().map(VirtQueue::snapshot))
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.queues.iter().map(VirtQueue::dma_footprint).sum()
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for RawDevice<H, T, QUEUE_SIZE> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        for idx in 0..self.num_queues() {
+            self.transport.queue_unset(idx);
+        }
+    }
+}
+
+bitflags! {
+    /// Features of a device whose type the driver doesn't know, so any bit may be set.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct RawFeature: u64 {
+        // device independent
+        const RING_INDIRECT_DESC    = Feature::RING_INDIRECT_DESC.bits();
+        const RING_EVENT_IDX        = Feature::RING_EVENT_IDX.bits();
+        const VERSION_1             = Feature::VERSION_1.bits();
+        const IN_ORDER              = Feature::IN_ORDER.bits();
+
+        // Device specific features, and any others.
+        const _ = !0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::fake::{FakeTransport, State},
+    };
+    use alloc::sync::Arc;
+    use std::{sync::Mutex, thread};
+
+    const QUEUE_SIZE: usize = 4;
+
+    type FakeRaw = RawDevice<FakeHal, FakeTransport<[u32; 2]>, QUEUE_SIZE>;
+
+    fn make_device(
+        config_space: &mut [u32; 2],
+        device_features: u64,
+    ) -> (FakeRaw, Arc<Mutex<State>>) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Sound,
+            QUEUE_SIZE as u32,
+            2,
+            device_features,
+            config_space,
+        );
+        (RawDevice::new(transport, 1 << 0, 2).unwrap(), state)
+    }
+
+    #[test]
+    fn features() {
+        let mut config_space = [0; 2];
+        let (device, state) = make_device(
+            &mut config_space,
+            (1 << 0) | (1 << 1) | (Feature::RING_EVENT_IDX | Feature::VERSION_1).bits(),
+        );
+
+        assert_eq!(device.device_type(), DeviceType::Sound);
+        assert_eq!(device.num_queues(), 2);
+        assert_eq!(
+            state.lock().unwrap().driver_features,
+            (1 << 0) | Feature::VERSION_1.bits()
+        );
+        assert!(device.supports(0));
+        assert!(!device.supports(1));
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = [0x1234_5678, 0];
+        let (mut device, _) = make_device(&mut config_space, Feature::VERSION_1.bits());
+
+        // SAFETY: The fields are within the fake config space.
+        unsafe {
+            assert_eq!(device.read_config::<u32>(0), Ok(0x1234_5678));
+            assert_eq!(device.read_config::<u16>(2), Ok(0x1234));
+            assert_eq!(device.read_config::<u32>(2), Err(Error::InvalidParam));
+            assert_eq!(device.write_config(4, 42u32), Ok(()));
+            assert_eq!(device.read_config::<u32>(4), Ok(42));
+        }
+        // The fake transport claims the whole array, but nothing past it.
+        assert_eq!(device.config_space_len(), 8);
+        assert_eq!(device.read_config_value::<u8>(0), Ok(0x78));
+        assert_eq!(device.read_config_value::<u32>(4), Ok(42));
+        assert_eq!(
+            device.read_config_value::<u32>(8),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        drop(device);
+        assert_eq!(config_space[1], 42);
+    }
+
+    #[test]
+    fn queue() {
+        let mut config_space = [0; 2];
+        let (mut device, state) = make_device(&mut config_space, Feature::VERSION_1.bits());
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, 1);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<QUEUE_SIZE>(1, |request| {
+                    assert_eq!(request, b"ping");
+                    b"pong".to_vec()
+                }));
+        });
+
+        let request = *b"ping";
+        let mut response = [0; 4];
+        // SAFETY: The buffers are popped below, before they are dropped.
+        let token = unsafe { device.queue(1).add(&[&request], &mut [&mut response]) }.unwrap();
+        device.notify(1);
+        handle.join().unwrap();
+        assert_eq!(device.queue(1).peek_used(), Some(token));
+        // SAFETY: These are the same buffers which were passed to `add`.
+        let len = unsafe {
+            device
+                .queue(1)
+                .pop_used(token, &[&request], &mut [&mut response])
+        }
+        .unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(&response, b"pong");
+    }
+}
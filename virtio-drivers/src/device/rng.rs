@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO entropy devices.
+
+use crate::device::common::Feature;
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use core::cmp::min;
+
+const QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+const SUPPORTED_FEATURES: Feature = Feature::VERSION_1.union(Feature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: Feature = Feature::VERSION_1;
+
+/// Driver for a VirtIO entropy device.
+///
+/// The device has no configuration and a single virtqueue (the requestq) on which the driver
+/// places empty buffers for the device to fill with random bytes.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::rng::VirtIORng;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut rng = VirtIORng::<HalImpl, _>::new(transport)?;
+///
+/// // The device may return fewer bytes than requested, so keep asking until the buffer is full.
+/// let mut seed = [0; 32];
+/// let mut filled = 0;
+/// while filled < seed.len() {
+///     filled += rng.request_entropy(&mut seed[filled..])?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIORng<H: Hal, T: Transport> {
+    transport: T,
+    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIORng<H, T> {
+    /// Create a new VirtIO-Rng driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+        let queue = VirtQueue::new(&mut transport, QUEUE, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        Ok(VirtIORng {
+            transport,
+            queue,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [self.queue.can_pop()],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Asks the device to fill the given buffer with random bytes, and blocks until it has done so.
+    ///
+    /// Returns the number of bytes the device actually wrote, starting from the beginning of
+    /// `buf`. This may be less than the length of `buf`, in which case the caller can call this
+    /// again with the remainder of the buffer.
+    pub fn request_entropy(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let len = self
+            .queue
+            .add_notify_wait_pop(&[], &mut [buf], &mut self.transport)?;
+        // Don't trust the device to report a length no greater than the buffer it was given.
+        Ok(min(len as usize, buf.len()))
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIORng<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIORng::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(&self.transport, [self.queue.snapshot()])
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIORng<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(QUEUE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+        Error,
+    };
+    use std::thread;
+
+    #[test]
+    fn request_entropy() {
+        let mut config_space = ();
+        let (transport, state) = FakeTransport::new(
+            DeviceType::EntropySource,
+            QUEUE_SIZE.into(),
+            1,
+            Feature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut rng = VirtIORng::<FakeHal, FakeTransport<()>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device filling the buffer.
+        let entropy: [u8; 64] = core::array::from_fn(|i| i as u8 ^ 0xa5);
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<{ QUEUE_SIZE as usize }>(QUEUE, &entropy);
+        });
+
+        let mut buffer = [0; 64];
+        assert_eq!(rng.request_entropy(&mut buffer).unwrap(), 64);
+        assert_eq!(buffer, entropy);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn request_entropy_short() {
+        let mut config_space = ();
+        let (transport, state) = FakeTransport::new(
+            DeviceType::EntropySource,
+            QUEUE_SIZE.into(),
+            1,
+            Feature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        let mut rng = VirtIORng::<FakeHal, FakeTransport<()>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device only filling part of the buffer.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<{ QUEUE_SIZE as usize }>(QUEUE, &[0x42; 16]);
+        });
+
+        let mut buffer = [0; 64];
+        assert_eq!(rng.request_entropy(&mut buffer).unwrap(), 16);
+        assert_eq!(buffer[..16], [0x42; 16]);
+        assert_eq!(buffer[16..], [0; 48]);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn missing_required_features() {
+        let mut config_space = ();
+        let (transport, state) = FakeTransport::new(
+            DeviceType::EntropySource,
+            QUEUE_SIZE.into(),
+            1,
+            0,
+            &mut config_space,
+        );
+
+        assert_eq!(
+            VirtIORng::<FakeHal, FakeTransport<()>>::new(transport).err(),
+            Some(Error::FeatureNegotiationFailed(Feature::VERSION_1.bits()))
+        );
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::FAILED);
+    }
+}
@@ -0,0 +1,703 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO SCSI host devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::{owning::OwningQueue, VirtQueue};
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::{ReadOnly, Volatile};
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::cmp::min;
+use core::mem::offset_of;
+use log::{debug, info};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+const CONTROL_QUEUE: u16 = 0;
+const EVENT_QUEUE: u16 = 1;
+const REQUEST_QUEUE: u16 = 2;
+const QUEUE_SIZE: u16 = 16;
+const EVENT_QUEUE_SIZE: usize = 4;
+/// The size of a `virtio_scsi_event`.
+const EVENT_SIZE: usize = 16;
+/// The size of the command descriptor block field in requests, which the driver asks the device to
+/// use.
+const CDB_SIZE: usize = 32;
+/// The size of the sense data field in responses, which the driver asks the device to use.
+const SENSE_SIZE: usize = 96;
+/// The largest LUN which can be addressed with the single level LUN structure the device uses.
+const MAX_LUN: u32 = 0x3fff;
+const SUPPORTED_FEATURES: ScsiFeature =
+    ScsiFeature::VERSION_1.union(ScsiFeature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: ScsiFeature = ScsiFeature::VERSION_1;
+
+/// Driver for a VirtIO SCSI host device.
+///
+/// Commands are sent to a logical unit identified by its target and LUN, and are executed
+/// synchronously on the first request queue. Events from the device, such as hotplug notifications,
+/// aren't interpreted but are drained by [`drain_events`](Self::drain_events) so the device can
+/// keep reporting them.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::scsi::{ScsiData, ScsiResponseCode, VirtIOScsi};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut scsi = VirtIOScsi::<HalImpl, _>::new(transport)?;
+///
+/// // Send an INQUIRY command to LUN 0 of target 0.
+/// let mut inquiry = [0; 36];
+/// let response = scsi.execute_command(
+///     0,
+///     0,
+///     &[0x12, 0, 0, 0, inquiry.len() as u8, 0],
+///     ScsiData::FromDevice(&mut inquiry),
+/// )?;
+/// if response.response() == ScsiResponseCode::OK && response.status() == 0 {
+///     println!("Vendor: {:?}", &inquiry[8..16]);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOScsi<H: Hal, T: Transport> {
+    transport: T,
+    control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    event_queue: OwningQueue<H, EVENT_QUEUE_SIZE, EVENT_SIZE>,
+    request_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    num_queues: u32,
+    max_target: u16,
+    max_lun: u32,
+    /// The ID to use for the next command.
+    next_id: u64,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOScsi<H, T> {
+    /// Create a new VirtIO-Scsi driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+
+        // These are the only fields the driver may write.
+        transport.write_config::<H, u32>(offset_of!(ScsiConfig, cdb_size), CDB_SIZE as u32)?;
+        transport.write_config::<H, u32>(offset_of!(ScsiConfig, sense_size), SENSE_SIZE as u32)?;
+        let (num_queues, max_target, max_lun) = transport.read_config_space_atomic(|| {
+            Ok::<_, Error>((
+                transport.read_config::<H, u32>(offset_of!(ScsiConfig, num_queues))?,
+                transport.read_config::<H, u16>(offset_of!(ScsiConfig, max_target))?,
+                transport.read_config::<H, u32>(offset_of!(ScsiConfig, max_lun))?,
+            ))
+        })?;
+        info!(
+            "found a SCSI host with {} request queues, max target {}, max LUN {}",
+            num_queues, max_target, max_lun
+        );
+
+        let control_queue = VirtQueue::new(&mut transport, CONTROL_QUEUE, false, false, false)?;
+        let event_queue = OwningQueue::new(VirtQueue::new(
+            &mut transport,
+            EVENT_QUEUE,
+            false,
+            false,
+            false,
+        )?)?;
+        let request_queue = VirtQueue::new(&mut transport, REQUEST_QUEUE, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        if event_queue.should_notify() {
+            event_queue.notify_device(&mut transport);
+        }
+
+        Ok(VirtIOScsi {
+            transport,
+            control_queue,
+            event_queue,
+            request_queue,
+            num_queues,
+            max_target,
+            max_lun: min(max_lun, MAX_LUN),
+            next_id: 0,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the number of request queues the device has.
+    ///
+    /// Only the first one is currently used.
+    pub fn num_queues(&self) -> u32 {
+        self.num_queues
+    }
+
+    /// Returns the highest target number the device supports.
+    pub fn max_target(&self) -> u16 {
+        self.max_target
+    }
+
+    /// Returns the highest LUN the device supports.
+    pub fn max_lun(&self) -> u32 {
+        self.max_lun
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.control_queue.stats() + self.event_queue.stats() + self.request_queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            [
+                self.control_queue.can_pop(),
+                self.event_queue.can_pop(),
+                self.request_queue.can_pop(),
+            ],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Discards any events the device has reported, giving their buffers back to the device.
+    ///
+    /// Returns the number of events discarded.
+    pub fn drain_events(&mut self) -> Result<usize> {
+        let mut count = 0;
+        while self
+            .event_queue
+            .poll(&mut self.transport, |event| {
+                debug!("discarding SCSI event {:?}", event);
+                Ok(Some(()))
+            })?
+            .is_some()
+        {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Sends the given SCSI command to the given logical unit, and blocks until the device has
+    /// completed it.
+    ///
+    /// `cdb` is the command descriptor block, which may be at most 32 bytes long. Returns
+    /// [`Error::InvalidParam`] if it is longer than that, or if the target or LUN is out of range.
+    ///
+    /// Errors reported by the device or the logical unit aren't turned into an `Err`, but are
+    /// available from the returned [`ScsiResponse`].
+    pub fn execute_command(
+        &mut self,
+        target: u16,
+        lun: u32,
+        cdb: &[u8],
+        data: ScsiData,
+    ) -> Result<ScsiResponse> {
+        if target > self.max_target || lun > self.max_lun || target > 0xff {
+            return Err(Error::InvalidParam);
+        }
+        if cdb.len() > CDB_SIZE {
+            return Err(Error::InvalidParam);
+        }
+
+        let mut req = CmdReq {
+            lun: encode_lun(target as u8, lun as u16),
+            id: self.next_id.to_le_bytes(),
+            task_attr: TASK_ATTR_SIMPLE,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        };
+        req.cdb[..cdb.len()].copy_from_slice(cdb);
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut resp = CmdResp::default();
+        match data {
+            ScsiData::FromDevice(buf) if !buf.is_empty() => {
+                self.request_queue.add_notify_wait_pop(
+                    &[req.as_bytes()],
+                    &mut [resp.as_mut_bytes(), buf],
+                    &mut self.transport,
+                )?
+            }
+            ScsiData::ToDevice(buf) if !buf.is_empty() => self.request_queue.add_notify_wait_pop(
+                &[req.as_bytes(), buf],
+                &mut [resp.as_mut_bytes()],
+                &mut self.transport,
+            )?,
+            // An empty buffer is the same as no data.
+            _ => self.request_queue.add_notify_wait_pop(
+                &[req.as_bytes()],
+                &mut [resp.as_mut_bytes()],
+                &mut self.transport,
+            )?,
+        };
+
+        Ok(ScsiResponse {
+            response: ScsiResponseCode(resp.response),
+            status: resp.status,
+            residual: u32::from_le(resp.residual),
+            // Don't trust the device to report a length no greater than the sense buffer.
+            sense_len: min(u32::from_le(resp.sense_len) as usize, SENSE_SIZE),
+            sense: resp.sense,
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOScsi<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOScsi::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(
+            &self.transport,
+            [
+                self.control_queue.snapshot(),
+                self.event_queue.snapshot(),
+                self.request_queue.snapshot(),
+            ],
+        )
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.control_queue.dma_footprint()
+            + self.event_queue.dma_footprint()
+            + self.request_queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOScsi<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(CONTROL_QUEUE);
+        self.transport.queue_unset(EVENT_QUEUE);
+        self.transport.queue_unset(REQUEST_QUEUE);
+    }
+}
+
+/// Encodes the given target and LUN in the single level LUN structure used by virtio-scsi.
+fn encode_lun(target: u8, lun: u16) -> [u8; 8] {
+    [1, target, 0x40 | (lun >> 8) as u8, lun as u8, 0, 0, 0, 0]
+}
+
+/// The data buffer for a SCSI command, if any.
+#[derive(Debug)]
+pub enum ScsiData<'a> {
+    /// The command doesn't transfer any data.
+    None,
+    /// The command reads data from the device into the buffer.
+    FromDevice(&'a mut [u8]),
+    /// The command writes the contents of the buffer to the device.
+    ToDevice(&'a [u8]),
+}
+
+/// The response to a SCSI command.
+#[derive(Clone, Debug)]
+pub struct ScsiResponse {
+    response: ScsiResponseCode,
+    status: u8,
+    residual: u32,
+    sense_len: usize,
+    sense: [u8; SENSE_SIZE],
+}
+
+impl ScsiResponse {
+    /// Returns the response code from the device, which says whether the command was delivered to
+    /// the logical unit.
+    pub fn response(&self) -> ScsiResponseCode {
+        self.response
+    }
+
+    /// Returns the SCSI status code from the logical unit, e.g. 0 for GOOD or 2 for CHECK
+    /// CONDITION.
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    /// Returns the number of bytes of the data buffer which weren't transferred.
+    pub fn residual(&self) -> u32 {
+        self.residual
+    }
+
+    /// Returns the sense data reported by the logical unit.
+    pub fn sense(&self) -> &[u8] {
+        &self.sense[..self.sense_len]
+    }
+}
+
+/// The virtio-scsi response code of a command.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ScsiResponseCode(u8);
+
+impl ScsiResponseCode {
+    /// The command completed, and the status and sense data are valid.
+    pub const OK: ScsiResponseCode = ScsiResponseCode(0);
+    /// The data buffer was too small for the data the device wanted to transfer.
+    pub const OVERRUN: ScsiResponseCode = ScsiResponseCode(1);
+    /// The command was aborted by a task management function.
+    pub const ABORTED: ScsiResponseCode = ScsiResponseCode(2);
+    /// The target doesn't exist.
+    pub const BAD_TARGET: ScsiResponseCode = ScsiResponseCode(3);
+    /// The command was aborted because of a reset or other event.
+    pub const RESET: ScsiResponseCode = ScsiResponseCode(4);
+    /// The command wasn't processed because the device is busy.
+    pub const BUSY: ScsiResponseCode = ScsiResponseCode(5);
+    /// The command failed due to a problem in the connection between the host and the target.
+    pub const TRANSPORT_FAILURE: ScsiResponseCode = ScsiResponseCode(6);
+    /// The target is suffering a failure and retrying won't help.
+    pub const TARGET_FAILURE: ScsiResponseCode = ScsiResponseCode(7);
+    /// The nexus is suffering a failure but retrying on a different path may help.
+    pub const NEXUS_FAILURE: ScsiResponseCode = ScsiResponseCode(8);
+    /// Some other failure.
+    pub const FAILURE: ScsiResponseCode = ScsiResponseCode(9);
+}
+
+/// The SIMPLE task attribute.
+const TASK_ATTR_SIMPLE: u8 = 0;
+
+/// The device-readable part of a `virtio_scsi_req_cmd`.
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct CmdReq {
+    lun: [u8; 8],
+    /// Little-endian command ID.
+    id: [u8; 8],
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+/// The device-writable part of a `virtio_scsi_req_cmd`.
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct CmdResp {
+    sense_len: u32,
+    residual: u32,
+    status_qualifier: u16,
+    status: u8,
+    response: u8,
+    sense: [u8; SENSE_SIZE],
+}
+
+impl Default for CmdResp {
+    fn default() -> Self {
+        Self {
+            sense_len: 0,
+            residual: 0,
+            status_qualifier: 0,
+            status: 0,
+            // Assume failure until the device says otherwise.
+            response: ScsiResponseCode::FAILURE.0,
+            sense: [0; SENSE_SIZE],
+        }
+    }
+}
+
+#[repr(C)]
+struct ScsiConfig {
+    num_queues: ReadOnly<u32>,
+    seg_max: ReadOnly<u32>,
+    max_sectors: ReadOnly<u32>,
+    cmd_per_lun: ReadOnly<u32>,
+    event_info_size: ReadOnly<u32>,
+    sense_size: Volatile<u32>,
+    cdb_size: Volatile<u32>,
+    max_channel: ReadOnly<u16>,
+    max_target: ReadOnly<u16>,
+    max_lun: ReadOnly<u32>,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct ScsiFeature: u64 {
+        /// A single request can include both device-readable and device-writable data buffers.
+        const INOUT             = 1 << 0;
+        /// The host reports hotplug events for LUNs and targets.
+        const HOTPLUG           = 1 << 1;
+        /// The host reports changes to logical unit parameters.
+        const CHANGE            = 1 << 2;
+        /// The extended fields for T10 protection information are supported.
+        const T10_PI            = 1 << 3;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of a SCSI host device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to `VirtIOScsi::new_with_features`
+/// to forbid the feature. Use `trailing_zeros` to get the bit number for `VirtIOScsi::supports`.
+pub mod features {
+    use super::ScsiFeature;
+    pub use crate::transport::features::*;
+
+    /// A single request can include both device-readable and device-writable data buffers.
+    pub const INOUT: u64 = ScsiFeature::INOUT.bits();
+    /// The host reports hotplug events for LUNs and targets.
+    pub const HOTPLUG: u64 = ScsiFeature::HOTPLUG.bits();
+    /// The host reports changes to logical unit parameters.
+    pub const CHANGE: u64 = ScsiFeature::CHANGE.bits();
+    /// The extended fields for T10 protection information are supported.
+    pub const T10_PI: u64 = ScsiFeature::T10_PI.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::sync::Arc;
+    use core::mem::size_of;
+    use std::{sync::Mutex, thread};
+
+    fn new_config() -> ScsiConfig {
+        ScsiConfig {
+            num_queues: ReadOnly::new(1),
+            seg_max: ReadOnly::new(128),
+            max_sectors: ReadOnly::new(0xffff),
+            cmd_per_lun: ReadOnly::new(128),
+            event_info_size: ReadOnly::new(16),
+            sense_size: Volatile::new(0),
+            cdb_size: Volatile::new(0),
+            max_channel: ReadOnly::new(0),
+            max_target: ReadOnly::new(2),
+            max_lun: ReadOnly::new(0x4000),
+        }
+    }
+
+    fn make_scsi(
+        config_space: &mut ScsiConfig,
+    ) -> (
+        VirtIOScsi<FakeHal, FakeTransport<ScsiConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::ScsiHost,
+            QUEUE_SIZE.into(),
+            3,
+            ScsiFeature::VERSION_1.bits(),
+            config_space,
+        );
+        (VirtIOScsi::new(transport).unwrap(), state)
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = new_config();
+        let (scsi, _state) = make_scsi(&mut config_space);
+        assert_eq!(scsi.num_queues(), 1);
+        assert_eq!(scsi.max_target(), 2);
+        // LUNs above 16383 can't be addressed.
+        assert_eq!(scsi.max_lun(), 0x3fff);
+        drop(scsi);
+        assert_eq!(config_space.cdb_size.0, CDB_SIZE as u32);
+        assert_eq!(config_space.sense_size.0, SENSE_SIZE as u32);
+    }
+
+    #[test]
+    fn inquiry() {
+        let mut config_space = new_config();
+        let (mut scsi, state) = make_scsi(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, REQUEST_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(REQUEST_QUEUE, |request| {
+                    assert_eq!(request.len(), size_of::<CmdReq>());
+                    assert_eq!(request[0..8], [1, 1, 0x40, 5, 0, 0, 0, 0]);
+                    assert_eq!(request[19..25], [0x12, 0, 0, 0, 8, 0]);
+
+                    let mut response = CmdResp {
+                        residual: 0,
+                        response: ScsiResponseCode::OK.0,
+                        ..Default::default()
+                    }
+                    .as_bytes()
+                    .to_vec();
+                    response.extend_from_slice(b"inquired");
+                    response
+                }));
+        });
+
+        let mut buffer = [0; 8];
+        let response = scsi
+            .execute_command(
+                1,
+                5,
+                &[0x12, 0, 0, 0, 8, 0],
+                ScsiData::FromDevice(&mut buffer),
+            )
+            .unwrap();
+        assert_eq!(response.response(), ScsiResponseCode::OK);
+        assert_eq!(response.status(), 0);
+        assert_eq!(response.sense(), &[] as &[u8]);
+        assert_eq!(&buffer, b"inquired");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn check_condition() {
+        let mut config_space = new_config();
+        let (mut scsi, state) = make_scsi(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, REQUEST_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(REQUEST_QUEUE, |request| {
+                    // The data to write follows the request header.
+                    assert_eq!(request[size_of::<CmdReq>()..], [0xaa; 4]);
+                    let mut sense = [0; SENSE_SIZE];
+                    sense[..3].copy_from_slice(&[0x70, 0, 0x05]);
+                    CmdResp {
+                        // The device claims more sense data than there is room for.
+                        sense_len: 1000,
+                        status: 2,
+                        response: ScsiResponseCode::OK.0,
+                        sense,
+                        ..Default::default()
+                    }
+                    .as_bytes()
+                    .to_vec()
+                }));
+        });
+
+        let response = scsi
+            .execute_command(0, 0, &[0x2a; 10], ScsiData::ToDevice(&[0xaa; 4]))
+            .unwrap();
+        assert_eq!(response.status(), 2);
+        assert_eq!(response.sense().len(), SENSE_SIZE);
+        assert_eq!(response.sense()[..3], [0x70, 0, 0x05]);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn invalid_address() {
+        let mut config_space = new_config();
+        let (mut scsi, _state) = make_scsi(&mut config_space);
+        assert_eq!(
+            scsi.execute_command(3, 0, &[0; 6], ScsiData::None)
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+        assert_eq!(
+            scsi.execute_command(0, 0x4000, &[0; 6], ScsiData::None)
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+        assert_eq!(
+            scsi.execute_command(0, 0, &[0; 33], ScsiData::None)
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn drain_events() {
+        let mut config_space = new_config();
+        let (mut scsi, state) = make_scsi(&mut config_space);
+        assert_eq!(scsi.drain_events(), Ok(0));
+
+        for _ in 0..2 {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<EVENT_QUEUE_SIZE>(EVENT_QUEUE, &[0x01; EVENT_SIZE]);
+        }
+        assert_eq!(scsi.drain_events(), Ok(2));
+        assert_eq!(scsi.drain_events(), Ok(0));
+
+        // The buffers were given back to the device, so it can report more events.
+        for _ in 0..EVENT_QUEUE_SIZE {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<EVENT_QUEUE_SIZE>(EVENT_QUEUE, &[0x02; EVENT_SIZE]);
+        }
+        assert_eq!(scsi.drain_events(), Ok(EVENT_QUEUE_SIZE));
+    }
+}
@@ -0,0 +1,989 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for VirtIO sound devices.
+
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::volatile::ReadOnly;
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+#[cfg(feature = "async")]
+use crate::{notifier::register_interrupt, Notifier};
+use crate::{Error, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use core::mem::{offset_of, size_of};
+use log::info;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+const CONTROL_QUEUE: u16 = 0;
+// The event queue (1) isn't used.
+const TX_QUEUE: u16 = 2;
+const QUEUE_SIZE: u16 = 8;
+const SUPPORTED_FEATURES: SoundFeature =
+    SoundFeature::VERSION_1.union(SoundFeature::NOTIFICATION_DATA);
+const REQUIRED_FEATURES: SoundFeature = SoundFeature::VERSION_1;
+
+/// Driver for a VirtIO sound device.
+///
+/// The device has a number of jacks, PCM streams and channel maps, which can be enumerated with
+/// [`jack_info`](Self::jack_info), [`pcm_info`](Self::pcm_info) and
+/// [`chmap_info`](Self::chmap_info). Only playback is supported for now, i.e. output streams.
+///
+/// A stream goes through the usual lifecycle: its parameters are set, it is prepared and started,
+/// PCM frames are queued for playback, and it is then stopped and released.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::sound::{PcmFormat, PcmParams, PcmRate, VirtIOSound};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, frames: &[u8]) -> Result<(), Error> {
+/// let mut sound = VirtIOSound::<HalImpl, _>::new(transport)?;
+///
+/// let params = PcmParams {
+///     buffer_bytes: 8192,
+///     period_bytes: 2048,
+///     channels: 2,
+///     format: PcmFormat::S16,
+///     rate: PcmRate::Rate48000,
+/// };
+/// let stream = sound.open_stream(&params)?;
+/// sound.start(stream)?;
+/// for period in frames.chunks(params.period_bytes as usize) {
+///     sound.queue_pcm(stream, period)?;
+/// }
+/// sound.stop(stream)?;
+/// sound.release(stream)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOSound<H: Hal, T: Transport> {
+    transport: T,
+    control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    tx_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+    /// Notified when the device raises an interrupt.
+    #[cfg(feature = "async")]
+    notifier: Arc<Notifier>,
+}
+
+impl<H: Hal, T: Transport> VirtIOSound<H, T> {
+    /// Create a new VirtIO-Sound driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_features(transport, 0)
+    }
+
+    /// Like [`new`](Self::new), but masks off `forbidden_features` as
+    /// [`Transport::begin_init_with_mask`] does.
+    pub fn new_with_features(mut transport: T, forbidden_features: u64) -> Result<Self> {
+        transport.begin_init_with_mask(
+            SUPPORTED_FEATURES,
+            REQUIRED_FEATURES,
+            forbidden_features,
+        )?;
+
+        let (jacks, streams, chmaps) = transport.read_config_space_atomic(|| {
+            Ok::<_, Error>((
+                transport.read_config::<H, u32>(offset_of!(SoundConfig, jacks))?,
+                transport.read_config::<H, u32>(offset_of!(SoundConfig, streams))?,
+                transport.read_config::<H, u32>(offset_of!(SoundConfig, chmaps))?,
+            ))
+        })?;
+        info!(
+            "found a sound device with {} jacks, {} streams and {} channel maps",
+            jacks, streams, chmaps
+        );
+
+        let control_queue = VirtQueue::new(&mut transport, CONTROL_QUEUE, false, false, false)?;
+        let tx_queue = VirtQueue::new(&mut transport, TX_QUEUE, false, false, false)?;
+        #[cfg(feature = "async")]
+        let notifier = register_interrupt::<H>(transport.device_type())?;
+        transport.finish_init();
+
+        Ok(VirtIOSound {
+            transport,
+            control_queue,
+            tx_queue,
+            jacks,
+            streams,
+            chmaps,
+            #[cfg(feature = "async")]
+            notifier,
+        })
+    }
+
+    /// Returns the number of jacks which the device has.
+    pub fn jacks(&self) -> u32 {
+        self.jacks
+    }
+
+    /// Returns the number of PCM streams which the device has, of either direction.
+    pub fn streams(&self) -> u32 {
+        self.streams
+    }
+
+    /// Returns the number of channel maps which the device has.
+    pub fn chmaps(&self) -> u32 {
+        self.chmaps
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.control_queue.stats() + self.tx_queue.stats()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.ack_interrupt_detailed().acknowledged()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        InterruptDetails::new(
+            self.transport.ack_interrupt_status(),
+            // The event queue isn't set up, so never has anything to pop.
+            [self.control_queue.can_pop(), false, self.tx_queue.can_pop()],
+        )
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        &self.notifier
+    }
+
+    /// Returns information about the jack with the given ID, which must be less than
+    /// [`jacks`](Self::jacks).
+    pub fn jack_info(&mut self, jack_id: u32) -> Result<JackInfo> {
+        let info: SndJackInfo = self.query_info(VIRTIO_SND_R_JACK_INFO, jack_id, self.jacks)?;
+        Ok(JackInfo {
+            hda_fn_nid: info.hda_fn_nid,
+            features: info.features,
+            hda_reg_defconf: info.hda_reg_defconf,
+            hda_reg_caps: info.hda_reg_caps,
+            connected: info.connected != 0,
+        })
+    }
+
+    /// Returns information about the PCM stream with the given ID, which must be less than
+    /// [`streams`](Self::streams).
+    pub fn pcm_info(&mut self, stream_id: u32) -> Result<PcmInfo> {
+        let info: SndPcmInfo = self.query_info(VIRTIO_SND_R_PCM_INFO, stream_id, self.streams)?;
+        Ok(PcmInfo {
+            hda_fn_nid: info.hda_fn_nid,
+            features: info.features,
+            formats: info.formats,
+            rates: info.rates,
+            direction: PcmDirection::from_wire(info.direction)?,
+            channels_min: info.channels_min,
+            channels_max: info.channels_max,
+        })
+    }
+
+    /// Returns information about the channel map with the given ID, which must be less than
+    /// [`chmaps`](Self::chmaps).
+    pub fn chmap_info(&mut self, chmap_id: u32) -> Result<ChmapInfo> {
+        let info: SndChmapInfo = self.query_info(VIRTIO_SND_R_CHMAP_INFO, chmap_id, self.chmaps)?;
+        Ok(ChmapInfo {
+            hda_fn_nid: info.hda_fn_nid,
+            direction: PcmDirection::from_wire(info.direction)?,
+            channels: info.channels,
+            positions: info.positions,
+        })
+    }
+
+    /// Finds the first output stream which supports the given parameters, sets them on it and
+    /// prepares it, and returns its ID. The stream can then be [`start`](Self::start)ed.
+    ///
+    /// Returns [`Error::Unsupported`] if no output stream supports the parameters.
+    pub fn open_stream(&mut self, params: &PcmParams) -> Result<u32> {
+        for stream_id in 0..self.streams {
+            let info = self.pcm_info(stream_id)?;
+            if info.direction == PcmDirection::Output && info.supports(params) {
+                self.set_params(stream_id, params)?;
+                self.prepare(stream_id)?;
+                return Ok(stream_id);
+            }
+        }
+        Err(Error::Unsupported)
+    }
+
+    /// Sets the parameters of the given stream. The stream must not be running.
+    pub fn set_params(&mut self, stream_id: u32, params: &PcmParams) -> Result {
+        self.check_stream(stream_id)?;
+        let request = SndPcmSetParams {
+            hdr: SndPcmHdr::new(VIRTIO_SND_R_PCM_SET_PARAMS, stream_id),
+            buffer_bytes: params.buffer_bytes,
+            period_bytes: params.period_bytes,
+            features: 0,
+            channels: params.channels,
+            format: params.format as u8,
+            rate: params.rate as u8,
+            padding: 0,
+        };
+        self.request(request.as_bytes(), &mut [])
+    }
+
+    /// Prepares the given stream, i.e. allocates the resources it needs on the device, after its
+    /// parameters have been set.
+    pub fn prepare(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_PREPARE, stream_id)
+    }
+
+    /// Starts playback on the given prepared stream.
+    pub fn start(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_START, stream_id)
+    }
+
+    /// Stops playback on the given running stream. It can be started again afterwards.
+    pub fn stop(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_STOP, stream_id)
+    }
+
+    /// Releases the resources of the given stopped stream. Its parameters must be set and it must
+    /// be prepared again before it can be started again.
+    pub fn release(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_RELEASE, stream_id)
+    }
+
+    /// Queues the given PCM frames for playback on the given stream, and blocks until the device
+    /// has consumed them.
+    ///
+    /// The frames must be in the format set on the stream, and should be
+    /// [`period_bytes`](PcmParams::period_bytes) long. Returns the device's latency in bytes, i.e.
+    /// how much earlier data it still has to play.
+    pub fn queue_pcm(&mut self, stream_id: u32, frames: &[u8]) -> Result<u32> {
+        self.check_stream(stream_id)?;
+        if frames.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let xfer = SndPcmXfer { stream_id };
+        // Don't report success if the device doesn't write a status.
+        let mut status = SndPcmStatus {
+            status: VIRTIO_SND_S_IO_ERR,
+            latency_bytes: 0,
+        };
+        self.tx_queue.add_notify_wait_pop(
+            &[xfer.as_bytes(), frames],
+            &mut [status.as_mut_bytes()],
+            &mut self.transport,
+        )?;
+        status_to_result(status.status)?;
+        Ok(status.latency_bytes)
+    }
+
+    fn check_stream(&self, stream_id: u32) -> Result {
+        if stream_id >= self.streams {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Sends a request with only a PCM header to the device for the given stream.
+    fn pcm_request(&mut self, code: u32, stream_id: u32) -> Result {
+        self.check_stream(stream_id)?;
+        self.request(SndPcmHdr::new(code, stream_id).as_bytes(), &mut [])
+    }
+
+    /// Queries information about the item with the given ID, which must be less than `count`.
+    fn query_info<I: FromBytes + IntoBytes + KnownLayout>(
+        &mut self,
+        code: u32,
+        id: u32,
+        count: u32,
+    ) -> Result<I> {
+        if id >= count {
+            return Err(Error::InvalidParam);
+        }
+        let request = SndQueryInfo {
+            hdr: SndHdr { code },
+            start_id: id,
+            count: 1,
+            size: size_of::<I>() as u32,
+        };
+        let mut info = I::new_zeroed();
+        self.request(request.as_bytes(), info.as_mut_bytes())?;
+        Ok(info)
+    }
+
+    /// Sends the given request on the control queue and waits for it to complete. Any payload
+    /// which the device returns after the status is written to `response`.
+    fn request(&mut self, request: &[u8], response: &mut [u8]) -> Result {
+        // Don't report success if the device doesn't write a status.
+        let mut hdr = SndHdr {
+            code: VIRTIO_SND_S_IO_ERR,
+        };
+        if response.is_empty() {
+            self.control_queue.add_notify_wait_pop(
+                &[request],
+                &mut [hdr.as_mut_bytes()],
+                &mut self.transport,
+            )?;
+        } else {
+            self.control_queue.add_notify_wait_pop(
+                &[request],
+                &mut [hdr.as_mut_bytes(), response],
+                &mut self.transport,
+            )?;
+        }
+        status_to_result(hdr.code)
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOSound<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.transport.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        VirtIOSound::ack_interrupt(self)
+    }
+
+    fn reset(&mut self) {
+        self.transport.set_status(DeviceStatus::empty());
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.transport.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot::new(
+            &self.transport,
+            [self.control_queue.snapshot(), self.tx_queue.snapshot()],
+        )
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.transport.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.transport.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.control_queue.dma_footprint() + self.tx_queue.dma_footprint()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOSound<H, T> {
+    fn drop(&mut self) {
+        // Reset the device so that it stops using any buffers still in flight, which the queues
+        // will release when they are dropped. Then clear any pointers pointing to DMA regions, so
+        // the device doesn't try to access them after they have been freed.
+        self.transport.set_status(DeviceStatus::empty());
+        self.transport.queue_unset(CONTROL_QUEUE);
+        self.transport.queue_unset(TX_QUEUE);
+    }
+}
+
+fn status_to_result(status: u32) -> Result {
+    match status {
+        VIRTIO_SND_S_OK => Ok(()),
+        VIRTIO_SND_S_BAD_MSG => Err(Error::InvalidParam),
+        VIRTIO_SND_S_NOT_SUPP => Err(Error::Unsupported),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// Information about a jack, as returned by [`VirtIOSound::jack_info`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct JackInfo {
+    /// The HDA function group node ID which the jack belongs to.
+    pub hda_fn_nid: u32,
+    /// The jack's features.
+    pub features: u32,
+    /// The HDA pin configuration default register value.
+    pub hda_reg_defconf: u32,
+    /// The HDA pin capabilities register value.
+    pub hda_reg_caps: u32,
+    /// Whether something is plugged into the jack.
+    pub connected: bool,
+}
+
+/// Information about a PCM stream, as returned by [`VirtIOSound::pcm_info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PcmInfo {
+    /// The HDA function group node ID which the stream belongs to.
+    pub hda_fn_nid: u32,
+    /// The stream's features.
+    pub features: u32,
+    /// A bitmap of the supported formats, with bit `n` set for the [`PcmFormat`] numbered `n`.
+    pub formats: u64,
+    /// A bitmap of the supported rates, with bit `n` set for the [`PcmRate`] numbered `n`.
+    pub rates: u64,
+    /// Whether the stream is for playback or capture.
+    pub direction: PcmDirection,
+    /// The minimum number of channels.
+    pub channels_min: u8,
+    /// The maximum number of channels.
+    pub channels_max: u8,
+}
+
+impl PcmInfo {
+    /// Returns whether the stream supports the given format.
+    pub fn supports_format(&self, format: PcmFormat) -> bool {
+        self.formats & (1 << format as u8) != 0
+    }
+
+    /// Returns whether the stream supports the given rate.
+    pub fn supports_rate(&self, rate: PcmRate) -> bool {
+        self.rates & (1 << rate as u8) != 0
+    }
+
+    /// Returns whether the stream supports the format, rate and number of channels of the given
+    /// parameters.
+    pub fn supports(&self, params: &PcmParams) -> bool {
+        self.supports_format(params.format)
+            && self.supports_rate(params.rate)
+            && (self.channels_min..=self.channels_max).contains(&params.channels)
+    }
+}
+
+/// Information about a channel map, as returned by [`VirtIOSound::chmap_info`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChmapInfo {
+    /// The HDA function group node ID which the channel map belongs to.
+    pub hda_fn_nid: u32,
+    /// Whether the channel map is for playback or capture streams.
+    pub direction: PcmDirection,
+    /// The number of valid entries in `positions`.
+    pub channels: u8,
+    /// The position of each channel, e.g. front left.
+    pub positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
+}
+
+/// The parameters of a PCM stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PcmParams {
+    /// The size of the device's buffer for the stream, in bytes.
+    pub buffer_bytes: u32,
+    /// The size of each period, i.e. the chunk of the buffer after which the device reports
+    /// progress, in bytes. Frames should be queued a period at a time.
+    pub period_bytes: u32,
+    /// The number of channels.
+    pub channels: u8,
+    /// The format of each sample.
+    pub format: PcmFormat,
+    /// The number of frames per second.
+    pub rate: PcmRate,
+}
+
+/// Whether a PCM stream is for playback or capture.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PcmDirection {
+    /// Playback, from the driver to the device.
+    Output = 0,
+    /// Capture, from the device to the driver.
+    Input = 1,
+}
+
+impl PcmDirection {
+    fn from_wire(direction: u8) -> Result<Self> {
+        match direction {
+            0 => Ok(Self::Output),
+            1 => Ok(Self::Input),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+/// PCM sample formats, numbered as in the stream's `formats` bitmap.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum PcmFormat {
+    ImaAdpcm = 0,
+    MuLaw = 1,
+    ALaw = 2,
+    S8 = 3,
+    U8 = 4,
+    S16 = 5,
+    U16 = 6,
+    S18_3 = 7,
+    U18_3 = 8,
+    S20_3 = 9,
+    U20_3 = 10,
+    S24_3 = 11,
+    U24_3 = 12,
+    S20 = 13,
+    U20 = 14,
+    S24 = 15,
+    U24 = 16,
+    S32 = 17,
+    U32 = 18,
+    Float = 19,
+    Float64 = 20,
+    DsdU8 = 21,
+    DsdU16 = 22,
+    DsdU32 = 23,
+    Iec958Subframe = 24,
+}
+
+/// PCM frame rates, numbered as in the stream's `rates` bitmap.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[allow(missing_docs)]
+pub enum PcmRate {
+    Rate5512 = 0,
+    Rate8000 = 1,
+    Rate11025 = 2,
+    Rate16000 = 3,
+    Rate22050 = 4,
+    Rate32000 = 5,
+    Rate44100 = 6,
+    Rate48000 = 7,
+    Rate64000 = 8,
+    Rate88200 = 9,
+    Rate96000 = 10,
+    Rate176400 = 11,
+    Rate192000 = 12,
+    Rate384000 = 13,
+}
+
+#[repr(C)]
+struct SoundConfig {
+    /// The number of jacks.
+    jacks: ReadOnly<u32>,
+    /// The number of PCM streams.
+    streams: ReadOnly<u32>,
+    /// The number of channel maps.
+    chmaps: ReadOnly<u32>,
+}
+
+// Request codes.
+const VIRTIO_SND_R_JACK_INFO: u32 = 1;
+const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+
+// Status codes.
+const VIRTIO_SND_S_OK: u32 = 0x8000;
+const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
+const VIRTIO_SND_S_IO_ERR: u32 = 0x8003;
+
+/// The maximum number of channels in a channel map.
+const VIRTIO_SND_CHMAP_MAX_SIZE: usize = 18;
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SndHdr {
+    /// A request code, or a status code in a response.
+    code: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct SndQueryInfo {
+    hdr: SndHdr,
+    start_id: u32,
+    count: u32,
+    /// The size of each item of information which the device should return.
+    size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SndJackInfo {
+    hda_fn_nid: u32,
+    features: u32,
+    hda_reg_defconf: u32,
+    hda_reg_caps: u32,
+    connected: u8,
+    padding: [u8; 7],
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SndPcmInfo {
+    hda_fn_nid: u32,
+    features: u32,
+    formats: u64,
+    rates: u64,
+    direction: u8,
+    channels_min: u8,
+    channels_max: u8,
+    padding: [u8; 5],
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SndChmapInfo {
+    hda_fn_nid: u32,
+    direction: u8,
+    channels: u8,
+    positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct SndPcmHdr {
+    hdr: SndHdr,
+    stream_id: u32,
+}
+
+impl SndPcmHdr {
+    fn new(code: u32, stream_id: u32) -> Self {
+        Self {
+            hdr: SndHdr { code },
+            stream_id,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct SndPcmSetParams {
+    hdr: SndPcmHdr,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+/// The header of a buffer of PCM frames on the tx queue.
+#[repr(C)]
+#[derive(Debug, Immutable, IntoBytes, KnownLayout)]
+struct SndPcmXfer {
+    stream_id: u32,
+}
+
+/// The status which the device writes after consuming a buffer of PCM frames.
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SndPcmStatus {
+    status: u32,
+    latency_bytes: u32,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct SoundFeature: u64 {
+        /// Control elements are available.
+        const CTLS                  = 1 << 0;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+/// The feature bits of a sound device, along with the device-independent ones.
+///
+/// Each constant is a mask with the feature's bit set, as passed to
+/// `VirtIOSound::new_with_features` to forbid the feature. Use `trailing_zeros` to get the bit
+/// number for `VirtIOSound::supports`.
+pub mod features {
+    use super::SoundFeature;
+    pub use crate::transport::features::*;
+
+    /// Control elements are available.
+    pub const CTLS: u64 = SoundFeature::CTLS.bits();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, State},
+            DeviceType,
+        },
+    };
+    use alloc::sync::Arc;
+    use std::{sync::Mutex, thread};
+
+    fn make_sound(
+        config_space: &mut SoundConfig,
+    ) -> (
+        VirtIOSound<FakeHal, FakeTransport<SoundConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Sound,
+            QUEUE_SIZE.into(),
+            4,
+            SoundFeature::VERSION_1.bits(),
+            config_space,
+        );
+        (VirtIOSound::new(transport).unwrap(), state)
+    }
+
+    fn new_config() -> SoundConfig {
+        SoundConfig {
+            jacks: ReadOnly::new(1),
+            streams: ReadOnly::new(2),
+            chmaps: ReadOnly::new(1),
+        }
+    }
+
+    fn new_params() -> PcmParams {
+        PcmParams {
+            buffer_bytes: 8192,
+            period_bytes: 2048,
+            channels: 2,
+            format: PcmFormat::S16,
+            rate: PcmRate::Rate48000,
+        }
+    }
+
+    /// Responds to one control request with the given status followed by the given payload, after
+    /// checking that the request is as expected.
+    fn respond_to_control(state: &Mutex<State>, expected: &[u8], status: u32, payload: &[u8]) {
+        State::wait_until_queue_notified(state, CONTROL_QUEUE);
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(CONTROL_QUEUE, |request| {
+                assert_eq!(request, expected);
+                let mut response = SndHdr { code: status }.as_bytes().to_vec();
+                response.extend_from_slice(payload);
+                response
+            }));
+    }
+
+    fn pcm_info(direction: PcmDirection) -> SndPcmInfo {
+        SndPcmInfo {
+            hda_fn_nid: 0,
+            features: 0,
+            formats: 1 << PcmFormat::S16 as u8,
+            rates: 1 << PcmRate::Rate44100 as u8 | 1 << PcmRate::Rate48000 as u8,
+            direction: direction as u8,
+            channels_min: 1,
+            channels_max: 2,
+            padding: [0; 5],
+        }
+    }
+
+    fn pcm_info_request(stream_id: u32) -> SndQueryInfo {
+        SndQueryInfo {
+            hdr: SndHdr {
+                code: VIRTIO_SND_R_PCM_INFO,
+            },
+            start_id: stream_id,
+            count: 1,
+            size: size_of::<SndPcmInfo>() as u32,
+        }
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = new_config();
+        let (sound, _) = make_sound(&mut config_space);
+        assert_eq!(sound.jacks(), 1);
+        assert_eq!(sound.streams(), 2);
+        assert_eq!(sound.chmaps(), 1);
+    }
+
+    #[test]
+    fn enumerate() {
+        let mut config_space = new_config();
+        let (mut sound, state) = make_sound(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            let jack = SndJackInfo {
+                hda_fn_nid: 1,
+                features: 0,
+                hda_reg_defconf: 0x1234,
+                hda_reg_caps: 0x5678,
+                connected: 1,
+                padding: [0; 7],
+            };
+            let request = SndQueryInfo {
+                hdr: SndHdr {
+                    code: VIRTIO_SND_R_JACK_INFO,
+                },
+                start_id: 0,
+                count: 1,
+                size: size_of::<SndJackInfo>() as u32,
+            };
+            respond_to_control(&state, request.as_bytes(), VIRTIO_SND_S_OK, jack.as_bytes());
+
+            let info = pcm_info(PcmDirection::Input);
+            respond_to_control(
+                &state,
+                pcm_info_request(1).as_bytes(),
+                VIRTIO_SND_S_OK,
+                info.as_bytes(),
+            );
+
+            let mut positions = [0; VIRTIO_SND_CHMAP_MAX_SIZE];
+            positions[..2].copy_from_slice(&[3, 4]);
+            let chmap = SndChmapInfo {
+                hda_fn_nid: 0,
+                direction: PcmDirection::Output as u8,
+                channels: 2,
+                positions,
+            };
+            let request = SndQueryInfo {
+                hdr: SndHdr {
+                    code: VIRTIO_SND_R_CHMAP_INFO,
+                },
+                start_id: 0,
+                count: 1,
+                size: size_of::<SndChmapInfo>() as u32,
+            };
+            respond_to_control(
+                &state,
+                request.as_bytes(),
+                VIRTIO_SND_S_OK,
+                chmap.as_bytes(),
+            );
+        });
+
+        assert_eq!(
+            sound.jack_info(0),
+            Ok(JackInfo {
+                hda_fn_nid: 1,
+                features: 0,
+                hda_reg_defconf: 0x1234,
+                hda_reg_caps: 0x5678,
+                connected: true,
+            })
+        );
+        let info = sound.pcm_info(1).unwrap();
+        assert_eq!(info.direction, PcmDirection::Input);
+        assert!(info.supports(&new_params()));
+        assert!(!info.supports_rate(PcmRate::Rate8000));
+        let chmap = sound.chmap_info(0).unwrap();
+        assert_eq!(chmap.direction, PcmDirection::Output);
+        assert_eq!(&chmap.positions[..chmap.channels as usize], &[3, 4]);
+        handle.join().unwrap();
+
+        // Out of range IDs are rejected without asking the device.
+        assert_eq!(sound.jack_info(1), Err(Error::InvalidParam));
+        assert_eq!(sound.pcm_info(2).unwrap_err(), Error::InvalidParam);
+        assert_eq!(sound.chmap_info(1).unwrap_err(), Error::InvalidParam);
+    }
+
+    #[test]
+    fn playback() {
+        let mut config_space = new_config();
+        let (mut sound, state) = make_sound(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            // Stream 0 is for capture, so stream 1 is chosen.
+            for (stream_id, direction) in [(0, PcmDirection::Input), (1, PcmDirection::Output)] {
+                respond_to_control(
+                    &state,
+                    pcm_info_request(stream_id).as_bytes(),
+                    VIRTIO_SND_S_OK,
+                    pcm_info(direction).as_bytes(),
+                );
+            }
+            let set_params = SndPcmSetParams {
+                hdr: SndPcmHdr::new(VIRTIO_SND_R_PCM_SET_PARAMS, 1),
+                buffer_bytes: 8192,
+                period_bytes: 2048,
+                features: 0,
+                channels: 2,
+                format: PcmFormat::S16 as u8,
+                rate: PcmRate::Rate48000 as u8,
+                padding: 0,
+            };
+            respond_to_control(&state, set_params.as_bytes(), VIRTIO_SND_S_OK, &[]);
+            for code in [VIRTIO_SND_R_PCM_PREPARE, VIRTIO_SND_R_PCM_START] {
+                let request = SndPcmHdr::new(code, 1);
+                respond_to_control(&state, request.as_bytes(), VIRTIO_SND_S_OK, &[]);
+            }
+
+            State::wait_until_queue_notified(&state, TX_QUEUE);
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(TX_QUEUE, |request| {
+                    let mut expected = SndPcmXfer { stream_id: 1 }.as_bytes().to_vec();
+                    expected.extend_from_slice(&[0x42; 16]);
+                    assert_eq!(request, expected);
+                    SndPcmStatus {
+                        status: VIRTIO_SND_S_OK,
+                        latency_bytes: 1024,
+                    }
+                    .as_bytes()
+                    .to_vec()
+                }));
+
+            for code in [VIRTIO_SND_R_PCM_STOP, VIRTIO_SND_R_PCM_RELEASE] {
+                let request = SndPcmHdr::new(code, 1);
+                respond_to_control(&state, request.as_bytes(), VIRTIO_SND_S_OK, &[]);
+            }
+        });
+
+        let stream = sound.open_stream(&new_params()).unwrap();
+        assert_eq!(stream, 1);
+        assert_eq!(sound.start(stream), Ok(()));
+        assert_eq!(sound.queue_pcm(stream, &[0x42; 16]), Ok(1024));
+        assert_eq!(sound.stop(stream), Ok(()));
+        assert_eq!(sound.release(stream), Ok(()));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn open_stream_unsupported() {
+        let mut config_space = new_config();
+        let (mut sound, state) = make_sound(&mut config_space);
+
+        let handle = thread::spawn(move || {
+            for stream_id in 0..2 {
+                respond_to_control(
+                    &state,
+                    pcm_info_request(stream_id).as_bytes(),
+                    VIRTIO_SND_S_OK,
+                    pcm_info(PcmDirection::Output).as_bytes(),
+                );
+            }
+            // The device rejects a request it doesn't understand.
+            let request = SndPcmHdr::new(VIRTIO_SND_R_PCM_START, 0);
+            respond_to_control(&state, request.as_bytes(), VIRTIO_SND_S_BAD_MSG, &[]);
+        });
+
+        // Neither stream supports 8 channels.
+        let params = PcmParams {
+            channels: 8,
+            ..new_params()
+        };
+        assert_eq!(sound.open_stream(&params), Err(Error::Unsupported));
+        assert_eq!(sound.start(0), Err(Error::InvalidParam));
+        handle.join().unwrap();
+
+        // Unknown streams and empty buffers are rejected without asking the device.
+        assert_eq!(sound.start(2), Err(Error::InvalidParam));
+        assert_eq!(sound.queue_pcm(0, &[]), Err(Error::InvalidParam));
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: MIT
+
+//! Driver for watchdog devices which the guest must keep petting through a VirtIO queue.
+//!
+//! There is no standard VirtIO watchdog device type, so this is built on [`RawDevice`] and works
+//! with any device which follows this simple protocol:
+//!
+//! - The device configuration space starts with a little-endian `u32`, the number of seconds the
+//!   device waits for a keepalive before it fires. It may be 0, or the config space may be too
+//!   small to hold it, if the device doesn't say.
+//! - Queue 0 carries keepalives. Each is a single device-readable buffer holding
+//!   [`KEEPALIVE`], which the device uses without writing anything back.
+
+use crate::device::raw::RawDevice;
+use crate::device::{DeviceSnapshot, InterruptDetails, VirtioDevice};
+use crate::hal::Hal;
+use crate::transport::{DeviceType, Transport};
+#[cfg(feature = "async")]
+use crate::Notifier;
+#[cfg(feature = "stats")]
+use crate::QueueStats;
+use crate::{Error, Result};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+use core::time::Duration;
+
+const QUEUE: u16 = 0;
+const QUEUE_SIZE: usize = 4;
+
+/// The contents of every keepalive request.
+pub static KEEPALIVE: [u8; 4] = *b"PET\0";
+
+/// Driver for a watchdog device, see the [module documentation](self) for the protocol.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::watchdog::VirtIOWatchdog;
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut watchdog = VirtIOWatchdog::<HalImpl, _>::new(transport)?;
+/// if watchdog.timeout().is_some() {
+///     // Pet the watchdog comfortably within its timeout, e.g. from a timer firing twice as often.
+///     watchdog.pet()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOWatchdog<H: Hal, T: Transport> {
+    device: RawDevice<H, T, QUEUE_SIZE>,
+    timeout: Option<Duration>,
+}
+
+impl<H: Hal, T: Transport> VirtIOWatchdog<H, T> {
+    /// Creates a new watchdog driver, and reads the device's timeout.
+    ///
+    /// The device must already be of a type which follows this protocol, as there is no device
+    /// type to check.
+    pub fn new(transport: T) -> Result<Self> {
+        let device = RawDevice::new(transport, 0, 1)?;
+        let timeout = match device.read_config_value::<u32>(0) {
+            Ok(0) | Err(Error::ConfigSpaceTooSmall) => None,
+            Ok(seconds) => Some(Duration::from_secs(seconds.into())),
+            Err(e) => return Err(e),
+        };
+        Ok(VirtIOWatchdog { device, timeout })
+    }
+
+    /// Returns the counters of operations on all of the device's virtqueues.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        self.device.stats()
+    }
+
+    /// Returns how long the device waits for a keepalive before it fires, if it says.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Sends a keepalive to the device.
+    ///
+    /// This never waits for the device. Keepalives which it has already consumed are popped
+    /// first, so a driver which pets regularly never runs out of descriptors.
+    ///
+    /// Returns [`Error::QueueFull`] if the device hasn't consumed any of the keepalives still in
+    /// the queue. It is notified about them again in that case, but the caller should treat this
+    /// as the device not keeping up. If the device needs a reset [`Error::DeviceNeedsReset`] is
+    /// returned instead, without sending anything, or [`Error::DeviceRemoved`] if it has been
+    /// removed.
+    pub fn pet(&mut self) -> Result {
+        if !self.device.is_present() {
+            return Err(Error::DeviceRemoved);
+        }
+        if self.device.needs_reset() {
+            return Err(Error::DeviceNeedsReset);
+        }
+        self.reclaim()?;
+        // SAFETY: `KEEPALIVE` is a static, so it outlives the request.
+        let result = unsafe { self.device.queue(QUEUE).add(&[&KEEPALIVE], &mut []) };
+        match result {
+            Ok(_) | Err(Error::QueueFull) => self.device.notify(QUEUE),
+            Err(_) => {}
+        }
+        result.map(|_| ())
+    }
+
+    /// Returns how many keepalives the device hasn't consumed yet, after popping those it has.
+    pub fn pending(&mut self) -> Result<usize> {
+        self.reclaim()?;
+        Ok(QUEUE_SIZE - self.device.queue(QUEUE).available_desc())
+    }
+
+    /// Pops all the keepalives which the device has consumed.
+    fn reclaim(&mut self) -> Result {
+        let queue = self.device.queue(QUEUE);
+        while let Some(token) = queue.peek_used() {
+            // SAFETY: Every request in the queue is `KEEPALIVE` alone.
+            unsafe { queue.pop_used(token, &[&KEEPALIVE], &mut []) }?;
+        }
+        Ok(())
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.device.ack_interrupt()
+    }
+
+    /// Acknowledges a pending interrupt, if any, and returns what it was raised for and which
+    /// queues have used buffers waiting to be popped.
+    pub fn ack_interrupt_detailed(&mut self) -> InterruptDetails {
+        self.device.ack_interrupt_detailed()
+    }
+
+    /// Returns the notifier which is notified when the device raises an interrupt, for an async
+    /// executor to await before calling [`ack_interrupt`](Self::ack_interrupt).
+    #[cfg(feature = "async")]
+    pub fn notifier(&self) -> &Arc<Notifier> {
+        self.device.notifier()
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOWatchdog<H, T> {
+    fn device_type(&self) -> DeviceType {
+        self.device.device_type()
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.device.ack_interrupt()
+    }
+
+    fn reset(&mut self) {
+        VirtioDevice::reset(&mut self.device);
+    }
+
+    fn needs_reset(&self) -> bool {
+        self.device.needs_reset()
+    }
+
+    fn debug_snapshot(&self) -> DeviceSnapshot {
+        self.device.debug_snapshot()
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.device.negotiated_features()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device.offered_features()
+    }
+
+    fn dma_footprint(&self) -> usize {
+        self.device.dma_footprint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal, transport::fake::FakeTransport, transport::features::VERSION_1,
+    };
+    use alloc::vec;
+
+    #[test]
+    fn timeout() {
+        let mut config_space = 30u32;
+        let (transport, _) = FakeTransport::new(
+            DeviceType::Invalid,
+            QUEUE_SIZE as u32,
+            1,
+            VERSION_1,
+            &mut config_space,
+        );
+        let watchdog = VirtIOWatchdog::<FakeHal, _>::new(transport).unwrap();
+        assert_eq!(watchdog.timeout(), Some(Duration::from_secs(30)));
+
+        let mut config_space = ();
+        let (transport, _) = FakeTransport::new(
+            DeviceType::Invalid,
+            QUEUE_SIZE as u32,
+            1,
+            VERSION_1,
+            &mut config_space,
+        );
+        let watchdog = VirtIOWatchdog::<FakeHal, _>::new(transport).unwrap();
+        assert_eq!(watchdog.timeout(), None);
+    }
+
+    #[test]
+    fn pet() {
+        let mut config_space = 10u32;
+        let (transport, state) = FakeTransport::new(
+            DeviceType::Invalid,
+            QUEUE_SIZE as u32,
+            1,
+            VERSION_1,
+            &mut config_space,
+        );
+        let mut watchdog = VirtIOWatchdog::<FakeHal, _>::new(transport).unwrap();
+
+        // The device doesn't consume anything until the queue is full.
+        for count in 1..=QUEUE_SIZE {
+            assert_eq!(watchdog.pet(), Ok(()));
+            assert_eq!(state.lock().unwrap().queues[0].notify_count, count);
+        }
+        assert_eq!(watchdog.pending(), Ok(QUEUE_SIZE));
+        // The device is still told about the keepalives it hasn't consumed.
+        assert_eq!(watchdog.pet(), Err(Error::QueueFull));
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, QUEUE_SIZE + 1);
+
+        // Once the device consumes one, petting works again.
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<QUEUE_SIZE>(0, |request| {
+                assert_eq!(request, KEEPALIVE);
+                vec![]
+            }));
+        assert_eq!(watchdog.pending(), Ok(QUEUE_SIZE - 1));
+        assert_eq!(watchdog.pet(), Ok(()));
+        assert_eq!(watchdog.pending(), Ok(QUEUE_SIZE));
+    }
+}
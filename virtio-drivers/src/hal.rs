@@ -1,16 +1,54 @@
 // SPDX-License-Identifier: MIT
 
-#[cfg(test)]
+#[cfg(any(test, feature = "test-utils"))]
 pub mod fake;
 
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
+#[cfg(feature = "alloc")]
+use crate::pages;
+#[cfg(feature = "async")]
+use crate::{transport::DeviceType, Notifier};
 use crate::{Error, Result, PAGE_SIZE};
-use core::{marker::PhantomData, ptr::NonNull};
+#[cfg(feature = "async")]
+use alloc::sync::Arc;
+#[cfg(feature = "alloc")]
+use core::{cmp::min, sync::atomic::AtomicU64};
+use core::{
+    fmt::{self, Display, Formatter},
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{fence, Ordering},
+};
+use log::warn;
 
 /// A physical address as used for virtio.
 pub type PhysAddr = usize;
 
+/// What a DMA region is allocated for, to be logged if the allocation fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum DmaPurpose {
+    /// The descriptor table or rings of a virtqueue.
+    Ring,
+    /// An indirect descriptor table.
+    IndirectTable,
+    /// A buffer for requests or data.
+    Buffer,
+    /// The transport's own structures shared with the device.
+    Transport,
+}
+
+impl Display for DmaPurpose {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ring => "virtqueue rings",
+            Self::IndirectTable => "indirect descriptor table",
+            Self::Buffer => "buffer",
+            Self::Transport => "transport",
+        })
+    }
+}
+
 /// A region of contiguous physical memory used for DMA.
 #[derive(Debug)]
 pub struct Dma<H: Hal> {
@@ -31,12 +69,18 @@ impl<H: Hal> Dma<H> {
     /// Allocates the given number of pages of physically contiguous memory to be used for DMA in
     /// the given direction.
     ///
-    /// The pages will be zeroed.
-    pub fn new(pages: usize, direction: BufferDirection) -> Result<Self> {
+    /// The pages will be zeroed. If the allocation fails, the number of pages and what they were
+    /// for are logged before returning [`Error::DmaError`], to help with sizing the DMA heap.
+    pub fn new(pages: usize, direction: BufferDirection, purpose: DmaPurpose) -> Result<Self> {
         let (paddr, vaddr) = H::dma_alloc(pages, direction);
         if paddr == 0 {
+            warn!(
+                "Failed to allocate {} pages of DMA memory for {}",
+                pages, purpose
+            );
             return Err(Error::DmaError);
         }
+        validate_dma_region(paddr, vaddr, pages * PAGE_SIZE);
         Ok(Self {
             paddr,
             vaddr,
@@ -56,6 +100,11 @@ impl<H: Hal> Dma<H> {
         NonNull::new((self.vaddr.as_ptr() as usize + offset) as _).unwrap()
     }
 
+    /// Returns the size of the DMA region in bytes, which is always a whole number of pages.
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
     /// Returns a pointer to the entire DMA region as a slice.
     pub fn raw_slice(&self) -> NonNull<[u8]> {
         let raw_slice =
@@ -73,6 +122,271 @@ impl<H: Hal> Drop for Dma<H> {
     }
 }
 
+/// A buffer of DMA memory which stays shared with devices for as long as it exists, so that it can
+/// be reused for many requests without being shared and unshared each time.
+///
+/// Drivers which accept one, such as [`VirtIOBlk::read_blocks_into`], put its physical address
+/// straight into the virtqueue rather than going through [`Hal::share`], which would otherwise
+/// bounce the data through a newly shared buffer on platforms such as confidential VMs.
+///
+/// [`VirtIOBlk::read_blocks_into`]: crate::device::blk::VirtIOBlk::read_blocks_into
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct DmaBuffer<H: Hal> {
+    dma: Dma<H>,
+    len: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal> DmaBuffer<H> {
+    /// Allocates a zeroed buffer of the given length, to be used for DMA in the given direction.
+    ///
+    /// Returns [`Error::InvalidParam`] if the length is 0, or [`Error::DmaError`] if the allocation
+    /// fails.
+    pub fn new(len: usize, direction: BufferDirection) -> Result<Self> {
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            dma: Dma::new(pages(len), direction, DmaPurpose::Buffer)?,
+            len,
+        })
+    }
+
+    /// Returns the physical address of the buffer, as seen by devices.
+    pub fn paddr(&self) -> PhysAddr {
+        self.dma.paddr()
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the buffer is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the contents of the buffer.
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: The DMA region is valid, initialised and at least `len` bytes long. Devices only
+        // access it while a request borrowing the buffer is in flight, so not while this reference
+        // exists.
+        unsafe { core::slice::from_raw_parts(self.dma.vaddr(0).as_ptr(), self.len) }
+    }
+
+    /// Returns the contents of the buffer mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: As for `as_slice`, and we have a unique reference to the buffer.
+        unsafe { core::slice::from_raw_parts_mut(self.dma.vaddr(0).as_ptr(), self.len) }
+    }
+
+    /// Returns where the buffer is in memory, to pass to the virtqueue as already shared.
+    pub(crate) fn region(&self) -> SharedRegion {
+        SharedRegion {
+            vaddr: self.dma.vaddr(0),
+            paddr: self.dma.paddr(),
+            len: self.len,
+        }
+    }
+}
+
+/// A region of memory which is already shared with the device, so buffers within it can be given
+/// to a virtqueue without being shared again.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SharedRegion {
+    vaddr: NonNull<u8>,
+    paddr: PhysAddr,
+    len: usize,
+}
+
+impl SharedRegion {
+    /// Returns the physical address of the given buffer if it lies entirely within the region.
+    pub(crate) fn paddr_of(&self, buffer: NonNull<[u8]>) -> Option<PhysAddr> {
+        let offset =
+            (buffer.as_ptr() as *mut u8 as usize).checked_sub(self.vaddr.as_ptr() as usize)?;
+        if offset.checked_add(buffer.len())? <= self.len {
+            Some(self.paddr + offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// A pool of DMA memory, allocated once up front and split into fixed size slots which can be
+/// handed out for transient buffers such as request headers.
+///
+/// On platforms where DMA memory has to be explicitly shared with the host (e.g. confidential
+/// VMs), this avoids sharing and unsharing a new buffer for every request.
+/// [`VirtIOBlk`](crate::device::blk::VirtIOBlk) keeps one for the headers and responses of its
+/// blocking requests.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct DmaPool<H: Hal> {
+    dma: Dma<H>,
+    slot_size: usize,
+    num_slots: usize,
+    /// A bitmap of which slots are free, bit `n` being set if slot `n` is free.
+    free: AtomicU64,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal> DmaPool<H> {
+    /// Allocates a pool of the given number of pages, split into slots of `slot_size` bytes.
+    ///
+    /// At most 64 slots are used, regardless of how many would fit.
+    pub fn new(pages: usize, slot_size: usize, direction: BufferDirection) -> Result<Self> {
+        if slot_size == 0 || slot_size > pages * PAGE_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let num_slots = min((pages * PAGE_SIZE) / slot_size, u64::BITS as usize);
+        let free = if num_slots == u64::BITS as usize {
+            u64::MAX
+        } else {
+            (1 << num_slots) - 1
+        };
+        Ok(Self {
+            dma: Dma::new(pages, direction, DmaPurpose::Buffer)?,
+            slot_size,
+            num_slots,
+            free: AtomicU64::new(free),
+        })
+    }
+
+    /// Returns the size in bytes of each slot, which is the largest buffer the pool can provide.
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Returns the size in bytes of the DMA memory the pool holds, which is always a whole number
+    /// of pages.
+    pub fn size(&self) -> usize {
+        self.dma.size()
+    }
+
+    /// Allocates a zeroed buffer of the given length from the pool.
+    ///
+    /// Returns [`Error::InvalidParam`] if the length is larger than the slot size, or
+    /// [`Error::DmaError`] if all slots are in use.
+    pub fn alloc(&self, len: usize) -> Result<PoolBuffer<'_, H>> {
+        if len == 0 || len > self.slot_size {
+            return Err(Error::InvalidParam);
+        }
+        let mut free = self.free.load(Ordering::Acquire);
+        loop {
+            if free == 0 {
+                return Err(Error::DmaError);
+            }
+            let slot = free.trailing_zeros() as usize;
+            match self.free.compare_exchange_weak(
+                free,
+                free & !(1 << slot),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let buffer = PoolBuffer {
+                        pool: self,
+                        slot,
+                        len,
+                    };
+                    // SAFETY: The slot was free, so nothing else is accessing it.
+                    unsafe {
+                        buffer.vaddr().as_ptr().write_bytes(0, len);
+                    }
+                    return Ok(buffer);
+                }
+                Err(current) => free = current,
+            }
+        }
+    }
+}
+
+/// A buffer allocated from a [`DmaPool`], which is returned to the pool when dropped.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct PoolBuffer<'a, H: Hal> {
+    pool: &'a DmaPool<H>,
+    slot: usize,
+    len: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal> PoolBuffer<'_, H> {
+    /// Returns the physical address of the buffer, as seen by devices.
+    pub fn paddr(&self) -> PhysAddr {
+        self.pool.dma.paddr() + self.slot * self.pool.slot_size
+    }
+
+    /// Returns a pointer to the start of the buffer.
+    pub fn vaddr(&self) -> NonNull<u8> {
+        self.pool.dma.vaddr(self.slot * self.pool.slot_size)
+    }
+
+    /// Returns a pointer to the buffer as a slice.
+    pub fn raw_slice(&self) -> NonNull<[u8]> {
+        NonNull::slice_from_raw_parts(self.vaddr(), self.len)
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the buffer is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns where the buffer is in memory, to pass to the virtqueue as already shared.
+    pub(crate) fn region(&self) -> SharedRegion {
+        SharedRegion {
+            vaddr: self.vaddr(),
+            paddr: self.paddr(),
+            len: self.len,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal> Drop for PoolBuffer<'_, H> {
+    fn drop(&mut self) {
+        debug_assert!(self.slot < self.pool.num_slots);
+        self.pool.free.fetch_or(1 << self.slot, Ordering::Release);
+    }
+}
+
+/// Checks that a region returned by [`Hal::dma_alloc`] for `size` bytes keeps to the alignment and
+/// contiguity contract documented there, and panics if it doesn't.
+///
+/// This is a no-op in release builds. The driver calls it on every DMA allocation in debug builds,
+/// and HAL implementations can call it from their own tests too.
+pub fn validate_dma_region(paddr: PhysAddr, vaddr: NonNull<u8>, size: usize) {
+    let vaddr = vaddr.as_ptr() as usize;
+    debug_assert_ne!(size, 0, "DMA region is empty");
+    debug_assert_ne!(paddr, 0, "DMA region has physical address 0");
+    debug_assert_eq!(
+        paddr % PAGE_SIZE,
+        0,
+        "DMA region physical address {:#x} isn't page aligned",
+        paddr
+    );
+    debug_assert_eq!(
+        vaddr % PAGE_SIZE,
+        0,
+        "DMA region virtual address {:#x} isn't page aligned",
+        vaddr
+    );
+    debug_assert!(
+        paddr.checked_add(size).is_some() && vaddr.checked_add(size).is_some(),
+        "DMA region of {:#x} bytes at {:#x} (virtual {:#x}) wraps around the address space",
+        size,
+        paddr,
+        vaddr
+    );
+}
+
 /// The interface which a particular hardware implementation must implement.
 ///
 /// # Safety
@@ -84,7 +398,13 @@ pub unsafe trait Hal {
     /// use.
     ///
     /// Returns both the physical address which the device can use to access the memory, and a
-    /// pointer to the start of it which the driver can use to access it.
+    /// pointer to the start of it which the driver can use to access it. Both must be aligned to
+    /// [`PAGE_SIZE`], and the pages must be contiguous both physically and in the driver's address
+    /// space, so that the whole `pages * PAGE_SIZE` bytes can be reached from either. The physical
+    /// address 0 means the allocation failed, so it must not be returned for a real allocation.
+    ///
+    /// In debug builds the driver checks what this returns with [`validate_dma_region`], and
+    /// panics if it is misaligned.
     ///
     /// # Implementation safety
     ///
@@ -143,6 +463,102 @@ pub unsafe trait Hal {
     /// previously returned by the corresponding `share` call.
     unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection);
 
+    /// Unshares a buffer which the device was still using when its virtqueue was torn down,
+    /// without copying anything back, as the original buffer may no longer exist.
+    ///
+    /// This is called when a queue is dropped with buffers still in flight, after the device has
+    /// been reset. It should release anything `share` allocated for the buffer.
+    ///
+    /// The default implementation does nothing, which is correct if `share` doesn't allocate.
+    ///
+    /// # Safety
+    ///
+    /// `paddr`, `size` and `direction` must describe a buffer previously returned by `share` and
+    /// not yet unshared. The device must no longer be accessing it.
+    unsafe fn unshare_abandoned(_paddr: PhysAddr, _size: usize, _direction: BufferDirection) {}
+
+    /// Makes any writes by the driver to the given shared buffer visible to the device, e.g. by
+    /// flushing caches on platforms where DMA isn't cache coherent.
+    ///
+    /// This is called for each buffer after it has been shared and before the device is told
+    /// about it, so before the device is notified. Memory allocated with `dma_alloc` (such as the
+    /// virtqueue rings) is never synced, so it must be coherent.
+    ///
+    /// The default implementation does nothing, which is correct for coherent platforms.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` and `size` must describe a buffer previously returned by `share` and not yet
+    /// unshared.
+    unsafe fn sync_for_device(_paddr: PhysAddr, _size: usize, _direction: BufferDirection) {}
+
+    /// Makes any writes by the device to the given shared buffer visible to the driver, e.g. by
+    /// invalidating caches on platforms where DMA isn't cache coherent.
+    ///
+    /// This is called for each buffer after the device has returned it in the used ring and before
+    /// it is unshared, so `unshare` always sees the data written by the device.
+    ///
+    /// The default implementation does nothing, which is correct for coherent platforms.
+    ///
+    /// # Safety
+    ///
+    /// `paddr` and `size` must describe a buffer previously returned by `share` and not yet
+    /// unshared.
+    unsafe fn sync_for_cpu(_paddr: PhysAddr, _size: usize, _direction: BufferDirection) {}
+
+    /// Returns the size of the windows which a single buffer shared with the device must stay
+    /// within, e.g. the page size for an implementation which bounces or maps buffers a page at a
+    /// time.
+    ///
+    /// Virtqueues split any buffer which crosses a multiple of this size, counting from virtual
+    /// address 0, into one descriptor for each window, so each part is shared separately. This
+    /// means a chain may need more descriptors than it has buffers. It must not be 0.
+    ///
+    /// The default implementation returns `usize::MAX`, so buffers are never split.
+    fn max_contiguous_dma() -> usize {
+        usize::MAX
+    }
+
+    /// Returns the current time, in whatever units the implementation likes, for measuring how
+    /// long requests spend in flight.
+    ///
+    /// Virtqueues record this when each chain is added, and return it from
+    /// [`VirtQueue::pop_used_timed`](crate::VirtQueue::pop_used_timed). It should be monotonic.
+    ///
+    /// The default implementation always returns 0.
+    fn now() -> u64 {
+        0
+    }
+
+    /// Arranges for [`Notifier::notify`] to be called on the given notifier whenever the device
+    /// being set up, of the given type, raises an interrupt.
+    ///
+    /// Drivers call this once while they are being set up. Implementations which can't tell which
+    /// device is being set up or route its interrupt should return [`Error::Unsupported`], in which
+    /// case the driver is set up anyway, and the platform can get the notifier from the driver and
+    /// notify it from its own interrupt handler.
+    ///
+    /// The default implementation always returns [`Error::Unsupported`].
+    #[cfg(feature = "async")]
+    fn register_interrupt(_device_type: DeviceType, _notifier: Arc<Notifier>) -> Result {
+        Err(Error::Unsupported)
+    }
+
+    /// Issues a full memory barrier, so that the device sees all of the driver's earlier writes to
+    /// shared memory before any of its later accesses, including MMIO writes which notify it.
+    ///
+    /// Virtqueues call this before making a new descriptor chain available, before checking
+    /// whether the device wants to be notified, and before every notification. This is what keeps
+    /// the device from acting on a stale available index on weakly-ordered architectures such as
+    /// aarch64 and RISC-V.
+    ///
+    /// The default implementation is a sequentially consistent [`fence`], which only orders accesses
+    /// to normal memory. Platforms where that isn't enough to order them against MMIO, or where the
+    /// device isn't coherent with the CPU, should override this with a stronger barrier.
+    fn mb() {
+        fence(Ordering::SeqCst);
+    }
+
     /// Performs memory mapped read from location of `src`. `src` itself is not modified,
     /// the value is returned instead.
     ///
@@ -189,3 +605,106 @@ pub enum BufferDirection {
     /// The buffer may be read or written by both the device and the driver.
     Both,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+
+    #[test]
+    fn validate_dma_region_aligned() {
+        let dma = Dma::<FakeHal>::new(2, BufferDirection::Both, DmaPurpose::Buffer).unwrap();
+        validate_dma_region(dma.paddr(), dma.vaddr(0), dma.size());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "isn't page aligned")]
+    fn validate_dma_region_misaligned() {
+        let dma = Dma::<FakeHal>::new(2, BufferDirection::Both, DmaPurpose::Buffer).unwrap();
+        validate_dma_region(dma.paddr() + 8, dma.vaddr(8), PAGE_SIZE);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn dma_pool_alloc_free() {
+        let pool = DmaPool::<FakeHal>::new(1, 1024, BufferDirection::Both).unwrap();
+        assert_eq!(pool.slot_size(), 1024);
+
+        let buffers = [
+            pool.alloc(16).unwrap(),
+            pool.alloc(1024).unwrap(),
+            pool.alloc(1).unwrap(),
+            pool.alloc(512).unwrap(),
+        ];
+        for (i, buffer) in buffers.iter().enumerate() {
+            assert_eq!(buffer.paddr(), pool.dma.paddr() + i * 1024);
+        }
+        assert_eq!(pool.alloc(16).unwrap_err(), Error::DmaError);
+
+        // Freeing a buffer makes its slot available again, and it is zeroed when reused.
+        // SAFETY: The buffer is owned by this test and not shared with any device.
+        unsafe {
+            buffers[1].vaddr().as_ptr().write_bytes(0xff, 1024);
+        }
+        let [_, second, ..] = buffers;
+        let second_paddr = second.paddr();
+        drop(second);
+        let reused = pool.alloc(32).unwrap();
+        assert_eq!(reused.paddr(), second_paddr);
+        // SAFETY: The buffer is owned by this test and not shared with any device.
+        assert!(unsafe { reused.raw_slice().as_ref() }
+            .iter()
+            .all(|&b| b == 0));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn dma_pool_invalid_size() {
+        assert_eq!(
+            DmaPool::<FakeHal>::new(1, 0, BufferDirection::Both).unwrap_err(),
+            Error::InvalidParam
+        );
+        assert_eq!(
+            DmaPool::<FakeHal>::new(1, PAGE_SIZE + 1, BufferDirection::Both).unwrap_err(),
+            Error::InvalidParam
+        );
+
+        let pool = DmaPool::<FakeHal>::new(1, 64, BufferDirection::Both).unwrap();
+        assert_eq!(pool.alloc(0).unwrap_err(), Error::InvalidParam);
+        assert_eq!(pool.alloc(65).unwrap_err(), Error::InvalidParam);
+        // Only 64 slots are used even though more would fit.
+        let buffers: alloc::vec::Vec<_> = (0..64).map(|_| pool.alloc(64).unwrap()).collect();
+        assert_eq!(pool.alloc(64).unwrap_err(), Error::DmaError);
+        drop(buffers);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn dma_buffer() {
+        assert_eq!(
+            DmaBuffer::<FakeHal>::new(0, BufferDirection::Both).unwrap_err(),
+            Error::InvalidParam
+        );
+
+        let mut buffer = DmaBuffer::<FakeHal>::new(PAGE_SIZE + 1, BufferDirection::Both).unwrap();
+        assert_eq!(buffer.len(), PAGE_SIZE + 1);
+        assert!(buffer.as_slice().iter().all(|&b| b == 0));
+        buffer.as_mut_slice()[PAGE_SIZE] = 42;
+        assert_eq!(buffer.as_slice()[PAGE_SIZE], 42);
+
+        // Only buffers entirely within the region are found.
+        let paddr = buffer.paddr();
+        let region = buffer.region();
+        let slice = buffer.as_mut_slice();
+        assert_eq!(
+            region.paddr_of(NonNull::from(&mut slice[16..32])),
+            Some(paddr + 16)
+        );
+        assert_eq!(
+            region.paddr_of(NonNull::from(&mut slice[PAGE_SIZE..])),
+            Some(paddr + PAGE_SIZE)
+        );
+        assert_eq!(region.paddr_of(NonNull::from(&mut [0u8; 4][..])), None);
+    }
+}
@@ -5,10 +5,11 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 #![allow(missing_docs)]
 
-use crate::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+use crate::{validate_dma_region, BufferDirection, Hal, PhysAddr, PAGE_SIZE};
 use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
 use core::{
     alloc::Layout,
+    cell::Cell,
     ptr::{self, NonNull},
 };
 use zerocopy::FromZeros;
@@ -16,6 +17,13 @@ use zerocopy::FromZeros;
 #[derive(Debug)]
 pub struct FakeHal;
 
+impl FakeHal {
+    /// Sets the time returned by `now` on this thread.
+    pub fn set_time(time: u64) {
+        TIME.set(time);
+    }
+}
+
 /// Fake HAL implementation for use in unit tests.
 unsafe impl Hal for FakeHal {
     fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
@@ -24,14 +32,19 @@ unsafe impl Hal for FakeHal {
         // Safe because the size and alignment of the layout are non-zero.
         let ptr = unsafe { alloc_zeroed(layout) };
         if let Some(ptr) = NonNull::new(ptr) {
-            (ptr.as_ptr() as PhysAddr, ptr)
+            let paddr = virt_to_phys(ptr.as_ptr() as usize);
+            validate_dma_region(paddr, ptr, pages * PAGE_SIZE);
+            (paddr, ptr)
         } else {
             handle_alloc_error(layout);
         }
     }
 
-    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
         assert_ne!(pages, 0);
+        // Catch drivers passing back something other than what `dma_alloc` returned.
+        assert_eq!(paddr, virt_to_phys(vaddr.as_ptr() as usize));
+        validate_dma_region(paddr, vaddr, pages * PAGE_SIZE);
         let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
         // Safe because the layout is the same as was used when the memory was allocated by
         // `dma_alloc` above.
@@ -41,6 +54,10 @@ unsafe impl Hal for FakeHal {
         0
     }
 
+    fn now() -> u64 {
+        TIME.get()
+    }
+
     unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
         NonNull::new(paddr as _).unwrap()
     }
@@ -82,6 +99,88 @@ unsafe impl Hal for FakeHal {
             }
         }
     }
+
+    unsafe fn unshare_abandoned(paddr: PhysAddr, size: usize, _direction: BufferDirection) {
+        assert_ne!(size, 0);
+        assert_ne!(paddr, 0);
+        let vaddr = phys_to_virt(paddr);
+        // Free the buffer allocated by `share` without copying anything back.
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(vaddr as *mut u8, size)) });
+    }
+}
+
+std::thread_local! {
+    static OUTSTANDING_DMA: Cell<usize> = const { Cell::new(0) };
+    static OUTSTANDING_SHARES: Cell<usize> = const { Cell::new(0) };
+    static SHARES: Cell<usize> = const { Cell::new(0) };
+    static TIME: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Fake HAL implementation which behaves like [`FakeHal`], but also keeps track of how many DMA
+/// allocations and shared buffers made by the current thread haven't been released yet.
+#[derive(Debug)]
+pub struct TrackingHal;
+
+impl TrackingHal {
+    /// Returns the number of DMA allocations made on this thread which haven't been deallocated.
+    pub fn outstanding_dma() -> usize {
+        OUTSTANDING_DMA.get()
+    }
+
+    /// Returns the number of buffers shared on this thread which haven't been unshared.
+    pub fn outstanding_shares() -> usize {
+        OUTSTANDING_SHARES.get()
+    }
+
+    /// Returns the total number of buffers shared on this thread, including those since unshared.
+    pub fn shares() -> usize {
+        SHARES.get()
+    }
+}
+
+fn increment(counter: &'static std::thread::LocalKey<Cell<usize>>) {
+    counter.set(counter.get() + 1);
+}
+
+fn decrement(counter: &'static std::thread::LocalKey<Cell<usize>>) {
+    counter.set(
+        counter
+            .get()
+            .checked_sub(1)
+            .expect("Released more than was allocated"),
+    );
+}
+
+unsafe impl Hal for TrackingHal {
+    fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        increment(&OUTSTANDING_DMA);
+        FakeHal::dma_alloc(pages, direction)
+    }
+
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        decrement(&OUTSTANDING_DMA);
+        unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+        unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        increment(&OUTSTANDING_SHARES);
+        increment(&SHARES);
+        unsafe { FakeHal::share(buffer, direction) }
+    }
+
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        decrement(&OUTSTANDING_SHARES);
+        unsafe { FakeHal::unshare(paddr, buffer, direction) }
+    }
+
+    unsafe fn unshare_abandoned(paddr: PhysAddr, size: usize, direction: BufferDirection) {
+        decrement(&OUTSTANDING_SHARES);
+        unsafe { FakeHal::unshare_abandoned(paddr, size, direction) }
+    }
 }
 
 fn virt_to_phys(vaddr: usize) -> PhysAddr {
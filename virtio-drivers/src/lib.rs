@@ -12,7 +12,7 @@
 //! then construct the appropriate transport for the VirtIO device, e.g. for an MMIO device (perhaps
 //! discovered from the device tree):
 //! EXAMPLES REMOVED TEMPORARILY
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "test-utils")), no_std)]
 #![deny(unused_must_use, missing_docs)]
 #![allow(clippy::identity_op)]
 #![allow(dead_code)]
@@ -22,7 +22,11 @@ extern crate alloc;
 
 pub mod device;
 mod hal;
+#[cfg(feature = "async")]
+mod notifier;
 mod queue;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod transport;
 mod volatile;
 
@@ -31,7 +35,14 @@ use core::{
     ptr::{self, NonNull},
 };
 
-pub use self::hal::{BufferDirection, Hal, PhysAddr};
+pub use self::hal::{validate_dma_region, BufferDirection, Hal, PhysAddr};
+#[cfg(feature = "alloc")]
+pub use self::hal::{DmaBuffer, DmaPool, PoolBuffer};
+#[cfg(feature = "async")]
+pub use self::notifier::{Notified, Notifier};
+#[cfg(feature = "stats")]
+pub use self::queue::QueueStats;
+pub use self::queue::{QueueObserver, QueueSnapshot, RingAllocation, VirtQueue};
 
 /// The page size in bytes supported by the library (4 KiB).
 pub const PAGE_SIZE: usize = 0x1000;
@@ -62,6 +73,20 @@ pub enum Error {
     ConfigSpaceTooSmall,
     /// The device doesn't have any config space, but the driver expects some.
     ConfigSpaceMissing,
+    /// The device didn't complete the request within the given bound.
+    Timeout,
+    /// The device has set `DEVICE_NEEDS_RESET`, or the driver has given up on it, so it must be
+    /// reset before any more requests can be made.
+    DeviceNeedsReset,
+    /// The device has been removed, e.g. by a surprise hot-unplug, so it can't complete any more
+    /// requests and the driver should be dropped.
+    DeviceRemoved,
+    /// The device doesn't offer some features which the driver requires. Contains the missing
+    /// feature bits.
+    FeatureNegotiationFailed(u64),
+    /// The device didn't accept the features the driver negotiated: it cleared `FEATURES_OK`
+    /// rather than keeping it set.
+    FeaturesNotAccepted,
 }
 
 #[cfg(feature = "alloc")]
@@ -95,6 +120,13 @@ impl Display for Error {
                     "The device doesn't have any config space, but the driver expects some"
                 )
             }
+            Self::Timeout => write!(f, "Request timed out"),
+            Self::DeviceNeedsReset => write!(f, "Device needs to be reset"),
+            Self::DeviceRemoved => write!(f, "Device has been removed"),
+            Self::FeatureNegotiationFailed(missing) => {
+                write!(f, "Device doesn't offer required features {:#x}", missing)
+            }
+            Self::FeaturesNotAccepted => write!(f, "Device didn't accept the negotiated features"),
         }
     }
 }
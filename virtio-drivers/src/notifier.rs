@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: MIT
+
+//! Waking async tasks from device interrupts.
+
+use crate::{transport::DeviceType, Error, Hal, Result};
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Nobody is registering or waking the waker.
+const IDLE: u8 = 0;
+/// A task is replacing the waker.
+const REGISTERING: u8 = 1 << 0;
+/// An interrupt handler is taking the waker to wake it.
+const WAKING: u8 = 1 << 1;
+
+/// Lets an async task wait for a device's interrupts.
+///
+/// Every driver creates one of these when it is set up, and passes it to
+/// [`Hal::register_interrupt`] so that the platform's interrupt handler can call
+/// [`notify`](Self::notify) when the device raises an interrupt. An executor can then await
+/// [`notified`](Self::notified) instead of polling the device, and call the driver's
+/// `ack_interrupt` once it completes.
+///
+/// `notify` never blocks or allocates, so it is safe to call from an interrupt handler, as long as
+/// waking the registered [`Waker`] is.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    /// Whether there has been an interrupt which no [`Notified`] future has yet consumed.
+    pending: AtomicBool,
+    /// Guards access to `waker`, see the `IDLE`, `REGISTERING` and `WAKING` states.
+    state: AtomicU8,
+    /// The waker of the task waiting for the next interrupt, if any.
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// SAFETY: `waker` is only accessed by whoever moved `state` away from `IDLE`, so never from two
+// threads at once.
+unsafe impl Send for Notifier {}
+
+// SAFETY: As above.
+unsafe impl Sync for Notifier {}
+
+impl Notifier {
+    /// Creates a new notifier, with no interrupt pending.
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            state: AtomicU8::new(IDLE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Records that the device has raised an interrupt, and wakes the task waiting for it, if any.
+    pub fn notify(&self) {
+        self.pending.store(true, Ordering::Release);
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == IDLE {
+            // SAFETY: We moved the state away from IDLE, so nobody else is accessing the waker.
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+        // Otherwise either another call is already waking the task, or the task is registering its
+        // waker and will see the WAKING bit when it finishes.
+    }
+
+    /// Returns a future which completes once the device has raised an interrupt since the last
+    /// such future completed.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notifier: self }
+    }
+
+    /// Consumes the pending interrupt, if any, returning whether there was one.
+    fn take_pending(&self) -> bool {
+        self.pending.swap(false, Ordering::AcqRel)
+    }
+
+    /// Registers the waker to be woken by the next call to `notify`, replacing any waker
+    /// previously registered.
+    fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(IDLE, REGISTERING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // SAFETY: We moved the state away from IDLE, so nobody else is accessing the waker.
+                let slot = unsafe { &mut *self.waker.get() };
+                if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                    *slot = Some(waker.clone());
+                }
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `notify` was called while we were registering, and left the waker to us.
+                    let waker = slot.take();
+                    self.state.store(IDLE, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // `notify` is running right now, so make sure we are polled again.
+            Err(_) => waker.wake_by_ref(),
+        }
+    }
+}
+
+/// A future which completes once the device has raised an interrupt.
+///
+/// Returned by [`Notifier::notified`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    notifier: &'a Notifier,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.notifier.take_pending() {
+            return Poll::Ready(());
+        }
+        // Check again after registering the waker, so that an interrupt between the two isn't
+        // missed.
+        self.notifier.register(cx.waker());
+        if self.notifier.take_pending() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Creates the notifier for a new device of the given type, and asks the HAL to route the
+/// device's interrupt to it.
+///
+/// The notifier is still returned if the HAL doesn't support this, so the caller can notify it
+/// from its own interrupt handler.
+pub(crate) fn register_interrupt<H: Hal>(device_type: DeviceType) -> Result<Arc<Notifier>> {
+    let notifier = Arc::new(Notifier::new());
+    match H::register_interrupt(device_type, notifier.clone()) {
+        Ok(()) | Err(Error::Unsupported) => Ok(notifier),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{pin::pin, sync::atomic::AtomicUsize};
+    use std::{task::Wake, thread};
+
+    /// A waker which counts how many times it has been woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn notify_before_wait() {
+        let notifier = Notifier::new();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        notifier.notify();
+        notifier.notify();
+        assert_eq!(pin!(notifier.notified()).poll(&mut cx), Poll::Ready(()));
+        // Both notifications were consumed together.
+        assert_eq!(pin!(notifier.notified()).poll(&mut cx), Poll::Pending);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn notify_wakes() {
+        let notifier = Arc::new(Notifier::new());
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut notified = pin!(notifier.notified());
+        assert_eq!(notified.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(notified.as_mut().poll(&mut cx), Poll::Pending);
+
+        let handle = thread::spawn({
+            let notifier = notifier.clone();
+            move || notifier.notify()
+        });
+        handle.join().unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(notified.as_mut().poll(&mut cx), Poll::Ready(()));
+
+        // The waker was taken, so a later notification doesn't wake it again.
+        notifier.notify();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn register_interrupt_unsupported() {
+        let notifier = register_interrupt::<crate::hal::fake::FakeHal>(DeviceType::Block).unwrap();
+        assert_eq!(Arc::strong_count(&notifier), 1);
+    }
+}
@@ -2,32 +2,53 @@
 
 #![deny(unsafe_op_in_unsafe_fn)]
 
+pub mod negotiated;
 #[cfg(feature = "alloc")]
 pub mod owning;
-
-use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
-use crate::transport::Transport;
+pub mod packed;
+
+use crate::hal::{BufferDirection, Dma, DmaPurpose, Hal, PhysAddr, SharedRegion};
+use crate::transport::{
+    convert_endian,
+    features::{NOTIFICATION_DATA, RING_RESET},
+    ConfigValue, DeviceStatus, Endian, Transport,
+};
 use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
 #[cfg(feature = "alloc")]
 use alloc::boxed::Box;
 use bitflags::bitflags;
-#[cfg(test)]
+#[cfg(any(test, feature = "test-utils"))]
 use core::cmp::min;
 use core::convert::TryInto;
+use core::fmt::{self, Display, Formatter};
 use core::hint::spin_loop;
 use core::mem::{size_of, take};
-#[cfg(test)]
-use core::ptr;
-use core::ptr::NonNull;
-use core::sync::atomic::{fence, AtomicU16, Ordering};
+#[cfg(feature = "stats")]
+use core::ops::Add;
+use core::ptr::{self, NonNull};
+#[cfg(feature = "stats")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU16, Ordering};
+use log::warn;
 use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
+/// How many times the used ring is polled between checks of whether the device needs a reset, as
+/// reading the device status may be much slower than reading memory.
+pub(crate) const NEEDS_RESET_POLL_INTERVAL: usize = 1024;
+
 /// The mechanism for bulk data transport on virtio devices.
 ///
 /// Each device can have zero or more virtqueues.
 ///
-/// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
-///   in the available and used rings. It must be a power of 2 and fit in a [`u16`].
+/// * `SIZE`: The maximum size of the queue. Unless a smaller size is chosen with
+///   [`with_size`](Self::with_size), this is both the number of descriptors, and the number of
+///   slots in the available and used rings. It must be a power of 2 and fit in a [`u16`].
+///
+/// The queue keeps track of which descriptor chains are in flight, and doesn't trust the device to
+/// return only those: popping a token which the device made up, or which isn't in flight, fails
+/// with [`Error::WrongToken`] and leaves the queue as it was. In debug builds it also panics if
+/// any descriptors have gone missing when it is dropped. Chains still in flight when the queue is
+/// dropped are not leaks: their buffers are released.
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
     /// DMA guard
@@ -49,21 +70,74 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
 
     /// The index of queue
     queue_idx: u16,
+    /// The number of descriptors and ring slots actually in use, a power of 2 no greater than
+    /// `SIZE`.
+    size: u16,
     /// The number of descriptors currently in use.
     num_used: u16,
     /// The head desc index of the free list.
     free_head: u16,
     /// Our trusted copy of `desc` that the device can't access.
     desc_shadow: [Descriptor; SIZE],
+    /// Whether each descriptor points to a buffer in a region which was already shared with the
+    /// device when it was added, and so mustn't be unshared.
+    premapped: [bool; SIZE],
     /// Our trusted copy of `avail.idx`.
     avail_idx: u16,
     last_used_idx: u16,
+    /// The number of buffers added to the available ring since `should_notify` was last called.
+    num_added: AtomicU16,
     /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
     event_idx: bool,
+    /// The byte order of the fields of the descriptors and rings.
+    endian: Endian,
+    /// Whether we have asked the device for used buffer notifications.
+    dev_notify: bool,
+    /// Whether `should_notify` may return true, as set by `set_notify`.
+    notify_enabled: bool,
+    /// Whether the `VIRTIO_F_IN_ORDER` feature has been negotiated.
+    in_order: bool,
+    /// The head descriptor of the chain added at each available ring slot. When `in_order` is set
+    /// this is used to find the next used token, as the device may not write a used element for
+    /// every chain.
+    in_order_heads: [u16; SIZE],
+    /// The total device-writable length of the chain starting at each head descriptor. This is
+    /// the used length of chains which the device skipped over in an in-order batch.
+    writable_len: [u32; SIZE],
+    /// The time from `Hal::now` at which the chain starting at each head descriptor was added.
+    submitted_at: [u64; SIZE],
+    /// The caller's cookie for the chain starting at each head descriptor, given to
+    /// `add_with_cookie`, or 0.
+    cookies: [u64; SIZE],
+    /// The token and length of the used element describing the current in-order batch, if some of
+    /// the batch has already been popped.
+    in_order_batch: Option<(u16, u32)>,
+    /// Whether the chain starting at each head descriptor is in the queue, so that tokens from the
+    /// device can be checked before they are popped, and leaks caught in debug builds.
+    in_flight: [bool; SIZE],
+    /// The number of entries in `in_flight` which are true.
+    num_in_flight: u16,
+    /// The used length of each chain which the device completed while a blocking wait was waiting
+    /// for a different one, indexed by its head descriptor. These have been taken off the used
+    /// ring, and are popped before anything still on it.
+    set_aside: [Option<u32>; SIZE],
+    /// The number of entries in `set_aside` which are `Some`.
+    num_set_aside: u16,
+    /// Counters for `stats`, other than `notifications`.
+    #[cfg(feature = "stats")]
+    stats: QueueStats,
+    /// The number of times `should_notify` has returned true. This is separate from `stats` as
+    /// `should_notify` only takes `&self`.
+    #[cfg(feature = "stats")]
+    notifications: AtomicU64,
+    /// Told about each operation on the queue, if installed with `set_observer`.
+    observer: Option<&'static dyn QueueObserver>,
     #[cfg(feature = "alloc")]
     indirect: bool,
+    /// The indirect descriptor tables of the chains currently in the queue, indexed by the head
+    /// descriptor pointing to them.
     #[cfg(feature = "alloc")]
-    indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+    indirect_lists: [Option<IndirectList<H>>; SIZE],
 }
 
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
@@ -72,15 +146,71 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// Creates a new VirtQueue.
     ///
     /// * `indirect`: Whether to use indirect descriptors. This should be set if the
-    ///   `VIRTIO_F_INDIRECT_DESC` feature has been negotiated with the device.
+    ///   `VIRTIO_F_INDIRECT_DESC` feature has been negotiated with the device. Indirect descriptor
+    ///   tables need a driver-private copy, so this is ignored unless the `alloc` feature is
+    ///   enabled.
     /// * `event_idx`: Whether to use the `used_event` and `avail_event` fields for notification
     ///   suppression. This should be set if the `VIRTIO_F_EVENT_IDX` feature has been negotiated
     ///   with the device.
+    /// * `in_order`: Whether the device uses buffers in the order they were made available. This
+    ///   should be set if the `VIRTIO_F_IN_ORDER` feature has been negotiated with the device.
+    ///
+    /// The queue has `SIZE` descriptors. Returns [`Error::InvalidParam`] if the device doesn't
+    /// support a queue that big.
     pub fn new<T: Transport>(
         transport: &mut T,
         idx: u16,
         indirect: bool,
         event_idx: bool,
+        in_order: bool,
+    ) -> Result<Self> {
+        if transport.max_queue_size(idx) < SIZE as u32 {
+            return Err(Error::InvalidParam);
+        }
+        Self::with_size(transport, idx, SIZE as u16, indirect, event_idx, in_order)
+    }
+
+    /// Creates a new VirtQueue with about `requested_size` descriptors, rather than `SIZE`.
+    ///
+    /// The requested size is rounded up to a power of two, and then clamped to `SIZE` and to the
+    /// maximum the device supports for the queue (rounded down to a power of two). Use
+    /// [`size`](Self::size) to find out the resulting size. Returns [`Error::InvalidParam`] if
+    /// `requested_size` is 0 or the device doesn't support the queue at all.
+    ///
+    /// The other parameters are as for [`new`](Self::new).
+    pub fn with_size<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        requested_size: u16,
+        indirect: bool,
+        event_idx: bool,
+        in_order: bool,
+    ) -> Result<Self> {
+        Self::with_allocation(
+            transport,
+            idx,
+            requested_size,
+            indirect,
+            event_idx,
+            in_order,
+            RingAllocation::default(),
+        )
+    }
+
+    /// Like [`with_size`](Self::with_size), but choosing how the descriptor table and rings are
+    /// allocated.
+    ///
+    /// [`RingAllocation::PerArea`] reduces the largest contiguous allocation asked of
+    /// [`Hal::dma_alloc`], at the cost of an extra page. It is ignored for transports which require
+    /// the legacy layout, as that puts all of the queue in a single region.
+    pub fn with_allocation<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        requested_size: u16,
+        indirect: bool,
+        event_idx: bool,
+        in_order: bool,
+        allocation: RingAllocation,
     ) -> Result<Self> {
         #[allow(clippy::let_unit_value)]
         let _ = Self::SIZE_OK;
@@ -88,13 +218,15 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         if transport.queue_used(idx) {
             return Err(Error::AlreadyUsed);
         }
-        if transport.max_queue_size(idx) < SIZE as u32 {
-            return Err(Error::InvalidParam);
-        }
-        let size = SIZE as u16;
+        let size = queue_size(requested_size, SIZE as u16, transport.max_queue_size(idx))
+            .ok_or(Error::InvalidParam)?;
+        #[cfg(not(feature = "alloc"))]
+        let _ = indirect;
 
         let layout = if transport.requires_legacy_layout() {
             VirtQueueLayout::allocate_legacy(size)?
+        } else if allocation == RingAllocation::PerArea {
+            VirtQueueLayout::allocate_per_area(size)?
         } else {
             VirtQueueLayout::allocate_flexible(size)?
         };
@@ -107,8 +239,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             layout.device_area_paddr(),
         );
 
-        let desc =
-            nonnull_slice_from_raw_parts(layout.descriptors_vaddr().cast::<Descriptor>(), SIZE);
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<Descriptor>(),
+            size.into(),
+        );
         let avail = layout.avail_vaddr().cast();
         let used = layout.used_vaddr().cast();
 
@@ -123,32 +257,62 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             }
         }
 
-        #[cfg(feature = "alloc")]
-        const NONE: Option<NonNull<[Descriptor]>> = None;
         Ok(VirtQueue {
             layout,
             desc,
             avail,
             used,
             queue_idx: idx,
+            size,
             num_used: 0,
             free_head: 0,
             desc_shadow,
+            premapped: [false; SIZE],
             avail_idx: 0,
             last_used_idx: 0,
+            num_added: AtomicU16::new(0),
             event_idx,
+            endian: transport.config_endian(),
+            dev_notify: true,
+            notify_enabled: true,
+            in_order,
+            in_order_heads: [0; SIZE],
+            writable_len: [0; SIZE],
+            submitted_at: [0; SIZE],
+            cookies: [0; SIZE],
+            in_order_batch: None,
+            in_flight: [false; SIZE],
+            num_in_flight: 0,
+            set_aside: [None; SIZE],
+            num_set_aside: 0,
+            #[cfg(feature = "stats")]
+            stats: QueueStats::default(),
+            #[cfg(feature = "stats")]
+            notifications: AtomicU64::new(0),
+            observer: None,
             #[cfg(feature = "alloc")]
             indirect,
             #[cfg(feature = "alloc")]
-            indirect_lists: [NONE; SIZE],
+            indirect_lists: [const { None }; SIZE],
         })
     }
 
+    /// Returns the size of the queue, i.e. the number of descriptors it has.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
     /// Add buffers to the virtqueue, return a token.
     ///
+    /// `inputs` are read by the device and `outputs` are written by it. They are added as a single
+    /// chain with all of `inputs` first, in order, followed by all of `outputs`, as the spec
+    /// requires. So a request made of a header, a data buffer for the device to fill and a status
+    /// byte is added with `add(&[header], &mut [data, status])`. The total length of `outputs` is
+    /// what an in-order device is assumed to have written to chains it skips over.
+    ///
     /// The buffers must not be empty.
     ///
-    /// Ref: linux virtio_ring.c virtqueue_add
+    /// Ref: 2.6.4 Message Framing, linux virtio_ring.c virtqueue_add
     ///
     /// # Safety
     ///
@@ -158,43 +322,140 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // SAFETY: Our caller promises the same as `add_premapped` requires.
+        unsafe { self.add_premapped(inputs, outputs, &[]) }
+    }
+
+    /// Like [`add`](Self::add), but associates the given cookie with the chain, to be returned by
+    /// [`pop_used_with_cookie`](Self::pop_used_with_cookie).
+    ///
+    /// This lets an event-driven caller carry its own context, such as a request ID, through the
+    /// round-trip to the device without keeping a table indexed by token.
+    ///
+    /// # Safety
+    ///
+    /// As for [`add`](Self::add).
+    pub unsafe fn add_with_cookie<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        cookie: u64,
+    ) -> Result<u16> {
+        // SAFETY: Our caller promises the same as `add` requires.
+        let token = unsafe { self.add(inputs, outputs) }?;
+        self.cookies[usize::from(token)] = cookie;
+        Ok(token)
+    }
+
+    /// Adds each of the given buffers to the virtqueue as a chain of its own for the device to
+    /// write to, e.g. to fill a receive queue, and then notifies the device once if it needs to
+    /// be.
+    ///
+    /// The token for `buffers[i]` is written to `tokens[i]`, and at most `tokens.len()` buffers
+    /// are added. If the queue fills up or a buffer can't be added part way through, the buffers
+    /// added so far are kept and notified, and their number is returned; the caller can add the
+    /// rest once some have been popped. An error is only returned if not even the first buffer
+    /// could be added.
+    ///
+    /// # Safety
+    ///
+    /// Each buffer which is added must remain valid and not be accessed until a call to
+    /// `pop_used` with its token succeeds.
+    pub unsafe fn add_many(
+        &mut self,
+        buffers: &mut [&mut [u8]],
+        tokens: &mut [u16],
+        transport: &mut impl Transport,
+    ) -> Result<usize> {
+        let mut added = 0;
+        for (buffer, token) in buffers.iter_mut().zip(tokens) {
+            // SAFETY: Our caller promises that the buffer remains valid until it is popped.
+            match unsafe { self.add(&[], &mut [buffer]) } {
+                Ok(new_token) => *token = new_token,
+                Err(e) if added == 0 => return Err(e),
+                Err(_) => break,
+            }
+            added += 1;
+        }
+
+        if added != 0 && self.should_notify() {
+            self.notify_device(transport);
+        }
+        Ok(added)
+    }
+
+    /// Like [`add`](Self::add), but buffers which lie within one of the given regions, which are
+    /// already shared with the device, are used directly rather than being shared again.
+    ///
+    /// Chains are never given indirect descriptors when there are any regions, as buffers in them
+    /// would need tracking separately.
+    ///
+    /// # Safety
+    ///
+    /// As for `add`. The regions must also stay shared until the chain has been popped.
+    pub(crate) unsafe fn add_premapped<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        premapped: &[SharedRegion],
     ) -> Result<u16> {
         if inputs.is_empty() && outputs.is_empty() {
             return Err(Error::InvalidParam);
         }
-        let descriptors_needed = inputs.len() + outputs.len();
+        let descriptors_needed = segment_count(inputs, outputs, H::max_contiguous_dma());
+        let writable_len = outputs.iter().map(|buffer| buffer.len()).sum::<usize>();
         // Only consider indirect descriptors if the alloc feature is enabled, as they require
         // allocation.
         #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
+        let indirect = self.indirect && premapped.is_empty();
+        let free = usize::from(self.free_descriptors());
+        #[cfg(feature = "alloc")]
+        if free == 0
+            || descriptors_needed > self.size.into()
+            || (!indirect && descriptors_needed > free)
         {
+            #[cfg(feature = "stats")]
+            {
+                self.stats.queue_full += 1;
+            }
             return Err(Error::QueueFull);
         }
         #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
+        if descriptors_needed > free {
+            #[cfg(feature = "stats")]
+            {
+                self.stats.queue_full += 1;
+            }
             return Err(Error::QueueFull);
         }
 
         #[cfg(feature = "alloc")]
-        let head = if self.indirect && descriptors_needed > 1 {
-            self.add_indirect(inputs, outputs)
+        let head = if indirect && descriptors_needed > 1 {
+            self.add_indirect(inputs, outputs, descriptors_needed)?
         } else {
-            self.add_direct(inputs, outputs)
+            self.add_direct(inputs, outputs, premapped)
         };
         #[cfg(not(feature = "alloc"))]
-        let head = self.add_direct(inputs, outputs);
+        let head = self.add_direct(inputs, outputs, premapped);
+        let in_flight = &mut self.in_flight[usize::from(head)];
+        debug_assert!(!*in_flight, "Descriptor chain {} added twice.", head);
+        *in_flight = true;
+        self.num_in_flight += 1;
 
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
+        let avail_slot = self.avail_idx & (self.size - 1);
         // SAFETY: Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
-            (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
+            (*self.avail.as_ptr()).ring[avail_slot as usize] = self.to_device(head);
         }
+        self.in_order_heads[usize::from(avail_slot)] = head;
+        self.writable_len[usize::from(head)] = writable_len.try_into().unwrap_or(u32::MAX);
+        self.submitted_at[usize::from(head)] = H::now();
+        self.cookies[usize::from(head)] = 0;
 
         // Write barrier so that device sees changes to descriptor table and available ring before
         // change to available index.
-        fence(Ordering::SeqCst);
+        H::mb();
 
         // increase head of avail ring
         self.avail_idx = self.avail_idx.wrapping_add(1);
@@ -202,7 +463,17 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         unsafe {
             (*self.avail.as_ptr())
                 .idx
-                .store(self.avail_idx, Ordering::Release);
+                .store(self.to_device(self.avail_idx), Ordering::Release);
+        }
+        let num_added = self.num_added.get_mut();
+        *num_added = num_added.saturating_add(1);
+        #[cfg(feature = "stats")]
+        {
+            self.stats.submissions += 1;
+        }
+        if let Some(observer) = self.observer {
+            let readable_len = inputs.iter().map(|buffer| buffer.len()).sum::<usize>();
+            observer.on_submit(self.queue_idx, head, readable_len + writable_len);
         }
 
         Ok(head)
@@ -212,23 +483,31 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
+        premapped: &[SharedRegion],
     ) -> u16 {
         // allocate descriptors from free list
         let head = self.free_head;
         let mut last = self.free_head;
+        let mut descriptors = 0;
 
-        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+        for (buffer, direction) in InputOutputIter::new(inputs, outputs, H::max_contiguous_dma()) {
             assert_ne!(buffer.len(), 0);
 
             // Write to desc_shadow then copy.
             let desc = &mut self.desc_shadow[usize::from(self.free_head)];
-            // SAFETY: Safe because our caller promises that the buffers live at least until `pop_used`
-            // returns them.
-            unsafe {
-                desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
+            if let Some(paddr) = premapped.iter().find_map(|region| region.paddr_of(buffer)) {
+                desc.set_addr(paddr, buffer.len(), direction, DescFlags::NEXT);
+                self.premapped[usize::from(self.free_head)] = true;
+            } else {
+                // SAFETY: Safe because our caller promises that the buffers live at least until
+                // `pop_used` returns them.
+                unsafe {
+                    desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
+                }
             }
             last = self.free_head;
             self.free_head = desc.next;
+            descriptors += 1;
 
             self.write_desc(last);
         }
@@ -239,7 +518,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             .remove(DescFlags::NEXT);
         self.write_desc(last);
 
-        self.num_used += (inputs.len() + outputs.len()) as u16;
+        self.num_used += descriptors;
 
         head
     }
@@ -249,14 +528,19 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
-    ) -> u16 {
+        descriptors_needed: usize,
+    ) -> Result<u16> {
         let head = self.free_head;
 
-        // Allocate and fill in indirect descriptor list.
-        let mut indirect_list =
-            <[Descriptor]>::new_box_zeroed_with_elems(inputs.len() + outputs.len()).unwrap();
-        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
-            let desc = &mut indirect_list[i];
+        // Allocate the table the device will read before touching any queue state, so that a
+        // failure leaves the queue unchanged.
+        let mut indirect_list = IndirectList::new(descriptors_needed)?;
+
+        // Fill in the driver's copy of the indirect descriptor list.
+        for (i, (buffer, direction)) in
+            InputOutputIter::new(inputs, outputs, H::max_contiguous_dma()).enumerate()
+        {
+            let desc = &mut indirect_list.shadow[i];
             // SAFETY: Safe because our caller promises that the buffers live at least until `pop_used`
             // returns them.
             unsafe {
@@ -265,59 +549,88 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             desc.next = (i + 1) as u16;
         }
         indirect_list
+            .shadow
             .last_mut()
             .unwrap()
             .flags
             .remove(DescFlags::NEXT);
+        indirect_list.write_table(self.endian);
 
-        // Need to store pointer to indirect_list too, because direct_desc.set_buf will only store
-        // the physical DMA address which might be different.
-        assert!(self.indirect_lists[usize::from(head)].is_none());
-        self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
-
-        // Write a descriptor pointing to indirect descriptor list. We use Box::leak to prevent the
-        // indirect list from being freed when this function returns; recycle_descriptors is instead
-        // responsible for freeing the memory after the buffer chain is popped.
+        // Write a descriptor pointing to the indirect descriptor table. The list is kept until
+        // `recycle_descriptors` frees it after the buffer chain is popped.
         let direct_desc = &mut self.desc_shadow[usize::from(head)];
         self.free_head = direct_desc.next;
-        // SAFETY: Relying on `recycle_descriptors()` to clean up leaked memory
-        unsafe {
-            direct_desc.set_buf::<H>(
-                Box::leak(indirect_list).as_bytes().into(),
-                BufferDirection::DriverToDevice,
-                DescFlags::INDIRECT,
-            );
-        }
+        direct_desc.set_table(&indirect_list);
         self.write_desc(head);
         self.num_used += 1;
 
-        head
+        assert!(self.indirect_lists[usize::from(head)].is_none());
+        self.indirect_lists[usize::from(head)] = Some(indirect_list);
+
+        Ok(head)
     }
 
     /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
     /// them, then pops them.
     ///
-    /// This assumes that the device isn't processing any other buffers at the same time.
+    /// If the device completes other chains in the queue first, they are set aside for
+    /// [`pop_used`](Self::pop_used) to pop later, with [`QueueObserver::on_set_aside`] told about
+    /// each, and the wait carries on. A used element for a chain which isn't in flight still fails
+    /// with [`Error::WrongToken`].
+    ///
+    /// The device is always notified if notifications have been disabled with
+    /// [`set_notify`](Self::set_notify), as it might otherwise never see the buffers.
     ///
     /// The buffers must not be empty.
+    ///
+    /// Returns [`Error::DeviceNeedsReset`] without adding anything if the device needs a reset.
+    /// If it sets `DEVICE_NEEDS_RESET` while the buffers are in flight, it is reset so that it
+    /// stops using them and marked as failed, and the same error is returned. Either way the
+    /// driver must be reset or dropped before the queue can be used again.
+    ///
+    /// Similarly [`Error::DeviceRemoved`] is returned if the device has been removed, before or
+    /// while the buffers are in flight. The driver must be dropped in that case.
     pub fn add_notify_wait_pop<'a>(
         &mut self,
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
         transport: &mut impl Transport,
     ) -> Result<u32> {
-        // SAFETY: Safe because we don't return until the same token has been popped, so the buffers remain
-        // valid and are not otherwise accessed until then.
-        let token = unsafe { self.add(inputs, outputs) }?;
+        self.add_notify_wait_pop_premapped(inputs, outputs, &[], transport)
+    }
+
+    /// Like [`add_notify_wait_pop`](Self::add_notify_wait_pop), but buffers which lie within the
+    /// given regions are used without being shared, as for
+    /// [`add_premapped`](Self::add_premapped).
+    pub(crate) fn add_notify_wait_pop_premapped<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        premapped: &[SharedRegion],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        check_usable(transport)?;
+        // SAFETY: Safe because we don't return until the same token has been popped or the device
+        // has been reset, so the buffers remain valid and are not otherwise accessed until the
+        // device is done with them, and the region outlives this call.
+        let token = unsafe { self.add_premapped(inputs, outputs, premapped) }?;
 
         // Notify the queue.
-        if self.should_notify() {
-            transport.notify(self.queue_idx);
+        if !self.notify_enabled {
+            self.notify(transport);
+        } else if self.should_notify() {
+            self.notify_device(transport);
         }
 
-        // Wait until there is at least one element in the used ring.
-        while !self.can_pop() {
-            spin_loop();
+        // Wait until there is at least one element in the used ring. The device may complete
+        // other chains first, e.g. ones added with `add` which it processes out of order, so set
+        // those aside for their callers to pop and keep waiting. Each chain is only set aside
+        // once, and ours never is, so this ends after at most one pass over the queue.
+        for _ in 0..self.size {
+            wait_unless_needs_reset(transport, || self.used_pending())?;
+            if !self.set_aside_used(token) {
+                break;
+            }
         }
 
         // SAFETY: Safe because these are the same buffers as we passed to `add` above and they are still
@@ -325,36 +638,253 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         unsafe { self.pop_used(token, inputs, outputs) }
     }
 
+    /// Returns whether the chain with the given token has been used, so that `pop_used` can pop
+    /// it now.
+    ///
+    /// Any other chains ahead of it on the used ring are set aside, as by a blocking wait, so that
+    /// their callers can still pop them afterwards.
+    pub(crate) fn token_used(&mut self, token: u16) -> bool {
+        if self
+            .set_aside
+            .get(usize::from(token))
+            .is_some_and(Option::is_some)
+        {
+            return true;
+        }
+        // Each chain is only set aside once, so this ends after at most one pass over the queue.
+        for _ in 0..self.size {
+            if !self.used_pending() {
+                return false;
+            }
+            if !self.set_aside_used(token) {
+                break;
+            }
+        }
+        self.used_pending() && self.next_used().0 .0 == token
+    }
+
+    /// Takes the next element off the used ring and sets it aside to be popped later, if it is for
+    /// a chain in flight other than `expected`. Returns whether it did so.
+    ///
+    /// Anything else is left for `pop_used` to pop or reject.
+    fn set_aside_used(&mut self, expected: u16) -> bool {
+        let ((index, len), batch) = self.next_used();
+        if index == expected
+            || !self
+                .in_flight
+                .get(usize::from(index))
+                .is_some_and(|&in_flight| in_flight)
+            || self.set_aside[usize::from(index)].is_some()
+        {
+            return false;
+        }
+        self.set_aside[usize::from(index)] = Some(len);
+        self.num_set_aside += 1;
+        self.advance_used(batch);
+        if let Some(observer) = self.observer {
+            observer.on_set_aside(self.queue_idx, expected, index, len);
+        }
+        true
+    }
+
     /// Advise the device whether used buffer notifications are needed.
     ///
+    /// If `VIRTIO_F_EVENT_IDX` has been negotiated this moves the `used_event` threshold, so that
+    /// when enabled the device will send a notification as soon as it uses the next buffer, and
+    /// when disabled none will be sent until notifications are enabled again. Otherwise it sets the
+    /// `VIRTQ_AVAIL_F_NO_INTERRUPT` flag.
+    ///
     /// See Virtio v1.1 2.6.7 Used Buffer Notification Suppression
     pub fn set_dev_notify(&mut self, enable: bool) {
-        let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
-        if !self.event_idx {
+        self.dev_notify = enable;
+        if self.event_idx {
+            // There's no way to turn notifications off entirely with `used_event`, so set it to
+            // the index furthest from where the device is. Enabling them again moves it back
+            // before the device could get there.
+            let used_event = if enable {
+                self.last_used_idx
+            } else {
+                self.last_used_idx.wrapping_sub(1)
+            };
+            self.write_used_event(used_event);
+        } else {
+            let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
             // SAFETY: Safe because self.avail points to a valid, aligned, initialised, dereferenceable, readable
             // instance of AvailRing.
             unsafe {
                 (*self.avail.as_ptr())
                     .flags
-                    .store(avail_ring_flags, Ordering::Release)
+                    .store(self.to_device(avail_ring_flags), Ordering::Release)
             }
         }
     }
 
-    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// Installs an observer to be told about every descriptor chain added to or popped from the
+    /// queue and every notification sent to the device, or removes it if `None` is given.
+    ///
+    /// When no observer is installed, the only cost is checking for one. The observer stays with
+    /// this queue, so must be installed again on any queue created to replace it, e.g. by a
+    /// driver's `reset`.
+    pub fn set_observer(&mut self, observer: Option<&'static dyn QueueObserver>) {
+        self.observer = observer;
+    }
+
+    /// Enables or disables notifying the device after adding new buffers to the virtqueue.
+    ///
+    /// While disabled, [`should_notify`](Self::should_notify) always returns false without looking
+    /// at whether the device wants to be notified, so a driver can add a batch of buffers and
+    /// decide for itself when to kick the device with [`notify`](Self::notify). This is separate
+    /// from the device's own notification suppression: buffers added while disabled are still
+    /// counted, so if notifications are enabled again the next call to `should_notify` takes all of
+    /// them into account, including whether the device asked about any of them with the
+    /// `avail_event` index when `VIRTIO_F_EVENT_IDX` has been negotiated.
+    pub fn set_notify(&mut self, enabled: bool) {
+        self.notify_enabled = enabled;
+    }
+
+    /// Notifies the device about all buffers added since it was last notified, if there are any.
+    ///
+    /// Unlike [`should_notify`](Self::should_notify), this ignores both [`set_notify`] and whether
+    /// the device has suppressed notifications, as an unneeded notification is harmless.
+    ///
+    /// [`set_notify`]: Self::set_notify
+    pub fn notify(&mut self, transport: &mut impl Transport) {
+        if self.num_added.swap(0, Ordering::AcqRel) == 0 {
+            return;
+        }
+        self.notify_device(transport);
+        #[cfg(feature = "stats")]
+        self.notifications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Notifies the device about this queue, telling it the new available index too if
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    pub(crate) fn notify_device(&self, transport: &mut impl Transport) {
+        // Make sure the device sees the new available index before it is notified.
+        H::mb();
+        if let Some(observer) = self.observer {
+            observer.on_notify(self.queue_idx);
+        }
+        if transport.negotiated_features() & NOTIFICATION_DATA != 0 {
+            transport.notify_with_data(
+                self.queue_idx,
+                u32::from(self.queue_idx) | u32::from(self.avail_idx) << 16,
+            );
+        } else {
+            transport.notify(self.queue_idx);
+        }
+    }
+
+    /// Writes the `used_event` field of the available ring.
+    fn write_used_event(&mut self, used_event: u16) {
+        // SAFETY: Safe because self.avail points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of AvailRing, whose first `self.size` entries are in use and followed
+        // by `used_event`.
+        unsafe {
+            (*ptr::addr_of!((*self.avail.as_ptr()).ring)
+                .cast::<AtomicU16>()
+                .add(self.size.into()))
+            .store(self.to_device(used_event), Ordering::Release);
+        }
+    }
+
+    /// Returns whether the driver should notify the device after adding new buffers to the
     /// virtqueue.
     ///
-    /// This will be false if the device has supressed notifications.
+    /// This will be false if the device has supressed notifications. If `VIRTIO_F_EVENT_IDX` has
+    /// been negotiated, it is only true if the device asked to be notified about one of the buffers
+    /// added since the last call, so a driver can add a batch of buffers and then call this once.
+    /// It is always false while notifications are disabled with [`set_notify`](Self::set_notify).
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_split
     pub fn should_notify(&self) -> bool {
-        if self.event_idx {
+        if !self.notify_enabled {
+            // Keep counting the added buffers until notifications are enabled again.
+            return false;
+        }
+        let num_added = self.num_added.swap(0, Ordering::AcqRel);
+
+        // Make sure the device sees the new available index before we check whether it wants to
+        // be notified, or we might miss a notification.
+        H::mb();
+        let notify = if self.event_idx {
             // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event.load(Ordering::Acquire) };
-            self.avail_idx >= avail_event.wrapping_add(1)
+            // instance of UsedRing, whose first `self.size` entries are in use and followed by
+            // `avail_event`.
+            let avail_event = self.to_cpu(unsafe {
+                (*ptr::addr_of!((*self.used.as_ptr()).ring)
+                    .cast::<UsedElem>()
+                    .add(self.size.into())
+                    .cast::<AtomicU16>())
+                .load(Ordering::Acquire)
+            });
+            vring_need_event(
+                avail_event,
+                self.avail_idx,
+                self.avail_idx.wrapping_sub(num_added),
+            )
         } else {
             // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
             // instance of UsedRing.
-            unsafe { (*self.used.as_ptr()).flags.load(Ordering::Acquire) & 0x0001 == 0 }
+            let flags = unsafe { (*self.used.as_ptr()).flags.load(Ordering::Acquire) };
+            self.to_cpu(flags) & 0x0001 == 0
+        };
+        #[cfg(feature = "stats")]
+        if notify {
+            self.notifications.fetch_add(1, Ordering::Relaxed);
+        }
+        notify
+    }
+
+    /// Returns the number of bytes of DMA memory the queue currently holds, as allocated with
+    /// [`Hal::dma_alloc`].
+    ///
+    /// This includes the descriptor table and rings, and the indirect descriptor tables of any
+    /// chains in the queue. Buffers added to the queue are shared with [`Hal::share`] rather than
+    /// allocated, so aren't counted.
+    pub fn dma_footprint(&self) -> usize {
+        let footprint = self.layout.dma_footprint();
+        #[cfg(feature = "alloc")]
+        let footprint = footprint
+            + self
+                .indirect_lists
+                .iter()
+                .flatten()
+                .map(|list| list.dma.size())
+                .sum::<usize>();
+        footprint
+    }
+
+    /// Returns the number of bytes of DMA memory which
+    /// [`with_allocation`](Self::with_allocation) would allocate for the descriptor table and rings
+    /// of the given queue, without creating it.
+    ///
+    /// The size is chosen from `requested_size` and [`Transport::max_queue_size`] in the same way,
+    /// so this can be used to plan DMA usage before picking a size. Indirect descriptor tables are
+    /// allocated as chains are added, so aren't included; see [`dma_footprint`](Self::dma_footprint)
+    /// for those. Returns [`Error::InvalidParam`] if `requested_size` is 0 or the device doesn't
+    /// support the queue at all.
+    pub fn ring_footprint<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        requested_size: u16,
+        allocation: RingAllocation,
+    ) -> Result<usize> {
+        let size = queue_size(requested_size, SIZE as u16, transport.max_queue_size(idx))
+            .ok_or(Error::InvalidParam)?;
+        Ok(VirtQueueLayout::<H>::footprint_for(
+            size,
+            transport.requires_legacy_layout(),
+            allocation,
+        ))
+    }
+
+    /// Returns the counters of operations on the queue since it was created.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            notifications: self.notifications.load(Ordering::Relaxed),
+            ..self.stats
         }
     }
 
@@ -365,49 +895,242 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         // SAFETY: Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
         // else reads or writes the descriptor during this block.
         unsafe {
-            (*self.desc.as_ptr())[index] = self.desc_shadow[index].clone();
+            (*self.desc.as_ptr())[index] = self.desc_shadow[index].converted(self.endian);
         }
     }
 
     /// Returns whether there is a used element that can be popped.
     pub fn can_pop(&self) -> bool {
-        // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        self.last_used_idx != unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
+        self.num_set_aside != 0 || self.used_pending()
+    }
+
+    /// Returns whether the used ring has elements which haven't been popped or set aside yet.
+    fn used_pending(&self) -> bool {
+        self.last_used_idx != self.used_idx()
+    }
+
+    /// Reads the used ring's `idx`, which the device writes.
+    fn used_idx(&self) -> u16 {
+        // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of UsedRing.
+        self.to_cpu(unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) })
+    }
+
+    /// Converts a descriptor or ring field from the driver's byte order to the device's.
+    fn to_device<V: ConfigValue>(&self, value: V) -> V {
+        convert_endian(value, Endian::NATIVE, self.endian)
+    }
+
+    /// Converts a descriptor or ring field from the device's byte order to the driver's.
+    fn to_cpu<V: ConfigValue>(&self, value: V) -> V {
+        convert_endian(value, self.endian, Endian::NATIVE)
+    }
+
+    /// Returns a snapshot of the queue's indices and descriptor usage, for debugging.
+    ///
+    /// This only reads from the queue, and doesn't allocate or take any locks, so it can be called
+    /// from an interrupt handler or while panicking.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            queue_idx: self.queue_idx,
+            size: self.size,
+            avail_idx: self.avail_idx,
+            used_idx: self.used_idx(),
+            last_used_idx: self.last_used_idx,
+            descriptors_in_use: self.num_used,
+        }
     }
 
     /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
     /// `None` if the used ring is empty.
+    ///
+    /// Chains which a blocking wait set aside, because the device completed them while it was
+    /// waiting for a different chain, come before anything still on the used ring.
     pub fn peek_used(&self) -> Option<u16> {
-        if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-            // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable,
-            // readable instance of UsedRing.
-            Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
+        if let Some(token) = self.first_set_aside() {
+            return Some(token);
+        }
+        if self.used_pending() {
+            let last_used_slot = self.last_used_idx & (self.size - 1);
+            if self.in_order {
+                Some(self.in_order_heads[usize::from(last_used_slot)])
+            } else {
+                Some(self.read_used_elem(last_used_slot).0)
+            }
         } else {
             None
         }
     }
 
+    /// Returns the lowest token which has been set aside, if any.
+    fn first_set_aside(&self) -> Option<u16> {
+        if self.num_set_aside == 0 {
+            return None;
+        }
+        (0..self.size).find(|&token| self.set_aside[usize::from(token)].is_some())
+    }
+
+    /// Returns the token and length of the next element on the used ring, along with the in-order
+    /// batch to continue with after it. The used ring must not be empty.
+    fn next_used(&self) -> ((u16, u32), Option<(u16, u32)>) {
+        // In order, this is simply the next chain which was made available.
+        if self.in_order {
+            self.in_order_used(self.last_used_idx, self.in_order_batch)
+        } else {
+            (
+                self.read_used_elem(self.last_used_idx & (self.size - 1)),
+                None,
+            )
+        }
+    }
+
+    /// Moves past the next element on the used ring, continuing with the given in-order batch.
+    fn advance_used(&mut self, batch: Option<(u16, u32)>) {
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        // Don't carry a batch past the end of what the device has used, in case it never reported
+        // the token we are waiting for.
+        self.in_order_batch = batch.filter(|_| self.used_pending());
+
+        // Ask for a notification when the next buffer is used, unless they are suppressed.
+        if self.event_idx && self.dev_notify {
+            self.write_used_event(self.last_used_idx);
+        }
+    }
+
+    /// Returns the token and length of the used element in the given used ring slot.
+    ///
+    /// An ID too big for a token is returned as `u16::MAX`, which is never a valid token as the
+    /// queue can't have that many descriptors.
+    fn read_used_elem(&self, slot: u16) -> (u16, u32) {
+        // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of UsedRing.
+        let elem = unsafe { &(*self.used.as_ptr()).ring[usize::from(slot)] };
+        let id = self.to_cpu(elem.id);
+        (u16::try_from(id).unwrap_or(u16::MAX), self.to_cpu(elem.len))
+    }
+
+    /// Returns the token and used length of the used element at the given used index when
+    /// `VIRTIO_F_IN_ORDER` has been negotiated, along with the batch to continue with after it.
+    ///
+    /// An in-order device may write a single used element for a batch of chains, with the token
+    /// of the last one, and skip the used ring slots of the rest. The chains before the last one
+    /// were used in full.
+    fn in_order_used(
+        &self,
+        used_idx: u16,
+        batch: Option<(u16, u32)>,
+    ) -> ((u16, u32), Option<(u16, u32)>) {
+        let slot = used_idx & (self.size - 1);
+        let token = self.in_order_heads[usize::from(slot)];
+        let (last_token, last_len) = batch.unwrap_or_else(|| self.read_used_elem(slot));
+        if last_token == token {
+            ((token, last_len), None)
+        } else {
+            (
+                (token, self.writable_len[usize::from(token)]),
+                Some((last_token, last_len)),
+            )
+        }
+    }
+
+    /// Fills `out` with the token and used length of as many pending used elements as fit, in the
+    /// order the device completed them, without popping any of them. Returns the number of entries
+    /// written.
+    ///
+    /// This lets a driver find out about a whole burst of completions from a single read of the
+    /// used index. Each entry must still be popped with `pop_used`, as the buffers need to be
+    /// unshared. As for [`peek_used`](Self::peek_used), chains which were set aside come first.
+    pub fn peek_used_batch(&self, mut out: &mut [(u16, u32)]) -> usize {
+        let mut set_aside = 0;
+        for (token, len) in (0..self.size)
+            .filter_map(|token| self.set_aside[usize::from(token)].map(|len| (token, len)))
+        {
+            let Some(entry) = take_first_mut(&mut out) else {
+                return set_aside;
+            };
+            *entry = (token, len);
+            set_aside += 1;
+        }
+
+        let used_idx = self.used_idx();
+        // Don't trust the device to report more used elements than the ring can hold.
+        let pending = usize::from(used_idx.wrapping_sub(self.last_used_idx)).min(self.size.into());
+        let count = pending.min(out.len());
+
+        let mut batch = self.in_order_batch;
+        for (i, entry) in out[..count].iter_mut().enumerate() {
+            let used_idx = self.last_used_idx.wrapping_add(i as u16);
+            if self.in_order {
+                (*entry, batch) = self.in_order_used(used_idx, batch);
+            } else {
+                *entry = self.read_used_elem(used_idx & (self.size - 1));
+            }
+        }
+
+        set_aside + count
+    }
+
     /// Returns the number of free descriptors.
+    ///
+    /// When `VIRTIO_F_IN_ORDER` has been negotiated this only counts those which can be used next
+    /// in ring order, so descriptors freed by popping a chain before an older one don't count
+    /// until the older one has been popped as well.
     pub fn available_desc(&self) -> usize {
+        let free = self.free_descriptors();
         #[cfg(feature = "alloc")]
         if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
-                0
-            } else {
-                SIZE
-            };
+            return if free == 0 { 0 } else { self.size.into() };
+        }
+
+        free.into()
+    }
+
+    /// Returns the number of descriptors which the next chain can use, starting from `free_head`.
+    fn free_descriptors(&self) -> u16 {
+        if !self.in_order {
+            return self.size - self.num_used;
         }
+        // In order, descriptors are handed out in ring order and keep their links to the next
+        // descriptor, so the free ones run from `free_head` up to the head of the oldest chain
+        // still in flight, which is the first head found after `free_head`.
+        (0..self.size)
+            .find(|&i| self.in_flight[usize::from((self.free_head + i) % self.size)])
+            .unwrap_or(self.size)
+    }
+
+    /// Returns the number of chains which have been added but not popped yet, whether or not the
+    /// device has used them.
+    pub fn in_flight(&self) -> usize {
+        self.num_in_flight.into()
+    }
 
-        SIZE - usize::from(self.num_used)
+    /// Returns how many chains of `descriptors_per_chain` descriptors each the queue can hold at
+    /// once, so that callers can pace their requests rather than run into [`Error::QueueFull`].
+    ///
+    /// With indirect descriptors every chain of more than one descriptor takes a single slot in
+    /// the queue, so this is the queue size. Otherwise it is the queue size divided by the chain
+    /// length. Chains with premapped buffers never use indirect descriptors, so this may
+    /// overestimate how many of them fit.
+    pub fn max_in_flight(&self, descriptors_per_chain: usize) -> usize {
+        let size = usize::from(self.size);
+        if descriptors_per_chain == 0 || descriptors_per_chain > size {
+            return 0;
+        }
+        #[cfg(feature = "alloc")]
+        if self.indirect {
+            return size;
+        }
+        size / descriptors_per_chain
     }
 
     /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
     /// list. Unsharing may involve copying data back to the original buffers, so they must be
     /// passed in too.
     ///
-    /// This will push all linked descriptors at the front of the free list.
+    /// This will push all linked descriptors at the front of the free list. When
+    /// `VIRTIO_F_IN_ORDER` has been negotiated the free list isn't touched at all, as descriptors
+    /// are always used in ring order: a whole run of chains rejoins the free descriptors at once,
+    /// when the oldest chain before them is popped.
     ///
     /// # Safety
     ///
@@ -419,52 +1142,61 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
     ) {
+        let in_flight = &mut self.in_flight[usize::from(head)];
+        debug_assert!(
+            *in_flight,
+            "Descriptor chain {} freed twice or never added.",
+            head
+        );
+        *in_flight = false;
+        self.num_in_flight -= 1;
+
         let original_free_head = self.free_head;
-        self.free_head = head;
+        if !self.in_order {
+            self.free_head = head;
+        }
 
         let head_desc = &mut self.desc_shadow[usize::from(head)];
         if head_desc.flags.contains(DescFlags::INDIRECT) {
             #[cfg(feature = "alloc")]
             {
-                // Find the indirect descriptor list, unshare it and move its descriptor to the free
-                // list.
+                // Find the indirect descriptor list and move its descriptor to the free list.
                 let indirect_list = self.indirect_lists[usize::from(head)].take().unwrap();
-                // SAFETY: We allocated the indirect list in `add_indirect`, and the device has
-                // finished accessing it by this point.
-                let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
-                let paddr = head_desc.addr;
                 head_desc.unset_buf();
                 self.num_used -= 1;
-                head_desc.next = original_free_head;
-
-                // SAFETY: Memory at `paddr` has been shared using `H::share`
-                unsafe {
-                    H::unshare(
-                        paddr as usize,
-                        indirect_list.as_mut_bytes().into(),
-                        BufferDirection::DriverToDevice,
-                    );
+                if !self.in_order {
+                    head_desc.next = original_free_head;
                 }
 
-                // Unshare the buffers in the indirect descriptor list, and free it.
-                assert_eq!(indirect_list.len(), inputs.len() + outputs.len());
-                for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
+                // Unshare the buffers in the indirect descriptor list. Only our own copy of the
+                // list is used for this, as the device may have modified the table it was given.
+                assert_eq!(
+                    indirect_list.shadow.len(),
+                    segment_count(inputs, outputs, H::max_contiguous_dma())
+                );
+                for (desc, (buffer, direction)) in indirect_list.shadow.iter().zip(
+                    InputOutputIter::new(inputs, outputs, H::max_contiguous_dma()),
+                ) {
                     assert_ne!(buffer.len(), 0);
 
                     // SAFETY: The caller ensures that the buffer is valid and matches the
                     // descriptor from which we got `paddr`.
                     unsafe {
-                        // Unshare the buffer (and perhaps copy its contents back to the original
-                        // buffer).
-                        H::unshare(indirect_list[i].addr as usize, buffer, direction);
+                        // Sync and unshare the buffer (and perhaps copy its contents back to the
+                        // original buffer).
+                        H::sync_for_cpu(desc.addr as usize, buffer.len(), direction);
+                        H::unshare(desc.addr as usize, buffer, direction);
                     }
                 }
+                // Dropping the list frees the DMA region holding the table.
                 drop(indirect_list);
             }
         } else {
             let mut next = Some(head);
 
-            for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+            for (buffer, direction) in
+                InputOutputIter::new(inputs, outputs, H::max_contiguous_dma())
+            {
                 assert_ne!(buffer.len(), 0);
 
                 let desc_index = next.expect("Descriptor chain was shorter than expected.");
@@ -474,16 +1206,21 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 desc.unset_buf();
                 self.num_used -= 1;
                 next = desc.next();
-                if next.is_none() {
+                if next.is_none() && !self.in_order {
                     desc.next = original_free_head;
                 }
 
                 self.write_desc(desc_index);
 
+                // Buffers which were already shared stay shared.
+                if take(&mut self.premapped[usize::from(desc_index)]) {
+                    continue;
+                }
                 // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
                 // from which we got `paddr`.
                 unsafe {
-                    // Unshare the buffer (and perhaps copy its contents back to the original buffer).
+                    // Sync and unshare the buffer (and perhaps copy its contents back to the original buffer).
+                    H::sync_for_cpu(paddr as usize, buffer.len(), direction);
                     H::unshare(paddr as usize, buffer, direction);
                 }
             }
@@ -494,7 +1231,8 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
-    /// If the given token is next on the device used queue, pops it and returns the total buffer
+    /// If the given token is next on the device used queue, or was set aside by
+    /// [`add_notify_wait_pop`](Self::add_notify_wait_pop), pops it and returns the total buffer
     /// length which was used (written) by the device.
     ///
     /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
@@ -509,42 +1247,252 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'a [u8]],
         outputs: &'a mut [&'a mut [u8]],
     ) -> Result<u32> {
-        if !self.can_pop() {
+        if let Some(len) = self
+            .set_aside
+            .get_mut(usize::from(token))
+            .and_then(Option::take)
+        {
+            self.num_set_aside -= 1;
+            // SAFETY: Safe because the caller ensures the buffers are valid and match the
+            // descriptor.
+            unsafe {
+                self.recycle_descriptors(token, inputs, outputs);
+            }
+            self.record_completion(token, len);
+            return Ok(len);
+        }
+        if !self.used_pending() {
             return Err(Error::NotReady);
         }
 
         // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-        let index;
-        let len;
-        // SAFETY: Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        unsafe {
-            index = (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16;
-            len = (*self.used.as_ptr()).ring[last_used_slot as usize].len;
-        }
+        let ((index, len), batch) = self.next_used();
 
         if index != token {
             // The device used a different descriptor chain to the one we were expecting.
             return Err(Error::WrongToken);
         }
-
+        if !self
+            .in_flight
+            .get(usize::from(index))
+            .is_some_and(|&in_flight| in_flight)
+        {
+            // The device made up the token or returned a chain twice, or the caller popped a
+            // token which isn't in flight. Either way its descriptors aren't ours to recycle.
+            warn!("Used ring returned chain {} which isn't in flight", index);
+            return Err(Error::WrongToken);
+        }
+
         // SAFETY: Safe because the caller ensures the buffers are valid and match the descriptor.
         unsafe {
             self.recycle_descriptors(index, inputs, outputs);
         }
-        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        self.advance_used(batch);
+        self.record_completion(index, len);
 
-        if self.event_idx {
-            // SAFETY: `avail` is valid (ToDo)
-            unsafe {
-                (*self.avail.as_ptr())
-                    .used_event
-                    .store(self.last_used_idx, Ordering::Release);
+        Ok(len)
+    }
+
+    /// Counts a chain which has been popped, and tells the observer about it.
+    fn record_completion(&mut self, token: u16, len: u32) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.completions += 1;
+        }
+        if let Some(observer) = self.observer {
+            observer.on_complete(self.queue_idx, token, len);
+        }
+    }
+
+    /// Like [`pop_used`](Self::pop_used), but also returns the time from [`Hal::now`] at which the
+    /// chain was added, so that the caller can work out how long it spent in flight.
+    ///
+    /// # Safety
+    ///
+    /// As for [`pop_used`](Self::pop_used).
+    pub unsafe fn pop_used_timed<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<(u32, u64)> {
+        // SAFETY: The caller ensures the buffers are valid and match the descriptor.
+        let len = unsafe { self.pop_used(token, inputs, outputs) }?;
+        Ok((len, self.submitted_at[usize::from(token)]))
+    }
+
+    /// Like [`pop_used`](Self::pop_used), but also returns the cookie which was passed to
+    /// [`add_with_cookie`](Self::add_with_cookie) for the chain, or 0 if it was added some other
+    /// way.
+    ///
+    /// # Safety
+    ///
+    /// As for [`pop_used`](Self::pop_used).
+    pub unsafe fn pop_used_with_cookie<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<(u32, u64)> {
+        // SAFETY: The caller ensures the buffers are valid and match the descriptor.
+        let len = unsafe { self.pop_used(token, inputs, outputs) }?;
+        Ok((len, self.cookies[usize::from(token)]))
+    }
+
+    /// Abandons every descriptor chain still in flight, releasing its buffers without copying
+    /// anything back, and returns the queue to the state it was in when it was created.
+    ///
+    /// `abandoned` is called with the token of each chain, after its buffers have been released,
+    /// so that the caller can clean up whatever it associated with the request. Afterwards all
+    /// descriptors are free and the available and used rings are empty, so the queue can be set up
+    /// on the device again.
+    ///
+    /// # Safety
+    ///
+    /// The device must have been reset, or have had the queue disabled, so that it no longer
+    /// accesses the queue or any of the buffers in it.
+    pub unsafe fn abort_all(&mut self, mut abandoned: impl FnMut(u16)) {
+        let heads = self.in_flight;
+
+        // SAFETY: Our caller promises that the device is no longer accessing the buffers.
+        unsafe { self.abandon_buffers() };
+        #[cfg(feature = "alloc")]
+        {
+            self.indirect_lists = [const { None }; SIZE];
+        }
+        self.desc_shadow = FromZeros::new_zeroed();
+        for i in 0..self.size {
+            self.desc_shadow[usize::from(i)].next = (i + 1) % self.size;
+            self.write_desc(i);
+        }
+        self.premapped = [false; SIZE];
+        self.num_used = 0;
+        self.free_head = 0;
+        self.avail_idx = 0;
+        self.last_used_idx = 0;
+        self.num_added.store(0, Ordering::Relaxed);
+        self.in_order_batch = None;
+        self.in_flight = [false; SIZE];
+        self.num_in_flight = 0;
+        self.set_aside = [None; SIZE];
+        self.num_set_aside = 0;
+        // SAFETY: Safe because self.avail and self.used point to valid, aligned, initialised,
+        // dereferenceable instances of AvailRing and UsedRing, which the device isn't accessing.
+        unsafe {
+            (*self.avail.as_ptr()).idx.store(0, Ordering::Release);
+            (*self.used.as_ptr()).idx.store(0, Ordering::Release);
+            (*self.used.as_ptr()).flags.store(0, Ordering::Release);
+            (*self.used.as_ptr())
+                .avail_event
+                .store(0, Ordering::Release);
+        }
+        self.set_dev_notify(self.dev_notify);
+
+        for token in (0..self.size).filter(|&token| heads[usize::from(token)]) {
+            abandoned(token);
+        }
+    }
+
+    /// Resets the queue on the device without resetting the rest of the device, and then sets it
+    /// up again in place, with the same size and DMA memory.
+    ///
+    /// Chains still in flight are abandoned as by [`abort_all`](Self::abort_all), with
+    /// `abandoned` called with the token of each. This can be used to recover a single queue
+    /// which the device has stopped processing.
+    ///
+    /// Returns [`Error::Unsupported`], leaving the queue as it was, if `VIRTIO_F_RING_RESET`
+    /// hasn't been negotiated.
+    pub fn reset(&mut self, transport: &mut impl Transport, abandoned: impl FnMut(u16)) -> Result {
+        if transport.negotiated_features() & RING_RESET == 0 {
+            return Err(Error::Unsupported);
+        }
+        transport.reset_queue(self.queue_idx)?;
+        // SAFETY: The device has finished resetting the queue, so it no longer accesses the queue
+        // or any of the buffers in it.
+        unsafe { self.abort_all(abandoned) };
+        transport.queue_set(
+            self.queue_idx,
+            self.size.into(),
+            self.layout.descriptors_paddr(),
+            self.layout.driver_area_paddr(),
+            self.layout.device_area_paddr(),
+        );
+        Ok(())
+    }
+}
+
+impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
+    /// Releases the buffers of all chains which were never popped. Buffers which have been popped
+    /// already had their descriptors cleared, so they won't be unshared again.
+    ///
+    /// # Safety
+    ///
+    /// The device must no longer be accessing any of the buffers.
+    unsafe fn abandon_buffers(&mut self) {
+        #[cfg(feature = "alloc")]
+        for indirect_list in self.indirect_lists.iter_mut().flatten() {
+            for desc in indirect_list.shadow.iter_mut() {
+                // SAFETY: The descriptor was set by `set_buf`, and our caller promises that the
+                // device is done with it.
+                unsafe { desc.abandon_buf::<H>() };
+            }
+        }
+        for (desc, premapped) in self.desc_shadow.iter_mut().zip(&self.premapped) {
+            // Indirect tables are freed along with `indirect_lists`, and premapped buffers were
+            // never shared by the queue.
+            if *premapped {
+                desc.unset_buf();
+            } else if !desc.flags.contains(DescFlags::INDIRECT) {
+                // SAFETY: As above.
+                unsafe { desc.abandon_buf::<H>() };
             }
         }
+    }
 
-        Ok(len)
+    /// Checks that every descriptor is either on the free list or part of exactly one chain which
+    /// is in flight, and panics if not.
+    #[cfg(debug_assertions)]
+    fn check_leaks(&self) {
+        let mut seen = [false; SIZE];
+        let mut mark = |index: u16| {
+            let seen = &mut seen[usize::from(index)];
+            assert!(!*seen, "Descriptor {} is in more than one chain.", index);
+            *seen = true;
+        };
+        // In order, free descriptors aren't kept on a list, so they can only be counted.
+        let mut free = usize::from(self.size - self.num_used);
+        if !self.in_order {
+            let mut next = self.free_head;
+            for _ in 0..free {
+                mark(next);
+                next = self.desc_shadow[usize::from(next)].next;
+            }
+            free = 0;
+        }
+        for head in (0..self.size).filter(|&head| self.in_flight[usize::from(head)]) {
+            let mut index = Some(head);
+            while let Some(i) = index {
+                mark(i);
+                index = self.desc_shadow[usize::from(i)].next();
+            }
+        }
+        let leaked = seen[..usize::from(self.size)]
+            .iter()
+            .filter(|&&seen| !seen)
+            .count()
+            .saturating_sub(free);
+        assert_eq!(leaked, 0, "{} descriptors leaked.", leaked);
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Drop for VirtQueue<H, SIZE> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.check_leaks();
+
+        // SAFETY: The device must have been reset or had the queue disabled before the queue is
+        // dropped.
+        unsafe { self.abandon_buffers() };
     }
 }
 
@@ -555,6 +1503,130 @@ unsafe impl<H: Hal, const SIZE: usize> Send for VirtQueue<H, SIZE> {}
 // data race.
 unsafe impl<H: Hal, const SIZE: usize> Sync for VirtQueue<H, SIZE> {}
 
+/// Receives the stream of operations on a [`VirtQueue`], once installed with
+/// [`VirtQueue::set_observer`], e.g. to trace them while debugging a device.
+///
+/// Unlike [`QueueStats`] this sees each operation, so a failing exchange with a device can be
+/// reconstructed. The methods are called synchronously from the queue operations, so should be
+/// quick. They do nothing by default.
+pub trait QueueObserver: Sync {
+    /// Called after a descriptor chain has been added to the available ring of the queue with the
+    /// given index, with its token and the total length of its buffers.
+    fn on_submit(&self, queue_idx: u16, token: u16, len: usize) {
+        let _ = (queue_idx, token, len);
+    }
+
+    /// Called before the device is notified about the queue with the given index.
+    fn on_notify(&self, queue_idx: u16) {
+        let _ = queue_idx;
+    }
+
+    /// Called after a descriptor chain has been popped from the used ring of the queue with the
+    /// given index, with its token and the length the device reported writing.
+    fn on_complete(&self, queue_idx: u16, token: u16, len: u32) {
+        let _ = (queue_idx, token, len);
+    }
+
+    /// Called when a blocking wait for the chain `expected` on the queue with the given index found
+    /// that the device completed the chain `token` first, with the length it reported writing, and
+    /// set it aside to be popped later.
+    fn on_set_aside(&self, queue_idx: u16, expected: u16, token: u16, len: u32) {
+        let _ = (queue_idx, expected, token, len);
+    }
+}
+
+impl fmt::Debug for dyn QueueObserver {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("QueueObserver")
+    }
+}
+
+/// The state of a virtqueue at some point, as returned by [`VirtQueue::snapshot`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueSnapshot {
+    /// The index of the queue on the device.
+    pub queue_idx: u16,
+    /// The number of descriptors in the queue.
+    pub size: u16,
+    /// The index of the next available ring entry the driver will write.
+    pub avail_idx: u16,
+    /// The index of the next used ring entry the device will write.
+    pub used_idx: u16,
+    /// The index of the next used ring entry the driver will pop.
+    pub last_used_idx: u16,
+    /// The number of descriptors which aren't on the free list.
+    pub descriptors_in_use: u16,
+}
+
+impl Display for QueueSnapshot {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "queue {}: avail {} used {} last used {}, {}/{} descriptors in use",
+            self.queue_idx,
+            self.avail_idx,
+            self.used_idx,
+            self.last_used_idx,
+            self.descriptors_in_use,
+            self.size
+        )
+    }
+}
+
+/// Counters of operations on a virtqueue, or on all the virtqueues of a device.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// The number of buffers (descriptor chains) added to the queue.
+    pub submissions: u64,
+    /// The number of buffers popped from the queue after the device used them.
+    pub completions: u64,
+    /// The number of times the driver was told to notify the device.
+    pub notifications: u64,
+    /// The number of times a buffer couldn't be added because the queue was full.
+    pub queue_full: u64,
+}
+
+#[cfg(feature = "stats")]
+impl Add for QueueStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            submissions: self.submissions + other.submissions,
+            completions: self.completions + other.completions,
+            notifications: self.notifications + other.notifications,
+            queue_full: self.queue_full + other.queue_full,
+        }
+    }
+}
+
+/// Returns the queue size to use for a requested size, given the driver's and the device's maxima.
+///
+/// The requested size is rounded up to a power of 2, and then clamped to `driver_max` (which must
+/// be a power of 2) and to `device_max` rounded down to a power of 2. Returns `None` if either the
+/// requested size or the device maximum is 0.
+fn queue_size(requested: u16, driver_max: u16, device_max: u32) -> Option<u16> {
+    if requested == 0 || device_max == 0 {
+        return None;
+    }
+    let requested = requested.checked_next_power_of_two().unwrap_or(1 << 15);
+    let device_max = 1 << device_max.min(u16::MAX.into()).ilog2();
+    Some(requested.min(driver_max).min(device_max))
+}
+
+/// How the descriptor table and rings of a [`VirtQueue`] are allocated, for transports which
+/// allow them to be in separate regions.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RingAllocation {
+    /// One region for the descriptor table and available ring, and another for the used ring, so
+    /// that the HAL knows which direction each is used in.
+    #[default]
+    Combined,
+    /// A separate region for each of the descriptor table, available ring and used ring.
+    PerArea,
+}
+
 /// The inner layout of a VirtQueue.
 ///
 /// Ref: 2.6 Split Virtqueues
@@ -574,6 +1646,15 @@ enum VirtQueueLayout<H: Hal> {
         /// (available ring).
         avail_offset: usize,
     },
+    /// Each area in its own region.
+    PerArea {
+        /// The region used for the descriptor area.
+        descriptors_dma: Dma<H>,
+        /// The region used for the driver area.
+        driver_dma: Dma<H>,
+        /// The region used for the device area.
+        device_dma: Dma<H>,
+    },
 }
 
 impl<H: Hal> VirtQueueLayout<H> {
@@ -585,7 +1666,7 @@ impl<H: Hal> VirtQueueLayout<H> {
         let (desc, avail, used) = queue_part_sizes(queue_size);
         let size = align_up(desc + avail) + align_up(used);
         // Allocate contiguous pages.
-        let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
+        let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both, DmaPurpose::Ring)?;
         Ok(Self::Legacy {
             dma,
             avail_offset: desc,
@@ -600,8 +1681,16 @@ impl<H: Hal> VirtQueueLayout<H> {
     /// and allows the HAL to know which DMA regions are used in which direction.
     fn allocate_flexible(queue_size: u16) -> Result<Self> {
         let (desc, avail, used) = queue_part_sizes(queue_size);
-        let driver_to_device_dma = Dma::new(pages(desc + avail), BufferDirection::DriverToDevice)?;
-        let device_to_driver_dma = Dma::new(pages(used), BufferDirection::DeviceToDriver)?;
+        let driver_to_device_dma = Dma::new(
+            pages(desc + avail),
+            BufferDirection::DriverToDevice,
+            DmaPurpose::Ring,
+        )?;
+        let device_to_driver_dma = Dma::new(
+            pages(used),
+            BufferDirection::DeviceToDriver,
+            DmaPurpose::Ring,
+        )?;
         Ok(Self::Modern {
             driver_to_device_dma,
             device_to_driver_dma,
@@ -609,6 +1698,64 @@ impl<H: Hal> VirtQueueLayout<H> {
         })
     }
 
+    /// Allocates a separate DMA region for each part of the virtqueue, so that no allocation is
+    /// bigger than the largest part.
+    ///
+    /// The descriptor table, available ring and used ring only need to be contiguous in
+    /// themselves.
+    ///
+    /// Ref: 2.6 Split Virtqueues
+    fn allocate_per_area(queue_size: u16) -> Result<Self> {
+        let (desc, avail, used) = queue_part_sizes(queue_size);
+        Ok(Self::PerArea {
+            descriptors_dma: Dma::new(
+                pages(desc),
+                BufferDirection::DriverToDevice,
+                DmaPurpose::Ring,
+            )?,
+            driver_dma: Dma::new(
+                pages(avail),
+                BufferDirection::DriverToDevice,
+                DmaPurpose::Ring,
+            )?,
+            device_dma: Dma::new(
+                pages(used),
+                BufferDirection::DeviceToDriver,
+                DmaPurpose::Ring,
+            )?,
+        })
+    }
+
+    /// Returns the total size in bytes of the DMA regions which would be allocated for a queue of
+    /// the given size, matching the choice made in [`VirtQueue::with_allocation`].
+    fn footprint_for(queue_size: u16, legacy: bool, allocation: RingAllocation) -> usize {
+        let (desc, avail, used) = queue_part_sizes(queue_size);
+        if legacy {
+            align_up(desc + avail) + align_up(used)
+        } else if allocation == RingAllocation::PerArea {
+            (pages(desc) + pages(avail) + pages(used)) * PAGE_SIZE
+        } else {
+            (pages(desc + avail) + pages(used)) * PAGE_SIZE
+        }
+    }
+
+    /// Returns the total size in bytes of the DMA regions.
+    fn dma_footprint(&self) -> usize {
+        match self {
+            Self::Legacy { dma, .. } => dma.size(),
+            Self::Modern {
+                driver_to_device_dma,
+                device_to_driver_dma,
+                ..
+            } => driver_to_device_dma.size() + device_to_driver_dma.size(),
+            Self::PerArea {
+                descriptors_dma,
+                driver_dma,
+                device_dma,
+            } => descriptors_dma.size() + driver_dma.size() + device_dma.size(),
+        }
+    }
+
     /// Returns the physical address of the descriptor area.
     fn descriptors_paddr(&self) -> PhysAddr {
         match self {
@@ -617,6 +1764,9 @@ impl<H: Hal> VirtQueueLayout<H> {
                 driver_to_device_dma,
                 ..
             } => driver_to_device_dma.paddr(),
+            Self::PerArea {
+                descriptors_dma, ..
+            } => descriptors_dma.paddr(),
         }
     }
 
@@ -628,6 +1778,9 @@ impl<H: Hal> VirtQueueLayout<H> {
                 driver_to_device_dma,
                 ..
             } => driver_to_device_dma.vaddr(0),
+            Self::PerArea {
+                descriptors_dma, ..
+            } => descriptors_dma.vaddr(0),
         }
     }
 
@@ -642,6 +1795,7 @@ impl<H: Hal> VirtQueueLayout<H> {
                 avail_offset,
                 ..
             } => driver_to_device_dma.paddr() + avail_offset,
+            Self::PerArea { driver_dma, .. } => driver_dma.paddr(),
         }
     }
 
@@ -656,6 +1810,7 @@ impl<H: Hal> VirtQueueLayout<H> {
                 avail_offset,
                 ..
             } => driver_to_device_dma.vaddr(*avail_offset),
+            Self::PerArea { driver_dma, .. } => driver_dma.vaddr(0),
         }
     }
 
@@ -669,6 +1824,7 @@ impl<H: Hal> VirtQueueLayout<H> {
                 device_to_driver_dma,
                 ..
             } => device_to_driver_dma.paddr(),
+            Self::PerArea { device_dma, .. } => device_dma.paddr(),
         }
     }
 
@@ -682,8 +1838,116 @@ impl<H: Hal> VirtQueueLayout<H> {
                 device_to_driver_dma,
                 ..
             } => device_to_driver_dma.vaddr(0),
+            Self::PerArea { device_dma, .. } => device_dma.vaddr(0),
+        }
+    }
+}
+
+/// An indirect descriptor table, along with the driver's own copy of it.
+///
+/// Ref: 2.7.5.3 Indirect Descriptors
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+struct IndirectList<H: Hal> {
+    /// The DMA region containing the table which the device reads.
+    dma: Dma<H>,
+    /// Our trusted copy of the table that the device can't access.
+    shadow: Box<[Descriptor]>,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Hal> IndirectList<H> {
+    /// Allocates a zeroed indirect descriptor table with room for `len` descriptors.
+    fn new(len: usize) -> Result<Self> {
+        let dma = Dma::new(
+            pages(len * size_of::<Descriptor>()),
+            BufferDirection::DriverToDevice,
+            DmaPurpose::IndirectTable,
+        )?;
+        let shadow = <[Descriptor]>::new_box_zeroed_with_elems(len).unwrap();
+        Ok(Self { dma, shadow })
+    }
+
+    /// Returns the length in bytes of the table.
+    fn len_bytes(&self) -> usize {
+        self.shadow.as_bytes().len()
+    }
+
+    /// Copies `shadow` to the DMA region in the given byte order, so it can be seen by the device.
+    fn write_table(&mut self, endian: Endian) {
+        let table = self.dma.vaddr(0).cast::<Descriptor>();
+        for (i, desc) in self.shadow.iter().enumerate() {
+            // SAFETY: The DMA region is at least as big as the shadow table, page aligned, and not
+            // accessed by the device until the head descriptor pointing to it is made available.
+            unsafe { table.add(i).write(desc.converted(endian)) };
+        }
+    }
+}
+
+/// Returns [`Error::DeviceRemoved`] if the device has gone away, or [`Error::DeviceNeedsReset`] if
+/// it needs a reset.
+///
+/// Removal is checked first, as the status of a removed device may read as needing a reset, and
+/// again after a reset is seen in case the device went away in between.
+pub(crate) fn check_usable(transport: &impl Transport) -> Result {
+    if !transport.is_present() {
+        Err(Error::DeviceRemoved)
+    } else if transport.needs_reset() {
+        if transport.is_present() {
+            Err(Error::DeviceNeedsReset)
+        } else {
+            Err(Error::DeviceRemoved)
+        }
+    } else {
+        Ok(())
+    }
+}
+
+/// Spins until `done` returns true, checking every [`NEEDS_RESET_POLL_INTERVAL`] polls whether the
+/// device has been removed or needs a reset.
+///
+/// If it has been removed [`Error::DeviceRemoved`] is returned. If it needs a reset the device is
+/// reset, so that it stops using any buffers in flight, and marked as failed so that it keeps
+/// reporting that it needs a reset, and [`Error::DeviceNeedsReset`] is returned.
+fn wait_unless_needs_reset(
+    transport: &mut impl Transport,
+    mut done: impl FnMut() -> bool,
+) -> Result {
+    let mut polls = 0usize;
+    while !done() {
+        polls = polls.wrapping_add(1);
+        if polls % NEEDS_RESET_POLL_INTERVAL == 0 {
+            match check_usable(transport) {
+                Ok(()) => {}
+                Err(Error::DeviceNeedsReset) => {
+                    warn!(
+                        "{:?} device needs a reset, abandoning the request",
+                        transport.device_type()
+                    );
+                    transport.set_status(DeviceStatus::empty());
+                    transport.set_status(DeviceStatus::FAILED);
+                    return Err(Error::DeviceNeedsReset);
+                }
+                Err(e) => {
+                    warn!(
+                        "{:?} device has been removed, abandoning the request",
+                        transport.device_type()
+                    );
+                    return Err(e);
+                }
+            }
         }
+        spin_loop();
     }
+    Ok(())
+}
+
+/// Returns whether an event index requires a notification, after the index it applies to has
+/// moved from `old` to `new`.
+///
+/// Ref: linux virtio_ring.h vring_need_event
+fn vring_need_event(event_idx: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event_idx).wrapping_sub(1) < new.wrapping_sub(old)
 }
 
 /// Returns the size in bytes of the descriptor table, available ring and used ring for a given
@@ -723,11 +1987,27 @@ impl Descriptor {
         direction: BufferDirection,
         extra_flags: DescFlags,
     ) {
-        // SAFETY: Safe because our caller promises that the buffer is valid.
-        unsafe {
-            self.addr = H::share(buf, direction) as u64;
-        }
-        self.len = buf.len().try_into().unwrap();
+        // SAFETY: Safe because our caller promises that the buffer is valid, and the address
+        // passed to `sync_for_device` was just returned by `share`.
+        let paddr = unsafe {
+            let paddr = H::share(buf, direction);
+            H::sync_for_device(paddr, buf.len(), direction);
+            paddr
+        };
+        self.set_addr(paddr, buf.len(), direction, extra_flags);
+    }
+
+    /// Sets the buffer address, length and flags, for a buffer which is already shared with the
+    /// device.
+    fn set_addr(
+        &mut self,
+        paddr: PhysAddr,
+        len: usize,
+        direction: BufferDirection,
+        extra_flags: DescFlags,
+    ) {
+        self.addr = paddr as u64;
+        self.len = len.try_into().unwrap();
         self.flags = extra_flags
             | match direction {
                 BufferDirection::DeviceToDriver => DescFlags::WRITE,
@@ -738,6 +2018,14 @@ impl Descriptor {
             };
     }
 
+    /// Points the descriptor at the given indirect descriptor table.
+    #[cfg(feature = "alloc")]
+    fn set_table<H: Hal>(&mut self, table: &IndirectList<H>) {
+        self.addr = table.dma.paddr() as u64;
+        self.len = table.len_bytes().try_into().unwrap();
+        self.flags = DescFlags::INDIRECT;
+    }
+
     /// Sets the buffer address and length to 0.
     ///
     /// This must only be called once the device has finished using the descriptor.
@@ -746,6 +2034,45 @@ impl Descriptor {
         self.len = 0;
     }
 
+    /// If the descriptor still points to a shared buffer, unshares it without copying anything
+    /// back and clears the descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The descriptor must have been set by `set_buf`, and the device must no longer be accessing
+    /// the buffer.
+    unsafe fn abandon_buf<H: Hal>(&mut self) {
+        if self.addr == 0 {
+            return;
+        }
+        let direction = if self.flags.contains(DescFlags::WRITE) {
+            BufferDirection::DeviceToDriver
+        } else {
+            BufferDirection::DriverToDevice
+        };
+        // SAFETY: Our caller promises that the address and length were set by `set_buf` from the
+        // value returned by `share`, and that the device is done with it.
+        unsafe {
+            H::unshare_abandoned(self.addr as usize, self.len as usize, direction);
+        }
+        self.unset_buf();
+    }
+
+    /// Returns a copy of the descriptor with its fields converted between the driver's byte order
+    /// and the given one, which is the same conversion in either direction.
+    fn converted(&self, endian: Endian) -> Self {
+        Self {
+            addr: convert_endian(self.addr, Endian::NATIVE, endian),
+            len: convert_endian(self.len, Endian::NATIVE, endian),
+            flags: DescFlags::from_bits_retain(convert_endian(
+                self.flags.bits(),
+                Endian::NATIVE,
+                endian,
+            )),
+            next: convert_endian(self.next, Endian::NATIVE, endian),
+        }
+    }
+
     /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
     /// if it is not (and thus this descriptor is the end of the chain).
     fn next(&self) -> Option<u16> {
@@ -808,11 +2135,23 @@ struct UsedElem {
 struct InputOutputIter<'a, 'b> {
     inputs: &'a [&'b [u8]],
     outputs: &'a mut [&'b mut [u8]],
+    /// The size of the windows which each segment must stay within, from
+    /// `Hal::max_contiguous_dma`.
+    window: usize,
+    /// The rest of the current buffer, after the segments already returned.
+    rest: Option<(NonNull<[u8]>, BufferDirection)>,
 }
 
 impl<'a, 'b> InputOutputIter<'a, 'b> {
-    fn new(inputs: &'a [&'b [u8]], outputs: &'a mut [&'b mut [u8]]) -> Self {
-        Self { inputs, outputs }
+    /// Iterates over the segments of the given buffers, inputs first, splitting any buffer which
+    /// crosses a multiple of `window` into a segment for each window.
+    fn new(inputs: &'a [&'b [u8]], outputs: &'a mut [&'b mut [u8]], window: usize) -> Self {
+        Self {
+            inputs,
+            outputs,
+            window,
+            rest: None,
+        }
     }
 }
 
@@ -820,15 +2159,50 @@ impl Iterator for InputOutputIter<'_, '_> {
     type Item = (NonNull<[u8]>, BufferDirection);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(input) = take_first(&mut self.inputs) {
-            Some(((*input).into(), BufferDirection::DriverToDevice))
+        let (buffer, direction) = if let Some(rest) = self.rest.take() {
+            rest
+        } else if let Some(input) = take_first(&mut self.inputs) {
+            ((*input).into(), BufferDirection::DriverToDevice)
         } else {
             let output = take_first_mut(&mut self.outputs)?;
-            Some(((*output).into(), BufferDirection::DeviceToDriver))
+            ((*output).into(), BufferDirection::DeviceToDriver)
+        };
+        let start = buffer.cast::<u8>();
+        let len = window_remaining(start.as_ptr() as usize, self.window).min(buffer.len());
+        if len < buffer.len() {
+            // SAFETY: `len` is less than the length of the buffer, so the rest of it starts within
+            // the same allocation.
+            let rest_start = unsafe { start.add(len) };
+            self.rest = Some((
+                nonnull_slice_from_raw_parts(rest_start, buffer.len() - len),
+                direction,
+            ));
         }
+        Some((nonnull_slice_from_raw_parts(start, len), direction))
     }
 }
 
+/// Returns how many bytes there are from `addr` to the next multiple of `window`.
+fn window_remaining(addr: usize, window: usize) -> usize {
+    window - addr % window
+}
+
+/// Returns how many descriptors the given buffers need, when each must stay within windows of the
+/// given size as for [`InputOutputIter`].
+///
+/// Empty buffers are counted as one descriptor, so that they are caught when they are added.
+fn segment_count(inputs: &[&[u8]], outputs: &[&mut [u8]], window: usize) -> usize {
+    inputs
+        .iter()
+        .map(|buffer| &**buffer)
+        .chain(outputs.iter().map(|buffer| &**buffer))
+        .map(|buffer| {
+            let first = window_remaining(buffer.as_ptr() as usize, window);
+            1 + buffer.len().saturating_sub(first).div_ceil(window)
+        })
+        .sum()
+}
+
 // TODO: Use `slice::take_first` once it is stable
 // (https://github.com/rust-lang/rust/issues/62280).
 fn take_first<'a, T>(slice: &mut &'a [T]) -> Option<&'a T> {
@@ -847,35 +2221,40 @@ fn take_first_mut<'a, T>(slice: &mut &'a mut [T]) -> Option<&'a mut T> {
 
 /// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
 ///
-/// The fake device always uses descriptors in order.
+/// The fake device always uses descriptors in order. The handler is passed the request and the
+/// total length of the device-writable buffers. The used length reported for the chain is
+/// `used_len` if given, otherwise the total length of the request and response.
 ///
 /// Returns true if a descriptor chain was available and processed, or false if no descriptors were
 /// available.
-#[cfg(test)]
+#[cfg(any(test, feature = "test-utils"))]
 pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
     descriptors: *const [Descriptor; QUEUE_SIZE],
     queue_driver_area: *const u8,
     queue_device_area: *mut u8,
-    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+    used_len: Option<u32>,
+    handler: impl FnOnce(Vec<u8>, usize) -> Vec<u8>,
 ) -> bool {
     use core::{ops::Deref, slice};
 
     let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
     let used_ring = queue_device_area as *mut UsedRing<QUEUE_SIZE>;
+    // The fake transport isn't legacy, so everything in the queue is little endian.
+    let read_descriptor = |descriptor: &Descriptor| descriptor.converted(Endian::Little);
 
     // SAFETY: Safe because the various pointers are properly aligned, dereferenceable, initialised, and
     // nothing else accesses them during this block.
     unsafe {
         // Make sure there is actually at least one descriptor available to read from.
-        if (*available_ring).idx.load(Ordering::Acquire) == (*used_ring).idx.load(Ordering::Acquire)
-        {
+        let used_idx = u16::from_le((*used_ring).idx.load(Ordering::Acquire));
+        if u16::from_le((*available_ring).idx.load(Ordering::Acquire)) == used_idx {
             return false;
         }
         // The fake device always uses descriptors in order, like VIRTIO_F_IN_ORDER, so
         // `used_ring.idx` marks the next descriptor we should take from the available ring.
-        let next_slot = (*used_ring).idx.load(Ordering::Acquire) & (QUEUE_SIZE as u16 - 1);
-        let head_descriptor_index = (*available_ring).ring[next_slot as usize];
-        let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
+        let next_slot = used_idx & (QUEUE_SIZE as u16 - 1);
+        let head_descriptor_index = u16::from_le((*available_ring).ring[next_slot as usize]);
+        let mut descriptor = read_descriptor(&(*descriptors)[head_descriptor_index as usize]);
 
         let input_length;
         let output;
@@ -892,6 +2271,10 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
                 ))
                 .unwrap(),
             );
+            let indirect_descriptor_list: Vec<Descriptor> = indirect_descriptor_list
+                .iter()
+                .map(read_descriptor)
+                .collect();
             let mut input = Vec::new();
             let mut indirect_descriptor_index = 0;
             while indirect_descriptor_index < indirect_descriptor_list.len() {
@@ -908,9 +2291,13 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
                 indirect_descriptor_index += 1;
             }
             input_length = input.len();
+            let writable_length = indirect_descriptor_list[indirect_descriptor_index..]
+                .iter()
+                .map(|descriptor| descriptor.len as usize)
+                .sum();
 
             // Let the test handle the request.
-            output = handler(input);
+            output = handler(input, writable_length);
 
             // Write the response to the remaining descriptors.
             let mut remaining_output = output.deref();
@@ -939,15 +2326,26 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
                 ));
 
                 if let Some(next) = descriptor.next() {
-                    descriptor = &(*descriptors)[next as usize];
+                    descriptor = read_descriptor(&(*descriptors)[next as usize]);
                 } else {
                     break;
                 }
             }
             input_length = input.len();
+            let mut writable_length = 0;
+            if descriptor.flags.contains(DescFlags::WRITE) {
+                let mut writable = descriptor.clone();
+                loop {
+                    writable_length += writable.len as usize;
+                    match writable.next() {
+                        Some(next) => writable = read_descriptor(&(*descriptors)[next as usize]),
+                        None => break,
+                    }
+                }
+            }
 
             // Let the test handle the request.
-            output = handler(input);
+            output = handler(input, writable_length);
 
             // Write the response to the remaining descriptors.
             let mut remaining_output = output.deref();
@@ -964,7 +2362,7 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
                     remaining_output = &remaining_output[length_to_write..];
 
                     if let Some(next) = descriptor.next() {
-                        descriptor = &(*descriptors)[next as usize];
+                        descriptor = read_descriptor(&(*descriptors)[next as usize]);
                     } else {
                         break;
                     }
@@ -974,9 +2372,13 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
         }
 
         // Mark the buffer as used.
-        (*used_ring).ring[next_slot as usize].id = head_descriptor_index.into();
-        (*used_ring).ring[next_slot as usize].len = (input_length + output.len()) as u32;
-        (*used_ring).idx.fetch_add(1, Ordering::AcqRel);
+        (*used_ring).ring[next_slot as usize].id = u32::from(head_descriptor_index).to_le();
+        (*used_ring).ring[next_slot as usize].len = used_len
+            .unwrap_or((input_length + output.len()) as u32)
+            .to_le();
+        (*used_ring)
+            .idx
+            .store(used_idx.wrapping_add(1).to_le(), Ordering::Release);
 
         true
     }
@@ -985,17 +2387,18 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "alloc")]
+    use crate::DmaBuffer;
     use crate::{
         device::common::Feature,
-        hal::fake::FakeHal,
+        test_utils::{FakeHal, FakeTransport, State, TrackingHal},
         transport::{
-            fake::{FakeTransport, QueueStatus, State},
             mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
             DeviceType,
         },
     };
     use core::ptr::NonNull;
-    use std::sync::{Arc, Mutex};
+    use std::sync::Mutex;
 
     #[test]
     fn queue_too_big() {
@@ -1004,9 +2407,77 @@ mod tests {
         let mut transport =
             unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
         assert_eq!(
-            VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).unwrap_err(),
+            VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false, false).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn queue_size_clamping() {
+        assert_eq!(queue_size(0, 16, 16), None);
+        assert_eq!(queue_size(4, 16, 0), None);
+        // Rounded up to a power of two.
+        assert_eq!(queue_size(5, 16, 16), Some(8));
+        assert_eq!(queue_size(8, 16, 16), Some(8));
+        // Limited by the driver.
+        assert_eq!(queue_size(32, 16, 256), Some(16));
+        // Limited by the device, rounded down to a power of two.
+        assert_eq!(queue_size(16, 16, 12), Some(8));
+        assert_eq!(queue_size(u16::MAX, 1 << 15, u32::MAX), Some(1 << 15));
+    }
+
+    /// Tests that a queue smaller than `SIZE` is laid out for its actual size.
+    #[test]
+    fn with_size() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            6,
+            2,
+            Feature::RING_EVENT_IDX.bits(),
+            &mut config_space,
+        );
+        assert_eq!(
+            VirtQueue::<FakeHal, 16>::with_size(&mut transport, 1, 0, false, true, false)
+                .unwrap_err(),
             Error::InvalidParam
         );
+        let mut queue =
+            VirtQueue::<FakeHal, 16>::with_size(&mut transport, 0, 12, false, true, false).unwrap();
+        assert_eq!(queue.size(), 4);
+        assert_eq!(state.lock().unwrap().queues[0].size, 4);
+        assert_eq!(queue.available_desc(), 4);
+
+        let mut tokens = [0; 4];
+        for token in &mut tokens {
+            *token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        }
+        assert_eq!(
+            unsafe { queue.add(&[&[42]], &mut []) },
+            Err(Error::QueueFull)
+        );
+
+        // The device finds the buffers and writes the used ring where a queue of size 4 has them.
+        assert!(State::read_from_queue::<4>(&mut state.lock().unwrap(), 0) == [42]);
+        assert_eq!(queue.peek_used(), Some(tokens[0]));
+        assert_eq!(
+            unsafe { queue.pop_used(tokens[0], &[&[42]], &mut []) },
+            Ok(1)
+        );
+
+        // The used_event and avail_event fields follow the 4 ring entries.
+        let driver_area = state.lock().unwrap().queues[0].driver_area;
+        let device_area = state.lock().unwrap().queues[0].device_area;
+        // SAFETY: The rings were allocated for a queue of size 4, and are properly aligned and
+        // initialised.
+        unsafe {
+            let avail = driver_area as *const AvailRing<4>;
+            assert_eq!(u16::from_le((*avail).used_event.load(Ordering::Acquire)), 1);
+            let used = device_area as *const UsedRing<4>;
+            (*used).avail_event.store(1u16.to_le(), Ordering::Release);
+        }
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert!(queue.should_notify());
     }
 
     #[test]
@@ -1015,9 +2486,9 @@ mod tests {
         // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
         let mut transport =
             unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
-        VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
         assert_eq!(
-            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap_err(),
             Error::AlreadyUsed
         );
     }
@@ -1028,7 +2499,8 @@ mod tests {
         // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
         let mut transport =
             unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
         assert_eq!(
             // SAFETY: This is the only use of `queue`. Supplying temporary buffers is ok.
             unsafe { queue.add(&[], &mut []) }.unwrap_err(),
@@ -1042,7 +2514,8 @@ mod tests {
         // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
         let mut transport =
             unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
         assert_eq!(queue.available_desc(), 4);
         assert_eq!(
             // SAFETY: This is the only use of `queue`. Supplying temporary buffers is ok.
@@ -1051,65 +2524,74 @@ mod tests {
         );
     }
 
+    /// Tests that the rings of a modern device are little-endian whatever the driver's byte
+    /// order is.
+    #[test]
+    fn ring_byte_order() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        let token = unsafe { queue.add(&[&[1, 2, 3]], &mut []) }.unwrap();
+
+        // SAFETY: Safe because the various parts of the queue are properly aligned,
+        // dereferenceable and initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let desc = &(*queue.desc.as_ptr())[usize::from(token)];
+            assert_eq!(desc.len.to_ne_bytes(), 3u32.to_le_bytes());
+            let avail = &*queue.avail.as_ptr();
+            assert_eq!(avail.ring[0].to_ne_bytes(), token.to_le_bytes());
+            assert_eq!(
+                avail.idx.load(Ordering::Acquire).to_ne_bytes(),
+                1u16.to_le_bytes()
+            );
+        }
+    }
+
     #[test]
     fn add_buffers() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
         // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
         let mut transport =
             unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
         assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.max_in_flight(2), 2);
+        assert_eq!(queue.max_in_flight(5), 0);
 
         // Add a buffer chain consisting of two device-readable parts followed by two
         // device-writable parts.
         let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
 
         assert_eq!(queue.available_desc(), 0);
+        assert_eq!(queue.in_flight(), 1);
         assert!(!queue.can_pop());
 
         // SAFETY: Safe because the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
-            let first_descriptor_index = (*queue.avail.as_ptr()).ring[0];
+            // The MMIO transport is modern, so the queue is little endian.
+            let desc =
+                |index: u16| (*queue.desc.as_ptr())[usize::from(index)].converted(Endian::Little);
+            let first_descriptor_index = u16::from_le((*queue.avail.as_ptr()).ring[0]);
             assert_eq!(first_descriptor_index, token);
+            assert_eq!(desc(first_descriptor_index).len, 2);
+            assert_eq!(desc(first_descriptor_index).flags, DescFlags::NEXT);
+            let second_descriptor_index = desc(first_descriptor_index).next;
+            assert_eq!(desc(second_descriptor_index).len, 1);
+            assert_eq!(desc(second_descriptor_index).flags, DescFlags::NEXT);
+            let third_descriptor_index = desc(second_descriptor_index).next;
+            assert_eq!(desc(third_descriptor_index).len, 2);
             assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].len,
-                2
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].flags,
-                DescFlags::NEXT
-            );
-            let second_descriptor_index =
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].len,
-                1
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].flags,
-                DescFlags::NEXT
-            );
-            let third_descriptor_index =
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].len,
-                2
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].flags,
+                desc(third_descriptor_index).flags,
                 DescFlags::NEXT | DescFlags::WRITE
             );
-            let fourth_descriptor_index =
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].len,
-                1
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].flags,
-                DescFlags::WRITE
-            );
+            let fourth_descriptor_index = desc(third_descriptor_index).next;
+            assert_eq!(desc(fourth_descriptor_index).len, 1);
+            assert_eq!(desc(fourth_descriptor_index).flags, DescFlags::WRITE);
         }
     }
 
@@ -1122,72 +2604,1109 @@ mod tests {
         // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
         let mut transport =
             unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
         assert_eq!(queue.available_desc(), 4);
 
         // Add a buffer chain consisting of two device-readable parts followed by two
         // device-writable parts.
+        assert_eq!(queue.max_in_flight(4), 4);
         let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
 
         assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.in_flight(), 1);
         assert!(!queue.can_pop());
 
         // SAFETY: Safe because the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
-            let indirect_descriptor_index = (*queue.avail.as_ptr()).ring[0];
+            // The MMIO transport is modern, so the queue is little endian.
+            let desc =
+                |index: u16| (*queue.desc.as_ptr())[usize::from(index)].converted(Endian::Little);
+            let indirect_descriptor_index = u16::from_le((*queue.avail.as_ptr()).ring[0]);
             assert_eq!(indirect_descriptor_index, token);
             assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
+                desc(indirect_descriptor_index).len as usize,
                 4 * size_of::<Descriptor>()
             );
+            assert_eq!(desc(indirect_descriptor_index).flags, DescFlags::INDIRECT);
+
+            let indirect_descriptors =
+                slice_from_raw_parts(desc(indirect_descriptor_index).addr as *const Descriptor, 4);
+            let indirect_descriptors: Vec<Descriptor> = (*indirect_descriptors)
+                .iter()
+                .map(|desc| desc.converted(Endian::Little))
+                .collect();
+            assert_eq!(indirect_descriptors[0].len, 2);
+            assert_eq!(indirect_descriptors[0].flags, DescFlags::NEXT);
+            assert_eq!(indirect_descriptors[0].next, 1);
+            assert_eq!(indirect_descriptors[1].len, 1);
+            assert_eq!(indirect_descriptors[1].flags, DescFlags::NEXT);
+            assert_eq!(indirect_descriptors[1].next, 2);
+            assert_eq!(indirect_descriptors[2].len, 2);
             assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].flags,
-                DescFlags::INDIRECT
+                indirect_descriptors[2].flags,
+                DescFlags::NEXT | DescFlags::WRITE
             );
+            assert_eq!(indirect_descriptors[2].next, 3);
+            assert_eq!(indirect_descriptors[3].len, 1);
+            assert_eq!(indirect_descriptors[3].flags, DescFlags::WRITE);
+        }
+    }
 
-            let indirect_descriptors = slice_from_raw_parts(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].addr
-                    as *const Descriptor,
-                4,
-            );
-            assert_eq!((*indirect_descriptors)[0].len, 2);
-            assert_eq!((*indirect_descriptors)[0].flags, DescFlags::NEXT);
-            assert_eq!((*indirect_descriptors)[0].next, 1);
-            assert_eq!((*indirect_descriptors)[1].len, 1);
-            assert_eq!((*indirect_descriptors)[1].flags, DescFlags::NEXT);
-            assert_eq!((*indirect_descriptors)[1].next, 2);
-            assert_eq!((*indirect_descriptors)[2].len, 2);
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn dma_footprint() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
+        // One page for the descriptors and available ring, and one for the used ring.
+        assert_eq!(queue.dma_footprint(), 2 * PAGE_SIZE);
+
+        // A chain of more than one buffer gets an indirect descriptor table.
+        unsafe { queue.add(&[&[1, 2]], &mut [&mut [0]]) }.unwrap();
+        assert_eq!(queue.dma_footprint(), 3 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn ring_footprint() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            VirtQueue::<FakeHal, 4>::ring_footprint(&mut transport, 0, 0, RingAllocation::Combined),
+            Err(Error::InvalidParam)
+        );
+        // Clamped to the maximum the device supports.
+        assert_eq!(
+            VirtQueue::<FakeHal, 256>::ring_footprint(
+                &mut transport,
+                0,
+                256,
+                RingAllocation::PerArea
+            ),
+            Ok(3 * PAGE_SIZE)
+        );
+        let footprint =
+            VirtQueue::<FakeHal, 4>::ring_footprint(&mut transport, 0, 4, RingAllocation::Combined)
+                .unwrap();
+        let queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        assert_eq!(footprint, queue.dma_footprint());
+
+        // The legacy layout puts everything in one region, whatever was asked for.
+        let layout = VirtQueueLayout::<FakeHal>::allocate_legacy(4).unwrap();
+        assert_eq!(
+            VirtQueueLayout::<FakeHal>::footprint_for(4, true, RingAllocation::PerArea),
+            layout.dma_footprint()
+        );
+    }
+
+    #[test]
+    fn per_area_allocation() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue = VirtQueue::<FakeHal, 4>::with_allocation(
+            &mut transport,
+            0,
+            4,
+            false,
+            false,
+            false,
+            RingAllocation::PerArea,
+        )
+        .unwrap();
+        // One page each for the descriptors, available ring and used ring.
+        assert_eq!(queue.dma_footprint(), 3 * PAGE_SIZE);
+        {
+            let state = state.lock().unwrap();
+            let status = &state.queues[0];
+            // Each area starts a region of its own.
+            assert_eq!(status.driver_area % PAGE_SIZE, 0);
+            assert_eq!(status.device_area % PAGE_SIZE, 0);
+            assert_ne!(status.driver_area, status.descriptors);
+        }
+
+        // The device finds the rings wherever they are.
+        let token = unsafe { queue.add(&[&[1, 2]], &mut [&mut [0; 1]]) }.unwrap();
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |input| {
+            assert_eq!(input, [1, 2]);
+            vec![3]
+        }));
+        let mut output = [0; 1];
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1, 2]], &mut [&mut output]) },
+            Ok(3)
+        );
+    }
+
+    /// Tests that a buffer chain added with an indirect descriptor table can be processed by the
+    /// device and popped, returning the descriptor to the free list.
+    #[test]
+    fn pop_used_indirect() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_INDIRECT_DESC.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
+
+        let inputs: [&[u8]; 2] = [&[1, 2], &[3]];
+        let mut output_a = [0; 2];
+        let mut output_b = [0; 1];
+        let token = unsafe { queue.add(&inputs, &mut [&mut output_a, &mut output_b]) }.unwrap();
+        assert_eq!(queue.num_used, 1);
+
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |input| {
+            assert_eq!(input, vec![1, 2, 3]);
+            vec![4, 5, 6]
+        }));
+
+        assert!(queue.can_pop());
+        assert_eq!(
+            unsafe { queue.pop_used(token, &inputs, &mut [&mut output_a, &mut output_b]) }.unwrap(),
+            6
+        );
+        assert_eq!(output_a, [4, 5]);
+        assert_eq!(output_b, [6]);
+        assert_eq!(queue.num_used, 0);
+        assert_eq!(queue.in_flight(), 0);
+        assert!(queue.indirect_lists.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn drop_in_flight() {
+        let mut config_space = ();
+        let (mut transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_INDIRECT_DESC.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<TrackingHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
+
+        // One chain using an indirect table, one direct.
+        let mut output = [0; 2];
+        unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut output]) }.unwrap();
+        unsafe { queue.add(&[&[4]], &mut []) }.unwrap();
+        assert_eq!(TrackingHal::outstanding_shares(), 4);
+
+        drop(queue);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        assert_eq!(TrackingHal::outstanding_dma(), 0);
+    }
+
+    #[test]
+    fn abort_all() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_INDIRECT_DESC.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<TrackingHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
+
+        let mut output = [0; 2];
+        let first = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut output]) }.unwrap();
+        let second = unsafe { queue.add(&[&[4]], &mut []) }.unwrap();
+        assert_eq!(TrackingHal::outstanding_shares(), 4);
+
+        let mut abandoned = vec![];
+        // SAFETY: The fake device never accesses the queue.
+        unsafe { queue.abort_all(|token| abandoned.push(token)) };
+        assert_eq!(abandoned, [first, second]);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.snapshot().avail_idx, 0);
+        assert!(!queue.can_pop());
+
+        // The queue can be used from the start again.
+        let mut output = [0; 2];
+        let token = unsafe { queue.add(&[&[1]], &mut [&mut output]) }.unwrap();
+        assert_eq!(token, 0);
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |request| {
+            assert_eq!(request, [1]);
+            vec![5, 6]
+        }));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1]], &mut [&mut output]) },
+            Ok(3)
+        );
+        assert_eq!(output, [5, 6]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_RESET.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        let descriptors = state.lock().unwrap().queues[0].descriptors;
+
+        let token = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+
+        // The queue can't be reset until the feature has been negotiated.
+        assert_eq!(queue.reset(&mut transport, |_| {}), Err(Error::Unsupported));
+        assert_eq!(queue.available_desc(), 3);
+
+        transport.write_driver_features(Feature::RING_RESET.bits());
+        let mut abandoned = vec![];
+        assert_eq!(
+            queue.reset(&mut transport, |token| abandoned.push(token)),
+            Ok(())
+        );
+        assert_eq!(abandoned, [token]);
+        assert_eq!(queue.available_desc(), 4);
+        {
+            let state = state.lock().unwrap();
+            assert_eq!(state.queues[0].reset_count, 1);
+            // The queue is set up on the device again with the same memory.
+            assert_eq!(state.queues[0].size, 4);
+            assert_eq!(state.queues[0].descriptors, descriptors);
+        }
+
+        let token = unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |request| {
+            assert_eq!(request, [2]);
+            vec![]
+        }));
+        assert_eq!(unsafe { queue.pop_used(token, &[&[2]], &mut []) }, Ok(1));
+    }
+
+    #[test]
+    fn add_many() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        // Only as many buffers as fit are added, with a single notification.
+        let mut storage = [[0; 2]; 6];
+        let mut buffers: Vec<&mut [u8]> = storage.iter_mut().map(|b| b.as_mut_slice()).collect();
+        let mut tokens = [u16::MAX; 6];
+        let added = unsafe { queue.add_many(&mut buffers, &mut tokens, &mut transport) };
+        assert_eq!(added, Ok(4));
+        assert_eq!(tokens, [0, 1, 2, 3, u16::MAX, u16::MAX]);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 1);
+
+        // Once the queue is full nothing more can be added.
+        let added = unsafe { queue.add_many(&mut buffers[4..], &mut tokens[4..], &mut transport) };
+        assert_eq!(added, Err(Error::QueueFull));
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 1);
+
+        // The device fills the first buffer, and once it is popped another can be added.
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |request| {
+            assert_eq!(request, []);
+            vec![7, 8]
+        }));
+        assert_eq!(
+            unsafe { queue.pop_used(tokens[0], &[], &mut [&mut *buffers[0]]) },
+            Ok(2)
+        );
+        assert_eq!(buffers[0], [7, 8]);
+        let added = unsafe { queue.add_many(&mut buffers[4..], &mut tokens[4..], &mut transport) };
+        assert_eq!(added, Ok(1));
+        assert_eq!(tokens[4], tokens[0]);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 2);
+    }
+
+    /// Tests that `add_many` leaves notifying the device to the driver while notifications are
+    /// disabled.
+    #[test]
+    fn add_many_notify_disabled() {
+        let (mut transport, state) = FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut ());
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        queue.set_notify(false);
+        let mut storage = [[0; 2]; 2];
+        let mut buffers: Vec<&mut [u8]> = storage.iter_mut().map(|b| b.as_mut_slice()).collect();
+        let mut tokens = [u16::MAX; 2];
+        let added = unsafe { queue.add_many(&mut buffers, &mut tokens, &mut transport) };
+        assert_eq!(added, Ok(2));
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 0);
+
+        queue.notify(&mut transport);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 1);
+    }
+
+    /// Tests that buffers in an already shared region are neither shared nor unshared by the
+    /// queue, even with indirect descriptors available.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn add_premapped() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_INDIRECT_DESC.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<TrackingHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
+        let mut dma_buffer = DmaBuffer::<TrackingHal>::new(4, BufferDirection::Both).unwrap();
+        let paddr = dma_buffer.paddr();
+        let region = dma_buffer.region();
+
+        let inputs: [&[u8]; 1] = [&[1, 2]];
+        let mut status = [0; 1];
+        let token = unsafe {
+            queue.add_premapped(
+                &inputs,
+                &mut [dma_buffer.as_mut_slice(), &mut status],
+                &[region],
+            )
+        }
+        .unwrap();
+        // The chain is direct, and only the input and status buffers were shared.
+        assert_eq!(queue.num_used, 3);
+        assert_eq!(queue.desc_shadow[1].addr, paddr as u64);
+        assert_eq!(TrackingHal::outstanding_shares(), 2);
+
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |input| {
+            assert_eq!(input, vec![1, 2]);
+            vec![3, 4, 5, 6, 7]
+        }));
+        assert_eq!(
+            unsafe {
+                queue.pop_used(
+                    token,
+                    &inputs,
+                    &mut [dma_buffer.as_mut_slice(), &mut status],
+                )
+            }
+            .unwrap(),
+            7
+        );
+        assert_eq!(dma_buffer.as_slice(), [3, 4, 5, 6]);
+        assert_eq!(status, [7]);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+
+        // A premapped buffer still in flight when the queue is dropped isn't unshared either.
+        unsafe { queue.add_premapped(&[dma_buffer.as_slice()], &mut [], &[region]) }.unwrap();
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        drop(queue);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+        drop(dma_buffer);
+        assert_eq!(TrackingHal::outstanding_dma(), 0);
+    }
+
+    #[test]
+    fn double_free() {
+        let mut config_space = ();
+        let (mut transport, _) = FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let mut output = [0; 2];
+        let token = unsafe { queue.add(&[], &mut [&mut output]) }.unwrap();
+
+        // A buggy device reports the same chain as used twice.
+        // SAFETY: Safe because the used ring is properly aligned, dereferenceable and initialised,
+        // and nothing else is accessing it at the same time.
+        unsafe {
+            for slot in 0..2 {
+                (*queue.used.as_ptr()).ring[slot] = UsedElem {
+                    id: u32::from(token).to_le(),
+                    len: 0,
+                };
+            }
+            (*queue.used.as_ptr())
+                .idx
+                .store(2u16.to_le(), Ordering::Release);
+        }
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[], &mut [&mut output]) },
+            Ok(0)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[], &mut [&mut output]) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "1 descriptors leaked")]
+    fn leaked_descriptor() {
+        let mut config_space = ();
+        let (mut transport, _) = FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        // A chain still in flight is fine, but a descriptor counted as used without belonging to
+        // any chain is not.
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        queue.num_used += 1;
+        drop(queue);
+    }
+
+    #[test]
+    fn pop_used_timed() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let mut output_a = [0; 1];
+        let mut output_b = [0; 1];
+        FakeHal::set_time(100);
+        let token_a = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        FakeHal::set_time(150);
+        let token_b = unsafe { queue.add(&[], &mut [&mut output_b]) }.unwrap();
+        FakeHal::set_time(400);
+        state.lock().unwrap().write_to_queue::<4>(0, &[1]);
+        state.lock().unwrap().write_to_queue::<4>(0, &[2]);
+
+        assert_eq!(
+            unsafe { queue.pop_used_timed(token_a, &[], &mut [&mut output_a]) },
+            Ok((1, 100))
+        );
+        assert_eq!(
+            unsafe { queue.pop_used_timed(token_b, &[], &mut [&mut output_b]) },
+            Ok((1, 150))
+        );
+        assert_eq!(
+            unsafe { queue.pop_used_timed(token_b, &[], &mut [&mut output_b]) },
+            Err(Error::NotReady)
+        );
+    }
+
+    #[test]
+    fn pop_used_with_cookie() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let mut output_a = [0; 1];
+        let mut output_b = [0; 1];
+        let token_a =
+            unsafe { queue.add_with_cookie(&[], &mut [&mut output_a], 0x1234_5678_9abc) }.unwrap();
+        let token_b = unsafe { queue.add(&[], &mut [&mut output_b]) }.unwrap();
+        state.lock().unwrap().write_to_queue::<4>(0, &[1]);
+        assert_eq!(
+            unsafe { queue.pop_used_with_cookie(token_a, &[], &mut [&mut output_a]) },
+            Ok((1, 0x1234_5678_9abc))
+        );
+
+        // Chains added without a cookie get 0, even if they reuse the slot of one which had one.
+        let token_c = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        assert_eq!(token_c, token_a);
+        state.lock().unwrap().write_to_queue::<4>(0, &[2]);
+        state.lock().unwrap().write_to_queue::<4>(0, &[3]);
+        assert_eq!(
+            unsafe { queue.pop_used_with_cookie(token_b, &[], &mut [&mut output_b]) },
+            Ok((1, 0))
+        );
+        assert_eq!(
+            unsafe { queue.pop_used_with_cookie(token_c, &[], &mut [&mut output_a]) },
+            Ok((1, 0))
+        );
+    }
+
+    /// Adds three chains, has the fake device use them one at a time, and pops them all in the
+    /// order reported by `peek_used`.
+    fn used_sequence(in_order: bool) -> Vec<(u16, u32)> {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, in_order).unwrap();
+
+        let mut outputs = [[0; 2]; 3];
+        let mut tokens = vec![];
+        for output in &mut outputs {
+            tokens.push(unsafe { queue.add(&[], &mut [output]) }.unwrap());
+        }
+        for data in [&[1][..], &[2, 3], &[4]] {
+            state.lock().unwrap().write_to_queue::<4>(0, data);
+        }
+
+        let mut used = vec![];
+        while let Some(token) = queue.peek_used() {
+            let i = tokens.iter().position(|&t| t == token).unwrap();
+            let len = unsafe { queue.pop_used(token, &[], &mut [&mut outputs[i]]) }.unwrap();
+            used.push((token, len));
+        }
+        assert_eq!(outputs, [[1, 0], [2, 3], [4, 0]]);
+        used
+    }
+
+    #[test]
+    fn in_order_same_sequence() {
+        assert_eq!(used_sequence(true), used_sequence(false));
+    }
+
+    #[test]
+    fn in_order_batch() {
+        let mut config_space = ();
+        let (mut transport, _) = FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, true).unwrap();
+
+        let mut output_a = [0; 1];
+        let mut output_b = [0; 2];
+        let mut output_c = [0; 3];
+        let token_a = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        let token_b = unsafe { queue.add(&[], &mut [&mut output_b]) }.unwrap();
+        let token_c = unsafe { queue.add(&[], &mut [&mut output_c]) }.unwrap();
+
+        // Use all three chains as a batch, with a single used element for the last one.
+        // SAFETY: Safe because the used ring is properly aligned, dereferenceable and initialised,
+        // and nothing else is accessing it at the same time.
+        unsafe {
+            (*queue.used.as_ptr()).ring[0] = UsedElem {
+                id: u32::from(token_c).to_le(),
+                len: 2u32.to_le(),
+            };
+            (*queue.used.as_ptr())
+                .idx
+                .store(3u16.to_le(), Ordering::Release);
+        }
+
+        let mut out = [(0, 0); 4];
+        assert_eq!(queue.peek_used_batch(&mut out), 3);
+        assert_eq!(out[..3], [(token_a, 1), (token_b, 2), (token_c, 2)]);
+
+        assert_eq!(queue.peek_used(), Some(token_a));
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token_a, &[], &mut [&mut output_a]) },
+            Ok(1)
+        );
+        assert_eq!(queue.peek_used(), Some(token_b));
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) },
+            Ok(2)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token_c, &[], &mut [&mut output_c]) },
+            Ok(2)
+        );
+        assert_eq!(queue.peek_used(), None);
+        assert_eq!(queue.in_order_batch, None);
+        assert_eq!(queue.num_used, 0);
+    }
+
+    #[test]
+    fn in_order_ring_order() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, true).unwrap();
+
+        let mut output_a = [0; 2];
+        let token_a = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        assert_eq!(token_a, 0);
+
+        // Complete both chains, so that waiting for the second sets the first aside and pops the
+        // second one before it.
+        let handle = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, 0);
+                let used_ring = state.lock().unwrap().queues[0].device_area as *mut UsedRing<4>;
+                // SAFETY: The used ring is properly aligned, dereferenceable and initialised, and
+                // the driver doesn't write to it while waiting.
+                unsafe {
+                    (*used_ring).ring[0] = UsedElem {
+                        id: 0u32.to_le(),
+                        len: 2u32.to_le(),
+                    };
+                    (*used_ring).ring[1] = UsedElem {
+                        id: 1u32.to_le(),
+                        len: 1u32.to_le(),
+                    };
+                    (*used_ring).idx.store(2u16.to_le(), Ordering::Release);
+                }
+            }
+        });
+        let mut output_b = [0; 1];
+        assert_eq!(
+            queue.add_notify_wait_pop(&[], &mut [&mut output_b], &mut transport),
+            Ok(1)
+        );
+        handle.join().unwrap();
+
+        // Descriptor 1 is free again, but descriptors must be used in ring order, so the next
+        // chain carries on from descriptor 2.
+        assert_eq!(queue.available_desc(), 2);
+        let mut output_c = [0; 1];
+        let mut output_d = [0; 1];
+        let token_c = unsafe { queue.add(&[], &mut [&mut output_c, &mut output_d]) }.unwrap();
+        assert_eq!(token_c, 2);
+        assert_eq!(queue.desc_shadow[2].next(), Some(3));
+        assert_eq!(queue.desc_shadow[3].next(), None);
+
+        // Descriptor 0 comes next, and is still in flight.
+        assert_eq!(queue.available_desc(), 0);
+        let mut output_e = [0; 1];
+        let mut output_f = [0; 1];
+        assert_eq!(
+            unsafe { queue.add(&[], &mut [&mut output_e, &mut output_f]) },
+            Err(Error::QueueFull)
+        );
+
+        // Popping the first chain frees descriptors 0 and 1 together.
+        assert_eq!(
+            unsafe { queue.pop_used(token_a, &[], &mut [&mut output_a]) },
+            Ok(2)
+        );
+        assert_eq!(queue.available_desc(), 2);
+        let token_e = unsafe { queue.add(&[], &mut [&mut output_e, &mut output_f]) }.unwrap();
+        assert_eq!(token_e, 0);
+        assert_eq!(queue.desc_shadow[0].next(), Some(1));
+        assert_eq!(queue.desc_shadow[1].next(), None);
+    }
+
+    #[test]
+    fn pop_used_bogus_token() {
+        let mut config_space = ();
+        let (mut transport, _) = FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let mut output = [0; 2];
+        let token = unsafe { queue.add(&[], &mut [&mut output]) }.unwrap();
+        let available_desc = queue.available_desc();
+
+        // A device which returns a token it was never given, either out of range or for a chain
+        // which isn't in flight, must not make the driver recycle anything.
+        for bogus in [1000, u32::from(token) + 1] {
+            // SAFETY: Safe because the used ring is properly aligned, dereferenceable and
+            // initialised, and nothing else is accessing it at the same time.
+            unsafe {
+                (*queue.used.as_ptr()).ring[0] = UsedElem {
+                    id: bogus.to_le(),
+                    len: 2u32.to_le(),
+                };
+                (*queue.used.as_ptr())
+                    .idx
+                    .store(1u16.to_le(), Ordering::Release);
+            }
+            let bogus = u16::try_from(bogus).unwrap_or(u16::MAX);
+            assert_eq!(queue.peek_used(), Some(bogus));
             assert_eq!(
-                (*indirect_descriptors)[2].flags,
-                DescFlags::NEXT | DescFlags::WRITE
+                unsafe { queue.pop_used(bogus, &[], &mut [&mut output]) },
+                Err(Error::WrongToken)
             );
-            assert_eq!((*indirect_descriptors)[2].next, 3);
-            assert_eq!((*indirect_descriptors)[3].len, 1);
-            assert_eq!((*indirect_descriptors)[3].flags, DescFlags::WRITE);
+            assert_eq!(queue.available_desc(), available_desc);
+            assert_eq!(queue.peek_used(), Some(bogus));
         }
+
+        // Once the device gets it right the real chain can still be popped.
+        // SAFETY: As above.
+        unsafe {
+            (*queue.used.as_ptr()).ring[0] = UsedElem {
+                id: u32::from(token).to_le(),
+                len: 2u32.to_le(),
+            };
+        }
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[], &mut [&mut output]) },
+            Ok(2)
+        );
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.peek_used(), None);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        assert_eq!(queue.stats(), QueueStats::default());
+
+        let mut outputs = [[0; 1]; 4];
+        let [a, b, c, d] = &mut outputs;
+        let token_a = unsafe { queue.add(&[], &mut [a]) }.unwrap();
+        let token_b = unsafe { queue.add(&[], &mut [b]) }.unwrap();
+        assert!(queue.should_notify());
+        unsafe { queue.add(&[], &mut [c, d]) }.unwrap();
+        assert!(queue.should_notify());
+        assert_eq!(
+            unsafe { queue.add(&[&[1]], &mut []) },
+            Err(Error::QueueFull)
+        );
+
+        state.lock().unwrap().write_to_queue::<4>(0, &[1]);
+        state.lock().unwrap().write_to_queue::<4>(0, &[2]);
+        let [a, b, _, _] = &mut outputs;
+        unsafe { queue.pop_used(token_a, &[], &mut [a]) }.unwrap();
+        unsafe { queue.pop_used(token_b, &[], &mut [b]) }.unwrap();
+
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                submissions: 3,
+                completions: 2,
+                notifications: 2,
+                queue_full: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn peek_used_batch() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let mut out = [(0, 0); 4];
+        assert_eq!(queue.peek_used_batch(&mut out), 0);
+
+        let mut output_a = [0; 1];
+        let mut output_b = [0; 2];
+        let mut output_c = [0; 3];
+        let token_a = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        let token_b = unsafe { queue.add(&[], &mut [&mut output_b]) }.unwrap();
+        let token_c = unsafe { queue.add(&[], &mut [&mut output_c]) }.unwrap();
+
+        state.lock().unwrap().write_to_queue::<4>(0, &[1]);
+        state.lock().unwrap().write_to_queue::<4>(0, &[2, 3]);
+        state.lock().unwrap().write_to_queue::<4>(0, &[4, 5, 6]);
+
+        // Only as many entries as fit are returned, and nothing is popped.
+        assert_eq!(queue.peek_used_batch(&mut out[..2]), 2);
+        assert_eq!(out[..2], [(token_a, 1), (token_b, 2)]);
+        assert_eq!(queue.peek_used_batch(&mut out), 3);
+        assert_eq!(out[..3], [(token_a, 1), (token_b, 2), (token_c, 3)]);
+
+        assert_eq!(
+            unsafe { queue.pop_used(token_a, &[], &mut [&mut output_a]) },
+            Ok(1)
+        );
+        assert_eq!(queue.peek_used_batch(&mut out), 2);
+        assert_eq!(out[..2], [(token_b, 2), (token_c, 3)]);
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) },
+            Ok(2)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token_c, &[], &mut [&mut output_c]) },
+            Ok(3)
+        );
+        assert_eq!(output_a, [1]);
+        assert_eq!(output_b, [2, 3]);
+        assert_eq!(output_c, [4, 5, 6]);
+        assert_eq!(queue.peek_used_batch(&mut out), 0);
     }
 
     /// Tests that the queue advises the device that notifications are needed.
     #[test]
-    fn set_dev_notify() {
+    fn split_at_dma_windows() {
+        /// Like `TrackingHal`, but buffers mustn't cross a multiple of 16 bytes.
+        struct WindowHal;
+
+        unsafe impl Hal for WindowHal {
+            fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+                TrackingHal::dma_alloc(pages, direction)
+            }
+
+            unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+                unsafe { TrackingHal::dma_dealloc(paddr, vaddr, pages) }
+            }
+
+            unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+                unsafe { TrackingHal::mmio_phys_to_virt(paddr, size) }
+            }
+
+            unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+                let start = buffer.cast::<u8>().as_ptr() as usize;
+                assert_eq!(start / 16, (start + buffer.len() - 1) / 16);
+                unsafe { TrackingHal::share(buffer, direction) }
+            }
+
+            unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+                unsafe { TrackingHal::unshare(paddr, buffer, direction) }
+            }
+
+            fn max_contiguous_dma() -> usize {
+                16
+            }
+        }
+
+        assert_eq!(window_remaining(0x1000, 16), 16);
+        assert_eq!(window_remaining(0x1003, 16), 13);
+
         let mut config_space = ();
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<WindowHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        // A buffer of 24 bytes starting 4 bytes before a window boundary needs 3 descriptors.
+        let backing: Vec<u8> = (0..80).collect();
+        let offset = window_remaining(backing.as_ptr() as usize, 16) + 12;
+        let input = &backing[offset..offset + 24];
+        assert_eq!(segment_count(&[input], &[], 16), 3);
+        let mut output = [0; 1];
+        let token = unsafe { queue.add(&[input], &mut [&mut output]) }.unwrap();
+        assert_eq!(queue.num_used, 4);
+
+        // The device sees the same data, however it is split.
+        assert!(state.lock().unwrap().read_write_queue::<4>(0, |request| {
+            assert_eq!(request, input);
+            vec![42]
         }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[input], &mut [&mut output]) },
+            Ok(25)
+        );
+        assert_eq!(output, [42]);
+        assert_eq!(queue.num_used, 0);
+        assert_eq!(TrackingHal::outstanding_shares(), 0);
+
+        // Splitting can make a chain too long for the queue.
+        let input = &backing[offset..offset + 40];
+        assert_eq!(
+            unsafe { queue.add(&[input], &mut [&mut output]) },
+            Err(Error::QueueFull)
+        );
+    }
+
+    /// Tests that a blocking wait sets aside chains which the device completes before the one it is
+    /// waiting for, rather than failing with `WrongToken`.
+    #[test]
+    fn wait_sets_aside_other_completions() {
+        struct Recorder(Mutex<Vec<(u16, u16, u16, u32)>>);
+
+        impl QueueObserver for Recorder {
+            fn on_set_aside(&self, queue_idx: u16, expected: u16, token: u16, len: u32) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((queue_idx, expected, token, len));
+            }
+        }
+
+        static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        queue.set_observer(Some(&RECORDER));
+
+        let mut output_a = [0; 2];
+        let token_a = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        // The blocking request is added next, so gets the next descriptor.
+        let token_b = token_a + 1;
+
+        let handle = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, 0);
+                let used_ring = state.lock().unwrap().queues[0].device_area as *mut UsedRing<4>;
+                // Complete the non-blocking request first, then the blocking one.
+                // SAFETY: The used ring is properly aligned, dereferenceable and initialised, and
+                // the driver doesn't write to it while waiting.
+                unsafe {
+                    (*used_ring).ring[0] = UsedElem {
+                        id: u32::from(token_a).to_le(),
+                        len: 2u32.to_le(),
+                    };
+                    (*used_ring).ring[1] = UsedElem {
+                        id: u32::from(token_b).to_le(),
+                        len: 1u32.to_le(),
+                    };
+                    (*used_ring).idx.store(2u16.to_le(), Ordering::Release);
+                }
+            }
+        });
+        let mut output_b = [0; 1];
+        assert_eq!(
+            queue.add_notify_wait_pop(&[], &mut [&mut output_b], &mut transport),
+            Ok(1)
+        );
+        handle.join().unwrap();
+        assert_eq!(*RECORDER.0.lock().unwrap(), [(0, token_b, token_a, 2)]);
+
+        // The first request can still be popped.
+        assert!(queue.can_pop());
+        assert_eq!(queue.peek_used(), Some(token_a));
+        let mut out = [(0, 0); 2];
+        assert_eq!(queue.peek_used_batch(&mut out), 1);
+        assert_eq!(out[0], (token_a, 2));
+        assert_eq!(
+            unsafe { queue.pop_used(token_a, &[], &mut [&mut output_a]) },
+            Ok(2)
+        );
+        assert!(!queue.can_pop());
+        assert_eq!(queue.peek_used(), None);
+        assert_eq!(queue.in_flight(), 0);
+    }
+
+    #[test]
+    fn observer() {
+        #[derive(Debug, Eq, PartialEq)]
+        enum Event {
+            Submit(u16, u16, usize),
+            Notify(u16),
+            Complete(u16, u16, u32),
+        }
+
+        struct Recorder(Mutex<Vec<Event>>);
+
+        impl QueueObserver for Recorder {
+            fn on_submit(&self, queue_idx: u16, token: u16, len: usize) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(Event::Submit(queue_idx, token, len));
+            }
+
+            fn on_notify(&self, queue_idx: u16) {
+                self.0.lock().unwrap().push(Event::Notify(queue_idx));
+            }
+
+            fn on_complete(&self, queue_idx: u16, token: u16, len: u32) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(Event::Complete(queue_idx, token, len));
+            }
+        }
+
+        static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 2, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 1, false, false, false).unwrap();
+
+        // Nothing is recorded before the observer is installed.
+        let token = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        queue.notify(&mut transport);
+        assert!(state.lock().unwrap().read_write_queue::<4>(1, |_| vec![]));
+        unsafe { queue.pop_used(token, &[&[1]], &mut []) }.unwrap();
+        assert_eq!(*RECORDER.0.lock().unwrap(), []);
+
+        queue.set_observer(Some(&RECORDER));
+        let mut output = [0; 3];
+        let token = unsafe { queue.add(&[&[1, 2]], &mut [&mut output]) }.unwrap();
+        queue.notify(&mut transport);
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<4>(1, |_| vec![4, 5]));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1, 2]], &mut [&mut output]) },
+            Ok(4)
+        );
+        assert_eq!(
+            *RECORDER.0.lock().unwrap(),
+            [
+                Event::Submit(1, token, 5),
+                Event::Notify(1),
+                Event::Complete(1, token, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn barriers() {
+        thread_local! {
+            static BARRIERS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+        }
+
+        /// Like `FakeHal`, but counts memory barriers.
+        struct BarrierHal;
+
+        unsafe impl Hal for BarrierHal {
+            fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+                FakeHal::dma_alloc(pages, direction)
+            }
+
+            unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+                unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+            }
+
+            unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+                unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+            }
+
+            unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+                unsafe { FakeHal::share(buffer, direction) }
+            }
+
+            unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+                unsafe { FakeHal::unshare(paddr, buffer, direction) }
+            }
+
+            fn mb() {
+                BARRIERS.set(BARRIERS.get() + 1);
+            }
+        }
+
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<BarrierHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        // The chain must be visible before the available index is bumped.
+        BARRIERS.set(0);
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert_eq!(BARRIERS.get(), 1);
+
+        // And the index before checking whether to notify, and again before the notification.
+        assert!(queue.should_notify());
+        assert_eq!(BARRIERS.get(), 2);
+        queue.notify_device(&mut transport);
+        assert_eq!(BARRIERS.get(), 3);
+        assert!(state.lock().unwrap().queues[0]
+            .notified
+            .load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn set_dev_notify() {
+        let mut config_space = ();
+        let (mut transport, _state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
 
         // Check that the avail ring's flag is zero by default.
         assert_eq!(
-            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            u16::from_le(unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) }),
             0x0
         );
 
@@ -1195,7 +3714,7 @@ mod tests {
 
         // Check that the avail ring's flag is 1 after `disable_dev_notify`.
         assert_eq!(
-            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            u16::from_le(unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) }),
             0x1
         );
 
@@ -1203,28 +3722,146 @@ mod tests {
 
         // Check that the avail ring's flag is 0 after `enable_dev_notify`.
         assert_eq!(
-            unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) },
+            u16::from_le(unsafe { (*queue.avail.as_ptr()).flags.load(Ordering::Acquire) }),
             0x0
         );
     }
 
+    /// Tests that the queue moves the `used_event` index to suppress and request notifications,
+    /// and doesn't re-enable them when popping buffers.
+    #[test]
+    fn set_dev_notify_event_idx() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_EVENT_IDX.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true, false).unwrap();
+        // SAFETY: the available ring is properly aligned, dereferenceable and initialised.
+        let used_event = |queue: &VirtQueue<FakeHal, 4>| unsafe {
+            u16::from_le((*queue.avail.as_ptr()).used_event.load(Ordering::Acquire))
+        };
+
+        queue.set_dev_notify(false);
+        assert_eq!(used_event(&queue), 0xffff);
+
+        // Popping a buffer shouldn't ask for notifications while they are disabled.
+        let token = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<4>(0, |_| Vec::new()));
+        unsafe { queue.pop_used(token, &[&[1]], &mut []) }.unwrap();
+        assert_eq!(used_event(&queue), 0xffff);
+
+        queue.set_dev_notify(true);
+        assert_eq!(used_event(&queue), 1);
+
+        // Now popping should move the threshold along.
+        let token = unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<4>(0, |_| Vec::new()));
+        unsafe { queue.pop_used(token, &[&[2]], &mut []) }.unwrap();
+        assert_eq!(used_event(&queue), 2);
+    }
+
+    /// Submits 8 single-buffer requests, notifying the device whenever the queue says it should,
+    /// while the device only asks to be notified about every fourth buffer. Returns the number of
+    /// notifications sent.
+    fn count_notifications(event_idx: bool) -> usize {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            if event_idx {
+                Feature::RING_EVENT_IDX.bits()
+            } else {
+                0
+            },
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, event_idx, false).unwrap();
+
+        for i in 0..8u16 {
+            if i % 4 == 0 {
+                // The device asks to be kicked once the buffer three after this one is available.
+                // SAFETY: the used ring is properly aligned, dereferenceable and initialised.
+                unsafe {
+                    (*queue.used.as_ptr())
+                        .avail_event
+                        .store((i + 3).to_le(), Ordering::Release);
+                }
+            }
+            let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+            if queue.should_notify() {
+                transport.notify(0);
+            }
+            assert!(state
+                .lock()
+                .unwrap()
+                .read_write_queue::<4>(0, |_| Vec::new()));
+            unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+        }
+
+        let notify_count = state.lock().unwrap().queues[0].notify_count;
+        notify_count
+    }
+
+    /// Tests that using `avail_event` reduces the number of notifications sent.
+    #[test]
+    fn event_idx_reduces_notifications() {
+        assert_eq!(count_notifications(false), 8);
+        assert_eq!(count_notifications(true), 2);
+    }
+
+    /// Tests that a batch of buffers added together only needs one notification, as long as the
+    /// device asked to be notified about any of them.
+    #[test]
+    fn add_notify_event_idx_batch() {
+        let mut config_space = ();
+        let (mut transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_EVENT_IDX.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true, false).unwrap();
+
+        // SAFETY: the used ring is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*queue.used.as_ptr())
+                .avail_event
+                .store(1u16.to_le(), Ordering::Release);
+        }
+
+        // The device asked about the second buffer, which is in the middle of the batch.
+        for _ in 0..3 {
+            unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        }
+        assert!(queue.should_notify());
+        // Nothing new has been added since.
+        assert!(!queue.should_notify());
+    }
+
     /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
     /// notifications.
     #[test]
     fn add_notify() {
         let mut config_space = ();
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let (mut transport, _state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
 
         // Add a buffer chain with a single device-readable part.
         unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
@@ -1236,7 +3873,9 @@ mod tests {
         // initialised, and nothing else is accessing them at the same time.
         unsafe {
             // Suppress notifications.
-            (*queue.used.as_ptr()).flags.store(0x01, Ordering::Release);
+            (*queue.used.as_ptr())
+                .flags
+                .store(1u16.to_le(), Ordering::Release);
         }
 
         // Check that the transport would not be notified.
@@ -1248,18 +3887,15 @@ mod tests {
     #[test]
     fn add_notify_event_idx() {
         let mut config_space = ();
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: Feature::RING_EVENT_IDX.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        let (mut transport, _state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_EVENT_IDX.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true, false).unwrap();
 
         // Add a buffer chain with a single device-readable part.
         assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
@@ -1273,7 +3909,7 @@ mod tests {
             // Suppress notifications.
             (*queue.used.as_ptr())
                 .avail_event
-                .store(1, Ordering::Release);
+                .store(1u16.to_le(), Ordering::Release);
         }
 
         // Check that the transport would not be notified.
@@ -1285,4 +3921,177 @@ mod tests {
         // Check that the transport should be notified again now.
         assert!(queue.should_notify());
     }
+
+    /// Tests that disabling notifications leaves it to the driver to notify the device, and that
+    /// buffers added meanwhile are taken into account once they are enabled again.
+    #[test]
+    fn set_notify() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            1,
+            Feature::RING_EVENT_IDX.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true, false).unwrap();
+
+        queue.set_notify(false);
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+        queue.notify(&mut transport);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 1);
+        // Nothing has been added since, so there's nothing to notify about.
+        queue.notify(&mut transport);
+        assert_eq!(state.lock().unwrap().queues[0].notify_count, 1);
+
+        // The device asks about the second buffer, which is added while notifications are
+        // disabled.
+        // SAFETY: the used ring is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*queue.used.as_ptr())
+                .avail_event
+                .store(1u16.to_le(), Ordering::Release);
+        }
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+        queue.set_notify(true);
+        assert!(queue.should_notify());
+    }
+
+    /// Tests that the queue index and new available index are passed with the notification once
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    #[test]
+    fn notification_data() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            2,
+            Feature::NOTIFICATION_DATA.bits(),
+            &mut config_space,
+        );
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 1, false, false, false).unwrap();
+
+        // Without the feature, no data is passed.
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        queue.notify(&mut transport);
+        assert_eq!(state.lock().unwrap().queues[1].notify_count, 1);
+        assert_eq!(state.lock().unwrap().queues[1].notification_data, None);
+
+        transport.write_driver_features(Feature::NOTIFICATION_DATA.bits());
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        queue.notify(&mut transport);
+        assert_eq!(state.lock().unwrap().queues[1].notify_count, 2);
+        assert_eq!(
+            state.lock().unwrap().queues[1].notification_data,
+            Some(1 | 3 << 16)
+        );
+    }
+
+    /// Tests that waiting for a request stops once the device sets `DEVICE_NEEDS_RESET`, and that
+    /// no more requests are made until it is reset.
+    #[test]
+    fn needs_reset() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        state.lock().unwrap().status = DeviceStatus::DRIVER_OK;
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let handle = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, 0);
+                state.lock().unwrap().status |= DeviceStatus::DEVICE_NEEDS_RESET;
+            }
+        });
+        let mut buffer = [0; 4];
+        assert_eq!(
+            queue.add_notify_wait_pop(&[&[42]], &mut [&mut buffer], &mut transport),
+            Err(Error::DeviceNeedsReset)
+        );
+        handle.join().unwrap();
+        // The device was reset so that it stops using the buffers.
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::FAILED);
+        assert!(transport.needs_reset());
+
+        assert_eq!(
+            queue.add_notify_wait_pop(&[&[42]], &mut [], &mut transport),
+            Err(Error::DeviceNeedsReset)
+        );
+        assert_eq!(queue.available_desc(), 2);
+    }
+
+    #[test]
+    fn removed() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        state.lock().unwrap().status = DeviceStatus::DRIVER_OK;
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let handle = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                State::wait_until_queue_notified(&state, 0);
+                // The registers of a removed device read as all ones.
+                let mut state = state.lock().unwrap();
+                state.removed = true;
+                state.status = DeviceStatus::all();
+            }
+        });
+        let mut buffer = [0; 4];
+        assert_eq!(
+            queue.add_notify_wait_pop(&[&[42]], &mut [&mut buffer], &mut transport),
+            Err(Error::DeviceRemoved)
+        );
+        handle.join().unwrap();
+        // There is no device left to reset.
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::all());
+
+        assert_eq!(
+            queue.add_notify_wait_pop(&[&[42]], &mut [], &mut transport),
+            Err(Error::DeviceRemoved)
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+
+        let mut buffer = [0; 4];
+        let token = unsafe { queue.add(&[&[42], &[43]], &mut [&mut buffer]) }.unwrap();
+        assert_eq!(
+            queue.snapshot(),
+            QueueSnapshot {
+                queue_idx: 0,
+                size: 4,
+                avail_idx: 1,
+                used_idx: 0,
+                last_used_idx: 0,
+                descriptors_in_use: 3,
+            }
+        );
+
+        assert!(state
+            .lock()
+            .unwrap()
+            .read_write_queue::<4>(0, |_| vec![1, 2, 3, 4]));
+        assert_eq!(queue.snapshot().used_idx, 1);
+        unsafe { queue.pop_used(token, &[&[42], &[43]], &mut [&mut buffer]) }.unwrap();
+        assert_eq!(
+            queue.snapshot().to_string(),
+            "queue 0: avail 1 used 1 last used 1, 0/4 descriptors in use"
+        );
+    }
 }
@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: MIT
+
+//! Virtqueues using whichever layout was negotiated with the device.
+
+use super::packed::PackedVirtQueue;
+#[cfg(feature = "stats")]
+use super::QueueStats;
+use super::{QueueSnapshot, VirtQueue};
+use crate::hal::{Hal, SharedRegion};
+use crate::transport::{features::RING_PACKED, Transport};
+use crate::Result;
+
+/// A virtqueue with the packed layout if `VIRTIO_F_RING_PACKED` has been negotiated with the
+/// device, or the split layout otherwise.
+///
+/// This offers the parts of the [`VirtQueue`] API which drivers need to submit and complete
+/// requests, so a driver can support both layouts without caring which one the device chose.
+#[derive(Debug)]
+pub enum NegotiatedQueue<H: Hal, const SIZE: usize> {
+    /// A split virtqueue.
+    Split(VirtQueue<H, SIZE>),
+    /// A packed virtqueue.
+    Packed(PackedVirtQueue<H, SIZE>),
+}
+
+impl<H: Hal, const SIZE: usize> NegotiatedQueue<H, SIZE> {
+    /// Creates a new virtqueue with about `requested_size` descriptors, as for
+    /// [`VirtQueue::with_size`], using the packed layout if the transport has negotiated
+    /// `VIRTIO_F_RING_PACKED`.
+    ///
+    /// Packed queues don't use indirect descriptors, and the transport doesn't negotiate
+    /// `VIRTIO_F_IN_ORDER` along with `VIRTIO_F_RING_PACKED`, so `indirect` and `in_order` only
+    /// apply to split queues.
+    pub fn with_size<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        requested_size: u16,
+        indirect: bool,
+        event_idx: bool,
+        in_order: bool,
+    ) -> Result<Self> {
+        if transport.negotiated_features() & RING_PACKED != 0 {
+            PackedVirtQueue::with_size(transport, idx, requested_size, indirect, event_idx)
+                .map(Self::Packed)
+        } else {
+            VirtQueue::with_size(
+                transport,
+                idx,
+                requested_size,
+                indirect,
+                event_idx,
+                in_order,
+            )
+            .map(Self::Split)
+        }
+    }
+
+    /// Returns the split queue, or `None` if the queue uses the packed layout.
+    pub fn split_mut(&mut self) -> Option<&mut VirtQueue<H, SIZE>> {
+        match self {
+            Self::Split(queue) => Some(queue),
+            Self::Packed(_) => None,
+        }
+    }
+
+    /// Returns the size of the queue, i.e. the number of descriptors it has.
+    pub fn size(&self) -> u16 {
+        match self {
+            Self::Split(queue) => queue.size(),
+            Self::Packed(queue) => queue.size(),
+        }
+    }
+
+    /// Adds buffers to the virtqueue, as for [`VirtQueue::add`].
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // SAFETY: Our caller promises the same as both queues' `add` require.
+        unsafe {
+            match self {
+                Self::Split(queue) => queue.add(inputs, outputs),
+                Self::Packed(queue) => queue.add(inputs, outputs),
+            }
+        }
+    }
+
+    /// Adds the given buffers, notifies the device, waits until the device uses them and pops
+    /// them, as for [`VirtQueue::add_notify_wait_pop`].
+    pub fn add_notify_wait_pop<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        self.add_notify_wait_pop_premapped(inputs, outputs, &[], transport)
+    }
+
+    /// Like [`add_notify_wait_pop`](Self::add_notify_wait_pop), but buffers which lie within the
+    /// given regions are used without being shared again. Packed queues share them as usual.
+    pub(crate) fn add_notify_wait_pop_premapped<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        premapped: &[SharedRegion],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        match self {
+            Self::Split(queue) => {
+                queue.add_notify_wait_pop_premapped(inputs, outputs, premapped, transport)
+            }
+            Self::Packed(queue) => queue.add_notify_wait_pop(inputs, outputs, transport),
+        }
+    }
+
+    /// Returns whether the chain with the given token has been used, setting aside any chains used
+    /// before it, as for [`VirtQueue::token_used`].
+    pub(crate) fn token_used(&mut self, token: u16) -> bool {
+        match self {
+            Self::Split(queue) => queue.token_used(token),
+            Self::Packed(queue) => queue.token_used(token),
+        }
+    }
+
+    /// Advises the device whether used buffer notifications are needed.
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        match self {
+            Self::Split(queue) => queue.set_dev_notify(enable),
+            Self::Packed(queue) => queue.set_dev_notify(enable),
+        }
+    }
+
+    /// Returns whether the driver should notify the device after adding new buffers to the
+    /// virtqueue.
+    pub fn should_notify(&self) -> bool {
+        match self {
+            Self::Split(queue) => queue.should_notify(),
+            Self::Packed(queue) => queue.should_notify(),
+        }
+    }
+
+    /// Notifies the device about this queue.
+    pub(crate) fn notify_device(&self, transport: &mut impl Transport) {
+        match self {
+            Self::Split(queue) => queue.notify_device(transport),
+            Self::Packed(queue) => queue.notify_device(transport),
+        }
+    }
+
+    /// Returns the number of bytes of DMA memory the queue currently holds.
+    pub fn dma_footprint(&self) -> usize {
+        match self {
+            Self::Split(queue) => queue.dma_footprint(),
+            Self::Packed(queue) => queue.dma_footprint(),
+        }
+    }
+
+    /// Returns the counters of operations on the queue since it was created.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        match self {
+            Self::Split(queue) => queue.stats(),
+            Self::Packed(queue) => queue.stats(),
+        }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        match self {
+            Self::Split(queue) => queue.can_pop(),
+            Self::Packed(queue) => queue.can_pop(),
+        }
+    }
+
+    /// Returns a snapshot of the queue's indices and descriptor usage, for debugging.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        match self {
+            Self::Split(queue) => queue.snapshot(),
+            Self::Packed(queue) => queue.snapshot(),
+        }
+    }
+
+    /// Returns the token of the next used element without popping it, or `None` if there is none.
+    pub fn peek_used(&self) -> Option<u16> {
+        match self {
+            Self::Split(queue) => queue.peek_used(),
+            Self::Packed(queue) => queue.peek_used(),
+        }
+    }
+
+    /// Returns the number of chains which have been added but not popped yet.
+    pub fn in_flight(&self) -> usize {
+        match self {
+            Self::Split(queue) => queue.in_flight(),
+            Self::Packed(queue) => queue.in_flight(),
+        }
+    }
+
+    /// Returns how many chains of `descriptors_per_chain` descriptors each the queue can hold at
+    /// once.
+    pub fn max_in_flight(&self, descriptors_per_chain: usize) -> usize {
+        match self {
+            Self::Split(queue) => queue.max_in_flight(descriptors_per_chain),
+            Self::Packed(queue) => queue.max_in_flight(descriptors_per_chain),
+        }
+    }
+
+    /// Pops the used element with the given token, as for [`VirtQueue::pop_used`].
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        // SAFETY: Our caller promises the same as both queues' `pop_used` require.
+        unsafe {
+            match self {
+                Self::Split(queue) => queue.pop_used(token, inputs, outputs),
+                Self::Packed(queue) => queue.pop_used(token, inputs, outputs),
+            }
+        }
+    }
+
+    /// Abandons every descriptor chain still in flight, as for [`VirtQueue::abort_all`].
+    ///
+    /// # Safety
+    ///
+    /// The device must have been reset, or have had the queue disabled, so that it no longer
+    /// accesses the queue or any of the buffers in it.
+    pub unsafe fn abort_all(&mut self, abandoned: impl FnMut(u16)) {
+        // SAFETY: Our caller promises the same as both queues' `abort_all` require.
+        unsafe {
+            match self {
+                Self::Split(queue) => queue.abort_all(abandoned),
+                Self::Packed(queue) => queue.abort_all(abandoned),
+            }
+        }
+    }
+}
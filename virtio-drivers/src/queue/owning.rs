@@ -4,14 +4,21 @@ use super::VirtQueue;
 use crate::{transport::Transport, Error, Hal, Result};
 use alloc::boxed::Box;
 use core::convert::TryInto;
-use core::ptr::{null_mut, NonNull};
+use core::ptr::NonNull;
 use zerocopy::FromZeros;
 
 /// A wrapper around [`Queue`] that owns all the buffers that are passed to the queue.
+///
+/// Each buffer is added back to the queue once it has been handled, so the device always has
+/// buffers to fill, up to the number set with [`shrink_available`](Self::shrink_available) or
+/// [`grow`](Self::grow).
 #[derive(Debug)]
 pub struct OwningQueue<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> {
     queue: VirtQueue<H, SIZE>,
-    buffers: [NonNull<[u8; BUFFER_SIZE]>; SIZE],
+    /// The buffer for each token, if it has been allocated.
+    buffers: [Option<NonNull<[u8; BUFFER_SIZE]>>; SIZE],
+    /// The number of buffers to keep in the queue.
+    target: usize,
 }
 
 impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, BUFFER_SIZE> {
@@ -20,19 +27,78 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
     /// This will allocate `SIZE` buffers of `BUFFER_SIZE` bytes each and add them to the queue.
     ///
     /// The caller is responsible for notifying the device if `should_notify` returns true.
-    pub fn new(mut queue: VirtQueue<H, SIZE>) -> Result<Self> {
-        let mut buffers = [null_mut(); SIZE];
-        for (i, queue_buffer) in buffers.iter_mut().enumerate() {
-            let mut buffer: Box<[u8; BUFFER_SIZE]> = FromZeros::new_box_zeroed().unwrap();
-            // SAFETY: The buffer lives as long as the queue, as specified in the function safety
-            // requirement, and we don't access it until it is popped.
-            let token = unsafe { queue.add(&[], &mut [buffer.as_mut_slice()]) }?;
-            assert_eq!(i, token.into());
-            *queue_buffer = Box::into_raw(buffer);
+    pub fn new(queue: VirtQueue<H, SIZE>) -> Result<Self> {
+        let mut owning_queue = Self {
+            queue,
+            buffers: [None; SIZE],
+            target: SIZE,
+        };
+        owning_queue.fill()?;
+        Ok(owning_queue)
+    }
+
+    /// Allocates new buffers and adds them to the queue until there are `target` in it.
+    fn fill(&mut self) -> Result {
+        while self.queue.in_flight() < self.target {
+            let buffer: Box<[u8; BUFFER_SIZE]> = FromZeros::new_box_zeroed().unwrap();
+            let buffer = NonNull::new(Box::into_raw(buffer)).unwrap();
+            // SAFETY: The buffer lives until it is freed by `free_buffer` or when the queue is
+            // dropped, and we don't access it until it is popped.
+            let token = unsafe { self.queue.add(&[], &mut [&mut *buffer.as_ptr()]) };
+            let token = match token {
+                Ok(token) => token,
+                Err(e) => {
+                    // SAFETY: The buffer was never added to the queue.
+                    unsafe { drop(Box::from_raw(buffer.as_ptr())) };
+                    return Err(e);
+                }
+            };
+            let slot = &mut self.buffers[usize::from(token)];
+            assert!(slot.is_none(), "Token {} already has a buffer", token);
+            *slot = Some(buffer);
         }
-        let buffers = buffers.map(|ptr| NonNull::new(ptr).unwrap());
+        Ok(())
+    }
 
-        Ok(Self { queue, buffers })
+    /// Frees the buffer for the given token.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be in the queue, and there must be no other references to it.
+    unsafe fn free_buffer(&mut self, token: u16) {
+        if let Some(buffer) = self.buffers[usize::from(token)].take() {
+            // SAFETY: We obtained the buffer pointer from `Box::into_raw`, and our caller promises
+            // that nothing else is using it.
+            unsafe { drop(Box::from_raw(buffer.as_ptr())) };
+        }
+    }
+
+    /// Lowers the number of buffers kept in the queue to `target`, e.g. to give memory back while
+    /// it is short.
+    ///
+    /// Buffers the device has been given can't be taken back from it, so this doesn't free
+    /// anything straight away. Instead, as each buffer beyond the target is popped by
+    /// [`poll`](Self::poll), it is freed rather than added back to the queue.
+    pub fn shrink_available(&mut self, target: u16) {
+        self.target = self.target.min(target.into());
+    }
+
+    /// Raises the number of buffers kept in the queue to `target`, allocating and adding new
+    /// buffers straight away to make up the difference.
+    ///
+    /// Returns [`Error::InvalidParam`] if `target` is more than the size of the queue. The caller
+    /// is responsible for notifying the device if `should_notify` returns true.
+    pub fn grow(&mut self, target: u16) -> Result {
+        if usize::from(target) > usize::from(self.queue.size()) {
+            return Err(Error::InvalidParam);
+        }
+        self.target = self.target.max(target.into());
+        self.fill()
+    }
+
+    /// Returns the number of buffers currently in the queue.
+    pub fn available(&self) -> usize {
+        self.queue.in_flight()
     }
 
     /// Returns whether the driver should notify the device after adding a new buffer to the
@@ -43,6 +109,34 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
         self.queue.should_notify()
     }
 
+    /// Notifies the device about this queue, with notification data if it has been negotiated.
+    pub(crate) fn notify_device(&self, transport: &mut impl Transport) {
+        self.queue.notify_device(transport);
+    }
+
+    /// Returns whether there is a used buffer waiting to be popped.
+    pub fn can_pop(&self) -> bool {
+        self.queue.can_pop()
+    }
+
+    /// Returns the number of bytes of DMA memory the underlying queue holds.
+    ///
+    /// The buffers are allocated from the heap and shared with the device, so aren't counted.
+    pub fn dma_footprint(&self) -> usize {
+        self.queue.dma_footprint()
+    }
+
+    /// Returns a snapshot of the underlying queue's state, for debugging.
+    pub fn snapshot(&self) -> super::QueueSnapshot {
+        self.queue.snapshot()
+    }
+
+    /// Returns the counters of operations on the underlying queue.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> super::QueueStats {
+        self.queue.stats()
+    }
+
     /// Tells the device whether to send used buffer notifications.
     pub fn set_dev_notify(&mut self, enable: bool) {
         self.queue.set_dev_notify(enable);
@@ -63,6 +157,7 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
             let buffer = self
                 .buffers
                 .get_mut(usize::from(index))
+                .and_then(Option::as_mut)
                 .ok_or(Error::WrongToken)?
                 .as_mut();
             let new_token = self.queue.add(&[], &mut [buffer])?;
@@ -72,7 +167,7 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
         }
 
         if self.queue.should_notify() {
-            transport.notify(self.queue.queue_idx);
+            self.queue.notify_device(transport);
         }
 
         Ok(())
@@ -85,7 +180,13 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
 
         // SAFETY: The device has told us it has finished using the buffer, and there are no other
         // references to it.
-        let buffer = unsafe { self.buffers[usize::from(token)].as_mut() };
+        let buffer = unsafe {
+            self.buffers
+                .get_mut(usize::from(token))
+                .and_then(Option::as_mut)
+                .ok_or(Error::WrongToken)?
+                .as_mut()
+        };
         // SAFETY: We maintain a consistent mapping of tokens to buffers, so we pass the same buffer
         // to `pop_used` as we previously passed to `add` for the token. Once we add the buffer back
         // to the RX queue then we don't access it again until next time it is popped.
@@ -105,7 +206,9 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
     /// `Ok(None)`.
     ///
     /// If `handler` panics then the buffer will not be added back to the queue, so this should be
-    /// avoided.
+    /// avoided. The buffer is freed rather than added back if the queue has been shrunk with
+    /// [`shrink_available`](Self::shrink_available) and still has at least the target number of
+    /// buffers without it.
     pub fn poll<T>(
         &mut self,
         transport: &mut impl Transport,
@@ -117,6 +220,12 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> OwningQueue<H, SIZE, B
 
         let result = handler(buffer);
 
+        if self.queue.in_flight() >= self.target {
+            // SAFETY: The buffer was just popped from the queue so it's not in it, and `handler`
+            // has finished with it.
+            unsafe { self.free_buffer(token) };
+            return result;
+        }
         // SAFETY: The buffer was just popped from the queue so it's not in it, and there won't be
         // any other references until next time it's popped.
         unsafe {
@@ -147,10 +256,59 @@ impl<H: Hal, const SIZE: usize, const BUFFER_SIZE: usize> Drop
     for OwningQueue<H, SIZE, BUFFER_SIZE>
 {
     fn drop(&mut self) {
-        for buffer in self.buffers {
+        for buffer in self.buffers.into_iter().flatten() {
             // SAFETY: Safe because we obtained the buffer pointer from Box::into_raw, and it won't be used
             // anywhere else after the queue is destroyed.
             unsafe { drop(Box::from_raw(buffer.as_ptr())) };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{fake::FakeTransport, DeviceType},
+    };
+    use alloc::vec;
+
+    #[test]
+    fn shrink_and_grow() {
+        let mut config_space = ();
+        let (mut transport, state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, 0, &mut config_space);
+        let queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        let mut queue = OwningQueue::<FakeHal, 4, 8>::new(queue).unwrap();
+        assert_eq!(queue.available(), 4);
+
+        // Nothing is freed until the device gives buffers back.
+        queue.shrink_available(2);
+        assert_eq!(queue.available(), 4);
+        for i in 0..3 {
+            state.lock().unwrap().write_to_queue::<4>(0, &[i]);
+            assert_eq!(
+                queue.poll(&mut transport, |buffer| Ok(Some(buffer.to_vec()))),
+                Ok(Some(vec![i]))
+            );
+        }
+        // The first two buffers were freed, and the third added back.
+        assert_eq!(queue.available(), 2);
+        assert_eq!(queue.buffers.iter().flatten().count(), 2);
+
+        assert_eq!(queue.grow(5), Err(Error::InvalidParam));
+        assert_eq!(queue.grow(4), Ok(()));
+        assert_eq!(queue.available(), 4);
+        assert_eq!(queue.buffers.iter().flatten().count(), 4);
+
+        // The new buffers are used like the old ones.
+        for i in 0..4 {
+            state.lock().unwrap().write_to_queue::<4>(0, &[i, i]);
+            assert_eq!(
+                queue.poll(&mut transport, |buffer| Ok(Some(buffer.to_vec()))),
+                Ok(Some(vec![i, i]))
+            );
+        }
+        assert_eq!(queue.available(), 4);
+    }
+}
@@ -0,0 +1,1537 @@
+// SPDX-License-Identifier: MIT
+
+//! Packed virtqueues.
+
+#[cfg(feature = "stats")]
+use super::QueueStats;
+use super::{
+    check_usable, queue_size, segment_count, vring_need_event, wait_unless_needs_reset,
+    InputOutputIter, QueueSnapshot,
+};
+use crate::hal::{BufferDirection, Dma, DmaPurpose, Hal};
+#[cfg(any(test, feature = "test-utils"))]
+use crate::transport::fake::QueueStatus;
+use crate::transport::{features::NOTIFICATION_DATA, Transport};
+use crate::{nonnull_slice_from_raw_parts, pages, Error, Result};
+#[cfg(any(test, feature = "test-utils"))]
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+#[cfg(feature = "stats")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU16, Ordering};
+#[cfg(any(test, feature = "test-utils"))]
+use core::{cmp::min, ptr, slice};
+use log::warn;
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+/// A virtqueue using the packed layout, for devices which have negotiated
+/// `VIRTIO_F_RING_PACKED`.
+///
+/// This offers the same API as [`VirtQueue`](super::VirtQueue), so a driver can use whichever
+/// layout was negotiated with the device. If the device doesn't offer `VIRTIO_F_RING_PACKED`, the
+/// driver must use a split [`VirtQueue`](super::VirtQueue) instead.
+///
+/// * `SIZE`: The maximum size of the queue. This is the number of descriptors in the ring unless
+///   the queue is created with [`with_size`](Self::with_size), and must fit in 15 bits.
+///
+/// Ref: 2.8 Packed Virtqueues
+#[derive(Debug)]
+pub struct PackedVirtQueue<H: Hal, const SIZE: usize> {
+    /// DMA region containing the descriptor ring and both event suppression structures.
+    dma: Dma<H>,
+    /// Descriptor ring
+    ///
+    /// This is written by both the driver and the device, so we shouldn't trust any values read
+    /// back from it other than the used descriptors written by the device. Use `desc_shadow` to
+    /// keep track of what we wrote to it.
+    desc: NonNull<[PackedDescriptor]>,
+    /// Driver event suppression structure, written by the driver and read by the device.
+    driver_event: NonNull<EventSuppression>,
+    /// Device event suppression structure, written by the device and read by the driver.
+    device_event: NonNull<EventSuppression>,
+
+    /// The index of queue
+    queue_idx: u16,
+    /// The number of descriptors and ring slots actually in use, no greater than `SIZE`.
+    size: u16,
+    /// The number of descriptors currently in use.
+    num_used: u16,
+    /// The head of the list of free buffer IDs.
+    free_head: u16,
+    /// Our trusted copy of each descriptor in use, indexed by the ID it was allocated from rather
+    /// than by ring slot, as the device may overwrite slots out of order.
+    desc_shadow: [PackedDescriptor; SIZE],
+    /// The next ID in the free list, or in the chain for IDs which are in use.
+    next_id: [u16; SIZE],
+    /// The number of descriptors in the chain of each buffer ID in use.
+    chain_len: [u16; SIZE],
+    /// The ring slot to which the next descriptor will be written.
+    next_avail_idx: u16,
+    /// The driver's ring wrap counter, flipped each time `next_avail_idx` wraps around.
+    avail_wrap_counter: bool,
+    /// The ring slot at which the device will write the next used descriptor.
+    last_used_idx: u16,
+    /// The wrap counter of the device for the used descriptor at `last_used_idx`.
+    used_wrap_counter: bool,
+    /// The number of descriptors made available since `should_notify` was last called.
+    num_added: AtomicU16,
+    /// The flags last written to the driver event suppression structure.
+    driver_event_flags: EventFlags,
+    /// Whether to use descriptor-specific event suppression.
+    event_idx: bool,
+    /// The number of buffer IDs whose chains are in the queue.
+    num_in_flight: u16,
+    /// The used length of each chain which the device completed while a blocking wait was waiting
+    /// for a different one, indexed by its buffer ID. These have been taken off the ring, and are
+    /// popped before anything still on it.
+    set_aside: [Option<u32>; SIZE],
+    /// The number of entries in `set_aside` which are `Some`.
+    num_set_aside: u16,
+    /// Counters for `stats`, other than `notifications`.
+    #[cfg(feature = "stats")]
+    stats: QueueStats,
+    /// The number of times `should_notify` has returned true. This is separate from `stats` as
+    /// `should_notify` only takes `&self`.
+    #[cfg(feature = "stats")]
+    notifications: AtomicU64,
+}
+
+impl<H: Hal, const SIZE: usize> PackedVirtQueue<H, SIZE> {
+    const SIZE_OK: () = assert!(SIZE > 0 && SIZE <= 1 << 15);
+
+    /// Creates a new packed virtqueue.
+    ///
+    /// * `indirect`: Whether to use indirect descriptors. Packed queues don't currently use
+    ///   indirect descriptors, so this is ignored. It is accepted so that the signature matches
+    ///   [`VirtQueue::new`](super::VirtQueue::new).
+    /// * `event_idx`: Whether to use descriptor-specific event suppression. This should be set if
+    ///   the `VIRTIO_F_EVENT_IDX` feature has been negotiated with the device.
+    ///
+    /// The queue has `SIZE` descriptors. Returns [`Error::InvalidParam`] if the device doesn't
+    /// support a queue that big, or [`Error::Unsupported`] if the transport requires the legacy
+    /// layout, as packed queues can only be used by non-legacy devices.
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        if transport.max_queue_size(idx) < SIZE as u32 {
+            return Err(Error::InvalidParam);
+        }
+        Self::with_size(transport, idx, SIZE as u16, indirect, event_idx)
+    }
+
+    /// Creates a new packed virtqueue with about `requested_size` descriptors, rather than `SIZE`.
+    ///
+    /// The size is chosen as for [`VirtQueue::with_size`](super::VirtQueue::with_size), so that a
+    /// driver gets the same size whichever layout it uses. Use [`size`](Self::size) to find out
+    /// the result. The other parameters are as for [`new`](Self::new).
+    pub fn with_size<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        requested_size: u16,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+        let _ = indirect;
+
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        if transport.requires_legacy_layout() {
+            return Err(Error::Unsupported);
+        }
+        let size = queue_size(requested_size, SIZE as u16, transport.max_queue_size(idx))
+            .ok_or(Error::InvalidParam)?;
+
+        // The descriptor ring is written by both sides, so put it and the two event suppression
+        // structures in a single region.
+        let ring_size = usize::from(size) * size_of::<PackedDescriptor>();
+        let dma = Dma::new(
+            pages(ring_size + 2 * size_of::<EventSuppression>()),
+            BufferDirection::Both,
+            DmaPurpose::Ring,
+        )?;
+        let driver_event_offset = ring_size;
+        let device_event_offset = ring_size + size_of::<EventSuppression>();
+
+        transport.queue_set(
+            idx,
+            size.into(),
+            dma.paddr(),
+            dma.paddr() + driver_event_offset,
+            dma.paddr() + device_event_offset,
+        );
+
+        let desc = nonnull_slice_from_raw_parts(dma.vaddr(0).cast(), size.into());
+        let driver_event = dma.vaddr(driver_event_offset).cast();
+        let device_event = dma.vaddr(device_event_offset).cast();
+
+        Ok(PackedVirtQueue {
+            dma,
+            desc,
+            driver_event,
+            device_event,
+            queue_idx: idx,
+            size,
+            num_used: 0,
+            free_head: 0,
+            desc_shadow: FromZeros::new_zeroed(),
+            next_id: Self::free_list(),
+            chain_len: [0; SIZE],
+            next_avail_idx: 0,
+            avail_wrap_counter: true,
+            last_used_idx: 0,
+            used_wrap_counter: true,
+            num_added: AtomicU16::new(0),
+            driver_event_flags: EventFlags::ENABLE,
+            event_idx,
+            num_in_flight: 0,
+            set_aside: [None; SIZE],
+            num_set_aside: 0,
+            #[cfg(feature = "stats")]
+            stats: QueueStats::default(),
+            #[cfg(feature = "stats")]
+            notifications: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns `next_id` with all IDs linked together into the free list.
+    fn free_list() -> [u16; SIZE] {
+        let mut next_id = [0; SIZE];
+        for (i, next) in next_id.iter_mut().enumerate() {
+            *next = (i + 1) as u16;
+        }
+        next_id
+    }
+
+    /// Returns the size of the queue, i.e. the number of descriptors it has.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_add_packed
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let descriptors_needed = segment_count(inputs, outputs, H::max_contiguous_dma());
+        if usize::from(self.num_used) + descriptors_needed > usize::from(self.size) {
+            #[cfg(feature = "stats")]
+            {
+                self.stats.queue_full += 1;
+            }
+            return Err(Error::QueueFull);
+        }
+
+        let id = self.free_head;
+        let head_slot = self.next_avail_idx;
+        let mut head_flags = PackedDescFlags::empty();
+        let mut last_id = id;
+
+        for (i, (buffer, direction)) in
+            InputOutputIter::new(inputs, outputs, H::max_contiguous_dma()).enumerate()
+        {
+            assert_ne!(buffer.len(), 0);
+
+            let desc_id = self.free_head;
+            self.free_head = self.next_id[usize::from(desc_id)];
+            last_id = desc_id;
+
+            let desc = &mut self.desc_shadow[usize::from(desc_id)];
+            let next = if i + 1 < descriptors_needed {
+                PackedDescFlags::NEXT
+            } else {
+                PackedDescFlags::empty()
+            };
+            // SAFETY: Safe because our caller promises that the buffers live at least until
+            // `pop_used` returns them.
+            unsafe {
+                desc.set_buf::<H>(buffer, direction, next);
+            }
+            // The device reports the buffer ID of the last descriptor in the chain, but set it on
+            // all of them for consistency.
+            desc.id = id;
+
+            let flags = desc.flags | PackedDescFlags::avail(self.avail_wrap_counter);
+            if i == 0 {
+                // Don't make the chain available until all other descriptors have been written.
+                head_flags = flags;
+                self.write_desc(self.next_avail_idx, desc_id, PackedDescFlags::empty());
+            } else {
+                self.write_desc(self.next_avail_idx, desc_id, flags);
+            }
+
+            self.next_avail_idx += 1;
+            if self.next_avail_idx == self.size {
+                self.next_avail_idx = 0;
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+        }
+        // Keep the rest of the free list separate from the chain.
+        self.next_id[usize::from(last_id)] = self.free_head;
+        self.chain_len[usize::from(id)] = descriptors_needed as u16;
+        self.num_used += descriptors_needed as u16;
+        self.num_in_flight += 1;
+
+        // Write barrier so that device sees changes to the rest of the chain before the head
+        // flags mark it as available.
+        H::mb();
+        // SAFETY: `head_slot` is within the ring, and the device doesn't write to the slot until
+        // we make it available here.
+        unsafe {
+            self.desc_flags(head_slot)
+                .store(head_flags.bits().to_le(), Ordering::Release);
+        }
+
+        let num_added = self.num_added.get_mut();
+        *num_added = num_added.saturating_add(descriptors_needed as u16);
+        #[cfg(feature = "stats")]
+        {
+            self.stats.submissions += 1;
+        }
+
+        Ok(id)
+    }
+
+    /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
+    /// them, then pops them.
+    ///
+    /// Chains which the device uses first are set aside to be popped later, as for
+    /// [`VirtQueue::add_notify_wait_pop`](super::VirtQueue::add_notify_wait_pop).
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// If the device needs a reset this fails as for
+    /// [`VirtQueue::add_notify_wait_pop`](super::VirtQueue::add_notify_wait_pop).
+    pub fn add_notify_wait_pop<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        check_usable(transport)?;
+        // SAFETY: Safe because we don't return until the same token has been popped or the device
+        // has been reset, so the buffers remain valid and are not otherwise accessed until the
+        // device is done with them.
+        let token = unsafe { self.add(inputs, outputs) }?;
+
+        // Notify the queue.
+        if self.should_notify() {
+            self.notify_device(transport);
+        }
+
+        // Wait until there is at least one used descriptor, setting aside any for other chains.
+        // Each chain is only set aside once, and ours never is, so this ends after at most one
+        // pass over the queue.
+        for _ in 0..self.size {
+            wait_unless_needs_reset(transport, || self.used_pending())?;
+            if !self.set_aside_used(token) {
+                break;
+            }
+        }
+
+        // SAFETY: Safe because these are the same buffers as we passed to `add` above and they are
+        // still valid.
+        unsafe { self.pop_used(token, inputs, outputs) }
+    }
+
+    /// Advise the device whether used buffer notifications are needed.
+    ///
+    /// If `event_idx` is enabled, enabling notifications asks for one when the next buffer is used.
+    ///
+    /// Ref: 2.8.10 Event Suppression Structure Format
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        self.driver_event_flags = match (enable, self.event_idx) {
+            (false, _) => EventFlags::DISABLE,
+            (true, false) => EventFlags::ENABLE,
+            (true, true) => EventFlags::DESC,
+        };
+        if self.driver_event_flags == EventFlags::DESC {
+            self.write_driver_event_off_wrap();
+        }
+        // SAFETY: Safe because self.driver_event points to a valid, aligned, initialised,
+        // dereferenceable instance of EventSuppression.
+        unsafe {
+            (*self.driver_event.as_ptr())
+                .flags
+                .store(self.driver_event_flags.bits().to_le(), Ordering::Release);
+        }
+    }
+
+    /// Points the driver event suppression structure at the next used descriptor.
+    fn write_driver_event_off_wrap(&mut self) {
+        let off_wrap = self.last_used_idx | u16::from(self.used_wrap_counter) << 15;
+        // SAFETY: Safe because self.driver_event points to a valid, aligned, initialised,
+        // dereferenceable instance of EventSuppression.
+        unsafe {
+            (*self.driver_event.as_ptr())
+                .off_wrap
+                .store(off_wrap.to_le(), Ordering::Release);
+        }
+    }
+
+    /// Returns whether the driver should notify the device after adding new buffers to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has supressed notifications for all the descriptors added
+    /// since the last call.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_packed
+    pub fn should_notify(&self) -> bool {
+        let num_added = self.num_added.swap(0, Ordering::AcqRel);
+
+        // Make sure the device sees the new descriptors before we read its event suppression
+        // structure.
+        H::mb();
+        // SAFETY: Safe because self.device_event points to a valid, aligned, initialised,
+        // dereferenceable, readable instance of EventSuppression.
+        let (flags, off_wrap) = unsafe {
+            (
+                u16::from_le((*self.device_event.as_ptr()).flags.load(Ordering::Acquire)),
+                u16::from_le(
+                    (*self.device_event.as_ptr())
+                        .off_wrap
+                        .load(Ordering::Acquire),
+                ),
+            )
+        };
+
+        let notify = match EventFlags::from_bits_retain(flags) {
+            EventFlags::DISABLE => false,
+            EventFlags::DESC if self.event_idx => {
+                let new = self.next_avail_idx;
+                let old = new.wrapping_sub(num_added);
+                let mut event = off_wrap & !(1 << 15);
+                if (off_wrap >> 15 != 0) != self.avail_wrap_counter {
+                    event = event.wrapping_sub(self.size);
+                }
+                vring_need_event(event, new, old)
+            }
+            // Treat anything else, including reserved values, as enabled rather than risk never
+            // notifying the device.
+            _ => true,
+        };
+        #[cfg(feature = "stats")]
+        if notify {
+            self.notifications.fetch_add(1, Ordering::Relaxed);
+        }
+        notify
+    }
+
+    /// Notifies the device about this queue, telling it the next ring slot and wrap counter too if
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    pub(crate) fn notify_device(&self, transport: &mut impl Transport) {
+        // Make sure the device sees the newly available descriptors before it is notified.
+        H::mb();
+        if transport.negotiated_features() & NOTIFICATION_DATA != 0 {
+            transport.notify_with_data(
+                self.queue_idx,
+                u32::from(self.queue_idx)
+                    | u32::from(self.next_avail_idx) << 16
+                    | u32::from(self.avail_wrap_counter) << 31,
+            );
+        } else {
+            transport.notify(self.queue_idx);
+        }
+    }
+
+    /// Copies the shadow descriptor for the given ID to the given ring slot, with the given flags.
+    fn write_desc(&mut self, slot: u16, id: u16, flags: PackedDescFlags) {
+        let shadow = &self.desc_shadow[usize::from(id)];
+        // SAFETY: Safe because self.desc is properly aligned, dereferenceable and initialised, and
+        // the device doesn't access the slot until the chain is made available.
+        unsafe {
+            let desc = addr_of_mut!((*self.desc.as_ptr())[usize::from(slot)]);
+            addr_of_mut!((*desc).addr).write_volatile(shadow.addr.to_le());
+            addr_of_mut!((*desc).len).write_volatile(shadow.len.to_le());
+            addr_of_mut!((*desc).id).write_volatile(shadow.id.to_le());
+            self.desc_flags(slot)
+                .store(flags.bits().to_le(), Ordering::Release);
+        }
+    }
+
+    /// Returns the flags field of the descriptor in the given ring slot.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must be less than `self.size`.
+    unsafe fn desc_flags(&self, slot: u16) -> &AtomicU16 {
+        // SAFETY: Safe because self.desc is properly aligned, dereferenceable and initialised, the
+        // caller ensures that the slot is within it, and the flags are only accessed atomically.
+        unsafe {
+            AtomicU16::from_ptr(
+                addr_of_mut!((*self.desc.as_ptr())[usize::from(slot)].flags).cast::<u16>(),
+            )
+        }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.num_set_aside != 0 || self.used_pending()
+    }
+
+    /// Returns whether the device has used a descriptor at `last_used_idx` which hasn't been
+    /// popped or set aside yet.
+    fn used_pending(&self) -> bool {
+        // SAFETY: `last_used_idx` is always within the ring.
+        let flags = PackedDescFlags::from_bits_retain(u16::from_le(
+            unsafe { self.desc_flags(self.last_used_idx) }.load(Ordering::Acquire),
+        ));
+        flags.is_used(self.used_wrap_counter)
+    }
+
+    /// Returns the buffer ID and length of the used descriptor at `last_used_idx`. The device must
+    /// have used it.
+    fn next_used(&self) -> (u16, u32) {
+        // SAFETY: Safe because self.desc is properly aligned, dereferenceable and initialised, and
+        // the device has finished writing the used descriptor at `last_used_idx`, which is always
+        // within the ring.
+        unsafe {
+            let desc = addr_of!((*self.desc.as_ptr())[usize::from(self.last_used_idx)]);
+            (
+                u16::from_le(addr_of!((*desc).id).read_volatile()),
+                u32::from_le(addr_of!((*desc).len).read_volatile()),
+            )
+        }
+    }
+
+    /// Moves `last_used_idx` past the used descriptor of a chain of the given length.
+    fn advance_used(&mut self, chain_len: u16) {
+        // The device skips over the rest of the chain's slots when writing used descriptors.
+        self.last_used_idx += chain_len;
+        if self.last_used_idx >= self.size {
+            self.last_used_idx -= self.size;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+
+        if self.driver_event_flags == EventFlags::DESC {
+            self.write_driver_event_off_wrap();
+        }
+    }
+
+    /// Returns the number of descriptors in the chain of the given buffer ID, or 0 if it isn't in
+    /// the queue.
+    fn chain_len_of(&self, id: u16) -> u16 {
+        self.chain_len.get(usize::from(id)).copied().unwrap_or(0)
+    }
+
+    /// Returns whether the chain with the given token has been used, so that `pop_used` can pop
+    /// it now, setting aside any other chains used before it as for
+    /// [`VirtQueue::token_used`](super::VirtQueue::token_used).
+    pub(crate) fn token_used(&mut self, token: u16) -> bool {
+        if self
+            .set_aside
+            .get(usize::from(token))
+            .is_some_and(Option::is_some)
+        {
+            return true;
+        }
+        // Each chain is only set aside once, so this ends after at most one pass over the queue.
+        for _ in 0..self.size {
+            if !self.used_pending() {
+                return false;
+            }
+            if !self.set_aside_used(token) {
+                break;
+            }
+        }
+        self.used_pending() && self.next_used().0 == token
+    }
+
+    /// Takes the next used descriptor off the ring and sets its chain aside to be popped later, if
+    /// it is for a chain in flight other than `expected`. Returns whether it did so.
+    ///
+    /// Anything else is left for `pop_used` to pop or reject.
+    fn set_aside_used(&mut self, expected: u16) -> bool {
+        let (id, len) = self.next_used();
+        let chain_len = self.chain_len_of(id);
+        if id == expected || chain_len == 0 || self.set_aside[usize::from(id)].is_some() {
+            return false;
+        }
+        self.set_aside[usize::from(id)] = Some(len);
+        self.num_set_aside += 1;
+        self.advance_used(chain_len);
+        true
+    }
+
+    /// Returns the buffer ID (a.k.a. token) of the next used element without popping it, or `None`
+    /// if there are no used descriptors.
+    ///
+    /// Chains which a blocking wait set aside come before anything still on the ring.
+    pub fn peek_used(&self) -> Option<u16> {
+        if self.num_set_aside != 0 {
+            return (0..self.size).find(|&id| self.set_aside[usize::from(id)].is_some());
+        }
+        if self.used_pending() {
+            Some(self.next_used().0)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        usize::from(self.size - self.num_used)
+    }
+
+    /// Returns the number of chains which have been added but not popped yet, whether or not the
+    /// device has used them.
+    pub fn in_flight(&self) -> usize {
+        self.num_in_flight.into()
+    }
+
+    /// Returns how many chains of `descriptors_per_chain` descriptors each the queue can hold at
+    /// once. Packed queues don't use indirect descriptors, so this is the queue size divided by
+    /// the chain length.
+    pub fn max_in_flight(&self, descriptors_per_chain: usize) -> usize {
+        let size = usize::from(self.size);
+        if descriptors_per_chain == 0 || descriptors_per_chain > size {
+            return 0;
+        }
+        size / descriptors_per_chain
+    }
+
+    /// Returns a snapshot of the queue's indices and descriptor usage, for debugging.
+    ///
+    /// The indices are ring slots. A packed ring has no used index, so `used_idx` is the slot of
+    /// the next used descriptor the driver will look at, like `last_used_idx`.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            queue_idx: self.queue_idx,
+            size: self.size,
+            avail_idx: self.next_avail_idx,
+            used_idx: self.last_used_idx,
+            last_used_idx: self.last_used_idx,
+            descriptors_in_use: self.num_used,
+        }
+    }
+
+    /// Returns the counters of operations on the queue since it was created.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            notifications: self.notifications.load(Ordering::Relaxed),
+            ..self.stats
+        }
+    }
+
+    /// Returns the number of bytes of DMA memory the queue holds, as allocated with
+    /// [`Hal::dma_alloc`].
+    pub fn dma_footprint(&self) -> usize {
+        self.dma.size()
+    }
+
+    /// Unshares the buffers in the chain of the given buffer ID and returns the IDs to the free
+    /// list. Unsharing may involve copying data back to the original buffers, so they must be
+    /// passed in too.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add`.
+    unsafe fn recycle_descriptors<'a>(
+        &mut self,
+        id: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) {
+        let chain_len = self.chain_len[usize::from(id)];
+        assert_eq!(
+            usize::from(chain_len),
+            segment_count(inputs, outputs, H::max_contiguous_dma())
+        );
+
+        let mut desc_id = id;
+        for (i, (buffer, direction)) in
+            InputOutputIter::new(inputs, outputs, H::max_contiguous_dma()).enumerate()
+        {
+            assert_ne!(buffer.len(), 0);
+
+            let desc = &mut self.desc_shadow[usize::from(desc_id)];
+            let paddr = desc.addr;
+            desc.unset_buf();
+
+            // SAFETY: The caller ensures that the buffer is valid and matches the descriptor from
+            // which we got `paddr`.
+            unsafe {
+                // Sync and unshare the buffer (and perhaps copy its contents back to the original buffer).
+                H::sync_for_cpu(paddr as usize, buffer.len(), direction);
+                H::unshare(paddr as usize, buffer, direction);
+            }
+
+            if i + 1 < usize::from(chain_len) {
+                desc_id = self.next_id[usize::from(desc_id)];
+            }
+        }
+
+        // Put the whole chain at the front of the free list.
+        self.next_id[usize::from(desc_id)] = self.free_head;
+        self.free_head = id;
+        self.chain_len[usize::from(id)] = 0;
+        self.num_used -= chain_len;
+        self.num_in_flight -= 1;
+    }
+
+    /// If the given token is the next used element, pops it and returns the total buffer length
+    /// which was used (written) by the device.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx_packed
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+    ) -> Result<u32> {
+        if let Some(len) = self
+            .set_aside
+            .get_mut(usize::from(token))
+            .and_then(Option::take)
+        {
+            self.num_set_aside -= 1;
+            // SAFETY: Safe because the caller ensures the buffers are valid and match the
+            // descriptor.
+            unsafe {
+                self.recycle_descriptors(token, inputs, outputs);
+            }
+            self.record_completion();
+            return Ok(len);
+        }
+        if !self.used_pending() {
+            return Err(Error::NotReady);
+        }
+
+        let (id, len) = self.next_used();
+        if id != token {
+            // The device used a different descriptor chain to the one we were expecting.
+            return Err(Error::WrongToken);
+        }
+        // The token was returned by `add`, so it must be in use; check anyway rather than trust
+        // the caller or the device, which may have made it up.
+        let chain_len = self.chain_len_of(id);
+        if chain_len == 0 {
+            warn!("Used ring returned chain {} which isn't in flight", id);
+            return Err(Error::WrongToken);
+        }
+
+        // SAFETY: Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(id, inputs, outputs);
+        }
+        self.advance_used(chain_len);
+        self.record_completion();
+
+        Ok(len)
+    }
+
+    /// Counts a chain which has been popped.
+    fn record_completion(&mut self) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.completions += 1;
+        }
+    }
+
+    /// Abandons every descriptor chain still in flight, releasing its buffers without copying
+    /// anything back, and returns the queue to the state it was in when it was created, as for
+    /// [`VirtQueue::abort_all`](super::VirtQueue::abort_all).
+    ///
+    /// # Safety
+    ///
+    /// The device must have been reset, or have had the queue disabled, so that it no longer
+    /// accesses the queue or any of the buffers in it.
+    pub unsafe fn abort_all(&mut self, mut abandoned: impl FnMut(u16)) {
+        let chain_len = self.chain_len;
+
+        for desc in &mut self.desc_shadow {
+            // SAFETY: The descriptor was set by `set_buf`, and our caller promises that the device
+            // is done with it.
+            unsafe { desc.abandon_buf::<H>() };
+        }
+        // Clear the ring, so that descriptors the device used before aren't taken as used again
+        // once the wrap counters start over.
+        for slot in 0..self.size {
+            // SAFETY: `slot` is within the ring, which the device isn't accessing.
+            unsafe { self.desc_flags(slot) }.store(0, Ordering::Release);
+        }
+        // SAFETY: Safe because self.device_event points to a valid, aligned, initialised,
+        // dereferenceable instance of EventSuppression, which the device isn't accessing.
+        unsafe {
+            (*self.device_event.as_ptr())
+                .flags
+                .store(0, Ordering::Release);
+            (*self.device_event.as_ptr())
+                .off_wrap
+                .store(0, Ordering::Release);
+        }
+        self.next_id = Self::free_list();
+        self.chain_len = [0; SIZE];
+        self.num_used = 0;
+        self.free_head = 0;
+        self.next_avail_idx = 0;
+        self.avail_wrap_counter = true;
+        self.last_used_idx = 0;
+        self.used_wrap_counter = true;
+        self.num_added.store(0, Ordering::Relaxed);
+        self.num_in_flight = 0;
+        self.set_aside = [None; SIZE];
+        self.num_set_aside = 0;
+        self.set_dev_notify(self.driver_event_flags != EventFlags::DISABLE);
+
+        for id in (0..self.size).filter(|&id| chain_len[usize::from(id)] != 0) {
+            abandoned(id);
+        }
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Drop for PackedVirtQueue<H, SIZE> {
+    fn drop(&mut self) {
+        // Release any buffers which were never popped. Buffers which have been popped already had
+        // their descriptors cleared, so they won't be unshared again.
+        for desc in &mut self.desc_shadow {
+            // SAFETY: The descriptor was set by `set_buf`, and the device must have been reset or
+            // had the queue disabled before the queue is dropped.
+            unsafe { desc.abandon_buf::<H>() };
+        }
+    }
+}
+
+// SAFETY: None of the virt queue resources are tied to a particular thread.
+unsafe impl<H: Hal, const SIZE: usize> Send for PackedVirtQueue<H, SIZE> {}
+
+// SAFETY: A `&PackedVirtQueue` only allows reading from the various pointers it contains, so there
+// is no data race.
+unsafe impl<H: Hal, const SIZE: usize> Sync for PackedVirtQueue<H, SIZE> {}
+
+/// A descriptor in the packed descriptor ring.
+///
+/// Ref: 2.8.13 Packed Virtqueue Descriptor Format
+#[repr(C, align(16))]
+#[derive(Clone, Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: PackedDescFlags,
+}
+
+impl PackedDescriptor {
+    /// Sets the buffer address, length and flags, and shares it with the device.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the buffer lives at least as long as the descriptor is active.
+    unsafe fn set_buf<H: Hal>(
+        &mut self,
+        buf: NonNull<[u8]>,
+        direction: BufferDirection,
+        extra_flags: PackedDescFlags,
+    ) {
+        // SAFETY: Safe because our caller promises that the buffer is valid, and the address
+        // passed to `sync_for_device` was just returned by `share`.
+        unsafe {
+            self.addr = H::share(buf, direction) as u64;
+            H::sync_for_device(self.addr as usize, buf.len(), direction);
+        }
+        self.len = buf.len().try_into().unwrap();
+        self.flags = extra_flags
+            | match direction {
+                BufferDirection::DeviceToDriver => PackedDescFlags::WRITE,
+                BufferDirection::DriverToDevice => PackedDescFlags::empty(),
+                BufferDirection::Both => {
+                    panic!("Buffer passed to device should never use BufferDirection::Both.")
+                }
+            };
+    }
+
+    /// Sets the buffer address and length to 0.
+    ///
+    /// This must only be called once the device has finished using the descriptor.
+    fn unset_buf(&mut self) {
+        self.addr = 0;
+        self.len = 0;
+    }
+
+    /// If the descriptor still points to a shared buffer, unshares it without copying anything
+    /// back and clears the descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The descriptor must have been set by `set_buf`, and the device must no longer be accessing
+    /// the buffer.
+    unsafe fn abandon_buf<H: Hal>(&mut self) {
+        if self.addr == 0 {
+            return;
+        }
+        let direction = if self.flags.contains(PackedDescFlags::WRITE) {
+            BufferDirection::DeviceToDriver
+        } else {
+            BufferDirection::DriverToDevice
+        };
+        // SAFETY: Our caller promises that the address and length were set by `set_buf` from the
+        // value returned by `share`, and that the device is done with it.
+        unsafe {
+            H::unshare_abandoned(self.addr as usize, self.len as usize, direction);
+        }
+        self.unset_buf();
+    }
+}
+
+/// Packed descriptor flags
+#[derive(
+    Copy, Clone, Debug, Default, Eq, FromBytes, Immutable, IntoBytes, KnownLayout, PartialEq,
+)]
+#[repr(transparent)]
+struct PackedDescFlags(u16);
+
+bitflags! {
+    impl PackedDescFlags: u16 {
+        const NEXT = 1;
+        const WRITE = 2;
+        const INDIRECT = 4;
+        const AVAIL = 1 << 7;
+        const USED = 1 << 15;
+    }
+}
+
+impl PackedDescFlags {
+    /// Returns the `AVAIL` and `USED` flags marking a descriptor as available, for the given
+    /// driver wrap counter.
+    fn avail(wrap_counter: bool) -> Self {
+        if wrap_counter {
+            Self::AVAIL
+        } else {
+            Self::USED
+        }
+    }
+
+    /// Returns whether these flags mark a descriptor as used, for the given device wrap counter.
+    fn is_used(self, wrap_counter: bool) -> bool {
+        let avail = self.contains(Self::AVAIL);
+        let used = self.contains(Self::USED);
+        avail == used && used == wrap_counter
+    }
+}
+
+/// The driver and device event suppression structure.
+///
+/// Ref: 2.8.10 Event Suppression Structure Format
+#[repr(C, align(4))]
+#[derive(Debug)]
+struct EventSuppression {
+    /// Descriptor ring offset in bits 0..15, and wrap counter in bit 15. Only used when `flags`
+    /// is `EventFlags::DESC`.
+    off_wrap: AtomicU16,
+    flags: AtomicU16,
+}
+
+/// Values of the `flags` field of [`EventSuppression`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct EventFlags(u16);
+
+bitflags! {
+    impl EventFlags: u16 {
+        const ENABLE = 0;
+        const DISABLE = 1;
+        const DESC = 2;
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl PackedDescriptor {
+    /// Returns a copy of the descriptor as stored in the ring, converted from little-endian.
+    fn converted(&self) -> Self {
+        Self {
+            addr: u64::from_le(self.addr),
+            len: u32::from_le(self.len),
+            id: u16::from_le(self.id),
+            flags: PackedDescFlags::from_bits_retain(u16::from_le(self.flags.bits())),
+        }
+    }
+}
+
+/// A fake device processing a packed queue, for use in tests.
+///
+/// The fake device always uses buffers in order.
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) struct FakePackedDevice {
+    ring: *mut PackedDescriptor,
+    size: u16,
+    next_idx: u16,
+    wrap_counter: bool,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl FakePackedDevice {
+    /// Creates a fake device for the packed queue which the driver has set up with the given
+    /// status.
+    pub(crate) fn new(queue: &QueueStatus) -> Self {
+        assert_ne!(queue.descriptors, 0);
+        Self {
+            ring: queue.descriptors as *mut PackedDescriptor,
+            size: queue.size.try_into().unwrap(),
+            next_idx: 0,
+            wrap_counter: true,
+        }
+    }
+
+    fn slot(&self, offset: u16) -> (*mut PackedDescriptor, bool) {
+        let mut idx = self.next_idx + offset;
+        let mut wrap_counter = self.wrap_counter;
+        if idx >= self.size {
+            idx -= self.size;
+            wrap_counter = !wrap_counter;
+        }
+        // SAFETY: `idx` is within the ring.
+        (unsafe { self.ring.add(usize::from(idx)) }, wrap_counter)
+    }
+
+    /// Reads the next available buffer chain, passes the device-readable part to the handler,
+    /// and writes its response to the device-writable part.
+    ///
+    /// Returns false if no buffers were available.
+    pub(crate) fn read_write(&mut self, handler: impl FnOnce(Vec<u8>) -> Vec<u8>) -> bool {
+        let mut descriptors = Vec::new();
+        loop {
+            let (desc, wrap_counter) = self.slot(descriptors.len() as u16);
+            // SAFETY: The descriptor is within the ring, and the driver only writes the flags
+            // of an available descriptor atomically.
+            let desc = unsafe { ptr::read_volatile(desc) }.converted();
+            let avail = desc.flags.contains(PackedDescFlags::AVAIL);
+            let used = desc.flags.contains(PackedDescFlags::USED);
+            if avail == used || avail != wrap_counter {
+                assert!(descriptors.is_empty(), "Chain was only partly available.");
+                return false;
+            }
+            let has_next = desc.flags.contains(PackedDescFlags::NEXT);
+            descriptors.push(desc);
+            if !has_next {
+                break;
+            }
+        }
+
+        let mut input = Vec::new();
+        for desc in descriptors
+            .iter()
+            .filter(|desc| !desc.flags.contains(PackedDescFlags::WRITE))
+        {
+            // SAFETY: The driver shared the buffer with FakeHal, which uses identity mapping.
+            input.extend_from_slice(unsafe {
+                slice::from_raw_parts(desc.addr as *const u8, desc.len as usize)
+            });
+        }
+        let input_length = input.len();
+
+        let output = handler(input);
+        let mut remaining_output = output.as_slice();
+        for desc in descriptors
+            .iter()
+            .filter(|desc| desc.flags.contains(PackedDescFlags::WRITE))
+        {
+            let length_to_write = min(remaining_output.len(), desc.len as usize);
+            // SAFETY: The driver shared the buffer with FakeHal, which uses identity mapping.
+            unsafe {
+                ptr::copy(
+                    remaining_output.as_ptr(),
+                    desc.addr as *mut u8,
+                    length_to_write,
+                );
+            }
+            remaining_output = &remaining_output[length_to_write..];
+        }
+        assert_eq!(remaining_output.len(), 0);
+
+        // Write a single used descriptor for the chain, in the first slot.
+        let (first, _) = self.slot(0);
+        let last = descriptors.last().unwrap();
+        // SAFETY: The descriptor is within the ring, and the driver won't touch it until we
+        // mark it as used.
+        unsafe {
+            (*first).id = last.id.to_le();
+            (*first).len = ((input_length + output.len()) as u32).to_le();
+            let flags = if self.wrap_counter {
+                PackedDescFlags::AVAIL | PackedDescFlags::USED
+            } else {
+                PackedDescFlags::empty()
+            };
+            AtomicU16::from_ptr(addr_of_mut!((*first).flags).cast::<u16>())
+                .store(flags.bits().to_le(), Ordering::Release);
+        }
+
+        let (_, wrap_counter) = self.slot(descriptors.len() as u16);
+        self.next_idx = (self.next_idx + descriptors.len() as u16) % self.size;
+        self.wrap_counter = wrap_counter;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        test_utils::{FakeHal, FakeTransport, QueueStatus, State},
+        transport::{
+            mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION},
+            DeviceType,
+        },
+        PhysAddr,
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use std::sync::Mutex;
+
+    fn fake_transport(max_queue_size: u32, device_features: u64) -> FakeTransport<()> {
+        FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size,
+            device_features,
+            config_space: NonNull::dangling(),
+            state: Arc::new(Mutex::new(State {
+                queues: vec![QueueStatus::default()],
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn device_event(state: &Mutex<State>) -> *mut EventSuppression {
+        state.lock().unwrap().queues[0].device_area as *mut EventSuppression
+    }
+
+    #[test]
+    fn legacy_unsupported() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::Unsupported
+        );
+    }
+
+    #[test]
+    fn queue_too_big() {
+        let mut transport = fake_transport(4, 0);
+        assert_eq!(
+            PackedVirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn queue_set() {
+        let mut transport = fake_transport(4, 0);
+        let _queue = PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let state = transport.state.lock().unwrap();
+        let queue = &state.queues[0];
+        assert_eq!(queue.size, 4);
+        assert_eq!(
+            queue.driver_area,
+            queue.descriptors + 4 * size_of::<PackedDescriptor>()
+        );
+        assert_eq!(
+            queue.device_area,
+            queue.driver_area + size_of::<EventSuppression>()
+        );
+    }
+
+    #[test]
+    fn add_empty() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.add(&[], &mut []) }.unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn add_too_many() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(
+            unsafe { queue.add(&[&[], &[], &[]], &mut [&mut [], &mut []]) }.unwrap_err(),
+            Error::QueueFull
+        );
+    }
+
+    #[test]
+    fn add_buffers() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(queue.available_desc(), 0);
+        assert!(!queue.can_pop());
+
+        // SAFETY: Safe because the ring is properly aligned, dereferenceable and initialised, and
+        // nothing else is accessing it at the same time.
+        unsafe {
+            let ring: Vec<PackedDescriptor> = (*queue.desc.as_ptr())
+                .iter()
+                .map(PackedDescriptor::converted)
+                .collect();
+            assert_eq!(ring[0].len, 2);
+            assert_eq!(
+                ring[0].flags,
+                PackedDescFlags::NEXT | PackedDescFlags::AVAIL
+            );
+            assert_eq!(ring[1].len, 1);
+            assert_eq!(
+                ring[1].flags,
+                PackedDescFlags::NEXT | PackedDescFlags::AVAIL
+            );
+            assert_eq!(ring[2].len, 2);
+            assert_eq!(
+                ring[2].flags,
+                PackedDescFlags::NEXT | PackedDescFlags::WRITE | PackedDescFlags::AVAIL
+            );
+            assert_eq!(ring[3].len, 1);
+            assert_eq!(
+                ring[3].flags,
+                PackedDescFlags::WRITE | PackedDescFlags::AVAIL
+            );
+            assert!(ring.iter().all(|desc| desc.id == token));
+        }
+    }
+
+    /// Tests that buffers can be passed through the queue repeatedly, so that both the driver and
+    /// the device wrap around the ring several times.
+    #[test]
+    fn add_pop_wrap_around() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+
+        for i in 0..10u8 {
+            let input = [i, i + 1, i + 2];
+            let mut output = [0; 2];
+            let token = unsafe { queue.add(&[&input], &mut [&mut output]) }.unwrap();
+            assert!(!queue.can_pop());
+            assert_eq!(queue.peek_used(), None);
+
+            assert!(device.read_write(|request| {
+                assert_eq!(request, input);
+                vec![i * 2, i * 3]
+            }));
+            assert!(!device.read_write(|_| unreachable!()));
+
+            assert_eq!(queue.peek_used(), Some(token));
+            assert_eq!(
+                unsafe { queue.pop_used(token, &[&input], &mut [&mut output]) }.unwrap(),
+                5
+            );
+            assert_eq!(output, [i * 2, i * 3]);
+            assert_eq!(queue.available_desc(), 4);
+        }
+    }
+
+    /// Tests that several buffers can be in the queue at once, and must be popped in the order in
+    /// which the device used them.
+    #[test]
+    fn multiple_buffers() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+
+        let mut output_a = [0; 1];
+        let mut output_b = [0; 1];
+        let token_a = unsafe { queue.add(&[&[1]], &mut [&mut output_a]) }.unwrap();
+        let token_b = unsafe { queue.add(&[], &mut [&mut output_b]) }.unwrap();
+        assert_ne!(token_a, token_b);
+        assert_eq!(queue.available_desc(), 1);
+
+        assert!(device.read_write(|_| vec![10]));
+        assert!(device.read_write(|_| vec![20]));
+
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) }.unwrap_err(),
+            Error::WrongToken
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token_a, &[&[1]], &mut [&mut output_a]) }.unwrap(),
+            2
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) }.unwrap(),
+            1
+        );
+        assert_eq!(output_a, [10]);
+        assert_eq!(output_b, [20]);
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) }.unwrap_err(),
+            Error::NotReady
+        );
+    }
+
+    #[test]
+    fn with_size() {
+        let mut transport = fake_transport(8, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 8>::with_size(&mut transport, 0, 3, false, false).unwrap();
+        assert_eq!(queue.size(), 4);
+        assert_eq!(transport.state.lock().unwrap().queues[0].size, 4);
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+
+        // The ring wraps around after 4 slots rather than 8.
+        for i in 0..5u8 {
+            let mut output = [0; 1];
+            let token = unsafe { queue.add(&[&[i]], &mut [&mut output]) }.unwrap();
+            assert!(device.read_write(|_| vec![i]));
+            assert_eq!(
+                unsafe { queue.pop_used(token, &[&[i]], &mut [&mut output]) }.unwrap(),
+                2
+            );
+            assert_eq!(output, [i]);
+        }
+        assert_eq!(queue.snapshot().avail_idx, 2);
+        assert_eq!(
+            PackedVirtQueue::<FakeHal, 8>::with_size(&mut fake_transport(8, 0), 0, 0, false, false)
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    /// Tests that looking for a particular token sets aside the chains which the device used
+    /// before it, so that they can still be popped afterwards.
+    #[test]
+    fn token_used_sets_aside() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+
+        let mut output_a = [0; 1];
+        let mut output_b = [0; 1];
+        let token_a = unsafe { queue.add(&[], &mut [&mut output_a]) }.unwrap();
+        let token_b = unsafe { queue.add(&[], &mut [&mut output_b]) }.unwrap();
+        assert!(!queue.token_used(token_b));
+        assert_eq!(queue.in_flight(), 2);
+
+        assert!(device.read_write(|_| vec![10]));
+        assert!(!queue.token_used(token_b));
+        assert!(device.read_write(|_| vec![20]));
+        assert!(queue.token_used(token_b));
+
+        // A was set aside, so it comes first, but B can be popped now too.
+        assert_eq!(queue.peek_used(), Some(token_a));
+        assert_eq!(
+            unsafe { queue.pop_used(token_b, &[], &mut [&mut output_b]) }.unwrap(),
+            1
+        );
+        assert_eq!(queue.peek_used(), Some(token_a));
+        assert!(queue.token_used(token_a));
+        assert_eq!(
+            unsafe { queue.pop_used(token_a, &[], &mut [&mut output_a]) }.unwrap(),
+            1
+        );
+        assert_eq!(output_a, [10]);
+        assert_eq!(output_b, [20]);
+        assert!(!queue.can_pop());
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    #[test]
+    fn abort_all() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+
+        let mut output = [0; 1];
+        let token_a = unsafe { queue.add(&[&[1]], &mut [&mut output]) }.unwrap();
+        assert!(device.read_write(|_| vec![2]));
+        let token_b = unsafe { queue.add(&[&[3]], &mut []) }.unwrap();
+
+        let mut abandoned = Vec::new();
+        unsafe { queue.abort_all(|token| abandoned.push(token)) };
+        assert_eq!(abandoned, vec![token_a, token_b]);
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(queue.available_desc(), 4);
+        assert!(!queue.can_pop());
+        assert_eq!(queue.peek_used(), None);
+
+        // The queue starts again from the beginning of the ring.
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+        let token = unsafe { queue.add(&[&[4]], &mut [&mut output]) }.unwrap();
+        assert!(device.read_write(|request| {
+            assert_eq!(request, [4]);
+            vec![5]
+        }));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[4]], &mut [&mut output]) }.unwrap(),
+            2
+        );
+        assert_eq!(output, [5]);
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications.
+    #[test]
+    fn add_notify() {
+        let mut transport = fake_transport(4, 0);
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let device_event = device_event(&transport.state);
+
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert!(queue.should_notify());
+
+        // SAFETY: The device event suppression structure is valid and aligned.
+        unsafe {
+            (*device_event)
+                .flags
+                .store(EventFlags::DISABLE.bits().to_le(), Ordering::Release);
+        }
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+    }
+
+    /// Tests that the queue only notifies the device once the descriptor it asked for has been
+    /// made available, including across a ring wrap.
+    #[test]
+    fn add_notify_event_idx() {
+        let mut transport = fake_transport(4, Feature::RING_EVENT_IDX.bits());
+        let mut queue = PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+        let device_event = device_event(&transport.state);
+
+        // Ask to be notified about the descriptor in slot 2 of the first pass through the ring.
+        // SAFETY: The device event suppression structure is valid and aligned.
+        unsafe {
+            (*device_event)
+                .off_wrap
+                .store((2u16 | 1 << 15).to_le(), Ordering::Release);
+            (*device_event)
+                .flags
+                .store(EventFlags::DESC.bits().to_le(), Ordering::Release);
+        }
+
+        let mut notifications = 0;
+        for i in 0..8u8 {
+            let token = unsafe { queue.add(&[&[i]], &mut []) }.unwrap();
+            if queue.should_notify() {
+                notifications += 1;
+                // Only the add to slot 2 should trigger a notification.
+                assert_eq!(i, 2);
+            }
+            assert!(device.read_write(|_| Vec::new()));
+            unsafe { queue.pop_used(token, &[&[i]], &mut []) }.unwrap();
+        }
+        assert_eq!(notifications, 1);
+
+        // Now ask for slot 1 of the next pass, and add several buffers in a batch.
+        // SAFETY: The device event suppression structure is valid and aligned.
+        unsafe {
+            (*device_event)
+                .off_wrap
+                .store((1u16 | 1 << 15).to_le(), Ordering::Release);
+        }
+        let tokens: Vec<u16> = (0..3)
+            .map(|_| unsafe { queue.add(&[&[0]], &mut []) }.unwrap())
+            .collect();
+        assert!(queue.should_notify());
+        assert!(!queue.should_notify());
+        for token in tokens {
+            assert!(device.read_write(|_| Vec::new()));
+            unsafe { queue.pop_used(token, &[&[0]], &mut []) }.unwrap();
+        }
+    }
+
+    /// Tests that the next ring slot and wrap counter are passed with the notification once
+    /// `VIRTIO_F_NOTIFICATION_DATA` has been negotiated.
+    #[test]
+    fn notification_data() {
+        let mut transport = fake_transport(4, Feature::NOTIFICATION_DATA.bits());
+        transport.write_driver_features(Feature::NOTIFICATION_DATA.bits());
+        let mut queue =
+            PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+
+        let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        queue.notify_device(&mut transport);
+        assert_eq!(
+            transport.state.lock().unwrap().queues[0].notification_data,
+            Some(1 << 16 | 1 << 31)
+        );
+        assert!(device.read_write(|_| Vec::new()));
+        unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+
+        // Wrap around the ring, which flips the wrap counter.
+        for i in 0..4u8 {
+            let token = unsafe { queue.add(&[&[i]], &mut []) }.unwrap();
+            assert!(device.read_write(|_| Vec::new()));
+            unsafe { queue.pop_used(token, &[&[i]], &mut []) }.unwrap();
+        }
+        queue.notify_device(&mut transport);
+        assert_eq!(
+            transport.state.lock().unwrap().queues[0].notification_data,
+            Some(1 << 16)
+        );
+        assert_eq!(transport.state.lock().unwrap().queues[0].notify_count, 2);
+    }
+
+    /// Tests that the driver event suppression structure is updated to suppress or request used
+    /// buffer notifications.
+    #[test]
+    fn set_dev_notify() {
+        let mut transport = fake_transport(4, Feature::RING_EVENT_IDX.bits());
+        let mut queue = PackedVirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        let mut device = FakePackedDevice::new(&transport.state.lock().unwrap().queues[0]);
+        let driver_event: PhysAddr = transport.state.lock().unwrap().queues[0].driver_area;
+        let driver_event = driver_event as *const EventSuppression;
+        // SAFETY: The driver event suppression structure is valid and aligned.
+        let read_driver_event = || unsafe {
+            (
+                u16::from_le((*driver_event).flags.load(Ordering::Acquire)),
+                u16::from_le((*driver_event).off_wrap.load(Ordering::Acquire)),
+            )
+        };
+
+        queue.set_dev_notify(false);
+        assert_eq!(read_driver_event().0, EventFlags::DISABLE.bits());
+
+        queue.set_dev_notify(true);
+        assert_eq!(read_driver_event(), (EventFlags::DESC.bits(), 1 << 15));
+
+        // Popping a used buffer should move the event offset along.
+        let token = unsafe { queue.add(&[&[1], &[2]], &mut []) }.unwrap();
+        assert!(device.read_write(|_| Vec::new()));
+        unsafe { queue.pop_used(token, &[&[1], &[2]], &mut []) }.unwrap();
+        assert_eq!(read_driver_event(), (EventFlags::DESC.bits(), 2 | 1 << 15));
+    }
+}
@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: MIT
+
+//! Fakes for testing code which uses the drivers, without a real device or VMM.
+//!
+//! [`FakeTransport`] keeps the config space in ordinary memory and records what the driver does
+//! to the device in a shared [`State`], which a test plays the device with from another thread:
+//! it waits for a queue to be notified, then processes the request with
+//! [`State::read_write_queue`]. [`FakeHal`] allocates DMA memory from the heap and uses identity
+//! address translation, and [`TrackingHal`] additionally counts allocations and shared buffers
+//! which haven't been released.
+//!
+//! These are only available with the `test-utils` feature, which needs `std`.
+//!
+//! # Example
+//!
+//! ```
+//! use std::thread;
+//! use virtio_drivers::{
+//!     device::rng::VirtIORng,
+//!     test_utils::{FakeHal, FakeTransport, State},
+//!     transport::{features::VERSION_1, DeviceType},
+//! };
+//!
+//! let mut config_space = ();
+//! let (transport, state) =
+//!     FakeTransport::new(DeviceType::EntropySource, 8, 1, VERSION_1, &mut config_space);
+//! let mut rng = VirtIORng::<FakeHal, _>::new(transport).unwrap();
+//!
+//! let device = thread::spawn(move || {
+//!     State::wait_until_queue_notified(&state, 0);
+//!     assert!(state
+//!         .lock()
+//!         .unwrap()
+//!         .read_write_queue::<8>(0, |_request| vec![4; 4]));
+//! });
+//! let mut buffer = [0; 4];
+//! assert_eq!(rng.request_entropy(&mut buffer), Ok(4));
+//! assert_eq!(buffer, [4; 4]);
+//! device.join().unwrap();
+//! ```
+
+pub use crate::hal::fake::{FakeHal, TrackingHal};
+pub use crate::transport::fake::{FakeTransport, QueueStatus, State};
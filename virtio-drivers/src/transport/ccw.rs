@@ -0,0 +1,942 @@
+// SPDX-License-Identifier: MIT
+
+//! Channel I/O (CCW) transport for VirtIO, as used on s390x.
+
+use super::{DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport};
+use crate::{
+    align_up,
+    hal::{BufferDirection, Dma, DmaPurpose},
+    queue::Descriptor,
+    Error, Hal, PhysAddr, PAGE_SIZE,
+};
+use core::{
+    cell::Cell,
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use log::warn;
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+/// The control unit type with which VirtIO CCW devices identify themselves. The control unit
+/// model is the VirtIO device ID.
+const VIRTIO_CCW_CU_TYPE: u16 = 0x3832;
+
+// CCW command codes.
+//
+// Ref: virtio 4.3.2 Device Initialization
+const CCW_CMD_SET_VQ: u8 = 0x13;
+const CCW_CMD_VDEV_RESET: u8 = 0x33;
+const CCW_CMD_SET_IND: u8 = 0x43;
+const CCW_CMD_SET_CONF_IND: u8 = 0x53;
+const CCW_CMD_READ_FEAT: u8 = 0x12;
+const CCW_CMD_WRITE_FEAT: u8 = 0x11;
+const CCW_CMD_READ_CONF: u8 = 0x22;
+const CCW_CMD_WRITE_CONF: u8 = 0x21;
+const CCW_CMD_WRITE_STATUS: u8 = 0x31;
+const CCW_CMD_READ_VQ_CONF: u8 = 0x32;
+const CCW_CMD_SET_VIRTIO_REV: u8 = 0x83;
+const CCW_CMD_SENSE_ID: u8 = 0xe4;
+
+/// The revision used by legacy devices, which don't support `CCW_CMD_SET_VIRTIO_REV`.
+const LEGACY_REVISION: u16 = 0;
+/// The revision supporting virtio 1.0, which is the newest one the transport uses.
+const MODERN_REVISION: u16 = 1;
+
+/// The largest config space the transport keeps a copy of.
+const CONFIG_SPACE_SIZE: usize = 0x100;
+/// Offsets within the transport's DMA page.
+const INDICATORS_OFFSET: usize = 0;
+const CONFIG_INDICATORS_OFFSET: usize = 8;
+const CONFIG_OFFSET: usize = 0x100;
+const DEVICE_CONFIG_OFFSET: usize = 0x200;
+
+/// The number of queues which can be signalled through the classic 64-bit queue indicators.
+const MAX_QUEUES: u16 = u64::BITS as u16;
+
+/// Runs channel programs on the subchannel of a VirtIO CCW device.
+///
+/// Issuing I/O instructions and handling the I/O interrupts they raise is specific to the
+/// platform, so like [`Hal`] it is left to the user of the crate to implement.
+pub trait Subchannel {
+    /// Runs a channel program made of a single CCW with the given command code and a data area
+    /// holding the contents of `data`, and waits for it to complete.
+    ///
+    /// The data area is passed to the device with its current contents, as some commands which
+    /// read from the device also take parameters from it. Whatever the device writes to it must be
+    /// copied back into `data`. The suppress length indication flag should be set, so that it
+    /// isn't an error for the device to transfer fewer than `data.len()` bytes.
+    ///
+    /// Returns the number of bytes which were transferred, i.e. `data.len()` minus the residual
+    /// count. If the device rejects the command, returns [`Error::Unsupported`].
+    fn run_ccw(&self, command: u8, data: &mut [u8]) -> Result<usize, Error>;
+
+    /// Notifies the device that there are new buffers in the given queue, usually with the
+    /// virtio-ccw notification hypercall.
+    fn notify(&self, queue: u16);
+}
+
+/// An error encountered initialising a VirtIO CCW transport.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CcwError {
+    /// The SENSE ID command failed.
+    SenseIdFailed(Error),
+    /// The device reports a control unit type other than 0x3832, so isn't a VirtIO device.
+    BadControlUnitType(u16),
+    /// The device reports a device ID of 0.
+    ZeroDeviceId,
+    /// The device failed to set a revision for a reason other than not supporting it.
+    SetRevisionFailed(Error),
+    /// Failed to allocate DMA memory for the interrupt indicators.
+    DmaError,
+}
+
+impl Display for CcwError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::SenseIdFailed(e) => write!(f, "SENSE ID failed: {}.", e),
+            Self::BadControlUnitType(cu_type) => write!(
+                f,
+                "Invalid control unit type {:#06x} (expected 0x3832).",
+                cu_type
+            ),
+            Self::ZeroDeviceId => write!(f, "Device ID was zero."),
+            Self::SetRevisionFailed(e) => write!(f, "Setting the revision failed: {}.", e),
+            Self::DmaError => write!(f, "Failed to allocate DMA memory."),
+        }
+    }
+}
+
+/// VirtIO transport over the s390 channel subsystem.
+///
+/// Interrupts are signalled with classic indicators: the device sets a bit in a 64-bit word in
+/// guest memory for each queue it has used buffers in, and another for configuration changes,
+/// before raising an I/O interrupt. The [`Subchannel`] implementation is responsible for handling
+/// that I/O interrupt, and [`ack_interrupt_status`](Transport::ack_interrupt_status) then reads
+/// and clears the indicators.
+///
+/// The device configuration space can't be mapped, so the transport keeps a copy of it which it
+/// reads from the device whenever the config space or generation is asked for. Writes made
+/// through the pointer returned by [`config_space`](Transport::config_space) are sent to the
+/// device the next time the transport is used to access the config space, notify a queue or
+/// change the device status.
+///
+/// Ref: virtio 4.3 Virtio Over Channel I/O
+#[derive(Debug)]
+pub struct CcwTransport<H: Hal, C: Subchannel> {
+    subchannel: C,
+    device_type: DeviceType,
+    revision: u16,
+    /// The indicators and the copies of the config space.
+    dma: Dma<H>,
+    /// The status last written to the device, as it can't be read back at the revisions in use.
+    status: DeviceStatus,
+    /// The features last read from the device.
+    device_features: u64,
+    /// The features last written to the device.
+    driver_features: u64,
+    /// Whether the indicators have been registered since the device was last reset.
+    indicators_set: bool,
+    /// A bitmap of the queues which have been set up.
+    queues_used: u64,
+    /// The number of bytes of config space the device last transferred.
+    config_len: Cell<usize>,
+    /// Incremented whenever reading the config space finds that it has changed. This also makes
+    /// the transport `!Sync`, as the copy of the config space is updated through `&self`.
+    config_generation: Cell<u32>,
+}
+
+impl<H: Hal, C: Subchannel> CcwTransport<H, C> {
+    /// Constructs a new VirtIO CCW transport for the device on the given subchannel.
+    ///
+    /// This identifies the device, and sets the newest revision of the transport which both the
+    /// device and the driver support.
+    pub fn new(subchannel: C) -> Result<Self, CcwError> {
+        let mut sense_id = SenseId::new_zeroed();
+        subchannel
+            .run_ccw(CCW_CMD_SENSE_ID, sense_id.as_mut_bytes())
+            .map_err(CcwError::SenseIdFailed)?;
+        let cu_type = u16::from_be(sense_id.cu_type);
+        if cu_type != VIRTIO_CCW_CU_TYPE {
+            return Err(CcwError::BadControlUnitType(cu_type));
+        }
+        if sense_id.cu_model == 0 {
+            return Err(CcwError::ZeroDeviceId);
+        }
+
+        let mut revision_info = RevisionInfo {
+            revision: MODERN_REVISION.to_be(),
+            length: 0,
+        };
+        let revision =
+            match subchannel.run_ccw(CCW_CMD_SET_VIRTIO_REV, revision_info.as_mut_bytes()) {
+                Ok(_) => MODERN_REVISION,
+                // Legacy devices reject the command.
+                Err(Error::Unsupported) => LEGACY_REVISION,
+                Err(e) => return Err(CcwError::SetRevisionFailed(e)),
+            };
+
+        Ok(Self {
+            subchannel,
+            device_type: sense_id.cu_model.into(),
+            revision,
+            dma: Dma::new(1, BufferDirection::Both, DmaPurpose::Transport)
+                .map_err(|_| CcwError::DmaError)?,
+            status: DeviceStatus::empty(),
+            device_features: 0,
+            driver_features: 0,
+            indicators_set: false,
+            queues_used: 0,
+            config_len: Cell::new(0),
+            config_generation: Cell::new(0),
+        })
+    }
+
+    /// Returns the revision of the VirtIO CCW transport in use.
+    pub fn revision(&self) -> u16 {
+        self.revision
+    }
+
+    /// Returns the number of 32-bit words of feature bits which can be negotiated.
+    fn feature_words(&self) -> u8 {
+        match self.revision {
+            LEGACY_REVISION => 1,
+            _ => 2,
+        }
+    }
+
+    fn indicators(&self) -> &AtomicU64 {
+        // SAFETY: The indicators are aligned within the DMA region, which lives as long as self,
+        // and are only accessed atomically.
+        unsafe { self.dma.vaddr(INDICATORS_OFFSET).cast().as_ref() }
+    }
+
+    fn config_indicators(&self) -> &AtomicU64 {
+        // SAFETY: The indicators are aligned within the DMA region, which lives as long as self,
+        // and are only accessed atomically.
+        unsafe { self.dma.vaddr(CONFIG_INDICATORS_OFFSET).cast().as_ref() }
+    }
+
+    /// Registers the indicators with the device, which forgets them whenever it is reset.
+    fn set_indicators(&mut self) {
+        self.indicators().store(0, Ordering::Release);
+        self.config_indicators().store(0, Ordering::Release);
+        for (command, offset) in [
+            (CCW_CMD_SET_IND, INDICATORS_OFFSET),
+            (CCW_CMD_SET_CONF_IND, CONFIG_INDICATORS_OFFSET),
+        ] {
+            let mut address = ((self.dma.paddr() + offset) as u64).to_be();
+            if let Err(e) = self.subchannel.run_ccw(command, address.as_mut_bytes()) {
+                warn!(
+                    "Failed to set indicators with command {:#x}: {}",
+                    command, e
+                );
+                return;
+            }
+        }
+        self.indicators_set = true;
+    }
+
+    /// Returns the driver's copy of the config space, and the copy of what was last read from the
+    /// device.
+    #[allow(clippy::mut_from_ref)]
+    fn config_copies(&self) -> (&mut [u8], &mut [u8]) {
+        let config = self.dma.vaddr(CONFIG_OFFSET).as_ptr();
+        let device_config = self.dma.vaddr(DEVICE_CONFIG_OFFSET).as_ptr();
+        // SAFETY: Both copies of the config space are within the DMA region, and don't overlap.
+        // The transport isn't `Sync` and drivers only access the config space through the pointer
+        // from `config_space` while they aren't calling into the transport, so nothing else is
+        // accessing them.
+        unsafe {
+            (
+                &mut *ptr::slice_from_raw_parts_mut(config, CONFIG_SPACE_SIZE),
+                &mut *ptr::slice_from_raw_parts_mut(device_config, CONFIG_SPACE_SIZE),
+            )
+        }
+    }
+
+    /// Sends any writes made to the driver's copy of the config space to the device.
+    fn flush_config(&self) {
+        let len = self.config_len.get();
+        let (config, device_config) = self.config_copies();
+        if config[..len] == device_config[..len] {
+            return;
+        }
+        // This also writes back the read-only fields, which devices ignore.
+        let mut written = [0; CONFIG_SPACE_SIZE];
+        written[..len].copy_from_slice(&config[..len]);
+        match self
+            .subchannel
+            .run_ccw(CCW_CMD_WRITE_CONF, &mut written[..len])
+        {
+            Ok(_) => device_config[..len].copy_from_slice(&config[..len]),
+            Err(e) => warn!("Failed to write config space: {}", e),
+        }
+    }
+
+    /// Flushes any writes to the config space, then reads it again from the device, updating the
+    /// driver's copy and the generation if it has changed.
+    fn sync_config(&self) {
+        self.flush_config();
+        let mut read = [0; CONFIG_SPACE_SIZE];
+        match self.subchannel.run_ccw(CCW_CMD_READ_CONF, &mut read) {
+            Ok(read_len) => {
+                let read_len = read_len.min(CONFIG_SPACE_SIZE);
+                let (config, device_config) = self.config_copies();
+                if read_len != self.config_len.get() || config[..] != read[..] {
+                    config.copy_from_slice(&read);
+                    self.config_generation
+                        .set(self.config_generation.get().wrapping_add(1));
+                }
+                device_config.copy_from_slice(&read);
+                self.config_len.set(read_len);
+            }
+            Err(e) => warn!("Failed to read config space: {}", e),
+        }
+    }
+}
+
+impl<H: Hal, C: Subchannel> Transport for CcwTransport<H, C> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        let mut device_features = 0;
+        for index in 0..self.feature_words() {
+            let mut desc = FeatureDesc { features: 0, index };
+            match self
+                .subchannel
+                .run_ccw(CCW_CMD_READ_FEAT, desc.as_mut_bytes())
+            {
+                Ok(_) => {
+                    device_features |= u64::from(u32::from_le(desc.features)) << (32 * index);
+                }
+                Err(e) => warn!("Failed to read device features {}: {}", index, e),
+            }
+        }
+        self.device_features = device_features;
+        device_features
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        for index in 0..self.feature_words() {
+            let mut desc = FeatureDesc {
+                features: ((driver_features >> (32 * index)) as u32).to_le(),
+                index,
+            };
+            if let Err(e) = self
+                .subchannel
+                .run_ccw(CCW_CMD_WRITE_FEAT, desc.as_mut_bytes())
+            {
+                warn!("Failed to write driver features {}: {}", index, e);
+            }
+        }
+        self.driver_features = driver_features;
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device_features
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        if queue >= MAX_QUEUES {
+            return 0;
+        }
+        let mut vq_config = VqConfigBlock {
+            index: queue.to_be(),
+            num: 0,
+        };
+        match self
+            .subchannel
+            .run_ccw(CCW_CMD_READ_VQ_CONF, vq_config.as_mut_bytes())
+        {
+            Ok(_) => u16::from_be(vq_config.num).into(),
+            Err(e) => {
+                warn!("Failed to read config of queue {}: {}", queue, e);
+                0
+            }
+        }
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.flush_config();
+        self.subchannel.notify(queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        if status.is_empty() {
+            if let Err(e) = self.subchannel.run_ccw(CCW_CMD_VDEV_RESET, &mut []) {
+                warn!("Failed to reset device: {}", e);
+            }
+            // The device forgets its indicators and queues when it is reset.
+            self.indicators_set = false;
+            self.queues_used = 0;
+        } else {
+            self.flush_config();
+            if !self.indicators_set {
+                self.set_indicators();
+            }
+            let mut status_byte = [status.bits() as u8];
+            if let Err(e) = self
+                .subchannel
+                .run_ccw(CCW_CMD_WRITE_STATUS, &mut status_byte)
+            {
+                warn!("Failed to write status {:?}: {}", status, e);
+                return;
+            }
+        }
+        self.status = status;
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the alignment of legacy queues is given when setting them up.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        self.revision == LEGACY_REVISION
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) {
+        assert!(queue < MAX_QUEUES, "Queue {} can't be signalled", queue);
+        let result = if self.requires_legacy_layout() {
+            assert_eq!(
+                driver_area - descriptors,
+                size_of::<Descriptor>() * size as usize
+            );
+            assert_eq!(
+                device_area - descriptors,
+                align_up(
+                    size_of::<Descriptor>() * size as usize
+                        + size_of::<u16>() * (size as usize + 3)
+                )
+            );
+            let mut info = VqInfoBlockLegacy {
+                queue: (descriptors as u64).to_be(),
+                align: (PAGE_SIZE as u32).to_be(),
+                index: queue.to_be(),
+                num: (size as u16).to_be(),
+            };
+            self.subchannel.run_ccw(CCW_CMD_SET_VQ, info.as_mut_bytes())
+        } else {
+            let mut info = VqInfoBlock {
+                desc: (descriptors as u64).to_be(),
+                reserved: 0,
+                index: queue.to_be(),
+                num: (size as u16).to_be(),
+                driver: (driver_area as u64).to_be(),
+                device: (device_area as u64).to_be(),
+            };
+            self.subchannel.run_ccw(CCW_CMD_SET_VQ, info.as_mut_bytes())
+        };
+        match result {
+            Ok(_) => self.queues_used |= 1 << queue,
+            Err(e) => warn!("Failed to set up queue {}: {}", queue, e),
+        }
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        if queue >= MAX_QUEUES {
+            return;
+        }
+        let result = if self.requires_legacy_layout() {
+            let mut info = VqInfoBlockLegacy {
+                index: queue.to_be(),
+                ..FromZeros::new_zeroed()
+            };
+            self.subchannel.run_ccw(CCW_CMD_SET_VQ, info.as_mut_bytes())
+        } else {
+            let mut info = VqInfoBlock {
+                index: queue.to_be(),
+                ..FromZeros::new_zeroed()
+            };
+            self.subchannel.run_ccw(CCW_CMD_SET_VQ, info.as_mut_bytes())
+        };
+        if let Err(e) = result {
+            warn!("Failed to reset queue {}: {}", queue, e);
+        }
+        self.queues_used &= !(1 << queue);
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        queue < MAX_QUEUES && self.queues_used & (1 << queue) != 0
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
+        let mut status = InterruptStatus::empty();
+        if self.indicators().swap(0, Ordering::AcqRel) != 0 {
+            status |= InterruptStatus::QUEUE_INTERRUPT;
+        }
+        if self.config_indicators().swap(0, Ordering::AcqRel) & 1 != 0 {
+            status |= InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT;
+        }
+        status
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.indicators().load(Ordering::Acquire) != 0
+            || self.config_indicators().load(Ordering::Acquire) & 1 != 0
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.sync_config();
+        self.config_generation.get()
+    }
+
+    fn get_shared_memory_region(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        // The CCW transport has no way to advertise shared memory regions.
+        None
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>, Error> {
+        self.sync_config();
+        let len = self.config_len.get();
+        if len == 0 {
+            Err(Error::ConfigSpaceMissing)
+        } else if size_of::<T>() > len {
+            Err(Error::ConfigSpaceTooSmall)
+        } else {
+            Ok(self.dma.vaddr(CONFIG_OFFSET).cast())
+        }
+    }
+
+    fn config_space_len(&self) -> usize {
+        self.sync_config();
+        self.config_len.get()
+    }
+}
+
+impl<H: Hal, C: Subchannel> Drop for CcwTransport<H, C> {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.set_status(DeviceStatus::empty())
+    }
+}
+
+/// The data returned by `CCW_CMD_SENSE_ID`, without the command information words.
+#[repr(C, packed)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct SenseId {
+    reserved: u8,
+    cu_type: u16,
+    cu_model: u8,
+    dev_type: u16,
+    dev_model: u8,
+    unused: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct RevisionInfo {
+    revision: u16,
+    /// The length of the revision specific data following, of which there is none.
+    length: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct FeatureDesc {
+    /// Little-endian, unlike all the other fields of the transport.
+    features: u32,
+    index: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct VqConfigBlock {
+    index: u16,
+    num: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct VqInfoBlockLegacy {
+    queue: u64,
+    align: u32,
+    index: u16,
+    num: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, Immutable, IntoBytes, KnownLayout)]
+struct VqInfoBlock {
+    desc: u64,
+    reserved: u32,
+    index: u16,
+    num: u16,
+    driver: u64,
+    device: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hal::fake::FakeHal, transport::Endian};
+    use alloc::{vec, vec::Vec};
+    use bitflags::bitflags;
+    use core::cell::RefCell;
+
+    bitflags! {
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        struct TestFeatures: u64 {
+            const FOO = 1 << 0;
+            const BAR = 1 << 1;
+            const VERSION_1 = 1 << 32;
+        }
+    }
+
+    /// A fake VirtIO CCW device, which runs CCWs directly.
+    #[derive(Debug, Default)]
+    struct FakeDevice {
+        cu_type: u16,
+        device_id: u8,
+        /// Whether the device rejects `CCW_CMD_SET_VIRTIO_REV`.
+        legacy: bool,
+        revision: Option<u16>,
+        device_features: u64,
+        driver_features: u64,
+        status: u8,
+        indicators: u64,
+        config_indicators: u64,
+        queue_num_max: u16,
+        /// The info blocks of all `CCW_CMD_SET_VQ` commands, in order.
+        set_vq: Vec<Vec<u8>>,
+        config: Vec<u8>,
+        notified: Vec<u16>,
+    }
+
+    #[derive(Debug)]
+    struct FakeSubchannel {
+        device: RefCell<FakeDevice>,
+    }
+
+    fn copy_to(data: &mut [u8], bytes: &[u8]) -> usize {
+        let len = data.len().min(bytes.len());
+        data[..len].copy_from_slice(&bytes[..len]);
+        len
+    }
+
+    impl Subchannel for FakeSubchannel {
+        fn run_ccw(&self, command: u8, data: &mut [u8]) -> Result<usize, Error> {
+            let mut device = self.device.borrow_mut();
+            let transferred = match command {
+                CCW_CMD_SENSE_ID => {
+                    let sense_id = SenseId {
+                        reserved: 0xff,
+                        cu_type: device.cu_type.to_be(),
+                        cu_model: device.device_id,
+                        dev_type: 0,
+                        dev_model: 0,
+                        unused: 0,
+                    };
+                    copy_to(data, sense_id.as_bytes())
+                }
+                CCW_CMD_SET_VIRTIO_REV => {
+                    if device.legacy {
+                        return Err(Error::Unsupported);
+                    }
+                    let info = RevisionInfo::read_from_bytes(data).unwrap();
+                    device.revision = Some(u16::from_be(info.revision));
+                    data.len()
+                }
+                CCW_CMD_READ_FEAT => {
+                    let mut desc = FeatureDesc::read_from_bytes(data).unwrap();
+                    desc.features = ((device.device_features >> (32 * desc.index)) as u32).to_le();
+                    copy_to(data, desc.as_bytes())
+                }
+                CCW_CMD_WRITE_FEAT => {
+                    let desc = FeatureDesc::read_from_bytes(data).unwrap();
+                    let shift = 32 * desc.index;
+                    device.driver_features = device.driver_features & !(0xffff_ffff << shift)
+                        | u64::from(u32::from_le(desc.features)) << shift;
+                    data.len()
+                }
+                CCW_CMD_WRITE_STATUS => {
+                    device.status = data[0];
+                    data.len()
+                }
+                CCW_CMD_VDEV_RESET => {
+                    device.status = 0;
+                    device.indicators = 0;
+                    device.config_indicators = 0;
+                    0
+                }
+                CCW_CMD_SET_IND => {
+                    device.indicators = u64::from_be_bytes(data.try_into().unwrap());
+                    data.len()
+                }
+                CCW_CMD_SET_CONF_IND => {
+                    device.config_indicators = u64::from_be_bytes(data.try_into().unwrap());
+                    data.len()
+                }
+                CCW_CMD_READ_VQ_CONF => {
+                    let mut vq_config = VqConfigBlock::read_from_bytes(data).unwrap();
+                    vq_config.num = device.queue_num_max.to_be();
+                    copy_to(data, vq_config.as_bytes())
+                }
+                CCW_CMD_SET_VQ => {
+                    device.set_vq.push(data.to_vec());
+                    data.len()
+                }
+                CCW_CMD_READ_CONF => copy_to(data, &device.config),
+                CCW_CMD_WRITE_CONF => {
+                    let len = data.len().min(device.config.len());
+                    device.config[..len].copy_from_slice(&data[..len]);
+                    len
+                }
+                _ => return Err(Error::Unsupported),
+            };
+            Ok(transferred)
+        }
+
+        fn notify(&self, queue: u16) {
+            self.device.borrow_mut().notified.push(queue);
+        }
+    }
+
+    fn make_transport(device: FakeDevice) -> CcwTransport<FakeHal, FakeSubchannel> {
+        CcwTransport::new(FakeSubchannel {
+            device: RefCell::new(device),
+        })
+        .unwrap()
+    }
+
+    fn block_device() -> FakeDevice {
+        FakeDevice {
+            cu_type: VIRTIO_CCW_CU_TYPE,
+            device_id: 2,
+            device_features: (TestFeatures::FOO | TestFeatures::BAR | TestFeatures::VERSION_1)
+                .bits(),
+            queue_num_max: 4,
+            config: vec![0; 8],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn modern_init() {
+        let mut transport = make_transport(block_device());
+        assert_eq!(transport.device_type(), DeviceType::Block);
+        assert_eq!(transport.revision(), MODERN_REVISION);
+        assert!(!transport.requires_legacy_layout());
+        assert_eq!(
+            transport.subchannel.device.borrow().revision,
+            Some(MODERN_REVISION)
+        );
+
+        assert_eq!(
+            transport.begin_init(
+                TestFeatures::FOO | TestFeatures::VERSION_1,
+                TestFeatures::VERSION_1
+            ),
+            Ok(TestFeatures::FOO | TestFeatures::VERSION_1)
+        );
+        {
+            let device = transport.subchannel.device.borrow();
+            assert_eq!(
+                device.driver_features,
+                (TestFeatures::FOO | TestFeatures::VERSION_1).bits()
+            );
+            assert_eq!(
+                device.status,
+                (DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK)
+                    .bits() as u8
+            );
+            assert_eq!(
+                device.indicators,
+                (transport.dma.paddr() + INDICATORS_OFFSET) as u64
+            );
+            assert_eq!(
+                device.config_indicators,
+                (transport.dma.paddr() + CONFIG_INDICATORS_OFFSET) as u64
+            );
+        }
+
+        transport.finish_init();
+        let status = DeviceStatus::ACKNOWLEDGE
+            | DeviceStatus::DRIVER
+            | DeviceStatus::FEATURES_OK
+            | DeviceStatus::DRIVER_OK;
+        assert_eq!(transport.get_status(), status);
+        assert_eq!(
+            transport.subchannel.device.borrow().status,
+            status.bits() as u8
+        );
+    }
+
+    #[test]
+    fn legacy_init() {
+        let mut transport = make_transport(FakeDevice {
+            legacy: true,
+            ..block_device()
+        });
+        assert_eq!(transport.revision(), LEGACY_REVISION);
+        assert!(transport.requires_legacy_layout());
+        assert_eq!(transport.config_endian(), Endian::NATIVE);
+
+        // Only the first 32 feature bits can be negotiated, and VERSION_1 isn't required.
+        assert_eq!(
+            transport.read_device_features(),
+            TestFeatures::FOO.bits() | 0b10
+        );
+        assert_eq!(
+            transport.begin_init(
+                TestFeatures::FOO | TestFeatures::VERSION_1,
+                TestFeatures::FOO | TestFeatures::VERSION_1
+            ),
+            Ok(TestFeatures::FOO)
+        );
+        assert!(!transport.get_status().contains(DeviceStatus::FEATURES_OK));
+    }
+
+    #[test]
+    fn not_virtio() {
+        let result = CcwTransport::<FakeHal, _>::new(FakeSubchannel {
+            device: RefCell::new(FakeDevice {
+                cu_type: 0x3088,
+                ..block_device()
+            }),
+        });
+        assert_eq!(result.unwrap_err(), CcwError::BadControlUnitType(0x3088));
+
+        let result = CcwTransport::<FakeHal, _>::new(FakeSubchannel {
+            device: RefCell::new(FakeDevice {
+                device_id: 0,
+                ..block_device()
+            }),
+        });
+        assert_eq!(result.unwrap_err(), CcwError::ZeroDeviceId);
+    }
+
+    #[test]
+    fn queue_set() {
+        let mut transport = make_transport(block_device());
+        assert_eq!(transport.max_queue_size(1), 4);
+        assert_eq!(transport.max_queue_size(MAX_QUEUES), 0);
+
+        assert!(!transport.queue_used(1));
+        transport.queue_set(1, 4, 0x1000, 0x2000, 0x3000);
+        assert!(transport.queue_used(1));
+        let info = VqInfoBlock::read_from_bytes(
+            transport.subchannel.device.borrow().set_vq.last().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(u64::from_be(info.desc), 0x1000);
+        assert_eq!(u16::from_be(info.index), 1);
+        assert_eq!(u16::from_be(info.num), 4);
+        assert_eq!(u64::from_be(info.driver), 0x2000);
+        assert_eq!(u64::from_be(info.device), 0x3000);
+
+        transport.queue_unset(1);
+        assert!(!transport.queue_used(1));
+        let info = VqInfoBlock::read_from_bytes(
+            transport.subchannel.device.borrow().set_vq.last().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(u16::from_be(info.index), 1);
+        assert_eq!(info.desc, 0);
+        assert_eq!(info.num, 0);
+
+        // Resetting the device forgets its queues.
+        transport.queue_set(1, 4, 0x1000, 0x2000, 0x3000);
+        transport.set_status(DeviceStatus::empty());
+        assert!(!transport.queue_used(1));
+    }
+
+    #[test]
+    fn config_space() {
+        let mut transport = make_transport(FakeDevice {
+            config: [1u32, 2].as_bytes().to_vec(),
+            ..block_device()
+        });
+        assert_eq!(
+            transport.config_space::<[u32; 3]>(),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        let config = transport.config_space::<[u32; 2]>().unwrap();
+        // SAFETY: The config space is at least 8 bytes.
+        assert_eq!(unsafe { config.read_volatile() }, [1, 2]);
+
+        // The device changes its config space.
+        transport.subchannel.device.borrow_mut().config[..4].copy_from_slice(3u32.as_bytes());
+        let generation = transport.config_generation();
+        assert_ne!(generation, 0);
+        // SAFETY: The config space is at least 8 bytes.
+        let value = transport.read_config_space_atomic(|| unsafe { config.read_volatile() });
+        assert_eq!(value, [3, 2]);
+        assert_eq!(transport.config_generation(), generation);
+
+        // Writes are sent to the device when a queue is notified.
+        // SAFETY: The config space is at least 8 bytes.
+        unsafe { config.cast::<u32>().add(1).write_volatile(4) };
+        transport.notify(0);
+        let device = transport.subchannel.device.borrow();
+        assert_eq!(device.config, [3u32, 4].as_bytes());
+        assert_eq!(device.notified, [0]);
+    }
+
+    #[test]
+    fn config_space_missing() {
+        let transport = make_transport(FakeDevice {
+            config: vec![],
+            ..block_device()
+        });
+        assert_eq!(
+            transport.config_space::<u32>(),
+            Err(Error::ConfigSpaceMissing)
+        );
+    }
+
+    #[test]
+    fn ack_interrupt_status() {
+        let mut transport = make_transport(block_device());
+        transport
+            .begin_init(TestFeatures::VERSION_1, TestFeatures::VERSION_1)
+            .unwrap();
+        assert!(!transport.interrupt_pending());
+        assert_eq!(transport.ack_interrupt_status(), InterruptStatus::empty());
+
+        let (indicators, config_indicators) = {
+            let device = transport.subchannel.device.borrow();
+            (device.indicators, device.config_indicators)
+        };
+        // SAFETY: The indicators registered with the device are in the transport's DMA region,
+        // which FakeHal identity maps.
+        unsafe {
+            (*(indicators as *const AtomicU64)).fetch_or(1 << 1, Ordering::AcqRel);
+        }
+        assert!(transport.interrupt_pending());
+        assert_eq!(
+            transport.ack_interrupt_status(),
+            InterruptStatus::QUEUE_INTERRUPT
+        );
+        assert!(!transport.interrupt_pending());
+
+        // SAFETY: As above.
+        unsafe {
+            (*(indicators as *const AtomicU64)).fetch_or(1 << 0, Ordering::AcqRel);
+            (*(config_indicators as *const AtomicU64)).fetch_or(1, Ordering::AcqRel);
+        }
+        assert_eq!(
+            transport.ack_interrupt_status(),
+            InterruptStatus::QUEUE_INTERRUPT | InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT
+        );
+        assert!(!transport.ack_interrupt());
+    }
+}
@@ -2,10 +2,12 @@
 
 #![allow(missing_docs)]
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{
+    features::RING_RESET, DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport,
+};
 use crate::{
     queue::{fake_read_write_queue, Descriptor},
-    PhysAddr, Result,
+    Error, PhysAddr, Result,
 };
 use alloc::{sync::Arc, vec::Vec};
 use core::{
@@ -26,6 +28,32 @@ pub struct FakeTransport<C: 'static> {
     pub state: Arc<Mutex<State>>,
 }
 
+impl<C> FakeTransport<C> {
+    /// Creates a fake transport for a device of the given type with `num_queues` queues, each of
+    /// up to `max_queue_size` descriptors, and returns it along with the state it shares with the
+    /// test playing the device.
+    pub fn new(
+        device_type: DeviceType,
+        max_queue_size: u32,
+        num_queues: usize,
+        device_features: u64,
+        config_space: &mut C,
+    ) -> (Self, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            queues: (0..num_queues).map(|_| QueueStatus::default()).collect(),
+            ..Default::default()
+        }));
+        let transport = Self {
+            device_type,
+            max_queue_size,
+            device_features,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+}
+
 impl<C> Transport for FakeTransport<C> {
     fn device_type(&self) -> DeviceType {
         self.device_type
@@ -39,14 +67,32 @@ impl<C> Transport for FakeTransport<C> {
         self.state.lock().unwrap().driver_features = driver_features;
     }
 
+    fn negotiated_features(&self) -> u64 {
+        self.state.lock().unwrap().driver_features
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device_features
+    }
+
     fn max_queue_size(&mut self, _queue: u16) -> u32 {
         self.max_queue_size
     }
 
     fn notify(&mut self, queue: u16) {
-        self.state.lock().unwrap().queues[queue as usize]
-            .notified
-            .store(true, Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        let queue = &mut state.queues[queue as usize];
+        queue.notified.store(true, Ordering::SeqCst);
+        queue.notify_count += 1;
+    }
+
+    fn notify_with_data(&mut self, queue: u16, data: u32) {
+        self.notify(queue);
+        self.state.lock().unwrap().queues[queue as usize].notification_data = Some(data);
+    }
+
+    fn can_notify_with_data(&self) -> bool {
+        true
     }
 
     fn get_status(&self) -> DeviceStatus {
@@ -54,7 +100,16 @@ impl<C> Transport for FakeTransport<C> {
     }
 
     fn set_status(&mut self, status: DeviceStatus) {
-        self.state.lock().unwrap().status = status;
+        let mut state = self.state.lock().unwrap();
+        state.status = if state.reject_features {
+            status - DeviceStatus::FEATURES_OK
+        } else {
+            status
+        };
+    }
+
+    fn is_present(&self) -> bool {
+        !self.state.lock().unwrap().removed
     }
 
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
@@ -62,7 +117,7 @@ impl<C> Transport for FakeTransport<C> {
     }
 
     fn requires_legacy_layout(&self) -> bool {
-        false
+        self.state.lock().unwrap().legacy
     }
 
     fn queue_set(
@@ -92,22 +147,53 @@ impl<C> Transport for FakeTransport<C> {
         self.state.lock().unwrap().queues[queue as usize].descriptors != 0
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn reset_queue(&mut self, queue: u16) -> Result {
+        if self.negotiated_features() & RING_RESET == 0 {
+            return Err(Error::Unsupported);
+        }
+        self.queue_unset(queue);
+        self.state.lock().unwrap().queues[queue as usize].reset_count += 1;
+        Ok(())
+    }
+
+    fn can_reset_queue(&self) -> bool {
+        true
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
         let mut state = self.state.lock().unwrap();
-        let pending = state.interrupt_pending;
-        if pending {
+        if state.interrupt_pending {
             state.interrupt_pending = false;
+            InterruptStatus::QUEUE_INTERRUPT
+        } else {
+            InterruptStatus::empty()
         }
-        pending
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.state.lock().unwrap().interrupt_pending
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.state.lock().unwrap().config_generation
+    }
+
+    fn get_shared_memory_region(&mut self, _id: u8) -> Option<SharedMemoryRegion> {
+        None
     }
 
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
-        if TypeId::of::<T>() == TypeId::of::<C>() {
+        // Allow byte pointers too, for reading individual fields.
+        if TypeId::of::<T>() == TypeId::of::<C>() || TypeId::of::<T>() == TypeId::of::<u8>() {
             Ok(self.config_space.cast())
         } else {
             panic!("Unexpected config space type.");
         }
     }
+
+    fn config_space_len(&self) -> usize {
+        size_of::<C>()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -116,7 +202,15 @@ pub struct State {
     pub driver_features: u64,
     pub guest_page_size: u32,
     pub interrupt_pending: bool,
+    pub config_generation: u32,
     pub queues: Vec<QueueStatus>,
+    pub removed: bool,
+    /// Whether the device refuses the driver's features, by clearing `FEATURES_OK` whenever the
+    /// driver sets it.
+    pub reject_features: bool,
+    /// Whether the fake device only implements the legacy interface, so the driver neither needs
+    /// `VERSION_1` nor sets `FEATURES_OK`.
+    pub legacy: bool,
 }
 
 impl State {
@@ -130,7 +224,8 @@ impl State {
             queue.descriptors as *const [Descriptor; QUEUE_SIZE],
             queue.driver_area as *const u8,
             queue.device_area as *mut u8,
-            |input| {
+            None,
+            |input, _| {
                 assert_eq!(input, Vec::new());
                 data.to_owned()
             },
@@ -153,7 +248,8 @@ impl State {
             queue.descriptors as *const [Descriptor; QUEUE_SIZE],
             queue.driver_area as *const u8,
             queue.device_area as *mut u8,
-            |input| {
+            None,
+            |input, _| {
                 ret = Some(input);
                 Vec::new()
             },
@@ -179,10 +275,48 @@ impl State {
             queue.descriptors as *const [Descriptor; QUEUE_SIZE],
             queue.driver_area as *const u8,
             queue.device_area as *mut u8,
+            None,
+            |input, _| handler(input),
+        )
+    }
+
+    /// Like [`read_write_queue`](Self::read_write_queue), but also passes the handler the total
+    /// length of the device-writable buffers, so that it can size its response to fit.
+    pub fn read_write_queue_sized<const QUEUE_SIZE: usize>(
+        &mut self,
+        queue_index: u16,
+        handler: impl FnOnce(Vec<u8>, usize) -> Vec<u8>,
+    ) -> bool {
+        let queue = &self.queues[queue_index as usize];
+        assert_ne!(queue.descriptors, 0);
+        fake_read_write_queue(
+            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
+            queue.driver_area as *const u8,
+            queue.device_area as *mut u8,
+            None,
             handler,
         )
     }
 
+    /// Like [`read_write_queue`](Self::read_write_queue), but reports the given used length to the
+    /// driver rather than the actual length, e.g. to simulate a short read.
+    pub fn read_write_queue_with_used_len<const QUEUE_SIZE: usize>(
+        &mut self,
+        queue_index: u16,
+        used_len: u32,
+        handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+    ) -> bool {
+        let queue = &self.queues[queue_index as usize];
+        assert_ne!(queue.descriptors, 0);
+        fake_read_write_queue(
+            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
+            queue.driver_area as *const u8,
+            queue.device_area as *mut u8,
+            Some(used_len),
+            |input, _| handler(input),
+        )
+    }
+
     /// Waits until the given queue is notified.
     pub fn wait_until_queue_notified(state: &Mutex<Self>, queue_index: u16) {
         while !Self::poll_queue_notified(state, queue_index) {
@@ -208,4 +342,10 @@ pub struct QueueStatus {
     pub driver_area: PhysAddr,
     pub device_area: PhysAddr,
     pub notified: AtomicBool,
+    /// The number of times the queue has been notified.
+    pub notify_count: usize,
+    /// The data passed with the last notification, if `VIRTIO_F_NOTIFICATION_DATA` was negotiated.
+    pub notification_data: Option<u32>,
+    /// The number of times the queue has been reset on its own, with `VIRTIO_F_RING_RESET`.
+    pub reset_count: usize,
 }
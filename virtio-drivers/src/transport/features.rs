@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: MIT
+
+//! The device-independent feature bits, which are re-exported by the `features` module of each
+//! driver alongside its device-specific bits.
+//!
+//! Each constant is a mask with the feature's bit set, as passed to the `new_with_features`
+//! constructor of a driver to forbid the feature. Use `trailing_zeros` to get the bit number for
+//! [`Transport::supports`](super::Transport::supports) or a driver's `supports` method.
+
+use crate::device::common::Feature;
+
+/// The device notifies the driver when the available ring runs out, even if notifications are
+/// suppressed. (legacy)
+pub const NOTIFY_ON_EMPTY: u64 = Feature::NOTIFY_ON_EMPTY.bits();
+/// The device accepts any layout of descriptors for a request. (legacy)
+pub const ANY_LAYOUT: u64 = Feature::ANY_LAYOUT.bits();
+/// The driver can use indirect descriptor tables.
+pub const RING_INDIRECT_DESC: u64 = Feature::RING_INDIRECT_DESC.bits();
+/// The `used_event` and `avail_event` fields can be used to suppress notifications.
+pub const RING_EVENT_IDX: u64 = Feature::RING_EVENT_IDX.bits();
+/// The device complies with version 1 of the specification, rather than being a legacy device.
+pub const VERSION_1: u64 = Feature::VERSION_1.bits();
+/// The device's accesses to memory are limited or translated by the platform, e.g. by an IOMMU.
+pub const ACCESS_PLATFORM: u64 = Feature::ACCESS_PLATFORM.bits();
+/// The device supports the packed virtqueue layout.
+pub const RING_PACKED: u64 = Feature::RING_PACKED.bits();
+/// The device uses buffers in the order in which they were made available.
+pub const IN_ORDER: u64 = Feature::IN_ORDER.bits();
+/// The driver and device need platform-specific memory barriers rather than plain ones.
+pub const ORDER_PLATFORM: u64 = Feature::ORDER_PLATFORM.bits();
+/// The device supports single root I/O virtualisation.
+pub const SR_IOV: u64 = Feature::SR_IOV.bits();
+/// The driver passes extra data in its notifications to the device.
+pub const NOTIFICATION_DATA: u64 = Feature::NOTIFICATION_DATA.bits();
+/// The driver can reset a single queue without resetting the rest of the device.
+pub const RING_RESET: u64 = Feature::RING_RESET.bits();
@@ -2,7 +2,9 @@
 
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{
+    features::RING_RESET, DeviceStatus, DeviceType, InterruptStatus, SharedMemoryRegion, Transport,
+};
 use crate::{
     align_up,
     queue::Descriptor,
@@ -16,11 +18,14 @@ use core::{
     mem::{align_of, size_of},
     ptr::NonNull,
 };
+use log::warn;
 
 const MAGIC_VALUE: u32 = 0x7472_6976;
 pub(crate) const LEGACY_VERSION: u32 = 1;
 pub(crate) const MODERN_VERSION: u32 = 2;
 const CONFIG_SPACE_OFFSET: usize = 0x100;
+/// The usual size of the register region of a device.
+const MMIO_REGION_SIZE: usize = 0x200;
 
 /// The version of the VirtIO MMIO transport supported by a device.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -218,14 +223,40 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region id selection
+    ///
+    /// Writing to this register selects the shared memory region that the following operations on
+    /// SHMLenLow, SHMLenHigh, SHMBaseLow and SHMBaseHigh apply to.
+    shm_sel: WriteOnly<u32>,
+
+    /// Shared memory region length
+    ///
+    /// Reading from these registers returns the length of the selected region, or
+    /// 0xffffffffffffffff if there is no such region.
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+
+    /// Shared memory region guest physical address
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Virtual queue reset
+    ///
+    /// If VIRTIO_F_RING_RESET has been negotiated, writing one (0x1) to this register resets the
+    /// queue selected by QueueSel. It reads as one until the reset has finished.
+    queue_reset: Volatile<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 14],
 
     config_generation: ReadOnly<u32>,
 }
 
 impl VirtIOHeader {
     /// Constructs a fake VirtIO header for use in unit tests.
-    #[cfg(test)]
+    #[cfg(any(test, feature = "test-utils"))]
     pub fn make_fake_header(
         version: u32,
         device_id: u32,
@@ -268,6 +299,13 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: Default::default(),
+            shm_len_high: Default::default(),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            queue_reset: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
@@ -280,6 +318,10 @@ impl VirtIOHeader {
 pub struct MmioTransport<H: Hal> {
     header: NonNull<VirtIOHeader>,
     version: MmioVersion,
+    /// The features last read from the device.
+    device_features: u64,
+    /// The features last written to the device, as the register can't be read back.
+    driver_features: u64,
     _phantom: PhantomData<H>,
 }
 
@@ -302,6 +344,8 @@ impl<H: Hal> MmioTransport<H> {
         Ok(Self {
             header,
             version,
+            device_features: 0,
+            driver_features: 0,
             _phantom: PhantomData,
         })
     }
@@ -318,6 +362,36 @@ impl<H: Hal> MmioTransport<H> {
     }
 }
 
+/// Probes an array of `count` VirtIO MMIO slots `stride` bytes apart, starting at `base`, and
+/// returns a transport for each slot with a device behind it along with the device's type.
+///
+/// Slots with the wrong magic value or a device ID of 0 are empty, and are skipped. Devices
+/// reporting an unsupported version are skipped with a warning.
+///
+/// # Safety
+/// For each of the `count` slots, `base` plus `stride` times the slot index must point to a
+/// properly aligned valid VirtIO MMIO region, which must remain valid for the lifetime of the
+/// transports that are returned.
+pub unsafe fn probe<H: Hal>(
+    base: NonNull<u8>,
+    stride: usize,
+    count: usize,
+) -> impl Iterator<Item = (DeviceType, MmioTransport<H>)> {
+    (0..count).filter_map(move |slot| {
+        // SAFETY: Our caller promises that each slot is within the region they gave us.
+        let header = unsafe { base.byte_add(slot * stride) }.cast::<VirtIOHeader>();
+        // SAFETY: Our caller promises that each slot is a valid VirtIO MMIO region.
+        match unsafe { MmioTransport::<H>::new(header) } {
+            Ok(transport) => Some((transport.device_type(), transport)),
+            Err(MmioError::BadMagic(_) | MmioError::ZeroDeviceId) => None,
+            Err(e) => {
+                warn!("Skipping VirtIO MMIO slot {}: {}", slot, e);
+                None
+            }
+        }
+    })
+}
+
 // SAFETY: `header` is only used for MMIO, which can happen from any thread or CPU core.
 unsafe impl<H: Hal> Send for MmioTransport<H> {}
 
@@ -334,13 +408,14 @@ impl<H: Hal> Transport for MmioTransport<H> {
 
     fn read_device_features(&mut self) -> u64 {
         // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
-        unsafe {
+        self.device_features = unsafe {
             volwrite!(H, self.header, device_features_sel, 0); // device features [0, 32)
             let mut device_features_bits = volread!(H, self.header, device_features).into();
             volwrite!(H, self.header, device_features_sel, 1); // device features [32, 64)
             device_features_bits += (volread!(H, self.header, device_features) as u64) << 32;
             device_features_bits
-        }
+        };
+        self.device_features
     }
 
     fn write_driver_features(&mut self, driver_features: u64) {
@@ -356,6 +431,15 @@ impl<H: Hal> Transport for MmioTransport<H> {
                 (driver_features >> 32) as u32
             );
         }
+        self.driver_features = driver_features;
+    }
+
+    fn negotiated_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device_features
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
@@ -373,6 +457,17 @@ impl<H: Hal> Transport for MmioTransport<H> {
         }
     }
 
+    fn notify_with_data(&mut self, _queue: u16, data: u32) {
+        // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe {
+            volwrite!(H, self.header, queue_notify, data);
+        }
+    }
+
+    fn can_notify_with_data(&self) -> bool {
+        true
+    }
+
     fn get_status(&self) -> DeviceStatus {
         // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
         unsafe { volread!(H, self.header, status) }
@@ -385,6 +480,11 @@ impl<H: Hal> Transport for MmioTransport<H> {
         }
     }
 
+    fn is_present(&self) -> bool {
+        // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe { volread!(H, self.header, magic) == MAGIC_VALUE }
+    }
+
     fn set_guest_page_size(&mut self, guest_page_size: u32) {
         match self.version {
             MmioVersion::Legacy => {
@@ -428,7 +528,10 @@ impl<H: Hal> Transport for MmioTransport<H> {
                     )
                 );
                 let align = PAGE_SIZE as u32;
-                let pfn = (descriptors / PAGE_SIZE) as u32;
+                // The legacy interface only has a 32-bit page number, so the queue must be in the
+                // first 2^32 pages of guest physical memory.
+                let pfn = u32::try_from(descriptors / PAGE_SIZE)
+                    .expect("Legacy virtqueue page number doesn't fit in 32 bits");
                 assert_eq!(pfn as usize * PAGE_SIZE, descriptors);
                 // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
@@ -439,9 +542,20 @@ impl<H: Hal> Transport for MmioTransport<H> {
                 }
             }
             MmioVersion::Modern => {
+                // The size and addresses may only be written while the queue is disabled, and
+                // QueueReady must be written last, once they are all in place. A device may
+                // otherwise start using the queue with some of them still unset.
+                //
+                // Ref: 4.2.3.2 Virtqueue Configuration
                 // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
                     volwrite!(H, self.header, queue_sel, queue.into());
+                    assert_eq!(
+                        volread!(H, self.header, queue_ready),
+                        0,
+                        "Queue {} is already enabled",
+                        queue
+                    );
                     volwrite!(H, self.header, queue_num, size);
                     volwrite!(H, self.header, queue_desc_low, descriptors as u32);
                     volwrite!(H, self.header, queue_desc_high, (descriptors >> 32) as u32);
@@ -508,19 +622,77 @@ impl<H: Hal> Transport for MmioTransport<H> {
         }
     }
 
-    fn ack_interrupt(&mut self) -> bool {
+    fn reset_queue(&mut self, queue: u16) -> Result<(), Error> {
+        if self.negotiated_features() & RING_RESET == 0 {
+            return Err(Error::Unsupported);
+        }
+        // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe {
+            volwrite!(H, self.header, queue_sel, queue.into());
+            volwrite!(H, self.header, queue_reset, 1);
+            // Wait until the device has finished, when both QueueReset and QueueReady read zero.
+            while volread!(H, self.header, queue_reset) != 0 {
+                if !self.is_present() {
+                    return Err(Error::DeviceRemoved);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn can_reset_queue(&self) -> bool {
+        // Legacy devices only have 32 feature bits, so can't offer it anyway.
+        self.version == MmioVersion::Modern
+    }
+
+    fn ack_interrupt_status(&mut self) -> InterruptStatus {
         // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
             let interrupt = volread!(H, self.header, interrupt_status);
             if interrupt != 0 {
                 volwrite!(H, self.header, interrupt_ack, interrupt);
-                true
-            } else {
-                false
             }
+            InterruptStatus::from_bits_truncate(interrupt)
         }
     }
 
+    fn interrupt_pending(&self) -> bool {
+        // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe { volread!(H, self.header, interrupt_status) != 0 }
+    }
+
+    fn config_generation(&self) -> u32 {
+        match self.version {
+            // Legacy devices don't have a generation counter.
+            MmioVersion::Legacy => 0,
+            // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
+            MmioVersion::Modern => unsafe { volread!(H, self.header, config_generation) },
+        }
+    }
+
+    fn get_shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        // Shared memory regions were only added in the modern interface.
+        if self.version == MmioVersion::Legacy {
+            return None;
+        }
+        // SAFETY: Safe because self.header points to a valid VirtIO MMIO region.
+        let (len, base) = unsafe {
+            volwrite!(H, self.header, shm_sel, id.into());
+            let len = u64::from(volread!(H, self.header, shm_len_low))
+                | u64::from(volread!(H, self.header, shm_len_high)) << 32;
+            let base = u64::from(volread!(H, self.header, shm_base_low))
+                | u64::from(volread!(H, self.header, shm_base_high)) << 32;
+            (len, base)
+        };
+        if len == u64::MAX {
+            return None;
+        }
+        Some(SharedMemoryRegion {
+            base: base.try_into().ok()?,
+            len,
+        })
+    }
+
     fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
         if align_of::<T>() > 4 {
             // Panic as this should only happen if the driver is written incorrectly.
@@ -531,6 +703,12 @@ impl<H: Hal> Transport for MmioTransport<H> {
         }
         Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
     }
+
+    fn config_space_len(&self) -> usize {
+        // The size isn't advertised, but devices are laid out in register regions of
+        // MMIO_REGION_SIZE bytes, of which the config space is the rest after the header.
+        MMIO_REGION_SIZE - CONFIG_SPACE_OFFSET
+    }
 }
 
 impl<H: Hal> Drop for MmioTransport<H> {
@@ -539,3 +717,301 @@ impl<H: Hal> Drop for MmioTransport<H> {
         self.set_status(DeviceStatus::empty())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::{fake::FakeHal, BufferDirection};
+    use bitflags::bitflags;
+    use core::{cell::RefCell, mem::offset_of};
+    use zerocopy::{Immutable, IntoBytes};
+
+    bitflags! {
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        struct TestFeatures: u64 {
+            const FOO = 1 << 0;
+            const BAR = 1 << 1;
+            const VERSION_1 = 1 << 32;
+        }
+    }
+
+    #[test]
+    fn legacy_init() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 2, 0, 0b11, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(transport.version(), MmioVersion::Legacy);
+        assert!(transport.requires_legacy_layout());
+
+        // Legacy devices aren't expected to offer VERSION_1, even if the driver requires it.
+        assert_eq!(
+            transport.begin_init(
+                TestFeatures::FOO,
+                TestFeatures::FOO | TestFeatures::VERSION_1
+            ),
+            Ok(TestFeatures::FOO)
+        );
+        assert_eq!(transport.negotiated_features(), TestFeatures::FOO.bits());
+        // The fake header has a single features register, so it offers the same bits in both
+        // halves.
+        assert_eq!(transport.offered_features(), 0b11 << 32 | 0b11);
+        assert!(transport.supports(0));
+        assert!(!transport.supports(1));
+        assert!(!transport.supports(64));
+        assert_eq!(header.legacy_guest_page_size.0, PAGE_SIZE as u32);
+        // Legacy devices don't know about FEATURES_OK.
+        assert!(!header.status.0.contains(DeviceStatus::FEATURES_OK));
+
+        transport.finish_init();
+        assert_eq!(
+            header.status.0,
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::DRIVER_OK
+        );
+    }
+
+    #[test]
+    fn probe_slots() {
+        let mut slots = [
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4),
+            // An empty slot.
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 0, 0, 0, 4),
+            VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4),
+            VirtIOHeader::make_fake_header(3, 2, 0, 0, 4),
+            VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4),
+        ];
+        slots[2].magic = ReadOnly::new(0);
+        let base = NonNull::from(&mut slots).cast::<u8>();
+
+        // SAFETY: Every slot was created by `VirtIOHeader::make_fake_header()`.
+        let devices = unsafe { probe::<FakeHal>(base, size_of::<VirtIOHeader>(), slots.len()) }
+            .map(|(device_type, transport)| (device_type, transport.version()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            devices,
+            vec![
+                (DeviceType::Block, MmioVersion::Modern),
+                (DeviceType::Network, MmioVersion::Legacy),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_queue_set() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 2, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+
+        let descriptors = 0x42 * PAGE_SIZE;
+        let driver_area = descriptors + size_of::<Descriptor>() * 4;
+        let device_area = descriptors + PAGE_SIZE;
+        transport.queue_set(1, 4, descriptors, driver_area, device_area);
+        assert_eq!(header.queue_sel.0, 1);
+        assert_eq!(header.queue_num.0, 4);
+        assert_eq!(header.legacy_queue_align.0, PAGE_SIZE as u32);
+        assert_eq!(header.legacy_queue_pfn.0, 0x42);
+        // The modern registers must not be touched.
+        assert_eq!(header.queue_ready.0, 0);
+        assert_eq!(header.queue_desc_low.0, 0);
+        assert!(transport.queue_used(1));
+
+        transport.queue_unset(1);
+        assert_eq!(header.legacy_queue_pfn.0, 0);
+        assert!(!transport.queue_used(1));
+    }
+
+    thread_local! {
+        /// The offsets into the header of the registers written by `RecordingHal`, in order.
+        static WRITES: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// A HAL which records which MMIO registers are written.
+    struct RecordingHal;
+
+    impl RecordingHal {
+        /// Returns the offsets of the registers of `header` written since the last call.
+        fn take_writes(header: &VirtIOHeader) -> Vec<usize> {
+            let base = header as *const VirtIOHeader as usize;
+            WRITES.with_borrow_mut(|writes| writes.drain(..).map(|addr| addr - base).collect())
+        }
+    }
+
+    unsafe impl Hal for RecordingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        unsafe fn mmio_write<T>(dst: &mut T, value: T)
+        where
+            T: IntoBytes + Immutable,
+        {
+            WRITES.with_borrow_mut(|writes| writes.push(dst as *mut T as usize));
+            // SAFETY: Our caller promises that `dst` is a valid register.
+            unsafe { (dst as *mut T).write_volatile(value) }
+        }
+    }
+
+    #[test]
+    fn modern_queue_enable_order() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<RecordingHal>::new(NonNull::from(&mut header)) }.unwrap();
+        RecordingHal::take_writes(&header);
+
+        transport.queue_set(1, 4, 0x1000, 0x2000, 0x3000);
+        // The queue is selected first and enabled last, after everything else is programmed.
+        assert_eq!(
+            RecordingHal::take_writes(&header),
+            [
+                offset_of!(VirtIOHeader, queue_sel),
+                offset_of!(VirtIOHeader, queue_num),
+                offset_of!(VirtIOHeader, queue_desc_low),
+                offset_of!(VirtIOHeader, queue_desc_high),
+                offset_of!(VirtIOHeader, queue_driver_low),
+                offset_of!(VirtIOHeader, queue_driver_high),
+                offset_of!(VirtIOHeader, queue_device_low),
+                offset_of!(VirtIOHeader, queue_device_high),
+                offset_of!(VirtIOHeader, queue_ready),
+            ]
+        );
+        assert!(transport.queue_used(1));
+        RecordingHal::take_writes(&header);
+
+        // Disabling goes the other way round: the addresses are only cleared once the device has
+        // seen the queue disabled.
+        transport.queue_unset(1);
+        let writes = RecordingHal::take_writes(&header);
+        assert_eq!(
+            writes[..2],
+            [
+                offset_of!(VirtIOHeader, queue_sel),
+                offset_of!(VirtIOHeader, queue_ready),
+            ]
+        );
+        assert!(!writes[2..].contains(&offset_of!(VirtIOHeader, queue_ready)));
+        assert!(!transport.queue_used(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Queue 1 is already enabled")]
+    fn modern_queue_set_while_enabled() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        transport.queue_set(1, 4, 0x1000, 0x2000, 0x3000);
+        transport.queue_set(1, 4, 0x4000, 0x5000, 0x6000);
+    }
+
+    #[test]
+    fn is_present() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert!(transport.is_present());
+
+        // A surprise-removed device reads as all ones.
+        header.magic.0 = u32::MAX;
+        assert!(!transport.is_present());
+    }
+
+    #[test]
+    fn header_layout() {
+        assert_eq!(offset_of!(VirtIOHeader, shm_sel), 0xac);
+        assert_eq!(offset_of!(VirtIOHeader, shm_base_high), 0xbc);
+        assert_eq!(offset_of!(VirtIOHeader, queue_reset), 0xc0);
+        assert_eq!(offset_of!(VirtIOHeader, config_generation), 0xfc);
+    }
+
+    #[test]
+    fn interrupt_pending() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert!(!transport.interrupt_pending());
+        drop(transport);
+
+        header.interrupt_status = ReadOnly::new(1);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert!(transport.interrupt_pending());
+        // Checking doesn't acknowledge the interrupt.
+        assert!(transport.interrupt_pending());
+        assert!(transport.ack_interrupt());
+        drop(transport);
+        assert_eq!(header.interrupt_ack.0, 1);
+    }
+
+    #[test]
+    fn ack_interrupt_status() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        header.interrupt_status = ReadOnly::new(0b11);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            transport.ack_interrupt_status(),
+            InterruptStatus::QUEUE_INTERRUPT | InterruptStatus::DEVICE_CONFIGURATION_INTERRUPT
+        );
+        drop(transport);
+        assert_eq!(header.interrupt_ack.0, 0b11);
+    }
+
+    #[test]
+    fn shared_memory_region() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 26, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(0x20_0000);
+        header.shm_base_low = ReadOnly::new(0x4000_0000);
+        header.shm_base_high = ReadOnly::new(0x1);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+
+        assert_eq!(
+            transport.get_shared_memory_region(3),
+            Some(SharedMemoryRegion {
+                base: 0x1_4000_0000,
+                len: 0x20_0000,
+            })
+        );
+        assert_eq!(header.shm_sel.0, 3);
+    }
+
+    #[test]
+    fn shared_memory_region_missing() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 26, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(u32::MAX);
+        header.shm_len_high = ReadOnly::new(u32::MAX);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(transport.get_shared_memory_region(0), None);
+
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 26, 0, 0, 4);
+        // SAFETY: `header` was created by `VirtIOHeader::make_fake_header()`.
+        let mut transport =
+            unsafe { MmioTransport::<FakeHal>::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(transport.get_shared_memory_region(0), None);
+    }
+}
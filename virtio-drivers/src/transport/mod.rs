@@ -2,14 +2,22 @@
 
 //! VirtIO transports.
 
-#[cfg(test)]
+pub mod ccw;
+#[cfg(any(test, feature = "test-utils"))]
 pub mod fake;
+pub mod features;
 pub mod mmio;
 
-use crate::{PhysAddr, Result, PAGE_SIZE};
+use crate::device::common::Feature;
+use crate::{Error, Hal, PhysAddr, Result, PAGE_SIZE};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd, ptr::NonNull};
-use log::debug;
+use core::{
+    fmt::Debug,
+    mem::{align_of, size_of},
+    ops::BitAnd,
+    ptr::NonNull,
+};
+use log::{debug, warn};
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
 /// A VirtIO transport layer.
@@ -23,18 +31,84 @@ pub trait Transport {
     /// Writes device features.
     fn write_driver_features(&mut self, driver_features: u64);
 
+    /// Returns the features negotiated with the device, i.e. those last written with
+    /// `write_driver_features`.
+    fn negotiated_features(&self) -> u64;
+
+    /// Returns the features the device offered when they were last read with
+    /// `read_device_features`, before the driver dropped any it doesn't support.
+    ///
+    /// Comparing this with [`negotiated_features`](Self::negotiated_features) shows which
+    /// features the driver declined, e.g. when a device fails to initialise.
+    fn offered_features(&self) -> u64;
+
+    /// Returns whether the feature with the given bit number was negotiated with the device.
+    fn supports(&self, feature_bit: u32) -> bool {
+        feature_bit < u64::BITS && self.negotiated_features() & (1 << feature_bit) != 0
+    }
+
     /// Gets the max size of the given queue.
+    ///
+    /// This is read from the device without setting up the queue, so can be used to choose a queue
+    /// size before allocating it. Returns 0 if the queue isn't available.
     fn max_queue_size(&mut self, queue: u16) -> u32;
 
     /// Notifies the given queue on the device.
+    ///
+    /// Virtqueues issue [`Hal::mb`](crate::Hal::mb) before calling this, so the device sees the new
+    /// available index and any earlier config space writes by the time it is notified.
+    /// Implementations must not delay the notification past later accesses, nor let it overtake
+    /// their own earlier register writes.
     fn notify(&mut self, queue: u16);
 
+    /// Notifies the given queue on the device with the given notification data, which identifies
+    /// the queue in its low 16 bits and where the driver has got to in it in the rest. This is
+    /// used instead of [`notify`](Self::notify) once `VIRTIO_F_NOTIFICATION_DATA` has been
+    /// negotiated.
+    ///
+    /// The default implementation ignores the data and calls `notify`. Transports which don't
+    /// override it must return false from
+    /// [`can_notify_with_data`](Self::can_notify_with_data), so the feature is never negotiated.
+    ///
+    /// Ref: virtio 2.9 Driver Notifications
+    fn notify_with_data(&mut self, queue: u16, data: u32) {
+        let _ = data;
+        self.notify(queue);
+    }
+
+    /// Returns whether the transport can pass notification data to the device with
+    /// [`notify_with_data`](Self::notify_with_data). If not, `VIRTIO_F_NOTIFICATION_DATA` is never
+    /// negotiated.
+    ///
+    /// The default implementation returns false.
+    fn can_notify_with_data(&self) -> bool {
+        false
+    }
+
     /// Gets the device status.
     fn get_status(&self) -> DeviceStatus;
 
     /// Sets the device status.
     fn set_status(&mut self, status: DeviceStatus);
 
+    /// Returns whether the device must be reset before it can be used again, because it has set
+    /// `DEVICE_NEEDS_RESET` or the driver has given up on it and set `FAILED`.
+    fn needs_reset(&self) -> bool {
+        self.get_status()
+            .intersects(DeviceStatus::DEVICE_NEEDS_RESET | DeviceStatus::FAILED)
+    }
+
+    /// Returns whether the device is still there.
+    ///
+    /// Registers of a device which has been surprise-removed read as all ones, so this lets the
+    /// driver tell that apart from a device which needs a reset, and fail with
+    /// [`Error::DeviceRemoved`] rather than waiting for it.
+    ///
+    /// The default implementation returns true, for transports whose devices can't go away.
+    fn is_present(&self) -> bool {
+        true
+    }
+
     /// Sets the guest page size.
     fn set_guest_page_size(&mut self, guest_page_size: u32);
 
@@ -44,6 +118,10 @@ pub trait Transport {
     fn requires_legacy_layout(&self) -> bool;
 
     /// Sets up the given queue.
+    ///
+    /// The queue must not be in use already, as reported by [`queue_used`](Self::queue_used). Its
+    /// size and addresses are all programmed before it is enabled, so the device never sees it
+    /// half set up.
     fn queue_set(
         &mut self,
         queue: u16,
@@ -59,49 +137,335 @@ pub trait Transport {
     /// Returns whether the queue is in use, i.e. has a nonzero PFN or is marked as ready.
     fn queue_used(&mut self, queue: u16) -> bool;
 
+    /// Resets the given queue without resetting the rest of the device, and waits for the device
+    /// to finish. Afterwards the queue is disabled and can be set up again with
+    /// [`queue_set`](Self::queue_set).
+    ///
+    /// This may only be used once `VIRTIO_F_RING_RESET` has been negotiated, and returns
+    /// [`Error::Unsupported`] otherwise. The default implementation always returns
+    /// `Error::Unsupported`. Transports which don't override it must return false from
+    /// [`can_reset_queue`](Self::can_reset_queue), so the feature is never negotiated.
+    ///
+    /// Ref: virtio 2.6.1 Virtqueue Reset
+    fn reset_queue(&mut self, queue: u16) -> Result {
+        let _ = queue;
+        Err(Error::Unsupported)
+    }
+
+    /// Returns whether the transport can reset a single queue with
+    /// [`reset_queue`](Self::reset_queue). If not, `VIRTIO_F_RING_RESET` is never negotiated.
+    ///
+    /// The default implementation returns false.
+    fn can_reset_queue(&self) -> bool {
+        false
+    }
+
     /// Acknowledges an interrupt.
     ///
     /// Returns true on success.
-    fn ack_interrupt(&mut self) -> bool;
+    fn ack_interrupt(&mut self) -> bool {
+        !self.ack_interrupt_status().is_empty()
+    }
+
+    /// Acknowledges an interrupt, and returns what it was raised for.
+    ///
+    /// Returns an empty status if there was no interrupt to acknowledge.
+    fn ack_interrupt_status(&mut self) -> InterruptStatus;
+
+    /// Returns whether the device has raised an interrupt which hasn't been acknowledged yet,
+    /// without acknowledging it.
+    ///
+    /// This can be used to check several devices for work before deciding which to service.
+    fn interrupt_pending(&self) -> bool;
 
     /// Begins initializing the device.
     ///
     /// Ref: virtio 3.1.1 Device Initialization
     ///
-    /// Returns the negotiated set of features.
+    /// Returns the negotiated set of features, or [`Error::FeatureNegotiationFailed`] with the
+    /// missing bits if the device doesn't offer all of `required_features`. `VIRTIO_F_VERSION_1`
+    /// is only required on transports which don't use the legacy layout, as legacy devices never
+    /// offer it. `VIRTIO_F_IN_ORDER` isn't negotiated along with `VIRTIO_F_RING_PACKED`.
     fn begin_init<F: Flags<Bits = u64> + BitAnd<Output = F> + Debug>(
         &mut self,
         supported_features: F,
-    ) -> F {
+        required_features: F,
+    ) -> Result<F> {
+        self.begin_init_with_mask(supported_features, required_features, 0)
+    }
+
+    /// Like [`begin_init`](Self::begin_init), but treats the device as not offering any of the
+    /// feature bits in `forbidden_features`, so they are never negotiated.
+    ///
+    /// This can be used to force a device into a simpler mode, e.g. to work around a VMM which
+    /// doesn't implement some feature correctly. Required features are checked after the mask is
+    /// applied, so forbidding one of them fails with [`Error::FeatureNegotiationFailed`].
+    fn begin_init_with_mask<F: Flags<Bits = u64> + BitAnd<Output = F> + Debug>(
+        &mut self,
+        supported_features: F,
+        required_features: F,
+        forbidden_features: u64,
+    ) -> Result<F> {
+        self.begin_init_with_config(
+            supported_features,
+            required_features,
+            forbidden_features,
+            &[],
+        )
+    }
+
+    /// Like [`begin_init_with_mask`](Self::begin_init_with_mask), but also treats the device as
+    /// not offering any feature in `config_fields` whose config space fields don't fit within
+    /// [`config_space_len`](Self::config_space_len). Each feature is given with the byte offset of
+    /// the end of the last field it needs.
+    ///
+    /// This lets a driver work with a device whose config space stops short of the fields for
+    /// optional features, rather than failing with [`Error::ConfigSpaceTooSmall`] when it reads
+    /// them.
+    fn begin_init_with_config<F: Flags<Bits = u64> + BitAnd<Output = F> + Debug>(
+        &mut self,
+        supported_features: F,
+        required_features: F,
+        forbidden_features: u64,
+        config_fields: &[(F, usize)],
+    ) -> Result<F> {
         self.set_status(DeviceStatus::empty());
         self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
 
-        let device_features = F::from_bits_truncate(self.read_device_features());
+        let mut forbidden_features = forbidden_features;
+        if !self.can_notify_with_data() {
+            forbidden_features |= Feature::NOTIFICATION_DATA.bits();
+        }
+        if !self.can_reset_queue() {
+            forbidden_features |= Feature::RING_RESET.bits();
+        }
+        let offered_features = self.read_device_features() & !forbidden_features;
+        // Packed queues don't handle the batched used descriptors which `VIRTIO_F_IN_ORDER` allows,
+        // so prefer the packed layout to in-order use if the device offers both.
+        if offered_features & supported_features.bits() & Feature::RING_PACKED.bits() != 0 {
+            forbidden_features |= Feature::IN_ORDER.bits();
+        }
+        if !config_fields.is_empty() {
+            let config_len = self.config_space_len();
+            let short_features = config_fields
+                .iter()
+                .filter(|(_, end)| *end > config_len)
+                .fold(0, |bits, (feature, _)| bits | feature.bits())
+                & offered_features;
+            if short_features != 0 {
+                warn!(
+                    "Config space of {} bytes is too small for features {:?}",
+                    config_len,
+                    F::from_bits_retain(short_features)
+                );
+                forbidden_features |= short_features;
+            }
+        }
+        let device_features = F::from_bits_truncate(offered_features & !forbidden_features);
         debug!("Device features: {:?}", device_features);
+
+        let mut required_features = required_features.bits();
+        if self.requires_legacy_layout() {
+            required_features &= !Feature::VERSION_1.bits();
+        }
+        let missing_features = required_features & !device_features.bits();
+        if missing_features != 0 {
+            warn!(
+                "Device doesn't offer required features {:?}",
+                F::from_bits_retain(missing_features)
+            );
+            self.set_status(DeviceStatus::FAILED);
+            return Err(Error::FeatureNegotiationFailed(missing_features));
+        }
+
         let negotiated_features = device_features & supported_features;
         self.write_driver_features(negotiated_features.bits());
 
-        self.set_status(
-            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
-        );
+        // Legacy devices don't have the FEATURES_OK status bit, they take the features as written.
+        if !self.requires_legacy_layout() {
+            self.set_status(
+                DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
+            );
+            // The device clears FEATURES_OK again if it doesn't support the subset of its features
+            // which we chose.
+            if !self.get_status().contains(DeviceStatus::FEATURES_OK) {
+                warn!("Device didn't accept features {:?}", negotiated_features);
+                self.set_status(DeviceStatus::FAILED);
+                return Err(Error::FeaturesNotAccepted);
+            }
+        }
 
         self.set_guest_page_size(PAGE_SIZE as u32);
 
-        negotiated_features
+        Ok(negotiated_features)
     }
 
     /// Finishes initializing the device.
     fn finish_init(&mut self) {
-        self.set_status(
-            DeviceStatus::ACKNOWLEDGE
-                | DeviceStatus::DRIVER
-                | DeviceStatus::FEATURES_OK
-                | DeviceStatus::DRIVER_OK,
-        );
+        let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::DRIVER_OK;
+        if self.requires_legacy_layout() {
+            self.set_status(status);
+        } else {
+            self.set_status(status | DeviceStatus::FEATURES_OK);
+        }
     }
 
+    /// Gets the shared memory region with the given ID, if the device has one.
+    ///
+    /// Ref: virtio 2.10 Shared Memory Regions
+    fn get_shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion>;
+
     /// Gets the pointer to the config space.
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+
+    /// Returns the size of the config space in bytes.
+    ///
+    /// Transports which don't advertise the size return the largest the config space can be.
+    fn config_space_len(&self) -> usize;
+
+    /// Returns the byte order of multi-byte fields in the config space, and in the virtqueues.
+    ///
+    /// This is little endian, except for legacy devices which use the driver's native byte order.
+    ///
+    /// Ref: virtio 2.5.3 Legacy Interface: A Note on Device Configuration Space endian-ness
+    fn config_endian(&self) -> Endian {
+        if self.requires_legacy_layout() {
+            Endian::NATIVE
+        } else {
+            Endian::Little
+        }
+    }
+
+    /// Reads a value from the config space at the given byte offset, retrying like
+    /// [`read_config_space_atomic`](Self::read_config_space_atomic) so that it isn't read torn.
+    ///
+    /// Returns [`Error::ConfigSpaceTooSmall`] if the value doesn't fit within
+    /// [`config_space_len`](Self::config_space_len), or [`Error::InvalidParam`] if the offset isn't
+    /// aligned for `V`.
+    ///
+    /// Multi-byte values are converted from the device's [`config_endian`](Self::config_endian)
+    /// byte order.
+    fn read_config<H: Hal, V: ConfigValue>(&self, offset: usize) -> Result<V> {
+        let field = config_field::<V>(self, offset)?;
+        // SAFETY: The field is aligned and within the config space.
+        let value = self.read_config_space_atomic(|| unsafe { H::mmio_read(field.as_ref()) });
+        Ok(convert_endian(value, self.config_endian(), Endian::NATIVE))
+    }
+
+    /// Writes a value to the config space at the given byte offset.
+    ///
+    /// The same errors are returned as for [`read_config`](Self::read_config). The device must
+    /// allow the driver to write to that part of the config space.
+    fn write_config<H: Hal, V: ConfigValue>(&mut self, offset: usize, value: V) -> Result {
+        let mut field = config_field::<V>(self, offset)?;
+        let value = convert_endian(value, Endian::NATIVE, self.config_endian());
+        // SAFETY: The field is aligned and within the config space.
+        unsafe { H::mmio_write(field.as_mut(), value) };
+        Ok(())
+    }
+
+    /// Reads the config generation counter, which the device changes whenever the config space
+    /// changes.
+    ///
+    /// Transports without a generation counter return 0.
+    fn config_generation(&self) -> u32;
+
+    /// Calls `reader` to read from the config space, retrying until the config generation is the
+    /// same before and after, so that fields wider than a single register aren't read torn.
+    ///
+    /// Ref: virtio 2.5.1 Driver Requirements: Device Configuration Space
+    fn read_config_space_atomic<R>(&self, mut reader: impl FnMut() -> R) -> R {
+        loop {
+            let before = self.config_generation();
+            let value = reader();
+            if self.config_generation() == before {
+                return value;
+            }
+        }
+    }
+}
+
+/// Returns a pointer to the config space field of type `V` at the given byte offset, checking that
+/// it is aligned and within the config space.
+fn config_field<V>(transport: &(impl Transport + ?Sized), offset: usize) -> Result<NonNull<V>> {
+    // VirtIO only guarantees 4 byte alignment of the config space.
+    if offset % align_of::<V>() != 0 || align_of::<V>() > 4 || size_of::<V>() == 0 {
+        return Err(Error::InvalidParam);
+    }
+    let end = offset
+        .checked_add(size_of::<V>())
+        .ok_or(Error::ConfigSpaceTooSmall)?;
+    if end > transport.config_space_len() {
+        return Err(Error::ConfigSpaceTooSmall);
+    }
+    let config = transport.config_space::<u8>()?;
+    // SAFETY: We just checked that the offset is within the config space.
+    Ok(unsafe { config.add(offset) }.cast())
+}
+
+/// The byte order of multi-byte fields.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Endian {
+    /// Least significant byte first.
+    Little,
+    /// Most significant byte first.
+    Big,
+}
+
+impl Endian {
+    /// The byte order of the CPU the driver is running on.
+    pub const NATIVE: Self = if cfg!(target_endian = "big") {
+        Self::Big
+    } else {
+        Self::Little
+    };
+}
+
+/// A type of config space field which [`Transport::read_config`] and [`Transport::write_config`]
+/// can convert between the device's byte order and the driver's.
+pub trait ConfigValue: FromBytes + IntoBytes + Immutable {
+    /// Reverses the order of the bytes of the value, if it has more than one.
+    fn swap_bytes(self) -> Self;
+}
+
+macro_rules! impl_config_value {
+    ($($ty:ty),*) => {
+        $(
+            impl ConfigValue for $ty {
+                fn swap_bytes(self) -> Self {
+                    <$ty>::swap_bytes(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_config_value!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Arrays of bytes, such as strings, are the same in either byte order.
+impl<const N: usize> ConfigValue for [u8; N] {
+    fn swap_bytes(self) -> Self {
+        self
+    }
+}
+
+/// Converts a value from byte order `from` to byte order `to`.
+pub(crate) fn convert_endian<V: ConfigValue>(value: V, from: Endian, to: Endian) -> V {
+    if from == to {
+        value
+    } else {
+        value.swap_bytes()
+    }
+}
+
+/// A shared memory region advertised by a device, which is memory on the device side that the
+/// driver can access directly.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The guest physical address of the start of the region.
+    pub base: PhysAddr,
+    /// The length of the region in bytes.
+    pub len: u64,
 }
 
 /// DeviceStatus
@@ -137,6 +501,17 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The reasons for which a device raised an interrupt.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct InterruptStatus: u32 {
+        /// The device has used a buffer in at least one of its virtqueues.
+        const QUEUE_INTERRUPT = 1 << 0;
+        /// The device configuration has changed.
+        const DEVICE_CONFIGURATION_INTERRUPT = 1 << 1;
+    }
+}
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -166,6 +541,8 @@ pub enum DeviceType {
     IOMMU = 23,
     Memory = 24,
     Sound = 25,
+    FileSystem = 26,
+    PersistentMemory = 27,
 }
 
 impl From<u32> for DeviceType {
@@ -194,6 +571,8 @@ impl From<u32> for DeviceType {
             23 => DeviceType::IOMMU,
             24 => DeviceType::Memory,
             25 => DeviceType::Sound,
+            26 => DeviceType::FileSystem,
+            27 => DeviceType::PersistentMemory,
             _ => DeviceType::Invalid,
         }
     }
@@ -210,3 +589,200 @@ impl From<u8> for DeviceType {
         u32::from(virtio_device_id).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hal::fake::FakeHal, transport::fake::FakeTransport};
+
+    #[test]
+    fn read_config_space_atomic_retries() {
+        let mut config_space = ();
+        let (transport, state) = FakeTransport::new(DeviceType::Block, 4, 0, 0, &mut config_space);
+
+        // Simulate the device changing its config space during the first read.
+        let mut reads = 0;
+        let value = transport.read_config_space_atomic(|| {
+            reads += 1;
+            if reads == 1 {
+                state.lock().unwrap().config_generation += 1;
+            }
+            reads
+        });
+        assert_eq!(value, 2);
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn read_write_config() {
+        let mut config_space = [0x1234_5678u32, 0];
+        let (mut transport, _) = FakeTransport::new(DeviceType::Block, 4, 0, 0, &mut config_space);
+
+        assert_eq!(transport.read_config::<FakeHal, u32>(0), Ok(0x1234_5678));
+        assert_eq!(transport.read_config::<FakeHal, u16>(2), Ok(0x1234));
+        assert_eq!(transport.write_config::<FakeHal, u32>(4, 42), Ok(()));
+        assert_eq!(transport.read_config::<FakeHal, u32>(4), Ok(42));
+
+        // Unaligned and out of bounds accesses are rejected.
+        assert_eq!(
+            transport.read_config::<FakeHal, u32>(2),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            transport.read_config::<FakeHal, u32>(8),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(
+            transport.write_config::<FakeHal, [u8; 6]>(4, [0; 6]),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        drop(transport);
+        assert_eq!(config_space, [0x1234_5678, 42]);
+    }
+
+    #[test]
+    fn convert_config_endian() {
+        // A little endian modern device, read by a little endian and a big endian driver.
+        let raw = u32::from_le_bytes([0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(
+            convert_endian(raw, Endian::Little, Endian::Little),
+            0x1234_5678
+        );
+        let raw = u32::from_be_bytes([0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(
+            convert_endian(raw, Endian::Little, Endian::Big),
+            0x1234_5678
+        );
+
+        // A legacy device uses the driver's byte order, so nothing is converted either way.
+        assert_eq!(
+            convert_endian(0x1234_5678u32, Endian::Big, Endian::Big),
+            0x1234_5678
+        );
+        assert_eq!(
+            convert_endian(0x1234_5678u32, Endian::Little, Endian::Little),
+            0x1234_5678
+        );
+
+        // Writing converts back again.
+        assert_eq!(
+            convert_endian(0x1234u16, Endian::Big, Endian::Little).to_le_bytes(),
+            [0x12, 0x34]
+        );
+        assert_eq!(convert_endian(0x12u8, Endian::Big, Endian::Little), 0x12);
+        assert_eq!(
+            convert_endian(*b"tag", Endian::Big, Endian::Little),
+            *b"tag"
+        );
+    }
+
+    #[test]
+    fn modern_config_endian() {
+        let mut config_space = [0u32];
+        let (transport, _) = FakeTransport::new(DeviceType::Block, 4, 0, 0, &mut config_space);
+        assert_eq!(transport.config_endian(), Endian::Little);
+    }
+
+    #[test]
+    fn begin_init_with_mask() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            0,
+            (Feature::RING_INDIRECT_DESC | Feature::RING_EVENT_IDX | Feature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let supported = Feature::RING_INDIRECT_DESC | Feature::RING_EVENT_IDX | Feature::VERSION_1;
+
+        assert_eq!(
+            transport.begin_init_with_mask(
+                supported,
+                Feature::VERSION_1,
+                Feature::RING_EVENT_IDX.bits()
+            ),
+            Ok(Feature::RING_INDIRECT_DESC | Feature::VERSION_1)
+        );
+        assert_eq!(
+            transport.negotiated_features(),
+            (Feature::RING_INDIRECT_DESC | Feature::VERSION_1).bits()
+        );
+        // The masked feature is still reported as offered.
+        assert_eq!(transport.offered_features(), supported.bits());
+
+        // Forbidding a required feature fails negotiation.
+        assert_eq!(
+            transport.begin_init_with_mask(
+                supported,
+                Feature::VERSION_1,
+                Feature::VERSION_1.bits()
+            ),
+            Err(Error::FeatureNegotiationFailed(Feature::VERSION_1.bits()))
+        );
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::FAILED);
+    }
+
+    #[test]
+    fn begin_init_with_config() {
+        let mut config_space = [0u32; 2];
+        let (mut transport, _) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            0,
+            (Feature::RING_INDIRECT_DESC | Feature::RING_EVENT_IDX | Feature::VERSION_1).bits(),
+            &mut config_space,
+        );
+        let supported = Feature::RING_INDIRECT_DESC | Feature::RING_EVENT_IDX | Feature::VERSION_1;
+
+        // Only the feature whose field would end past the 8 byte config space is dropped.
+        assert_eq!(
+            transport.begin_init_with_config(
+                supported,
+                Feature::VERSION_1,
+                0,
+                &[
+                    (Feature::RING_INDIRECT_DESC, 8),
+                    (Feature::RING_EVENT_IDX, 12)
+                ]
+            ),
+            Ok(Feature::RING_INDIRECT_DESC | Feature::VERSION_1)
+        );
+    }
+
+    #[test]
+    fn begin_init_packed_not_in_order() {
+        let features = Feature::RING_PACKED | Feature::IN_ORDER | Feature::VERSION_1;
+        let (mut transport, _state) =
+            FakeTransport::new(DeviceType::Block, 4, 1, features.bits(), &mut ());
+
+        // The packed layout is preferred to in-order use.
+        assert_eq!(
+            transport.begin_init(features, Feature::VERSION_1),
+            Ok(Feature::RING_PACKED | Feature::VERSION_1)
+        );
+        // A driver which doesn't support packed queues still gets in-order use.
+        assert_eq!(
+            transport.begin_init(Feature::IN_ORDER | Feature::VERSION_1, Feature::VERSION_1),
+            Ok(Feature::IN_ORDER | Feature::VERSION_1)
+        );
+    }
+
+    #[test]
+    fn begin_init_features_not_accepted() {
+        let mut config_space = ();
+        let (mut transport, state) = FakeTransport::new(
+            DeviceType::Block,
+            4,
+            0,
+            Feature::VERSION_1.bits(),
+            &mut config_space,
+        );
+        state.lock().unwrap().reject_features = true;
+
+        assert_eq!(
+            transport.begin_init(Feature::VERSION_1, Feature::VERSION_1),
+            Err(Error::FeaturesNotAccepted)
+        );
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::FAILED);
+    }
+}
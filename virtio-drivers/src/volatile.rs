@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: MIT
 
-use crate::Hal;
+use crate::{Error, Hal, Result};
+use core::ptr::NonNull;
+
 /// An MMIO register which can only be read from.
 #[derive(Default)]
 #[repr(transparent)]
@@ -21,7 +23,7 @@ pub struct WriteOnly<T: IntoBytes + Immutable>(pub(crate) T);
 /// An MMIO register which may be both read and written.
 #[derive(Default)]
 #[repr(transparent)]
-pub struct Volatile<T: FromBytes + IntoBytes + Immutable>(T);
+pub struct Volatile<T: FromBytes + IntoBytes + Immutable>(pub(crate) T);
 
 impl<T: FromBytes + IntoBytes + Immutable> Volatile<T> {
     /// Construct a new instance for testing.
@@ -135,6 +137,76 @@ macro_rules! volwrite {
     };
 }
 
+/// Copies the first `out.len()` bytes of the MMIO region `src` into `out`, with a volatile read of
+/// each byte.
+///
+/// Returns [`Error::InvalidParam`] if `out` is longer than the region.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of its whole length.
+pub(crate) unsafe fn volatile_read_bytes<H: Hal>(src: NonNull<[u8]>, out: &mut [u8]) -> Result {
+    if out.len() > src.len() {
+        return Err(Error::InvalidParam);
+    }
+    let src = src.cast::<u8>();
+    for (i, byte) in out.iter_mut().enumerate() {
+        // SAFETY: The byte is within the region, which our caller promises is valid for reads.
+        *byte = unsafe { H::mmio_read(src.add(i).as_ref()) };
+    }
+    Ok(())
+}
+
+/// Copies `data` into the start of the MMIO region `dst`, with a volatile write of each byte.
+///
+/// Returns [`Error::InvalidParam`] if `data` is longer than the region.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of its whole length.
+pub(crate) unsafe fn volatile_write_bytes<H: Hal>(dst: NonNull<[u8]>, data: &[u8]) -> Result {
+    if data.len() > dst.len() {
+        return Err(Error::InvalidParam);
+    }
+    let dst = dst.cast::<u8>();
+    for (i, &byte) in data.iter().enumerate() {
+        // SAFETY: The byte is within the region, which our caller promises is valid for writes.
+        unsafe { H::mmio_write(dst.add(i).as_mut(), byte) };
+    }
+    Ok(())
+}
+
 pub(crate) use volread;
 pub(crate) use volwrite;
 use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+
+    #[test]
+    fn read_write_bytes() {
+        let mut region = [0u8; 8];
+        let ptr = NonNull::from(&mut region[..]);
+
+        // SAFETY: The region is a valid, unaliased buffer for the duration of the test.
+        unsafe {
+            assert_eq!(volatile_write_bytes::<FakeHal>(ptr, b"abc"), Ok(()));
+            let mut out = [0; 4];
+            assert_eq!(volatile_read_bytes::<FakeHal>(ptr, &mut out), Ok(()));
+            assert_eq!(&out, b"abc\0");
+
+            // Copies which don't fit in the region are rejected without touching it.
+            assert_eq!(
+                volatile_write_bytes::<FakeHal>(ptr, &[0xff; 9]),
+                Err(Error::InvalidParam)
+            );
+            assert_eq!(
+                volatile_read_bytes::<FakeHal>(ptr, &mut [0; 9]),
+                Err(Error::InvalidParam)
+            );
+        }
+        assert_eq!(&region, b"abc\0\0\0\0\0");
+    }
+}